@@ -0,0 +1,126 @@
+//! Builds the manifest of static assets, pre-compressing them so they can
+//! be served with gzip or Brotli content encoding without compressing at
+//! request time.
+//!
+//! For every file under `static/`, `{path}.gz` and `{path}.br` are written
+//! to `OUT_DIR`, along with `static_assets.rs` listing each file as a
+//! `StaticAsset` in `STATIC_ASSETS`, sorted by its path relative to
+//! `static/`. Entries carry the file's content type and a hash of its
+//! content for ETags. All are embedded with `include_bytes!`, so assets may
+//! be binary.
+//!
+//! Content types come from the file's extension, see [`CONTENT_TYPES`]. A
+//! file can be given another one with a `{name}.content-type` file next to
+//! it, holding the content type; these files aren't served themselves.
+
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Content types of the known file extensions. Others are served as
+/// `application/octet-stream`.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("css", "text/css; charset=utf-8"),
+    ("gif", "image/gif"),
+    ("html", "text/html; charset=utf-8"),
+    ("ico", "image/x-icon"),
+    ("jpg", "image/jpeg"),
+    ("js", "application/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("wasm", "application/wasm"),
+    ("webp", "image/webp"),
+    ("woff2", "font/woff2"),
+];
+
+/// Suffix of the files overriding the content type of another.
+const OVERRIDE_SUFFIX: &str = ".content-type";
+
+/// Collects the paths of the files under `dir`, relative to the static
+/// directory, using `/` as separator.
+fn collect(dir: &Path, prefix: &str, paths: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().into_string().unwrap();
+        let path = format!("{prefix}{name}");
+        if entry.file_type().unwrap().is_dir() {
+            collect(&entry.path(), &format!("{path}/"), paths);
+        } else if !path.ends_with(OVERRIDE_SUFFIX) {
+            paths.push(path);
+        }
+    }
+}
+
+/// Returns the content type of the file at `path`, relative to `dir`.
+fn content_type(dir: &Path, path: &str) -> String {
+    if let Ok(ctype) = fs::read_to_string(dir.join(format!("{path}{OVERRIDE_SUFFIX}"))) {
+        return ctype.trim().to_string();
+    }
+
+    let ext = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .unwrap_or_default();
+    CONTENT_TYPES
+        .iter()
+        .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+        .map_or("application/octet-stream", |(_, ctype)| ctype)
+        .to_string()
+}
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let out_dir = Path::new(&out_dir);
+    let static_dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("static");
+
+    println!("cargo:rerun-if-changed=static");
+
+    let mut paths = Vec::new();
+    collect(&static_dir, "", &mut paths);
+    paths.sort();
+
+    let mut table = String::from("static STATIC_ASSETS: &[StaticAsset] = &[\n");
+
+    for path in &paths {
+        let src = static_dir.join(path);
+        let data = fs::read(&src).unwrap();
+
+        let hash: String = Sha256::digest(&data)[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let gz_path = out_dir.join(format!("{path}.gz"));
+        fs::create_dir_all(gz_path.parent().unwrap()).unwrap();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gz.write_all(&data).unwrap();
+        fs::write(&gz_path, gz.finish().unwrap()).unwrap();
+
+        let br_path = out_dir.join(format!("{path}.br"));
+        let mut br = Vec::new();
+        {
+            let mut w = brotli::CompressorWriter::new(&mut br, 4096, 11, 22);
+            w.write_all(&data).unwrap();
+        }
+        fs::write(&br_path, br).unwrap();
+
+        table.push_str(&format!(
+            "    StaticAsset {{ path: {:?}, content_type: {:?}, hash: {:?}, data: include_bytes!({:?}), gzip: include_bytes!({:?}), brotli: include_bytes!({:?}) }},\n",
+            path,
+            content_type(&static_dir, path),
+            hash,
+            src.display().to_string(),
+            gz_path.display().to_string(),
+            br_path.display().to_string(),
+        ));
+    }
+
+    table.push_str("];\n");
+
+    fs::write(out_dir.join("static_assets.rs"), table).unwrap();
+}
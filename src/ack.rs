@@ -0,0 +1,152 @@
+//! Acknowledged WebSocket messages.
+//!
+//! GRIP delivers published messages at most once, and doesn't tell the
+//! publisher whether anyone got them. For applications needing more, a
+//! TEXT `ws-message` can be published with an acknowledgement request: its
+//! content is wrapped in an envelope naming the message,
+//!
+//! ```json
+//! {"type": "message", "id": "5f2b...", "channel": "news", "ack": true, "content": "hello"}
+//! ```
+//!
+//! and clients receiving it answer with
+//!
+//! ```json
+//! {"type": "ack", "id": "5f2b...", "channel": "news"}
+//! ```
+//!
+//! Acks arrive with the client's later WebSocket events. Each one from a
+//! connection subscribed to the channel is confirmed by publishing to the
+//! channel's confirmation channel, `{channel}.acks`:
+//!
+//! ```json
+//! {"type": "ack", "id": "5f2b...", "channel": "news", "connection": "b7c1...", "time": 1700000000}
+//! ```
+//!
+//! Publishers subscribed to it can republish messages that go unconfirmed,
+//! giving at-least-once delivery to the clients that ack.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::grip::unix_now;
+use crate::history;
+use crate::log_info;
+use crate::publish::Item;
+use crate::sse::SseEvent;
+use crate::ws::WsContext;
+
+/// Suffix of confirmation channel names.
+pub const CONFIRM_SUFFIX: &str = ".acks";
+
+/// Returns the channel acks of messages published to `channel` are
+/// confirmed on.
+pub fn confirmation_channel(channel: &str) -> String {
+    format!("{}{}", channel, CONFIRM_SUFFIX)
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: &'a str,
+    channel: &'a str,
+    ack: bool,
+    content: &'a str,
+}
+
+/// An acknowledgement sent by a client.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Ack {
+    pub id: String,
+    pub channel: String,
+}
+
+/// Returns `item` with its TEXT `ws-message` wrapped in an envelope
+/// requesting acknowledgement, under the item's id, or a new one if it has
+/// none. Items without TEXT content for WebSocket connections are returned
+/// as they are.
+pub fn request(mut item: Item) -> Item {
+    let id = item.id.get_or_insert_with(history::new_id).clone();
+
+    if let Some(ws) = &mut item.formats.ws_message {
+        if let Some(content) = &ws.content {
+            let envelope = Envelope {
+                kind: "message",
+                id: &id,
+                channel: &item.channel,
+                ack: true,
+                content,
+            };
+            ws.content =
+                Some(serde_json::to_string(&envelope).expect("envelopes always serialize"));
+        }
+    }
+
+    item
+}
+
+/// Parses a TEXT message from a client as an ack, if it is one.
+pub fn parse(text: &str) -> Option<Ack> {
+    #[derive(Deserialize)]
+    struct Message {
+        #[serde(rename = "type")]
+        kind: String,
+        #[serde(flatten)]
+        ack: Ack,
+    }
+
+    // skip parsing the messages that clearly aren't acks
+    if !text.trim_start().starts_with('{') || !text.contains("\"ack\"") {
+        return None;
+    }
+
+    serde_json::from_str::<Message>(text)
+        .ok()
+        .filter(|m| m.kind == "ack")
+        .map(|m| m.ack)
+}
+
+/// Confirms an ack from the connection of `ctx`, if the connection is
+/// subscribed to the acked message's channel. Returns whether it was
+/// confirmed.
+pub fn confirm(ctx: &mut WsContext, ack: &Ack) -> bool {
+    if !ctx.session().channels.contains(&ack.channel) {
+        log_info!("ignoring ack for unsubscribed channel {}", ack.channel);
+        return false;
+    }
+
+    let confirmation = json!({
+        "type": "ack",
+        "id": ack.id,
+        "channel": ack.channel,
+        "connection": ctx.connection_id,
+        "time": unix_now(),
+    })
+    .to_string();
+
+    let channel = confirmation_channel(&ack.channel);
+    let item = Item::new(channel.as_str())
+        .http_stream(SseEvent::new(&confirmation).with_event("ack").encode())
+        .http_response(confirmation.as_str())
+        .ws_message(confirmation.as_str());
+
+    ctx.publish(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_messages_under_their_item_id() {
+        let item = request(Item::new("news").with_id("7").ws_message("hello"));
+        assert_eq!(item.id.as_deref(), Some("7"));
+
+        let content = item.formats.ws_message.and_then(|ws| ws.content).unwrap();
+        assert_eq!(
+            content,
+            r#"{"type":"message","id":"7","channel":"news","ack":true,"content":"hello"}"#
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Client IP allow and deny lists.
+//!
+//! Each kind of request has its own pair of lists in the `fanout_config`
+//! Config Store, `acl_{list}_allow` and `acl_{list}_deny`, holding
+//! comma-separated CIDR blocks or single addresses, such as
+//! `10.0.0.0/8, 2001:db8::/32, 192.0.2.1`. A client matching the deny list
+//! is refused. Otherwise, if there is an allow list, the client must match
+//! it. Without either list all clients are allowed.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::config;
+use crate::log_warn;
+
+/// The requests a pair of lists applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum List {
+    /// The test endpoints.
+    Test,
+    /// The publish and webhook endpoints.
+    Publish,
+    /// Requests proxied to backends.
+    Proxy,
+}
+
+impl List {
+    fn as_str(&self) -> &'static str {
+        match self {
+            List::Test => "test",
+            List::Publish => "publish",
+            List::Proxy => "proxy",
+        }
+    }
+}
+
+/// A block of IP addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+/// Error parsing a CIDR block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CidrError {
+    BadAddress(String),
+    BadPrefixLength(String),
+}
+
+impl fmt::Display for CidrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CidrError::BadAddress(s) => write!(f, "invalid address: {}", s),
+            CidrError::BadPrefixLength(s) => write!(f, "invalid prefix length: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for CidrError {}
+
+impl Cidr {
+    /// Parses `addr/prefix-len`, or a single address.
+    pub fn parse(s: &str) -> Result<Self, CidrError> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| CidrError::BadAddress(addr.to_string()))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| CidrError::BadPrefixLength(len.to_string()))?,
+            None => max_len,
+        };
+
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    /// Returns whether the block contains `ip`.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+
+        let (net, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (
+                u128::from(u32::from(net)) << 96,
+                u128::from(u32::from(ip)) << 96,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), self.prefix_len),
+            _ => return false,
+        };
+
+        let mask = u128::MAX.checked_shl(128 - u32::from(bits)).unwrap_or(0);
+        net & mask == ip & mask
+    }
+}
+
+/// Parses a comma-separated list of CIDR blocks, skipping invalid ones.
+pub fn parse_list(value: &str) -> Vec<Cidr> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| match Cidr::parse(s) {
+            Ok(cidr) => Some(cidr),
+            Err(e) => {
+                log_warn!("ignoring ACL entry {s:?}: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+fn list_setting(list: List, kind: &str) -> Option<Vec<Cidr>> {
+    config::setting(&format!("acl_{}_{}", list.as_str(), kind)).map(|v| parse_list(&v))
+}
+
+/// Returns whether the client at `ip` may make requests of the given kind.
+/// Clients with unknown addresses are only allowed if there are no lists.
+pub fn allowed(list: List, ip: Option<IpAddr>) -> bool {
+    let deny = list_setting(list, "deny");
+    let allow = list_setting(list, "allow");
+
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return deny.is_none() && allow.is_none(),
+    };
+
+    if deny.is_some_and(|deny| deny.iter().any(|c| c.contains(ip))) {
+        return false;
+    }
+
+    match allow {
+        Some(allow) => allow.iter().any(|c| c.contains(ip)),
+        None => true,
+    }
+}
@@ -0,0 +1,155 @@
+//! Channel activity, and closing channels left idle.
+//!
+//! Every channel keeps some state in the state KV Store, its history and
+//! presence among others, which nothing removes once it goes quiet. The
+//! last time each channel was active (published to, subscribed to by a
+//! WebSocket connection, or kept alive by one) is tracked under
+//! `activity:channels`, and a sweep, made by `POST /admin/sweep` from a cron
+//! job, publishes a close to the channels idle for longer than
+//! `channel_idle_ttl` seconds and forgets their history and presence.
+//!
+//! SSE streams and long-polls don't count as activity by themselves, as the
+//! app doesn't hear from them once held. Times are only rewritten once they
+//! are a minute old, so busy channels don't cost a write on every event,
+//! and with a read-modify-write, so concurrent updates may drop each
+//! other's times. A channel whose time is lost is swept once active again.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::channels;
+use crate::config;
+use crate::grip::unix_now;
+use crate::history;
+use crate::log_error;
+use crate::presence;
+use crate::publish::{Item, PublishError, Publisher};
+
+/// Setting holding the seconds a channel may stay idle before a sweep
+/// closes it.
+pub const TTL_SETTING: &str = "channel_idle_ttl";
+
+const DEFAULT_TTL: u64 = 86400;
+
+/// Seconds a channel's time may be behind before it's rewritten.
+const GRANULARITY: u64 = 60;
+
+/// Most channels tracked, the longest idle being forgotten past it.
+const MAX_TRACKED: usize = 10_000;
+
+const KEY: &str = "activity:channels";
+
+/// Last activity of each channel, in seconds since the Unix epoch.
+type Activity = BTreeMap<String, u64>;
+
+/// Returns the seconds a channel may stay idle.
+pub fn ttl() -> u64 {
+    config::setting(TTL_SETTING)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_TTL)
+}
+
+fn load() -> Activity {
+    config::state_store()
+        .and_then(|store| store.lookup_str(KEY).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn save(activity: &Activity) {
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => return,
+    };
+
+    let value = serde_json::to_string(activity).expect("activity always serializes");
+    if let Err(e) = store.insert(KEY, value) {
+        log_error!("failed to save channel activity: {e}");
+    }
+}
+
+/// Records activity on `channels`. The channel every connection is
+/// subscribed to is never tracked, as closing it would close them all.
+pub fn touch<'a>(channels: impl IntoIterator<Item = &'a str>) {
+    if config::state_store().is_none() {
+        return;
+    }
+
+    let now = unix_now();
+    let mut activity = load();
+    let mut changed = false;
+    for channel in channels {
+        if channel == channels::ALL_CHANNEL {
+            continue;
+        }
+        let last = activity.entry(channel.to_string()).or_insert(0);
+        if *last + GRANULARITY <= now {
+            *last = now;
+            changed = true;
+        }
+    }
+    if !changed {
+        return;
+    }
+
+    if activity.len() > MAX_TRACKED {
+        let mut times: Vec<u64> = activity.values().copied().collect();
+        times.sort_unstable();
+        let cutoff = times[activity.len() - MAX_TRACKED];
+        activity.retain(|_, last| *last >= cutoff);
+    }
+    save(&activity);
+}
+
+/// A channel idle for longer than allowed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IdleChannel {
+    pub channel: String,
+    /// When it was last active, in seconds since the Unix epoch.
+    pub last_active: u64,
+}
+
+/// Returns the channels idle for more than `ttl` seconds, longest idle
+/// first.
+pub fn idle(ttl: u64) -> Vec<IdleChannel> {
+    let now = unix_now();
+    let mut idle: Vec<IdleChannel> = load()
+        .into_iter()
+        .filter(|&(_, last)| last.saturating_add(ttl) < now)
+        .map(|(channel, last_active)| IdleChannel {
+            channel,
+            last_active,
+        })
+        .collect();
+    idle.sort_by_key(|c| c.last_active);
+    idle
+}
+
+/// Closes the channels idle for longer than [`ttl`], publishing a close to
+/// their streams and connections, and forgets their state. Returns the
+/// channels closed. Nothing is forgotten if the close can't be published,
+/// so the next sweep tries again.
+pub fn sweep(publisher: &Publisher) -> Result<Vec<IdleChannel>, PublishError> {
+    let idle = idle(ttl());
+    if idle.is_empty() {
+        return Ok(idle);
+    }
+
+    let items: Vec<Item> = idle
+        .iter()
+        .map(|c| Item::new(c.channel.as_str()).close(None))
+        .collect();
+    publisher.publish_items(&items)?;
+
+    let mut activity = load();
+    for c in &idle {
+        history::clear(&c.channel);
+        presence::clear(&c.channel);
+        activity.remove(&c.channel);
+    }
+    save(&activity);
+
+    Ok(idle)
+}
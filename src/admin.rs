@@ -0,0 +1,345 @@
+//! Admin API.
+//!
+//! Endpoints under `/admin/` are for operators, and require the API key held
+//! in the `admin_api_key` secret as a bearer token. They answer with JSON.
+//! Channels are named in full, with any route prefix and tenant, as they
+//! are published to.
+//!
+//! * `GET /admin/channels`: the channels most recently published to.
+//! * `GET /admin/channels/{channel}`: the connections present on a channel
+//!   and the ids of its messages kept for replay.
+//! * `POST /admin/channels/{channel}/close`: closes the streams and
+//!   WebSocket connections subscribed to a channel, the latter with the
+//!   code given in the `code` query parameter, if any.
+//! * `POST /admin/channels/{channel}/flush-history`: forgets the messages
+//!   kept for replay on a channel.
+//! * `POST /admin/connections/{connection-id}/close`: disconnects a single
+//!   WebSocket connection, with the code given in the `code` query
+//!   parameter, if any, by publishing a close to its own channel.
+//! * `POST /admin/drain`: closes the streams and WebSocket connections
+//!   subscribed to the comma-separated `channels` query parameter, or to
+//!   any channel if it's `all`, before planned maintenance. WebSocket
+//!   connections are closed with the code given in the `code` query
+//!   parameter, 1012 (service restart) by default, and told when to
+//!   reconnect by a `reconnect-after={seconds}` reason if the
+//!   `reconnect_after` query parameter is given.
+//! * `GET /admin/config`: the settings in effect, which secrets are set,
+//!   and the route of the request's host.
+//! * `GET /admin/dead-letters`: the items that couldn't be published, see
+//!   [`crate::dead_letter`].
+//! * `POST /admin/dead-letters/redrive`: publishes them again, or only the
+//!   one named by the `id` query parameter.
+//! * `POST /admin/sweep`: closes the channels idle for longer than
+//!   `channel_idle_ttl` and forgets their state, see [`crate::activity`].
+//! * `GET /admin/scheduled`: the publishes scheduled for later, see
+//!   [`crate::schedule`].
+//! * `POST /admin/scheduled/deliver`: publishes those that are due, for a
+//!   cron job to call regularly.
+
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::activity;
+use crate::auth;
+use crate::channels;
+use crate::config;
+use crate::dead_letter;
+use crate::error::AppError;
+use crate::history;
+use crate::log_info;
+use crate::presence;
+use crate::publish::{Item, Publisher};
+use crate::router::Route;
+use crate::schedule;
+
+/// Path prefix of the admin endpoints.
+pub const PATH_PREFIX: &str = "/admin/";
+
+/// Close code WebSocket connections are drained with by default.
+const CLOSE_SERVICE_RESTART: u16 = 1012;
+
+/// Settings shown in the config snapshot.
+const SETTINGS: &[&str] = &[
+    "backend_tls_verify",
+    "channel_idle_ttl",
+    "channel_patterns",
+    "channel_templates",
+    "connection_hold_ttl",
+    "cors_allowed_headers",
+    "cors_allowed_origins",
+    "dead_letter_size",
+    "dynamic_backends",
+    "edge_signing_key_id",
+    "fallback_backend",
+    "forwarded_strip_inbound",
+    "grip_sig_iss",
+    "history_size",
+    "keep_alive_timeout",
+    "log_endpoint",
+    "log_level",
+    "maintenance_mode",
+    "maintenance_retry_after",
+    "metrics_endpoint",
+    "presence_ttl",
+    "prev_id_chaining",
+    "publish_auth",
+    "publish_backend",
+    "publish_batch_max",
+    "publish_idempotency_window",
+    "publish_jwt_iss",
+    "publish_retry_attempts",
+    "publish_retry_backoff_ms",
+    "publish_retry_statuses",
+    "publish_url",
+    "routing_rules",
+    "scheduled_publish_max",
+    "test_longpoll_timeout",
+    "test_longpoll_timeout_status",
+    "test_sse_catch_up",
+    "test_ws_protocols",
+    "webhooks",
+    "ws_allowed_origins",
+    "ws_compression",
+    "ws_message_prefix",
+    "ws_ping_reply",
+];
+
+/// Secrets the config snapshot says are set or not, never showing them.
+const SECRETS: &[&str] = &[
+    "admin_api_key",
+    "backend_ca_cert",
+    "channel_token_key",
+    "grip_sig_key",
+    "publish_api_key",
+    "publish_key",
+];
+
+fn json_response(body: &impl Serialize) -> Response {
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(serde_json::to_string(body).expect("admin responses always serialize") + "\n")
+}
+
+/// Handles a request to the admin API.
+pub fn handle(req: Request, route: &Route) -> Result<Response, AppError> {
+    if !auth::check_api_key(&req, auth::ADMIN_API_KEY_SECRET) {
+        return Err(AppError::unauthorized("Invalid API key."));
+    }
+
+    let path = req.get_path().to_string();
+    match path.as_str() {
+        "/admin/channels" => Ok(json_response(
+            &json!({ "channels": history::recent_channels() }),
+        )),
+        "/admin/config" => Ok(json_response(&config_snapshot(route))),
+        "/admin/dead-letters" => Ok(json_response(
+            &json!({ "dead_letters": dead_letter::list() }),
+        )),
+        "/admin/dead-letters/redrive" => redrive(&req),
+        "/admin/drain" => drain(&req),
+        "/admin/scheduled" => Ok(json_response(&json!({ "scheduled": schedule::list() }))),
+        "/admin/scheduled/deliver" => deliver_scheduled(),
+        "/admin/sweep" => sweep(),
+        _ => {
+            let connection = path
+                .strip_prefix("/admin/connections/")
+                .and_then(parse_channel_path);
+            if let Some((id, "close")) = connection {
+                return close_channel(&req, &channels::connection_channel(id));
+            }
+
+            match path
+                .strip_prefix("/admin/channels/")
+                .and_then(parse_channel_path)
+            {
+                Some((channel, "")) => Ok(inspect_channel(channel)),
+                Some((channel, "close")) => close_channel(&req, channel),
+                Some((channel, "flush-history")) => {
+                    history::clear(channel);
+                    log_info!("flushed history of {channel}");
+                    Ok(json_response(&json!({ "channel": channel })))
+                }
+                _ => Err(AppError::RoutingError(format!(
+                    "No admin endpoint at {}.",
+                    path
+                ))),
+            }
+        }
+    }
+}
+
+/// Splits the rest of a `/admin/channels/` or `/admin/connections/` path
+/// into the channel or connection and the action on it, empty for none.
+fn parse_channel_path(rest: &str) -> Option<(&str, &str)> {
+    let (channel, action) = rest.split_once('/').unwrap_or((rest, ""));
+    if channel.is_empty() {
+        None
+    } else {
+        Some((channel, action))
+    }
+}
+
+/// Returns whether `path` is an admin endpoint taking `POST` requests.
+pub fn is_action(path: &str) -> bool {
+    path == "/admin/dead-letters/redrive"
+        || path == "/admin/drain"
+        || path == "/admin/scheduled/deliver"
+        || path == "/admin/sweep"
+        || path
+            .strip_prefix("/admin/channels/")
+            .or_else(|| path.strip_prefix("/admin/connections/"))
+            .and_then(parse_channel_path)
+            .is_some_and(|(_, action)| !action.is_empty())
+}
+
+fn inspect_channel(channel: &str) -> Response {
+    let members = presence::members(channel);
+    let history: Vec<String> = history::since(channel, "")
+        .into_iter()
+        .map(|e| e.id)
+        .collect();
+
+    json_response(&json!({
+        "channel": channel,
+        "presence": { "count": members.len(), "connections": members },
+        "history": history,
+    }))
+}
+
+/// Parses the `code` query parameter of a close.
+fn close_code(req: &Request) -> Result<Option<u16>, AppError> {
+    match req.get_query_parameter("code") {
+        Some(code) => Ok(Some(code.parse::<u16>().map_err(|_| {
+            AppError::ParseError(format!("Invalid close code {}.", code))
+        })?)),
+        None => Ok(None),
+    }
+}
+
+fn close_channel(req: &Request, channel: &str) -> Result<Response, AppError> {
+    let code = close_code(req)?;
+
+    let publisher = Publisher::from_config()
+        .ok_or_else(|| AppError::GripError("Publishing is not configured.".into()))?;
+
+    publisher
+        .publish(Item::new(channel).close(code))
+        .map_err(|e| AppError::UpstreamError(format!("Closing {channel} failed: {e}")))?;
+
+    log_info!("closed connections on {channel}");
+    Ok(json_response(
+        &json!({ "channel": channel, "closed": true }),
+    ))
+}
+
+fn drain(req: &Request) -> Result<Response, AppError> {
+    let channels: Vec<&str> = match req.get_query_parameter("channels") {
+        Some("all") => vec![channels::ALL_CHANNEL],
+        Some(list) => list
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+    if channels.is_empty() {
+        return Err(AppError::ParseError(
+            "No channels to drain, give them or \"all\".".into(),
+        ));
+    }
+
+    let code = close_code(req)?.unwrap_or(CLOSE_SERVICE_RESTART);
+    let reconnect_after = match req.get_query_parameter("reconnect_after") {
+        Some(secs) => Some(
+            secs.parse::<u32>()
+                .map_err(|_| AppError::ParseError(format!("Invalid reconnect delay {}.", secs)))?,
+        ),
+        None => None,
+    };
+
+    let publisher = Publisher::from_config()
+        .ok_or_else(|| AppError::GripError("Publishing is not configured.".into()))?;
+
+    let items: Vec<Item> = channels
+        .iter()
+        .map(|&channel| match reconnect_after {
+            Some(secs) => {
+                Item::new(channel).close_with_reason(Some(code), format!("reconnect-after={secs}"))
+            }
+            None => Item::new(channel).close(Some(code)),
+        })
+        .collect();
+    publisher
+        .publish_items(&items)
+        .map_err(|e| AppError::UpstreamError(format!("Draining failed: {e}")))?;
+
+    log_info!("drained {} with code {code}", channels.join(", "));
+    Ok(json_response(&json!({
+        "channels": channels,
+        "code": code,
+        "reconnect_after": reconnect_after,
+    })))
+}
+
+fn config_snapshot(route: &Route) -> Value {
+    let settings: Map<String, Value> = SETTINGS
+        .iter()
+        .filter_map(|&name| config::setting(name).map(|v| (name.to_string(), v.into())))
+        .collect();
+
+    let secrets: Map<String, Value> = SECRETS
+        .iter()
+        .map(|&name| (name.to_string(), config::secret(name).is_some().into()))
+        .collect();
+
+    json!({
+        "settings": settings,
+        "secrets": secrets,
+        "route": {
+            "backend": route.backend,
+            "hold": route.hold.map(|h| h.as_str()),
+            "channel_prefix": route.channel_prefix,
+            "origin": route.origin,
+            "tenant": route.tenant,
+            "timeouts": {
+                "response": route.timeouts.response,
+                "stream": route.timeouts.stream,
+            },
+        },
+        "state_store": config::state_store().is_some(),
+    })
+}
+
+fn redrive(req: &Request) -> Result<Response, AppError> {
+    let publisher = Publisher::from_config()
+        .ok_or_else(|| AppError::GripError("Publishing is not configured.".into()))?;
+
+    let letters = match req.get_query_parameter("id") {
+        Some(id) => vec![dead_letter::get(id)
+            .ok_or_else(|| AppError::RoutingError(format!("No dead letter {}.", id)))?],
+        None => dead_letter::list(),
+    };
+
+    Ok(json_response(&dead_letter::redrive(&publisher, &letters)))
+}
+
+fn sweep() -> Result<Response, AppError> {
+    let publisher = Publisher::from_config()
+        .ok_or_else(|| AppError::GripError("Publishing is not configured.".into()))?;
+
+    let closed = activity::sweep(&publisher)
+        .map_err(|e| AppError::UpstreamError(format!("Sweeping failed: {e}")))?;
+
+    log_info!("swept {} idle channels", closed.len());
+    Ok(json_response(&json!({ "closed": closed })))
+}
+
+fn deliver_scheduled() -> Result<Response, AppError> {
+    let publisher = Publisher::from_config()
+        .ok_or_else(|| AppError::GripError("Publishing is not configured.".into()))?;
+
+    Ok(json_response(&schedule::deliver_due(&publisher)))
+}
@@ -0,0 +1,46 @@
+//! Authentication of requests made to the app's own endpoints.
+
+use fastly::Request;
+
+use crate::config;
+
+/// Secret holding the API key required by the publish endpoint.
+pub const PUBLISH_API_KEY_SECRET: &str = "publish_api_key";
+
+/// Secret holding the API key required by the admin endpoints.
+pub const ADMIN_API_KEY_SECRET: &str = "admin_api_key";
+
+/// Compares two byte strings in time independent of where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Returns the bearer token from the request's `Authorization` header.
+pub fn bearer_token(req: &Request) -> Option<&str> {
+    let value = req.get_header_str("Authorization")?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// Returns whether the request carries the API key held in `secret` as a
+/// bearer token. Requests are always rejected if the secret isn't set.
+pub fn check_api_key(req: &Request, secret: &str) -> bool {
+    let key = match config::secret(secret) {
+        Some(key) if !key.is_empty() => key,
+        _ => return false,
+    };
+
+    match bearer_token(req) {
+        Some(token) => constant_time_eq(token.as_bytes(), &key),
+        None => false,
+    }
+}
@@ -0,0 +1,84 @@
+//! Backends for proxied requests.
+//!
+//! By default requests are forwarded to pre-provisioned backends named after
+//! the request host (see [`crate::router`]), so every origin has to be set up
+//! in the service ahead of time. With the `dynamic_backends` setting on,
+//! routes naming an `origin` instead get a backend registered on the fly
+//! through the Compute dynamic backend API. Services not allowed to create
+//! dynamic backends fall back to the static ones.
+
+use fastly::backend::BackendCreationError;
+use fastly::Backend;
+
+use crate::config;
+use crate::router::Route;
+use crate::{log_debug, log_warn};
+
+/// Setting that, when `true`, registers dynamic backends for routes with an
+/// `origin`.
+pub const DYNAMIC_SETTING: &str = "dynamic_backends";
+
+/// Setting that, when `false`, turns off certificate verification for
+/// dynamic TLS backends. Only meant for testing against self-signed origins.
+pub const TLS_VERIFY_SETTING: &str = "backend_tls_verify";
+
+/// Secret holding a PEM-encoded CA certificate that dynamic TLS backends
+/// are verified against instead of the default trust store.
+pub const CA_CERT_SECRET: &str = "backend_ca_cert";
+
+/// Returns whether dynamic backends are enabled.
+pub fn dynamic_enabled() -> bool {
+    config::setting(DYNAMIC_SETTING).as_deref() == Some("true")
+}
+
+/// Returns the name of the backend to forward a request on `route` to,
+/// registering a dynamic backend for the route's origin if enabled.
+pub fn resolve(route: &Route, tls: bool) -> String {
+    let origin = match &route.origin {
+        Some(origin) if dynamic_enabled() => origin,
+        _ => return route.backend.clone(),
+    };
+
+    match register(origin, tls) {
+        Ok(name) => name,
+        Err(e) => {
+            log_warn!(
+                "failed to register backend for {origin}, using {}: {e}",
+                route.backend
+            );
+            route.backend.clone()
+        }
+    }
+}
+
+/// Registers a dynamic backend for `origin` (`host` or `host:port`) and
+/// returns its name.
+pub fn register(origin: &str, tls: bool) -> Result<String, BackendCreationError> {
+    let scheme = if tls { "https" } else { "http" };
+    let name = format!("dyn_{}_{}", scheme, origin.replace(':', "_"));
+    let host = origin.split(':').next().unwrap_or(origin);
+
+    let mut builder = Backend::builder(name.as_str(), origin).override_host(host);
+
+    if tls {
+        builder = builder.enable_ssl().sni_hostname(host);
+
+        if config::setting(TLS_VERIFY_SETTING).as_deref() != Some("false") {
+            builder = builder.check_certificate(host);
+        }
+
+        if let Some(ca) = config::secret(CA_CERT_SECRET) {
+            builder = builder.ca_certificate(String::from_utf8_lossy(&ca));
+        }
+    }
+
+    match builder.finish() {
+        Ok(backend) => {
+            log_debug!("registered backend {} for {origin}", backend.name());
+            Ok(backend.into_string())
+        }
+        // registered by an earlier request served by this instance
+        Err(BackendCreationError::NameInUse) => Ok(name),
+        Err(e) => Err(e),
+    }
+}
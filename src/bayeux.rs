@@ -0,0 +1,444 @@
+//! Native Bayeux protocol support.
+//!
+//! Bayeux clients such as Faye are served directly by this app instead of a
+//! separate origin. Each Bayeux channel maps onto a GRIP channel (see
+//! [`grip_channel`]), so anything published to that GRIP channel reaches the
+//! Bayeux subscribers.
+//!
+//! Two transports are supported:
+//!
+//! * `websocket`, via WebSocket-over-HTTP. Subscriptions are turned into GRIP
+//!   subscribe control messages, so no state is kept by the app.
+//! * `long-polling`, via HTTP POST. Subscriptions are kept in the state KV
+//!   Store, keyed by client id, and `/meta/connect` requests are held on the
+//!   client's channels until a message is published or the hold times out.
+//!
+//! Published payloads must be Bayeux message arrays, as produced by
+//! [`publish_payload`]. Messages published by Bayeux clients are sent on
+//! through the configured [`Publisher`], if any.
+
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config;
+use crate::error::AppError;
+use crate::grip::GripResponseBuilder;
+use crate::hex;
+use crate::publish::{Item, Publisher};
+use crate::router::Route;
+use crate::ws::{self, WsContext, WsHandler};
+use crate::ws_events::{self, WsEvent};
+use crate::{log_debug, log_error, log_warn};
+
+/// Prefix of the GRIP channels Bayeux channels are mapped onto.
+pub const GRIP_CHANNEL_PREFIX: &str = "bayeux";
+
+/// Seconds a long-polling `/meta/connect` request is held.
+pub const CONNECT_TIMEOUT: u32 = 45;
+
+const HANDSHAKE: &str = "/meta/handshake";
+const CONNECT: &str = "/meta/connect";
+const DISCONNECT: &str = "/meta/disconnect";
+const SUBSCRIBE: &str = "/meta/subscribe";
+const UNSUBSCRIBE: &str = "/meta/unsubscribe";
+
+const CONNECTION_TYPES: &[&str] = &["long-polling", "cross-origin-long-polling", "websocket"];
+
+/// A Bayeux message. Only the fields used by this implementation are
+/// modelled; unknown fields are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub channel: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub successful: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub supported_connection_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub advice: Option<Advice>,
+}
+
+/// Reconnection advice sent to clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Advice {
+    pub reconnect: String,
+    pub interval: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+impl Message {
+    /// Returns a successful reply to this message.
+    fn reply(&self) -> Message {
+        Message {
+            channel: self.channel.clone(),
+            id: self.id.clone(),
+            client_id: self.client_id.clone(),
+            successful: Some(true),
+            ..Default::default()
+        }
+    }
+
+    /// Returns a failed reply to this message, with a Bayeux error string
+    /// of the form `code:args:description`.
+    fn error_reply(&self, code: u16, description: &str) -> Message {
+        Message {
+            successful: Some(false),
+            error: Some(format!("{}:{}:{}", code, self.channel, description)),
+            ..self.reply()
+        }
+    }
+}
+
+/// Returns the GRIP channel a Bayeux channel is mapped onto.
+pub fn grip_channel(channel: &str) -> String {
+    format!("{}{}", GRIP_CHANNEL_PREFIX, channel)
+}
+
+/// Returns the payload to publish on a Bayeux channel's GRIP channel, for
+/// both the `http-response` and `ws-message` formats.
+pub fn publish_payload(channel: &str, data: &Value) -> String {
+    let msg = Message {
+        channel: channel.to_string(),
+        data: Some(data.clone()),
+        ..Default::default()
+    };
+
+    serde_json::to_string(&[msg]).expect("messages always serialize")
+}
+
+/// Parses a request body containing a single message or an array of them.
+pub fn parse_messages(body: &[u8]) -> Result<Vec<Message>, serde_json::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Box<Message>),
+        Many(Vec<Message>),
+    }
+
+    Ok(match serde_json::from_slice(body)? {
+        OneOrMany::One(m) => vec![*m],
+        OneOrMany::Many(m) => m,
+    })
+}
+
+/// Returns an error description if `channel` can't be subscribed or
+/// published to.
+fn check_subscription(channel: &str) -> Result<(), &'static str> {
+    if !channel.starts_with('/') || channel.len() < 2 {
+        return Err("invalid channel");
+    }
+    if channel.starts_with("/meta/") {
+        return Err("cannot subscribe to meta channels");
+    }
+    if channel.split('/').any(|seg| seg == "*" || seg == "**") {
+        return Err("wildcard subscriptions are not supported");
+    }
+
+    Ok(())
+}
+
+fn new_client_id() -> String {
+    hex::random(16)
+}
+
+/// Subscription changes requested by a batch of messages.
+#[derive(Debug, Default)]
+struct Outcome {
+    replies: Vec<Message>,
+    subscribed: Vec<String>,
+    unsubscribed: Vec<String>,
+    connect: Option<Message>,
+    disconnected: Vec<String>,
+}
+
+fn process(messages: Vec<Message>, connect_advice: Advice, route: &Route) -> Outcome {
+    let mut out = Outcome::default();
+    let publisher = Publisher::for_route(route);
+
+    for msg in messages {
+        let reply = match msg.channel.as_str() {
+            HANDSHAKE => Message {
+                client_id: Some(new_client_id()),
+                version: Some("1.0".to_string()),
+                supported_connection_types: Some(
+                    CONNECTION_TYPES.iter().map(|s| s.to_string()).collect(),
+                ),
+                advice: Some(connect_advice.clone()),
+                ..msg.reply()
+            },
+            _ if msg.client_id.is_none() => Message {
+                advice: Some(Advice {
+                    reconnect: "handshake".to_string(),
+                    interval: 0,
+                    timeout: None,
+                }),
+                ..msg.error_reply(401, "unknown client")
+            },
+            CONNECT => {
+                let reply = Message {
+                    advice: Some(connect_advice.clone()),
+                    ..msg.reply()
+                };
+                out.connect = Some(reply.clone());
+                reply
+            }
+            DISCONNECT => {
+                out.disconnected.extend(msg.client_id.clone());
+                msg.reply()
+            }
+            SUBSCRIBE | UNSUBSCRIBE => {
+                let sub = msg.subscription.clone().unwrap_or_default();
+                match check_subscription(&sub) {
+                    Ok(()) => {
+                        if msg.channel == SUBSCRIBE {
+                            out.subscribed.push(sub.clone());
+                        } else {
+                            out.unsubscribed.push(sub.clone());
+                        }
+                        Message {
+                            subscription: Some(sub),
+                            ..msg.reply()
+                        }
+                    }
+                    Err(e) => Message {
+                        subscription: Some(sub),
+                        ..msg.error_reply(403, e)
+                    },
+                }
+            }
+            ch if ch.starts_with("/meta/") => msg.error_reply(404, "unknown meta channel"),
+            _ => match (&publisher, check_subscription(&msg.channel)) {
+                (_, Err(e)) => msg.error_reply(403, e),
+                (None, Ok(())) => msg.error_reply(405, "publishing is not supported"),
+                (Some(publisher), Ok(())) => {
+                    let payload =
+                        publish_payload(&msg.channel, msg.data.as_ref().unwrap_or(&Value::Null));
+                    let item = Item::new(route.channel(&grip_channel(&msg.channel)))
+                        .http_response(payload.clone())
+                        .ws_message(payload);
+
+                    match publisher.publish(item) {
+                        Ok(()) => msg.reply(),
+                        Err(e) => {
+                            log_error!("failed to publish bayeux message: {e}");
+                            msg.error_reply(500, "publish failed")
+                        }
+                    }
+                }
+            },
+        };
+
+        out.replies.push(reply);
+    }
+
+    out
+}
+
+fn subscriptions_key(client_id: &str) -> String {
+    format!("bayeux:{}", client_id)
+}
+
+fn load_subscriptions(client_id: &str) -> Vec<String> {
+    config::state_store()
+        .and_then(|store| store.lookup_str(&subscriptions_key(client_id)).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn save_subscriptions(client_id: &str, subs: &[String]) {
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => {
+            log_warn!("no state store, bayeux long-polling subscriptions are not kept");
+            return;
+        }
+    };
+
+    let key = subscriptions_key(client_id);
+    let result = if subs.is_empty() {
+        store.delete(&key)
+    } else {
+        store.insert(
+            &key,
+            serde_json::to_string(subs).expect("strings serialize"),
+        )
+    };
+
+    if let Err(e) = result {
+        log_error!("failed to save bayeux subscriptions for {client_id}: {e}");
+    }
+}
+
+fn json_response(status: StatusCode, messages: &[Message]) -> Response {
+    Response::from_status(status)
+        .with_header("Content-Type", "application/json")
+        .with_body(serde_json::to_string(messages).expect("messages always serialize"))
+}
+
+/// Handles a long-polling Bayeux request forwarded by Fanout.
+pub fn handle_http(mut req: Request, route: &Route) -> Response {
+    let is_form = req
+        .get_header_str("Content-Type")
+        .map(|ct| ct.starts_with("application/x-www-form-urlencoded"))
+        .unwrap_or(false);
+
+    let body = req.take_body().into_bytes();
+    let body = if is_form {
+        form_urlencoded_message(&body).unwrap_or_default()
+    } else {
+        body
+    };
+
+    let messages = match parse_messages(&body) {
+        Ok(m) => m,
+        Err(e) => {
+            return AppError::ParseError(format!("Invalid Bayeux message: {e}")).response();
+        }
+    };
+
+    let advice = Advice {
+        reconnect: "retry".to_string(),
+        interval: 0,
+        timeout: Some(u64::from(CONNECT_TIMEOUT) * 1000),
+    };
+    let out = process(messages, advice, route);
+
+    let mut client_ids: Vec<&str> = out
+        .replies
+        .iter()
+        .filter(|m| m.successful == Some(true))
+        .filter_map(|m| m.client_id.as_deref())
+        .collect();
+    client_ids.dedup();
+
+    for client_id in &client_ids {
+        if out.disconnected.iter().any(|d| d == client_id) {
+            save_subscriptions(client_id, &[]);
+            continue;
+        }
+        if out.subscribed.is_empty() && out.unsubscribed.is_empty() {
+            continue;
+        }
+
+        let mut subs = load_subscriptions(client_id);
+        subs.retain(|s| !out.unsubscribed.contains(s));
+        for s in &out.subscribed {
+            if !subs.contains(s) {
+                subs.push(s.clone());
+            }
+        }
+        save_subscriptions(client_id, &subs);
+    }
+
+    // a lone connect is held until something is published, anything else is
+    // answered right away so the client can carry on
+    match (&out.connect, out.replies.len()) {
+        (Some(connect), 1) => {
+            let client_id = connect.client_id.as_deref().unwrap_or_default();
+            let mut channels: Vec<String> = load_subscriptions(client_id)
+                .iter()
+                .map(|s| route.channel(&grip_channel(s)))
+                .collect();
+            channels.push(route.channel(&grip_channel(&format!("/meta/client/{client_id}"))));
+
+            GripResponseBuilder::new()
+                .content_type("application/json")
+                .hold_response()
+                .channels(&channels)
+                .timeout(CONNECT_TIMEOUT)
+                .max_timeouts(&route.timeouts)
+                .body(serde_json::to_string(&out.replies).expect("messages always serialize"))
+                .build()
+        }
+        _ => json_response(StatusCode::OK, &out.replies),
+    }
+}
+
+fn form_urlencoded_message(body: &[u8]) -> Option<Vec<u8>> {
+    url::form_urlencoded::parse(body)
+        .find(|(k, _)| k == "message")
+        .map(|(_, v)| v.into_owned().into_bytes())
+}
+
+/// Serves Bayeux over a WebSocket connection.
+struct BayeuxWs<'a> {
+    route: &'a Route,
+}
+
+impl WsHandler for BayeuxWs<'_> {
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        let messages = match parse_messages(text.as_bytes()) {
+            Ok(m) => m,
+            Err(e) => {
+                log_warn!("ignoring invalid bayeux message: {e}");
+                return;
+            }
+        };
+
+        // with WebSocket there is no request to hold, so clients are told to
+        // wait out the connect interval while messages arrive on the socket
+        let advice = Advice {
+            reconnect: "retry".to_string(),
+            interval: u64::from(CONNECT_TIMEOUT) * 1000,
+            timeout: Some(u64::from(CONNECT_TIMEOUT) * 1000),
+        };
+
+        let route = self.route;
+        let result = process(messages, advice, route);
+
+        let subscribed: Vec<String> = result
+            .subscribed
+            .iter()
+            .map(|s| route.channel(&grip_channel(s)))
+            .collect();
+        let unsubscribed: Vec<String> = result
+            .unsubscribed
+            .iter()
+            .map(|s| route.channel(&grip_channel(s)))
+            .collect();
+        ctx.subscribe(&subscribed);
+        ctx.unsubscribe(&unsubscribed);
+
+        ctx.out.write_text(
+            &serde_json::to_string(&result.replies).expect("messages always serialize"),
+        );
+    }
+
+    fn on_close(&mut self, ctx: &mut WsContext, code: Option<u16>) {
+        log_debug!("bayeux websocket closed with code {code:?}");
+        ctx.out.write_event(&WsEvent::Close(code));
+    }
+}
+
+/// Handles a WebSocket-over-HTTP Bayeux request forwarded by Fanout.
+pub fn handle_ws(req: Request, route: &Route) -> Response {
+    ws::serve(req, &route.limits, &mut BayeuxWs { route })
+}
+
+/// Handles a Bayeux request forwarded by Fanout, using the transport
+/// matching the request.
+pub fn handle(req: Request, route: &Route) -> Response {
+    if req.get_header_str("Content-Type") == Some(ws_events::CONTENT_TYPE) {
+        handle_ws(req, route)
+    } else {
+        handle_http(req, route)
+    }
+}
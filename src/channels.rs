@@ -0,0 +1,168 @@
+//! Channel naming rules for channels chosen by clients.
+//!
+//! Services can restrict which channels clients may pick with the
+//! `channel_patterns` setting, a comma-separated list of names where a
+//! trailing `*` matches any suffix (e.g. `test, room-*, user-*`). Channel
+//! templates such as `user-{sub}` name channels derived from the verified
+//! claims of a client's channel token, letting each user be subscribed to
+//! their own channel alongside the one they asked for.
+//!
+//! Clients presenting a token with a `sub` claim also get a private channel,
+//! `user:{sub}`, which only they may subscribe to.
+
+use std::fmt;
+
+use crate::config;
+use crate::log_warn;
+use crate::tokens::ChannelClaims;
+
+/// Setting holding the channel patterns clients may subscribe to. All
+/// channels are allowed if unset.
+pub const PATTERNS_SETTING: &str = "channel_patterns";
+
+/// Setting holding channel templates every client presenting a channel
+/// token is subscribed to, e.g. `user-{sub}`.
+pub const TEMPLATES_SETTING: &str = "channel_templates";
+
+/// Prefix of private channels, followed by the `sub` of their owner.
+pub const PRIVATE_PREFIX: &str = "user:";
+
+/// Prefix of the channel of a single WebSocket connection, followed by its
+/// id.
+pub const CONNECTION_PREFIX: &str = "c:";
+
+/// Channel every WebSocket connection and HTTP stream is subscribed to, so
+/// they can all be closed at once by the admin API before maintenance. Its
+/// name can't be requested by clients.
+pub const ALL_CHANNEL: &str = "*";
+
+/// Longest channel name a client may request.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Reasons a requested channel name is refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelError {
+    Empty,
+    TooLong,
+    InvalidChar(char),
+    /// The name matches none of the configured channel patterns.
+    NotAllowed(String),
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::Empty => write!(f, "channel name is empty"),
+            ChannelError::TooLong => {
+                write!(f, "channel name is longer than {MAX_NAME_LEN} characters")
+            }
+            ChannelError::InvalidChar(c) => write!(f, "channel name contains {c:?}"),
+            ChannelError::NotAllowed(name) => write!(f, "channel {name} is not allowed"),
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+/// Returns the private channel of the user with subject `sub`.
+pub fn private_channel(sub: &str) -> String {
+    format!("{}{}", PRIVATE_PREFIX, sub)
+}
+
+/// Returns the channel only the WebSocket connection `connection_id` is
+/// subscribed to. Its name can't be requested by clients.
+pub fn connection_channel(connection_id: &str) -> String {
+    format!("{}{}", CONNECTION_PREFIX, connection_id)
+}
+
+/// Returns the subject owning a private channel, or `None` if the channel
+/// isn't private.
+pub fn private_owner(name: &str) -> Option<&str> {
+    name.strip_prefix(PRIVATE_PREFIX)
+}
+
+/// Checks that a client-supplied channel name is non-empty, at most
+/// [`MAX_NAME_LEN`] long and made up of ASCII letters, digits, `-`, `_` and
+/// `.` only, after the `user:` prefix of private channels.
+pub fn validate_name(name: &str) -> Result<(), ChannelError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(ChannelError::TooLong);
+    }
+    let name = private_owner(name).unwrap_or(name);
+
+    if name.is_empty() {
+        return Err(ChannelError::Empty);
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        return Err(ChannelError::InvalidChar(c));
+    }
+
+    Ok(())
+}
+
+/// Returns whether `name` matches `pattern`, where a trailing `*` in the
+/// pattern matches any suffix.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+/// Checks that a client-supplied channel name is valid and matches one of
+/// the configured channel patterns. Private channels aren't subject to the
+/// patterns, their owner is checked when subscribing instead, see
+/// [`crate::tokens::authorize`].
+pub fn check(name: &str) -> Result<(), ChannelError> {
+    validate_name(name)?;
+    if private_owner(name).is_some() {
+        return Ok(());
+    }
+
+    let patterns = match config::setting(PATTERNS_SETTING) {
+        Some(patterns) => patterns,
+        None => return Ok(()),
+    };
+
+    if split_list(&patterns).any(|p| matches(p, name)) {
+        Ok(())
+    } else {
+        Err(ChannelError::NotAllowed(name.to_string()))
+    }
+}
+
+/// Expands the `{sub}` placeholder of a channel template from token claims.
+/// Returns `None` if the claims lack a value or the result isn't a valid
+/// channel name.
+pub fn expand(template: &str, claims: &ChannelClaims) -> Option<String> {
+    let name = if template.contains("{sub}") {
+        template.replace("{sub}", claims.sub.as_deref()?)
+    } else {
+        template.to_string()
+    };
+
+    match validate_name(&name) {
+        Ok(()) => Some(name),
+        Err(e) => {
+            log_warn!("channel template {template} expands to an invalid name: {e}");
+            None
+        }
+    }
+}
+
+/// Returns the channels the configured templates expand to for `claims`.
+pub fn template_channels(claims: &ChannelClaims) -> Vec<String> {
+    match config::setting(TEMPLATES_SETTING) {
+        Some(templates) => split_list(&templates)
+            .filter_map(|t| expand(t, claims))
+            .collect(),
+        None => Vec::new(),
+    }
+}
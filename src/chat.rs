@@ -0,0 +1,177 @@
+//! Chat room demo.
+//!
+//! A small reference application built from the app's parts: each room is
+//! a channel, each participant a WebSocket connection at
+//! `/demo/chat/{room}/ws?nick={nickname}` subscribed to it, whose nickname
+//! is kept in its [`Session`](crate::session::Session). Messages a
+//! participant sends are published to the room through the configured
+//! [`Publisher`], along with `join` and `leave` messages as participants come
+//! and go. The room's [`presence`] gives the number of participants.
+//!
+//! All messages are JSON objects with a `type`:
+//!
+//! ```json
+//! {"type": "welcome", "nick": "ada", "members": 3}
+//! {"type": "join", "nick": "ada"}
+//! {"type": "message", "nick": "ada", "text": "hello", "time": 1700000000}
+//! {"type": "leave", "nick": "ada"}
+//! ```
+//!
+//! The page at `/demo/chat/{room}` is a browser client for it.
+
+use fastly::{Request, Response};
+use serde_json::{json, Value};
+
+use crate::channels;
+use crate::grip::{unix_now, GripControl};
+use crate::presence;
+use crate::publish::{Item, Publisher};
+use crate::router::Route;
+use crate::ws::{self, WsContext, WsHandler};
+use crate::ws_events::WsEvent;
+use crate::{log_error, log_warn};
+
+/// Path prefix of the chat endpoints.
+pub const PATH_PREFIX: &str = "/demo/chat/";
+
+/// Longest nickname accepted, in characters.
+const MAX_NICK_LEN: usize = 32;
+
+/// Longest message accepted, in characters.
+const MAX_TEXT_LEN: usize = 2000;
+
+/// Returns the channel of a room.
+pub fn room_channel(room: &str) -> String {
+    format!("chat-{}", room)
+}
+
+/// Returns a nickname with control characters removed and its length
+/// limited, or `None` if nothing is left.
+fn clean_nick(nick: &str) -> Option<String> {
+    let nick: String = nick
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NICK_LEN)
+        .collect();
+    let nick = nick.trim();
+    if nick.is_empty() {
+        None
+    } else {
+        Some(nick.to_string())
+    }
+}
+
+/// Serves a participant of a chat room.
+struct ChatWs<'a> {
+    channel: String,
+    nick: Option<String>,
+    route: &'a Route,
+}
+
+impl ChatWs<'_> {
+    fn publish(&self, message: Value) {
+        let publisher = match Publisher::for_route(self.route) {
+            Some(p) => p,
+            None => {
+                log_warn!("dropping chat message, publishing is not configured");
+                return;
+            }
+        };
+
+        let item = Item::new(self.channel.as_str()).ws_message(message.to_string());
+        if let Err(e) = publisher.publish(item) {
+            log_error!("failed to publish to {}: {e}", self.channel);
+        }
+    }
+
+    fn nick(&self, ctx: &mut WsContext) -> String {
+        ctx.session()
+            .identity
+            .clone()
+            .unwrap_or_else(|| "guest".to_string())
+    }
+
+    fn leave(&self, ctx: &mut WsContext) {
+        let nick = self.nick(ctx);
+        self.publish(json!({ "type": "leave", "nick": nick }));
+    }
+}
+
+impl WsHandler for ChatWs<'_> {
+    fn on_open(&mut self, ctx: &mut WsContext) {
+        let nick = self.nick.clone().unwrap_or_else(|| {
+            let id = &ctx.connection_id;
+            let suffix = id.get(id.len().saturating_sub(4)..).unwrap_or_default();
+            format!("guest-{}", suffix)
+        });
+        ctx.session().identity = Some(nick.clone());
+
+        ctx.subscribe(&[self.channel.as_str()]);
+        let members = presence::members(&self.channel).len();
+
+        ctx.out.write_text(
+            &json!({ "type": "welcome", "nick": nick, "members": members }).to_string(),
+        );
+        self.publish(json!({ "type": "join", "nick": nick }));
+    }
+
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        let text: String = text.chars().take(MAX_TEXT_LEN).collect();
+        if text.trim().is_empty() {
+            return;
+        }
+
+        let nick = self.nick(ctx);
+        self.publish(json!({
+            "type": "message",
+            "nick": nick,
+            "text": text,
+            "time": unix_now(),
+        }));
+    }
+
+    fn on_close(&mut self, ctx: &mut WsContext, code: Option<u16>) {
+        self.leave(ctx);
+        ctx.out.write_event(&WsEvent::Close(code));
+    }
+
+    fn on_disconnect(&mut self, ctx: &mut WsContext) {
+        self.leave(ctx);
+    }
+
+    fn keep_alive(&self) -> Option<GripControl> {
+        Some(self.route.keep_alive.to_control())
+    }
+}
+
+/// Handles a chat room WebSocket request forwarded by Fanout, at
+/// `/demo/chat/{room}/ws`.
+pub fn handle_ws(req: Request, room: &str, route: &Route) -> Response {
+    let nick = req.get_query_parameter("nick").and_then(clean_nick);
+
+    ws::serve(
+        req,
+        &route.limits,
+        &mut ChatWs {
+            channel: route.channel(&room_channel(room)),
+            nick,
+            route,
+        },
+    )
+}
+
+/// Returns the room named by a chat path and what follows it, if the room
+/// name is a valid channel name.
+pub fn parse_path(path: &str) -> Option<(&str, &str)> {
+    let rest = path.strip_prefix(PATH_PREFIX)?;
+    let (room, sub) = match rest.split_once('/') {
+        Some((room, sub)) => (room, sub),
+        None => (rest, ""),
+    };
+
+    if room.is_empty() {
+        return None;
+    }
+    channels::validate_name(&room_channel(room)).ok()?;
+    Some((room, sub))
+}
@@ -0,0 +1,51 @@
+//! Access to the Fastly stores that hold the app's configuration.
+//!
+//! All lookups are tolerant of the store not being linked to the service: a
+//! missing store behaves the same as a missing key, so the app keeps working
+//! with built-in defaults on a bare deployment.
+
+use fastly::{ConfigStore, KVStore, SecretStore};
+
+/// Name of the Config Store holding non-sensitive settings.
+pub const SETTINGS_STORE: &str = "fanout_config";
+
+/// Name of the Config Store mapping request hosts to routes.
+pub const ROUTES_STORE: &str = "fanout_routes";
+
+/// Name of the KV Store holding state shared between requests.
+pub const STATE_STORE: &str = "fanout_state";
+
+/// Name of the Secret Store holding keys and credentials.
+pub const SECRET_STORE: &str = "fanout_secrets";
+
+/// Returns the value of a setting from the settings Config Store.
+pub fn setting(key: &str) -> Option<String> {
+    lookup(SETTINGS_STORE, key)
+}
+
+/// Returns the value of `key` in the named Config Store.
+pub fn lookup(store: &str, key: &str) -> Option<String> {
+    ConfigStore::try_open(store)
+        .ok()?
+        .try_get(key)
+        .ok()
+        .flatten()
+}
+
+/// Returns the plaintext of a secret from the Secret Store.
+pub fn secret(name: &str) -> Option<Vec<u8>> {
+    let secret = SecretStore::open(SECRET_STORE).ok()?.try_get(name).ok()??;
+    secret.try_plaintext().ok().map(|b| b.to_vec())
+}
+
+/// Opens the KV Store holding shared state, if it is linked to the service.
+pub fn state_store() -> Option<KVStore> {
+    KVStore::open(STATE_STORE).ok().flatten()
+}
+
+/// Returns whether the app was built for local development with the
+/// `local-dev` feature: requests meant for Fanout are served in-process,
+/// `Grip-Sig` isn't verified and publishes are only logged.
+pub fn local_dev() -> bool {
+    cfg!(feature = "local-dev")
+}
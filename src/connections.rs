@@ -0,0 +1,250 @@
+//! Per-client limits on concurrent connections.
+//!
+//! A route's `rate_limit` can cap how many WebSocket connections to the
+//! app's own endpoints each client IP keeps open at once, with
+//! `max_connections`, so a single misbehaving client can't hold thousands
+//! of them. Each open connection is recorded as a hold of its client in the
+//! state KV Store under `conns:{client-ip}`, and clients already at the cap
+//! are refused with `429` before being handed off to Fanout.
+//!
+//! Only the request opening a connection reaches the app with the client's
+//! address, so it is passed on to the connection's events in the
+//! [`HOLD_HEADER`] request header, which Fanout forwards. The hold is taken
+//! on OPEN, refreshed by any later event and released on CLOSE or
+//! DISCONNECT. Holds of connections that vanish without Fanout saying so
+//! expire after `connection_hold_ttl` seconds without an event.
+//!
+//! SSE streams, long-polls and connections to proxied origins aren't
+//! counted, as the app never hears when they end. Holds are updated with a
+//! read-modify-write, so clients opening connections concurrently may get
+//! past the cap.
+
+use std::collections::BTreeMap;
+
+use fastly::Request;
+
+use crate::config;
+use crate::grip::unix_now;
+use crate::log_error;
+use crate::ratelimit::Limited;
+use crate::ws::CONNECTION_ID_HEADER;
+
+/// Request header carrying the address of the client that opened a
+/// connection counted against its cap.
+pub const HOLD_HEADER: &str = "Connection-Hold";
+
+/// Setting holding the seconds a hold lasts without an event.
+pub const TTL_SETTING: &str = "connection_hold_ttl";
+
+const DEFAULT_TTL: u64 = 600;
+
+/// Expiry of each hold of a client, by connection id, in seconds since the
+/// Unix epoch.
+type Holds = BTreeMap<String, u64>;
+
+fn ttl() -> u64 {
+    config::setting(TTL_SETTING)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_TTL)
+}
+
+fn key(client: &str) -> String {
+    format!("conns:{}", client)
+}
+
+fn load(client: &str) -> Holds {
+    let mut holds = config::state_store()
+        .and_then(|store| store.lookup_str(&key(client)).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    prune(&mut holds, unix_now());
+    holds
+}
+
+fn save(client: &str, holds: &Holds) {
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => return,
+    };
+
+    let result = if holds.is_empty() {
+        store.delete(&key(client))
+    } else {
+        store.insert(
+            &key(client),
+            serde_json::to_string(holds).expect("holds always serialize"),
+        )
+    };
+
+    if let Err(e) = result {
+        log_error!("failed to update connections of {client}: {e}");
+    }
+}
+
+/// Forgets the holds expired by `now`.
+fn prune(holds: &mut Holds, now: u64) {
+    holds.retain(|_, expires| *expires > now);
+}
+
+/// Refuses another connection if `holds` are already `max`, telling the
+/// client to retry once the soonest of them expires.
+fn admit(holds: &Holds, max: u32, now: u64) -> Result<(), Limited> {
+    if holds.len() < max as usize {
+        return Ok(());
+    }
+    let soonest = holds.values().min().copied().unwrap_or(now);
+    Err(Limited {
+        retry_after: soonest.saturating_sub(now).max(1),
+    })
+}
+
+/// Takes or extends the hold of a connection until `now + ttl`, returning
+/// whether it changed. Holds with over half their time left are kept as
+/// they are, so busy connections don't cost a write on every event.
+fn extend(holds: &mut Holds, connection_id: &str, now: u64, ttl: u64) -> bool {
+    if holds
+        .get(connection_id)
+        .is_some_and(|expires| *expires > now + ttl / 2)
+    {
+        return false;
+    }
+    holds.insert(connection_id.to_string(), now + ttl);
+    true
+}
+
+/// Returns whether `req` opens a WebSocket connection.
+fn is_websocket(req: &Request) -> bool {
+    req.get_header_str("Upgrade")
+        .is_some_and(|u| u.eq_ignore_ascii_case("websocket"))
+}
+
+/// Refuses the WebSocket connection `req` opens if its client already has
+/// `max` open, or passes the client's address on to the connection's
+/// events otherwise. Any hold header the request came with is removed, so
+/// clients can't forge one.
+pub fn check(req: &mut Request, max: Option<u32>) -> Result<(), Limited> {
+    req.remove_header(HOLD_HEADER);
+    let max = match max {
+        Some(max) if is_websocket(req) => max,
+        _ => return Ok(()),
+    };
+    let client = match req.get_client_ip_addr() {
+        Some(ip) => ip.to_string(),
+        None => return Ok(()),
+    };
+
+    admit(&load(&client), max, unix_now())?;
+    req.set_header(HOLD_HEADER, client);
+    Ok(())
+}
+
+/// The hold of a WebSocket connection forwarded by Fanout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hold {
+    client: String,
+    connection_id: String,
+}
+
+impl Hold {
+    /// Returns the hold of the connection `req` was made for, if it counts
+    /// against its client's cap.
+    pub fn from_request(req: &Request) -> Option<Hold> {
+        let client = req.get_header_str(HOLD_HEADER)?;
+        let connection_id = req.get_header_str(CONNECTION_ID_HEADER)?;
+        if client.is_empty() || connection_id.is_empty() {
+            return None;
+        }
+        Some(Hold {
+            client: client.to_string(),
+            connection_id: connection_id.to_string(),
+        })
+    }
+
+    /// Takes or extends the hold of a connection still open.
+    pub fn refresh(&self) {
+        let mut holds = load(&self.client);
+        if extend(&mut holds, &self.connection_id, unix_now(), ttl()) {
+            save(&self.client, &holds);
+        }
+    }
+
+    /// Releases the hold of a connection that has closed.
+    pub fn release(&self) {
+        let mut holds = load(&self.client);
+        if holds.remove(&self.connection_id).is_some() {
+            save(&self.client, &holds);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_websockets_only() {
+        let ws = Request::get("https://example.com/ws").with_header("Upgrade", "WebSocket");
+        let sse =
+            Request::get("https://example.com/sse").with_header("Accept", "text/event-stream");
+        assert!(is_websocket(&ws));
+        assert!(!is_websocket(&sse));
+    }
+
+    #[test]
+    fn reads_holds_of_connections() {
+        let req = Request::post("https://example.com/ws")
+            .with_header(HOLD_HEADER, "2001:db8::1")
+            .with_header(CONNECTION_ID_HEADER, "conn-1");
+        assert_eq!(
+            Hold::from_request(&req),
+            Some(Hold {
+                client: "2001:db8::1".into(),
+                connection_id: "conn-1".into(),
+            })
+        );
+
+        let req = Request::post("https://example.com/ws").with_header(HOLD_HEADER, "192.0.2.1");
+        assert_eq!(Hold::from_request(&req), None);
+    }
+
+    #[test]
+    fn admits_clients_under_the_cap() {
+        let mut holds = Holds::new();
+        assert!(admit(&holds, 2, 100).is_ok());
+
+        assert!(extend(&mut holds, "a", 100, 60));
+        assert!(extend(&mut holds, "b", 110, 60));
+        assert_eq!(admit(&holds, 2, 120), Err(Limited { retry_after: 40 }));
+        assert!(admit(&holds, 3, 120).is_ok());
+
+        // expired holds no longer count
+        prune(&mut holds, 160);
+        assert_eq!(holds.keys().collect::<Vec<_>>(), ["b"]);
+        assert!(admit(&holds, 2, 160).is_ok());
+    }
+
+    #[test]
+    fn extends_holds_past_half_their_time() {
+        let mut holds = Holds::new();
+        assert!(extend(&mut holds, "a", 100, 60));
+        assert_eq!(holds["a"], 160);
+
+        // plenty of time left, nothing to write
+        assert!(!extend(&mut holds, "a", 120, 60));
+        assert_eq!(holds["a"], 160);
+
+        assert!(extend(&mut holds, "a", 131, 60));
+        assert_eq!(holds["a"], 191);
+    }
+
+    #[test]
+    fn releases_holds() {
+        let mut holds = Holds::new();
+        extend(&mut holds, "a", 100, 60);
+        extend(&mut holds, "b", 100, 60);
+        assert!(holds.remove("a").is_some());
+        assert!(admit(&holds, 2, 100).is_ok());
+        assert!(holds.remove("a").is_none());
+    }
+}
@@ -0,0 +1,74 @@
+//! Cross-origin resource sharing for the app's own endpoints.
+//!
+//! Origins are allowed by the comma-separated `cors_allowed_origins` setting,
+//! which may also be `*` to allow any origin. Without it, no CORS headers are
+//! sent and browsers only allow same-origin use.
+
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+
+use crate::config;
+
+/// Setting listing the origins allowed to use the app's endpoints.
+pub const ALLOWED_ORIGINS_SETTING: &str = "cors_allowed_origins";
+
+/// Setting listing the request headers cross-origin requests may send.
+pub const ALLOWED_HEADERS_SETTING: &str = "cors_allowed_headers";
+
+const DEFAULT_ALLOWED_HEADERS: &str = "Authorization, Content-Type, Last-Event-ID";
+const ALLOWED_METHODS: &str = "GET, POST, OPTIONS";
+const PREFLIGHT_MAX_AGE: u32 = 86400;
+
+/// Returns the `Access-Control-Allow-Origin` value for a request from
+/// `origin`, or `None` if the origin isn't allowed.
+pub fn allow_origin(origin: &str) -> Option<String> {
+    let allowed = config::setting(ALLOWED_ORIGINS_SETTING)?;
+
+    allowed.split(',').map(str::trim).find_map(|a| {
+        if a == "*" {
+            Some("*".to_string())
+        } else if a.eq_ignore_ascii_case(origin) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the response to a CORS preflight request, or `None` if `req`
+/// isn't one.
+pub fn preflight(req: &Request) -> Option<Response> {
+    if req.get_method() != Method::OPTIONS
+        || req.get_header("Access-Control-Request-Method").is_none()
+    {
+        return None;
+    }
+
+    let resp = Response::from_status(StatusCode::NO_CONTENT).with_header("Vary", "Origin");
+
+    let allowed = match req.get_header_str("Origin").and_then(allow_origin) {
+        Some(a) => a,
+        None => return Some(resp),
+    };
+
+    let headers =
+        config::setting(ALLOWED_HEADERS_SETTING).unwrap_or_else(|| DEFAULT_ALLOWED_HEADERS.into());
+
+    Some(
+        resp.with_header("Access-Control-Allow-Origin", allowed)
+            .with_header("Access-Control-Allow-Methods", ALLOWED_METHODS)
+            .with_header("Access-Control-Allow-Headers", headers)
+            .with_header("Access-Control-Max-Age", PREFLIGHT_MAX_AGE.to_string()),
+    )
+}
+
+/// Adds CORS headers to a response to a request from `origin`.
+pub fn apply(origin: Option<&str>, mut resp: Response) -> Response {
+    resp.append_header("Vary", "Origin");
+
+    if let Some(allowed) = origin.and_then(allow_origin) {
+        resp.set_header("Access-Control-Allow-Origin", allowed);
+    }
+
+    resp
+}
@@ -0,0 +1,166 @@
+//! Items that couldn't be published.
+//!
+//! When a publish fails for good, after any retries, each of its items is
+//! kept in the state KV Store under `deadletter:{id}`, along with when and
+//! why it failed, rather than being lost. The ids of the dead letters are
+//! listed, oldest first, under `deadletter:index`, as the KV Store can't be
+//! listed from Compute. Only the `dead_letter_size` most recent are kept.
+//!
+//! The admin API lists dead letters and publishes them again, see
+//! [`crate::admin`]. The index is updated with a read-modify-write, so
+//! failures recorded concurrently may drop each other's entries.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::grip::unix_now;
+use crate::history;
+use crate::publish::{Item, PublishError, Publisher};
+use crate::{log_error, log_info};
+
+/// Setting holding how many dead letters are kept. `0` disables them.
+pub const SIZE_SETTING: &str = "dead_letter_size";
+
+const DEFAULT_SIZE: usize = 100;
+
+const PREFIX: &str = "deadletter:";
+
+const INDEX_KEY: &str = "deadletter:index";
+
+/// An item that couldn't be published.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: String,
+    pub channel: String,
+    pub item: Item,
+    /// When publishing failed, in seconds since the Unix epoch.
+    pub time: u64,
+    pub error: String,
+}
+
+/// Returns how many dead letters are kept.
+pub fn size() -> usize {
+    config::setting(SIZE_SETTING)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_SIZE)
+}
+
+fn key(id: &str) -> String {
+    format!("{}{}", PREFIX, id)
+}
+
+fn load_index() -> Vec<String> {
+    config::state_store()
+        .and_then(|store| store.lookup_str(INDEX_KEY).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn save_index(ids: &[String]) {
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => return,
+    };
+
+    let value = serde_json::to_string(ids).expect("ids always serialize");
+    if let Err(e) = store.insert(INDEX_KEY, value) {
+        log_error!("failed to save dead letter index: {e}");
+    }
+}
+
+/// Keeps items that couldn't be published because of `error`.
+pub fn record(items: &[Item], error: &PublishError) {
+    let size = size();
+    if size == 0 || items.is_empty() {
+        return;
+    }
+
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => return,
+    };
+
+    let mut ids = load_index();
+    for item in items {
+        let letter = DeadLetter {
+            id: history::new_id(),
+            channel: item.channel.clone(),
+            item: item.clone(),
+            time: unix_now(),
+            error: error.to_string(),
+        };
+
+        let value = serde_json::to_string(&letter).expect("dead letters always serialize");
+        match store.insert(&key(&letter.id), value) {
+            Ok(()) => ids.push(letter.id),
+            Err(e) => log_error!("failed to keep dead letter for {}: {e}", item.channel),
+        }
+    }
+
+    if ids.len() > size {
+        for id in ids.drain(..ids.len() - size) {
+            let _ = store.delete(&key(&id));
+        }
+    }
+
+    save_index(&ids);
+}
+
+/// Returns the dead letter with the given id, if it is kept.
+pub fn get(id: &str) -> Option<DeadLetter> {
+    config::state_store()
+        .and_then(|store| store.lookup_str(&key(id)).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+}
+
+/// Returns the dead letters kept, oldest first.
+pub fn list() -> Vec<DeadLetter> {
+    load_index().iter().filter_map(|id| get(id)).collect()
+}
+
+/// Forgets a dead letter.
+pub fn remove(id: &str) {
+    if let Some(store) = config::state_store() {
+        let _ = store.delete(&key(id));
+    }
+
+    let mut ids = load_index();
+    ids.retain(|i| i != id);
+    save_index(&ids);
+}
+
+/// Outcome of publishing dead letters again.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Redrive {
+    /// Ids of the dead letters published, and so forgotten.
+    pub published: Vec<String>,
+    /// Ids of the dead letters that failed again, and are still kept.
+    pub failed: Vec<String>,
+}
+
+/// Publishes dead letters again, forgetting those that get through.
+pub fn redrive(publisher: &Publisher, letters: &[DeadLetter]) -> Redrive {
+    let mut outcome = Redrive::default();
+
+    for letter in letters {
+        match publisher.try_publish_items(std::slice::from_ref(&letter.item)) {
+            Ok(()) => {
+                log_info!(
+                    "republished dead letter {} to {}",
+                    letter.id,
+                    letter.channel
+                );
+                remove(&letter.id);
+                outcome.published.push(letter.id.clone());
+            }
+            Err(e) => {
+                log_error!("failed to republish dead letter {}: {e}", letter.id);
+                outcome.failed.push(letter.id.clone());
+            }
+        }
+    }
+
+    outcome
+}
@@ -0,0 +1,1168 @@
+//! Deciding what becomes of a client request.
+//!
+//! A [`Router`] looks at a request and returns an [`Outcome`]: a response
+//! to send, a handoff to Fanout, or a request to forward straight to a
+//! backend. Requests to realm hosts (`*.fanoutcdn.com`) are
+//! served by the app's own [`Endpoint`]s, everything else is proxied,
+//! following the routing rules of [`crate::rules`]. The responses the app
+//! makes itself, errors included, get the route's security headers, see
+//! [`crate::headers`].
+//!
+//! Everything that needs the Fanout runtime, from ACL lookups to the
+//! endpoints themselves, goes through [`Handlers`], whose provided methods
+//! call the real thing. Implementations overriding them let the routing be
+//! exercised without it.
+
+use std::net::IpAddr;
+
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+
+use crate::acl;
+use crate::admin;
+use crate::backends;
+use crate::chat;
+use crate::config;
+use crate::connections;
+use crate::cors;
+use crate::error::AppError;
+use crate::geo::{self, Region};
+use crate::grip::{self, SigError};
+use crate::headers::HeaderPolicy;
+use crate::log_info;
+use crate::maintenance;
+use crate::methods;
+use crate::metrics;
+use crate::ratelimit::{self, Scope};
+use crate::router::{self, Route};
+use crate::rules::{self, Rule};
+use crate::signing;
+use crate::sockjs;
+use crate::tenant::{self, TenantSource};
+use crate::ws::CONNECTION_ID_HEADER;
+
+/// Host suffix of the realms whose endpoints the app serves itself.
+pub const REALM_SUFFIX: &str = ".fanoutcdn.com";
+
+/// Path prefixes the static assets are served under.
+pub const STATIC_PREFIXES: [&str; 2] = ["/test/static/", "/bayeux/static/"];
+
+/// The app's own endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Healthz,
+    Metrics,
+    Static,
+    Test,
+    Publish,
+    Admin,
+    Hooks,
+    Presence,
+    /// The page of a chat room.
+    ChatPage,
+    /// The WebSocket endpoint of a chat room.
+    ChatWs,
+    Demo,
+    Graphql,
+    Mqtt,
+    Stomp,
+    SocketIo,
+    SockJs,
+    Bayeux,
+}
+
+impl Endpoint {
+    /// Returns the endpoint serving `path` on realm hosts, or `None` if
+    /// requests to it are proxied.
+    pub fn for_path(path: &str) -> Option<Self> {
+        let under = |prefix: &str| path == prefix || path.starts_with(&format!("{prefix}/"));
+
+        let endpoint = match path {
+            "/healthz" => Endpoint::Healthz,
+            "/metrics" => Endpoint::Metrics,
+            "/graphql" => Endpoint::Graphql,
+            "/mqtt" => Endpoint::Mqtt,
+            "/stomp" => Endpoint::Stomp,
+            _ if STATIC_PREFIXES.iter().any(|p| path.starts_with(p)) => Endpoint::Static,
+            _ if under("/test") => Endpoint::Test,
+            _ if path.starts_with("/publish/") => Endpoint::Publish,
+            _ if path.starts_with(admin::PATH_PREFIX) => Endpoint::Admin,
+            _ if path.starts_with("/hooks/") => Endpoint::Hooks,
+            _ if path.starts_with("/presence/") => Endpoint::Presence,
+            _ if path.starts_with(chat::PATH_PREFIX) => match chat::parse_path(path) {
+                Some((_, "ws")) => Endpoint::ChatWs,
+                _ => Endpoint::ChatPage,
+            },
+            _ if under("/demo") => Endpoint::Demo,
+            _ if path.starts_with("/socket.io/") => Endpoint::SocketIo,
+            _ if under(sockjs::PATH_PREFIX) => Endpoint::SockJs,
+            _ if under("/bayeux") => Endpoint::Bayeux,
+            _ => return None,
+        };
+        Some(endpoint)
+    }
+
+    /// Returns the name requests to the endpoint are counted under.
+    pub fn name(self) -> &'static str {
+        match self {
+            Endpoint::Healthz => "healthz",
+            Endpoint::Metrics => "metrics",
+            Endpoint::Static | Endpoint::Demo => "static",
+            Endpoint::Test => "test",
+            Endpoint::Publish => "publish",
+            Endpoint::Admin => "admin",
+            Endpoint::Hooks => "hooks",
+            Endpoint::Presence => "presence",
+            Endpoint::ChatPage | Endpoint::ChatWs => "chat",
+            Endpoint::Graphql => "graphql",
+            Endpoint::Mqtt => "mqtt",
+            Endpoint::Stomp => "stomp",
+            Endpoint::SocketIo => "socketio",
+            Endpoint::SockJs => "sockjs",
+            Endpoint::Bayeux => "bayeux",
+        }
+    }
+
+    /// Returns whether the endpoint holds connections through Fanout, so
+    /// requests from clients are handed off rather than served.
+    pub fn via_fanout(self) -> bool {
+        matches!(
+            self,
+            Endpoint::Test
+                | Endpoint::ChatWs
+                | Endpoint::Graphql
+                | Endpoint::Mqtt
+                | Endpoint::Stomp
+                | Endpoint::SocketIo
+                | Endpoint::SockJs
+                | Endpoint::Bayeux
+        )
+    }
+
+    /// Returns whether requests to the endpoint must belong to a tenant on
+    /// hosts shared by several.
+    pub fn needs_tenant(self) -> bool {
+        !matches!(
+            self,
+            Endpoint::Healthz | Endpoint::Metrics | Endpoint::Static | Endpoint::Demo
+        )
+    }
+}
+
+/// Returns the ACL clients of the app's own endpoint at `path` must pass,
+/// if any.
+pub fn acl_list(path: &str) -> Option<acl::List> {
+    if path.starts_with("/publish/")
+        || path.starts_with("/hooks/")
+        || path.starts_with(admin::PATH_PREFIX)
+    {
+        Some(acl::List::Publish)
+    } else if path == "/test" || path.starts_with("/test/") {
+        Some(acl::List::Test)
+    } else {
+        None
+    }
+}
+
+/// Returns whether the app's own endpoint at `path` answers CORS preflight
+/// requests.
+pub fn answers_preflight(path: &str) -> bool {
+    let under = |prefix: &str| path == prefix || path.starts_with(&format!("{prefix}/"));
+
+    under("/test")
+        || under("/bayeux")
+        || under(sockjs::PATH_PREFIX)
+        || path.starts_with("/publish/")
+        || path.starts_with("/presence/")
+        || path.starts_with("/socket.io/")
+}
+
+/// Returns the methods accepted by the app's own endpoint at `path`, or
+/// `None` for paths that are proxied or whose protocols deal with methods
+/// themselves.
+pub fn allowed_methods(path: &str) -> Option<&'static [Method]> {
+    let is_ws = matches!(
+        path,
+        "/graphql"
+            | "/mqtt"
+            | "/stomp"
+            | "/test/ws"
+            | "/test/ws/echo"
+            | "/test/ws/broadcast"
+            | "/test/ws/detached"
+            | "/test/jsonrpc"
+    ) || (path.starts_with(chat::PATH_PREFIX) && path.ends_with("/ws"));
+
+    if is_ws {
+        Some(methods::GET_POST)
+    } else if path.starts_with("/publish/") || path.starts_with("/hooks/") || admin::is_action(path)
+    {
+        Some(methods::POST)
+    } else if matches!(path, "/healthz" | "/metrics" | "/test" | "/demo")
+        || [
+            "/test/",
+            "/presence/",
+            "/demo/",
+            "/bayeux/static/",
+            "/admin/",
+        ]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+    {
+        Some(methods::GET_HEAD)
+    } else {
+        None
+    }
+}
+
+/// What becomes of a request.
+#[derive(Debug)]
+pub enum Outcome {
+    /// Send this response to the client.
+    Respond(Response),
+    /// Hand the request off to Fanout, forwarding it to `backend`. If that
+    /// fails and `fallback` is set, the request is sent there directly.
+    Handoff {
+        req: Request,
+        backend: String,
+        fallback: bool,
+    },
+    /// Send the request straight to `backend`, bypassing Fanout.
+    Forward { req: Request, backend: String },
+}
+
+/// The endpoints and runtime services a [`Router`] relies on.
+///
+/// Only [`Handlers::serve`] must be implemented; the other methods default
+/// to the services of the Fanout runtime.
+pub trait Handlers {
+    /// Serves a request with one of the app's own endpoints.
+    fn serve(&self, endpoint: Endpoint, req: Request, route: &Route) -> Response;
+
+    /// Returns the route of requests to `host`.
+    fn route(&self, host: &str, tls: bool) -> Route {
+        router::route_for_host(host, tls)
+    }
+
+    /// Returns whether the client of `req` passes the ACL `list`.
+    fn allowed(&self, list: acl::List, req: &Request) -> bool {
+        let allowed = acl::allowed(list, req.get_client_ip_addr());
+        if !allowed {
+            log_info!("refusing client not allowed by the {list:?} ACL");
+        }
+        allowed
+    }
+
+    /// Answers `req` if it's a CORS preflight request.
+    fn preflight(&self, req: &Request) -> Option<Response> {
+        cors::preflight(req)
+    }
+
+    /// Refuses `req` if its method isn't one of `allowed`.
+    fn check_method(&self, req: &Request, allowed: &[Method]) -> Option<Response> {
+        let origin = req.get_header_str("Origin").map(str::to_string);
+        methods::check(req, allowed).map(|resp| cors::apply(origin.as_deref(), resp))
+    }
+
+    /// Returns the tenant of a request to `host`, if it has a valid one.
+    fn tenant(&self, req: &Request, host: &str, source: TenantSource) -> Option<String> {
+        tenant::resolve(req, host, source)
+    }
+
+    /// Verifies the `Grip-Sig` header of a request forwarded by Fanout.
+    fn verify_sig(&self, sig: &str) -> Result<(), SigError> {
+        grip::verify_request_sig(sig).map(|_| ())
+    }
+
+    /// Refuses a new connection over the connect rate limit of `route`.
+    fn limit(&self, req: &Request, route: &Route) -> Option<Response> {
+        match ratelimit::check(req, &route.rate_limit, Scope::Connect) {
+            Ok(()) => None,
+            Err(limited) => {
+                log_info!("refusing connection: {limited}");
+                Some(limited.response())
+            }
+        }
+    }
+
+    /// Refuses a new connection to the app's own endpoints over the cap of
+    /// `route` on the client's open connections, or has it counted against
+    /// the cap otherwise.
+    fn hold(&self, req: &mut Request, route: &Route) -> Option<Response> {
+        match connections::check(req, route.rate_limit.max_connections) {
+            Ok(()) => None,
+            Err(limited) => {
+                log_info!("refusing connection: {limited}");
+                Some(limited.response())
+            }
+        }
+    }
+
+    /// Returns the response refusing a request with `error`.
+    fn error(&self, error: AppError) -> Response {
+        error.response()
+    }
+
+    /// Refuses new connections while in maintenance mode.
+    fn maintenance(&self) -> Option<Response> {
+        if !maintenance::enabled() {
+            return None;
+        }
+        log_info!("refusing connection during maintenance");
+        Some(maintenance::response())
+    }
+
+    /// Returns the routing rule of a proxied request, if one matches.
+    fn rule(&self, host: &str, method: &str, path: &str) -> Option<Rule> {
+        rules::find(host, method, path)
+    }
+
+    /// Returns the IP address of the client of `req`.
+    fn client_ip(&self, req: &Request) -> Option<IpAddr> {
+        req.get_client_ip_addr()
+    }
+
+    /// Returns where the client of `req` is, for rules with regional
+    /// backends.
+    fn region(&self, req: &Request) -> Option<Region> {
+        geo::client_region(req)
+    }
+
+    /// Returns the backend proxied requests of `route` go to.
+    fn backend(&self, route: &Route, tls: bool) -> String {
+        backends::resolve(route, tls)
+    }
+
+    /// Signs a request before it leaves for a backend.
+    fn sign(&self, req: &mut Request) {
+        signing::sign(req);
+    }
+}
+
+/// Returns the response redirecting a plaintext request to HTTPS, keeping
+/// its method unless it's `GET` or `HEAD`.
+fn redirect_to_https(req: &Request) -> Response {
+    let mut url = req.get_url().clone();
+    // http and https are both special schemes, so this can't fail
+    let _ = url.set_scheme("https");
+
+    let status = if matches!(*req.get_method(), Method::GET | Method::HEAD) {
+        StatusCode::MOVED_PERMANENTLY
+    } else {
+        StatusCode::PERMANENT_REDIRECT
+    };
+    Response::from_status(status).with_header("Location", url.as_str())
+}
+
+/// Routes client requests to the app's endpoints or their backends.
+pub struct Router<H> {
+    handlers: H,
+}
+
+impl<H: Handlers> Router<H> {
+    pub fn new(handlers: H) -> Self {
+        Router { handlers }
+    }
+
+    /// Decides what becomes of a client request.
+    pub fn handle(&self, req: Request) -> Outcome {
+        let host = match req.get_url().host_str() {
+            Some(host) => host.to_string(),
+            None => {
+                let resp = self
+                    .handlers
+                    .error(AppError::RoutingError("Unknown host.".into()));
+                return Outcome::Respond(HeaderPolicy::default().apply(resp, false));
+            }
+        };
+        let tls = req.get_url().scheme().eq_ignore_ascii_case("https");
+
+        let mut route = self.handlers.route(&host, tls);
+
+        let outcome = self
+            .dispatch(req, &host, tls, &mut route)
+            .unwrap_or_else(|e| Outcome::Respond(self.handlers.error(e)));
+        match outcome {
+            Outcome::Respond(resp) => Outcome::Respond(route.headers.apply(resp, tls)),
+            other => other,
+        }
+    }
+
+    fn dispatch(
+        &self,
+        mut req: Request,
+        host: &str,
+        tls: bool,
+        route: &mut Route,
+    ) -> Result<Outcome, AppError> {
+        let path = req.get_path().to_string();
+
+        let endpoint = if host.ends_with(REALM_SUFFIX) {
+            Endpoint::for_path(&path)
+        } else {
+            None
+        };
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => return self.proxy(req, host, &path, route, tls),
+        };
+
+        if let Some(list) = acl_list(&path) {
+            if !self.handlers.allowed(list, &req) {
+                return Err(AppError::forbidden("Client not allowed."));
+            }
+        }
+
+        if answers_preflight(&path) {
+            if let Some(resp) = self.handlers.preflight(&req) {
+                return Ok(Outcome::Respond(resp));
+            }
+        }
+
+        if let Some(allowed) = allowed_methods(&path) {
+            if let Some(resp) = self.handlers.check_method(&req, allowed) {
+                return Ok(Outcome::Respond(resp));
+            }
+        }
+
+        if endpoint == Endpoint::ChatPage
+            && chat::parse_path(&path).is_none_or(|(_, s)| !s.is_empty())
+        {
+            return Err(AppError::RoutingError("No chat endpoint here.".into()));
+        }
+
+        // channels of hosts shared by several tenants live in the tenant's
+        // namespace, so requests that can't be placed in one are refused
+        if let Some(source) = route.tenant_source.filter(|_| endpoint.needs_tenant()) {
+            route.tenant = self.handlers.tenant(&req, host, source);
+            if route.tenant.is_none() {
+                return Err(AppError::forbidden("No tenant for the request."));
+            }
+        }
+
+        metrics::incr("requests_total", &[("endpoint", endpoint.name())]);
+
+        if !endpoint.via_fanout() || config::local_dev() {
+            // in local development there is no Fanout to hand off to, so
+            // requests are served as if Fanout had forwarded them
+            return Ok(Outcome::Respond(self.handlers.serve(endpoint, req, route)));
+        }
+
+        if let Some(sig) = req.get_header_str("Grip-Sig") {
+            // request claims to be from fanout, make sure it really is
+            if let Err(e) = self.handlers.verify_sig(sig) {
+                return Err(AppError::unauthorized(format!("Invalid Grip-Sig: {e}")));
+            }
+            return Ok(Outcome::Respond(self.handlers.serve(endpoint, req, route)));
+        }
+
+        // not from fanout, so this establishes a connection
+        if !tls && route.require_tls {
+            return Ok(Outcome::Respond(redirect_to_https(&req)));
+        }
+        if let Some(resp) = self.handlers.maintenance() {
+            return Ok(Outcome::Respond(resp));
+        }
+        if let Some(resp) = self.handlers.limit(&req, route) {
+            return Ok(Outcome::Respond(resp));
+        }
+        if let Some(resp) = self.handlers.hold(&mut req, route) {
+            return Ok(Outcome::Respond(resp));
+        }
+
+        // hand it off to fanout to manage
+        Ok(Outcome::Handoff {
+            req,
+            backend: format!("self_{}", host),
+            fallback: false,
+        })
+    }
+
+    fn proxy(
+        &self,
+        mut req: Request,
+        host: &str,
+        path: &str,
+        route: &Route,
+        tls: bool,
+    ) -> Result<Outcome, AppError> {
+        if !self.handlers.allowed(acl::List::Proxy, &req) {
+            return Err(AppError::forbidden("Client not allowed."));
+        }
+
+        // proxied requests all come from clients, as Fanout sends the events
+        // of their connections straight to the backend, so a connection id
+        // can only be made up, and their connections aren't counted against
+        // the client's cap, as the app never hears when they close
+        req.remove_header(CONNECTION_ID_HEADER);
+        req.remove_header(connections::HOLD_HEADER);
+
+        let rule = self.handlers.rule(host, req.get_method_str(), path);
+
+        // only requests through Fanout open connections, those forwarded
+        // directly are left to the backend
+        if !tls && route.require_tls && rule.as_ref().is_none_or(|rule| rule.fanout) {
+            return Ok(Outcome::Respond(redirect_to_https(&req)));
+        }
+
+        // clients are only located for rules that need it
+        let region = match &rule {
+            Some(rule) if !rule.regions.is_empty() => self.handlers.region(&req),
+            _ => None,
+        };
+
+        // clients split between backends stick to their side by IP address,
+        // as they could pick theirs with any header
+        let client = match &rule {
+            Some(rule) if rule.canary.is_some() || !rule.backends.is_empty() => {
+                self.handlers.client_ip(&req).map(|ip| ip.to_string())
+            }
+            _ => None,
+        };
+
+        let backend = match rule
+            .as_ref()
+            .and_then(|r| r.backend_for(host, region.as_ref(), client.as_deref()))
+        {
+            Some(backend) => backend,
+            None => self.handlers.backend(route, tls),
+        };
+
+        if let Some(rule) = &rule {
+            let forwarded_path = rule.rewrite_path(path);
+            if forwarded_path != path {
+                req.set_path(&forwarded_path);
+            }
+
+            if !rule.fanout {
+                metrics::incr("requests_total", &[("endpoint", "direct")]);
+                self.handlers.sign(&mut req);
+                return Ok(Outcome::Forward { req, backend });
+            }
+        }
+
+        if let Some(resp) = self.handlers.maintenance() {
+            return Ok(Outcome::Respond(resp));
+        }
+        if let Some(resp) = self.handlers.limit(&req, route) {
+            return Ok(Outcome::Respond(resp));
+        }
+
+        metrics::incr("requests_total", &[("endpoint", "proxy")]);
+        self.handlers.sign(&mut req);
+        Ok(Outcome::Handoff {
+            req,
+            backend,
+            fallback: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Handlers standing in for the runtime: every endpoint answers 200
+    /// naming itself, and everything else is decided by the fields.
+    #[derive(Default)]
+    struct Fake {
+        denied: Option<acl::List>,
+        tenant_source: Option<TenantSource>,
+        tenant: Option<String>,
+        bad_sig: bool,
+        limited: bool,
+        at_cap: bool,
+        maintenance: bool,
+        rule: Option<Rule>,
+        region: Option<Region>,
+        client_ip: Option<IpAddr>,
+        headers: HeaderPolicy,
+        require_tls: bool,
+    }
+
+    impl Handlers for Fake {
+        fn serve(&self, endpoint: Endpoint, _req: Request, route: &Route) -> Response {
+            Response::from_status(200)
+                .with_header("X-Endpoint", endpoint.name())
+                .with_header("X-Tenant", route.tenant.as_deref().unwrap_or("-"))
+        }
+
+        fn route(&self, host: &str, tls: bool) -> Route {
+            let mut route = Route::default_for_host(host, tls);
+            route.tenant_source = self.tenant_source;
+            route.headers = self.headers.clone();
+            route.require_tls = self.require_tls;
+            route
+        }
+
+        fn allowed(&self, list: acl::List, _req: &Request) -> bool {
+            self.denied != Some(list)
+        }
+
+        fn preflight(&self, req: &Request) -> Option<Response> {
+            (req.get_method() == Method::OPTIONS).then(|| Response::from_status(204))
+        }
+
+        fn check_method(&self, req: &Request, allowed: &[Method]) -> Option<Response> {
+            (!allowed.contains(req.get_method())).then(|| Response::from_status(405))
+        }
+
+        fn tenant(&self, _req: &Request, _host: &str, _source: TenantSource) -> Option<String> {
+            self.tenant.clone()
+        }
+
+        fn verify_sig(&self, _sig: &str) -> Result<(), SigError> {
+            if self.bad_sig {
+                Err(SigError::Malformed)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn error(&self, error: AppError) -> Response {
+            Response::from_status(error.status())
+        }
+
+        fn limit(&self, _req: &Request, _route: &Route) -> Option<Response> {
+            self.limited.then(|| Response::from_status(429))
+        }
+
+        fn hold(&self, _req: &mut Request, _route: &Route) -> Option<Response> {
+            self.at_cap.then(|| Response::from_status(429))
+        }
+
+        fn maintenance(&self) -> Option<Response> {
+            self.maintenance.then(|| Response::from_status(503))
+        }
+
+        fn rule(&self, _host: &str, _method: &str, _path: &str) -> Option<Rule> {
+            self.rule.clone()
+        }
+
+        fn client_ip(&self, _req: &Request) -> Option<IpAddr> {
+            self.client_ip
+        }
+
+        fn region(&self, _req: &Request) -> Option<Region> {
+            self.region.clone()
+        }
+
+        fn backend(&self, route: &Route, _tls: bool) -> String {
+            route.backend.clone()
+        }
+
+        fn sign(&self, req: &mut Request) {
+            req.set_header("X-Signed", "1");
+        }
+    }
+
+    const REALM: &str = "https://realm.fanoutcdn.com";
+
+    fn handle(fake: Fake, req: Request) -> Outcome {
+        Router::new(fake).handle(req)
+    }
+
+    fn served_by(outcome: Outcome) -> String {
+        match outcome {
+            Outcome::Respond(resp) => resp.get_header_str("X-Endpoint").unwrap_or("").to_string(),
+            other => panic!("expected a response, got {other:?}"),
+        }
+    }
+
+    fn status(outcome: Outcome) -> u16 {
+        match outcome {
+            Outcome::Respond(resp) => resp.get_status().as_u16(),
+            other => panic!("expected a response, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn classifies_paths() {
+        let cases = [
+            ("/healthz", Some(Endpoint::Healthz)),
+            ("/metrics", Some(Endpoint::Metrics)),
+            ("/test/static/app.js", Some(Endpoint::Static)),
+            ("/bayeux/static/client.js", Some(Endpoint::Static)),
+            ("/test", Some(Endpoint::Test)),
+            ("/test/ws", Some(Endpoint::Test)),
+            ("/publish/room", Some(Endpoint::Publish)),
+            ("/admin/channels", Some(Endpoint::Admin)),
+            ("/hooks/github", Some(Endpoint::Hooks)),
+            ("/presence/room", Some(Endpoint::Presence)),
+            ("/demo/chat/lobby", Some(Endpoint::ChatPage)),
+            ("/demo/chat/lobby/ws", Some(Endpoint::ChatWs)),
+            ("/demo", Some(Endpoint::Demo)),
+            ("/demo/app.js", Some(Endpoint::Demo)),
+            ("/graphql", Some(Endpoint::Graphql)),
+            ("/mqtt", Some(Endpoint::Mqtt)),
+            ("/stomp", Some(Endpoint::Stomp)),
+            ("/socket.io/", Some(Endpoint::SocketIo)),
+            ("/bayeux", Some(Endpoint::Bayeux)),
+            ("/", None),
+            ("/testing", None),
+            ("/api/items", None),
+        ];
+
+        for (path, endpoint) in cases {
+            assert_eq!(Endpoint::for_path(path), endpoint, "{path}");
+        }
+    }
+
+    #[test]
+    fn picks_acl_lists() {
+        assert_eq!(acl_list("/publish/room"), Some(acl::List::Publish));
+        assert_eq!(acl_list("/hooks/github"), Some(acl::List::Publish));
+        assert_eq!(acl_list("/admin/config"), Some(acl::List::Publish));
+        assert_eq!(acl_list("/test/static/app.js"), Some(acl::List::Test));
+        assert_eq!(acl_list("/bayeux/static/client.js"), None);
+        assert_eq!(acl_list("/healthz"), None);
+    }
+
+    #[test]
+    fn lists_allowed_methods() {
+        assert_eq!(allowed_methods("/test/ws"), Some(methods::GET_POST));
+        assert_eq!(
+            allowed_methods("/demo/chat/lobby/ws"),
+            Some(methods::GET_POST)
+        );
+        assert_eq!(allowed_methods("/publish/room"), Some(methods::POST));
+        assert_eq!(allowed_methods("/admin/drain"), Some(methods::POST));
+        assert_eq!(
+            allowed_methods("/admin/scheduled/deliver"),
+            Some(methods::POST)
+        );
+        assert_eq!(allowed_methods("/healthz"), Some(methods::GET_HEAD));
+        assert_eq!(allowed_methods("/bayeux"), None);
+        assert_eq!(allowed_methods("/api/items"), None);
+    }
+
+    #[test]
+    fn serves_plain_endpoints() {
+        let outcome = handle(Fake::default(), Request::get(format!("{REALM}/healthz")));
+        assert_eq!(served_by(outcome), "healthz");
+    }
+
+    #[test]
+    fn hands_off_client_connections_to_self() {
+        let outcome = handle(Fake::default(), Request::get(format!("{REALM}/test/ws")));
+        match outcome {
+            Outcome::Handoff {
+                backend, fallback, ..
+            } => {
+                assert_eq!(backend, "self_realm.fanoutcdn.com");
+                assert!(!fallback);
+            }
+            other => panic!("expected a handoff, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn serves_requests_forwarded_by_fanout() {
+        let req = Request::get(format!("{REALM}/test/ws")).with_header("Grip-Sig", "token");
+        assert_eq!(served_by(handle(Fake::default(), req)), "test");
+    }
+
+    #[test]
+    fn refuses_bad_grip_sig() {
+        let fake = Fake {
+            bad_sig: true,
+            ..Fake::default()
+        };
+        let req = Request::get(format!("{REALM}/test/ws")).with_header("Grip-Sig", "token");
+        assert_eq!(status(handle(fake, req)), 401);
+    }
+
+    #[test]
+    fn refuses_rate_limited_connections() {
+        let fake = Fake {
+            limited: true,
+            ..Fake::default()
+        };
+        let outcome = handle(fake, Request::get(format!("{REALM}/graphql")));
+        assert_eq!(status(outcome), 429);
+    }
+
+    #[test]
+    fn caps_connections_to_own_endpoints_only() {
+        let fake = Fake {
+            at_cap: true,
+            ..Fake::default()
+        };
+        let outcome = handle(fake, Request::get(format!("{REALM}/test/ws")));
+        assert_eq!(status(outcome), 429);
+
+        // proxied connections aren't counted, nor can clients claim a hold
+        let fake = Fake {
+            at_cap: true,
+            ..Fake::default()
+        };
+        let req = Request::get("https://api.example.com/stream")
+            .with_header(connections::HOLD_HEADER, "192.0.2.1");
+        match handle(fake, req) {
+            Outcome::Handoff { req, .. } => {
+                assert_eq!(req.get_header_str(connections::HOLD_HEADER), None)
+            }
+            _ => panic!("expected a handoff"),
+        }
+    }
+
+    #[test]
+    fn refuses_clients_outside_acl() {
+        let fake = Fake {
+            denied: Some(acl::List::Test),
+            ..Fake::default()
+        };
+        let outcome = handle(fake, Request::get(format!("{REALM}/test")));
+        assert_eq!(status(outcome), 403);
+    }
+
+    #[test]
+    fn answers_preflight_and_refuses_methods() {
+        let req = Request::new(Method::OPTIONS, format!("{REALM}/publish/room"));
+        assert_eq!(status(handle(Fake::default(), req)), 204);
+
+        let req = Request::get(format!("{REALM}/publish/room"));
+        assert_eq!(status(handle(Fake::default(), req)), 405);
+    }
+
+    #[test]
+    fn refuses_unknown_chat_paths() {
+        let outcome = handle(
+            Fake::default(),
+            Request::get(format!("{REALM}/demo/chat/lobby/x")),
+        );
+        assert_eq!(status(outcome), 404);
+    }
+
+    #[test]
+    fn requires_tenants_on_shared_hosts() {
+        let fake = Fake {
+            tenant_source: Some(TenantSource::Host),
+            ..Fake::default()
+        };
+        let outcome = handle(fake, Request::get(format!("{REALM}/presence/room")));
+        assert_eq!(status(outcome), 403);
+
+        let fake = Fake {
+            tenant_source: Some(TenantSource::Host),
+            tenant: Some("acme".into()),
+            ..Fake::default()
+        };
+        match handle(fake, Request::get(format!("{REALM}/presence/room"))) {
+            Outcome::Respond(resp) => assert_eq!(resp.get_header_str("X-Tenant"), Some("acme")),
+            other => panic!("expected a response, got {other:?}"),
+        }
+
+        let fake = Fake {
+            tenant_source: Some(TenantSource::Host),
+            ..Fake::default()
+        };
+        let outcome = handle(fake, Request::get(format!("{REALM}/healthz")));
+        assert_eq!(served_by(outcome), "healthz");
+    }
+
+    #[test]
+    fn proxies_other_hosts() {
+        let outcome = handle(
+            Fake::default(),
+            Request::get("https://api.example.com/items"),
+        );
+        match outcome {
+            Outcome::Handoff {
+                req,
+                backend,
+                fallback,
+            } => {
+                assert_eq!(backend, "https_backend_api.example.com");
+                assert!(fallback);
+                assert_eq!(req.get_header_str("X-Signed"), Some("1"));
+            }
+            other => panic!("expected a handoff, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn follows_direct_rules() {
+        let fake = Fake {
+            rule: Some(Rule {
+                host: None,
+                path: Some("/api/*".into()),
+                methods: Vec::new(),
+                backend: Some("api".into()),
+                fanout: false,
+                rewrite: Some("/v2/".into()),
+                regions: Default::default(),
+                canary: None,
+                backends: Vec::new(),
+            }),
+            ..Fake::default()
+        };
+        match handle(fake, Request::get("https://example.com/api/items")) {
+            Outcome::Forward { req, backend } => {
+                assert_eq!(backend, "api");
+                assert_eq!(req.get_path(), "/v2/items");
+            }
+            other => panic!("expected a forward, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn refuses_proxy_clients_outside_acl() {
+        let fake = Fake {
+            denied: Some(acl::List::Proxy),
+            ..Fake::default()
+        };
+        let outcome = handle(fake, Request::get("https://example.com/"));
+        assert_eq!(status(outcome), 403);
+    }
+
+    #[test]
+    fn picks_regional_backends() {
+        let rule = Rule {
+            host: None,
+            path: None,
+            methods: Vec::new(),
+            backend: Some("origin".into()),
+            fanout: true,
+            rewrite: None,
+            regions: [
+                ("EU".to_string(), "https_backend_eu_{host}".to_string()),
+                ("jp".to_string(), "origin_tokyo".to_string()),
+            ]
+            .into(),
+            canary: None,
+            backends: Vec::new(),
+        };
+        let backend = |continent: &str, country: &str| {
+            let fake = Fake {
+                rule: Some(rule.clone()),
+                region: Some(Region {
+                    continent: continent.into(),
+                    country: country.into(),
+                }),
+                ..Fake::default()
+            };
+            match handle(fake, Request::get("https://api.example.com/")) {
+                Outcome::Handoff { backend, .. } => backend,
+                other => panic!("expected a handoff, got {other:?}"),
+            }
+        };
+
+        assert_eq!(backend("EU", "DE"), "https_backend_eu_api.example.com");
+        assert_eq!(backend("AS", "JP"), "origin_tokyo");
+        assert_eq!(backend("NA", "US"), "origin");
+    }
+
+    #[test]
+    fn splits_clients_with_canaries() {
+        let rule = Rule {
+            host: None,
+            path: None,
+            methods: Vec::new(),
+            backend: Some("stable".into()),
+            fanout: true,
+            rewrite: None,
+            regions: Default::default(),
+            canary: Some(rules::Canary {
+                backend: "canary".into(),
+                percent: 25,
+            }),
+            backends: Vec::new(),
+        };
+        let backend = |req: Request, client_ip: Option<IpAddr>| {
+            let fake = Fake {
+                rule: Some(rule.clone()),
+                client_ip,
+                ..Fake::default()
+            };
+            match handle(fake, req) {
+                Outcome::Handoff { backend, .. } => backend,
+                other => panic!("expected a handoff, got {other:?}"),
+            }
+        };
+
+        let mut canaries = 0;
+        for i in 0..400u32 {
+            let ip = IpAddr::from(i.wrapping_mul(2654435761).to_be_bytes());
+            let req = || Request::get("https://api.example.com/");
+            let first = backend(req(), Some(ip));
+            assert_eq!(backend(req(), Some(ip)), first, "{ip} switched sides");
+            if first == "canary" {
+                canaries += 1;
+            }
+        }
+        assert!(
+            (60..140).contains(&canaries),
+            "{canaries} of 400 on the canary"
+        );
+
+        // clients can't pick their side with a connection id of their own
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let side = backend(Request::get("https://api.example.com/"), Some(ip));
+        for i in 0..20 {
+            let req = Request::get("https://api.example.com/")
+                .with_header(CONNECTION_ID_HEADER, format!("conn-{i}"));
+            assert_eq!(backend(req, Some(ip)), side);
+        }
+
+        assert_eq!(
+            backend(Request::get("https://api.example.com/"), None),
+            "stable"
+        );
+    }
+
+    #[test]
+    fn pins_clients_to_instances() {
+        let rule = Rule {
+            host: None,
+            path: None,
+            methods: Vec::new(),
+            backend: Some("origin".into()),
+            fanout: true,
+            rewrite: None,
+            regions: Default::default(),
+            canary: None,
+            backends: vec!["origin_1".into(), "origin_2".into(), "origin_3".into()],
+        };
+        let backend = |connection_id: &str, ip: [u8; 4]| {
+            let fake = Fake {
+                rule: Some(rule.clone()),
+                client_ip: Some(IpAddr::from(ip)),
+                ..Fake::default()
+            };
+            let req = Request::post("https://api.example.com/ws")
+                .with_header(CONNECTION_ID_HEADER, connection_id);
+            match handle(fake, req) {
+                Outcome::Handoff { req, backend, .. } => {
+                    assert!(!req.contains_header(CONNECTION_ID_HEADER));
+                    backend
+                }
+                other => panic!("expected a handoff, got {other:?}"),
+            }
+        };
+
+        let mut used = std::collections::BTreeSet::new();
+        for i in 0..30u8 {
+            let ip = [10, 0, 0, i];
+            let pinned = backend("conn-1", ip);
+            assert_eq!(backend("conn-2", ip), pinned, "{ip:?} moved");
+            assert!(rule.backends.contains(&pinned));
+            used.insert(pinned);
+        }
+        assert_eq!(used.len(), 3, "clients all went to {used:?}");
+    }
+
+    #[test]
+    fn refuses_new_connections_during_maintenance() {
+        let fake = || Fake {
+            maintenance: true,
+            ..Fake::default()
+        };
+
+        let outcome = handle(fake(), Request::get(format!("{REALM}/test/ws")));
+        assert_eq!(status(outcome), 503);
+
+        let outcome = handle(fake(), Request::get("https://api.example.com/"));
+        assert_eq!(status(outcome), 503);
+
+        // connections fanout already holds carry on
+        let req = Request::get(format!("{REALM}/test/ws")).with_header("Grip-Sig", "token");
+        assert_eq!(served_by(handle(fake(), req)), "test");
+
+        let outcome = handle(fake(), Request::get(format!("{REALM}/healthz")));
+        assert_eq!(served_by(outcome), "healthz");
+    }
+
+    #[test]
+    fn applies_security_headers_to_own_responses() {
+        let fake = || Fake {
+            headers: HeaderPolicy {
+                hsts_max_age: Some(3600),
+                ..HeaderPolicy::default()
+            },
+            ..Fake::default()
+        };
+        let header = |outcome: Outcome, name: &str| match outcome {
+            Outcome::Respond(resp) => resp.get_header_str(name).map(str::to_string),
+            other => panic!("expected a response, got {other:?}"),
+        };
+
+        let outcome = handle(fake(), Request::get(format!("{REALM}/healthz")));
+        assert_eq!(
+            header(outcome, "Strict-Transport-Security").as_deref(),
+            Some("max-age=3600")
+        );
+
+        // errors too, but HSTS only goes out over HTTPS
+        let req = Request::get("http://realm.fanoutcdn.com/demo/chat/lobby/nope");
+        let outcome = handle(fake(), req);
+        let resp = match outcome {
+            Outcome::Respond(resp) => resp,
+            other => panic!("expected a response, got {other:?}"),
+        };
+        assert_eq!(resp.get_status().as_u16(), 404);
+        assert_eq!(
+            resp.get_header_str("X-Content-Type-Options"),
+            Some("nosniff")
+        );
+        assert!(!resp.contains_header("Strict-Transport-Security"));
+
+        // backends' responses are their own business
+        let outcome = handle(fake(), Request::get("https://api.example.com/"));
+        assert!(matches!(outcome, Outcome::Handoff { .. }));
+    }
+
+    #[test]
+    fn redirects_plaintext_connections_to_https() {
+        let fake = || Fake {
+            require_tls: true,
+            ..Fake::default()
+        };
+        let location = |outcome: Outcome| match outcome {
+            Outcome::Respond(resp) => (
+                resp.get_status().as_u16(),
+                resp.get_header_str("Location").unwrap_or("").to_string(),
+            ),
+            other => panic!("expected a response, got {other:?}"),
+        };
+
+        let req = Request::get("http://realm.fanoutcdn.com/test/sse?x=1");
+        assert_eq!(
+            location(handle(fake(), req)),
+            (301, "https://realm.fanoutcdn.com/test/sse?x=1".to_string())
+        );
+
+        let req = Request::post("http://api.example.com/stream");
+        assert_eq!(
+            location(handle(fake(), req)),
+            (308, "https://api.example.com/stream".to_string())
+        );
+
+        // requests opening no connection, and those over TLS, go through
+        let req = Request::get("http://realm.fanoutcdn.com/healthz");
+        assert_eq!(served_by(handle(fake(), req)), "healthz");
+        let req = Request::get("https://api.example.com/stream");
+        assert!(matches!(handle(fake(), req), Outcome::Handoff { .. }));
+
+        // nor do proxied requests forwarded without Fanout
+        let direct = Fake {
+            rule: Some(Rule {
+                host: None,
+                path: Some("/api/*".into()),
+                methods: Vec::new(),
+                backend: Some("api".into()),
+                fanout: false,
+                rewrite: None,
+                regions: Default::default(),
+                canary: None,
+                backends: Vec::new(),
+            }),
+            ..fake()
+        };
+        let req = Request::get("http://api.example.com/api/items");
+        match handle(direct, req) {
+            Outcome::Forward { backend, .. } => assert_eq!(backend, "api"),
+            other => panic!("expected a forward, got {other:?}"),
+        }
+    }
+}
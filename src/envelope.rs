@@ -0,0 +1,126 @@
+//! Message envelopes with sequence numbers.
+//!
+//! The `envelope` transform (see [`crate::transform`]) wraps each message
+//! published to a channel in the same JSON envelope for every kind of
+//! subscriber, SSE, long-polling and WebSocket alike:
+//!
+//! ```json
+//! {"id": "42", "prev_id": "41", "ts": 1700000000000, "channel": "news", "data": {"text": "hi"}}
+//! ```
+//!
+//! Ids are sequence numbers counting the messages of each channel, see
+//! [`crate::sequence`], so clients can tell when they missed one and put
+//! messages back in order. `ts` is the server time in milliseconds and
+//! `data` the message, as JSON if it parses as such and as a string
+//! otherwise. The id is also the item's id, sent as the SSE `id:` field and
+//! the `Event-ID` header of long-polling responses, with the previous one
+//! as its `prev-id`, so Fanout can detect gaps too.
+//!
+//! Without the KV Store, messages keep their ids and have no `prev_id`.
+
+use serde_json::{json, Value};
+
+use crate::grip::unix_now_millis;
+use crate::publish::Item;
+use crate::sequence;
+use crate::sse::SseEvent;
+
+/// Returns the data of the message an item carries: the TEXT WebSocket
+/// message, the long-polling response body, or else the data of the SSE
+/// event.
+fn message_data(item: &Item) -> Option<String> {
+    let formats = &item.formats;
+    if let Some(content) = formats
+        .ws_message
+        .as_ref()
+        .and_then(|ws| ws.content.clone())
+    {
+        return Some(content);
+    }
+    if let Some(response) = &formats.http_response {
+        return Some(response.body.clone());
+    }
+
+    let stream = formats.http_stream.as_ref()?;
+    let data: Vec<&str> = stream
+        .content
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|d| d.strip_prefix(' ').unwrap_or(d))
+        .collect();
+    (!data.is_empty()).then(|| data.join("\n"))
+}
+
+/// Returns the SSE event name of stream content, if it has one.
+fn event_name(content: &str) -> Option<&str> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("event:"))
+        .map(str::trim)
+}
+
+/// Transform wrapping an item's message in an envelope. Items without a
+/// text message, such as closes or binary WebSocket messages, are left as
+/// they are.
+pub fn wrap(mut item: Item) -> Option<Item> {
+    if item
+        .formats
+        .http_stream
+        .as_ref()
+        .is_some_and(|s| s.action.is_some())
+    {
+        return Some(item);
+    }
+
+    let data = match message_data(&item) {
+        Some(data) => data,
+        None => return Some(item),
+    };
+
+    // items are already numbered when prev-id chaining is on
+    if !sequence::chaining() {
+        sequence::chain(&mut item);
+    }
+    let id = item.id.clone();
+
+    let ts = unix_now_millis();
+    let data = serde_json::from_str::<Value>(&data).unwrap_or(Value::String(data));
+    let envelope = json!({
+        "id": id,
+        "prev_id": item.prev_id,
+        "ts": ts,
+        "channel": item.channel,
+        "data": data,
+    })
+    .to_string();
+
+    if let Some(stream) = &mut item.formats.http_stream {
+        let mut event = SseEvent::new(envelope.as_str());
+        if let Some(id) = &id {
+            event = event.with_id(id.as_str());
+        }
+        if let Some(name) = event_name(&stream.content) {
+            event = event.with_event(name);
+        }
+        stream.content = event.encode();
+    }
+
+    if let Some(response) = &mut item.formats.http_response {
+        response.body = envelope.clone();
+        if let Some(id) = &id {
+            for (name, value) in &mut response.headers {
+                if name.eq_ignore_ascii_case("Event-ID") {
+                    *value = id.clone();
+                }
+            }
+        }
+    }
+
+    if let Some(ws) = &mut item.formats.ws_message {
+        if ws.content.is_some() {
+            ws.content = Some(envelope);
+        }
+    }
+
+    Some(item)
+}
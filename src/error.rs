@@ -0,0 +1,244 @@
+//! Errors of the app's own endpoints, and the responses they get.
+//!
+//! Endpoint handlers return an [`AppError`] for requests they can't serve.
+//! Its response carries the status matching the kind of error and a JSON
+//! body naming it:
+//!
+//! ```json
+//! {"error": "parse_error", "message": "Invalid channel: channel name is empty"}
+//! ```
+//!
+//! Errors are logged with the request context when turned into responses,
+//! server-side ones at `error` level and client ones at `info`.
+
+use fastly::http::StatusCode;
+use fastly::Response;
+use serde_json::json;
+use std::fmt;
+
+use crate::channels::ChannelError;
+use crate::hooks::HookError;
+use crate::limits::BodyError;
+use crate::logging;
+use crate::ratelimit::Limited;
+use crate::schedule::ScheduleError;
+use crate::tokens::TokenError;
+use crate::{log_error, log_info};
+
+/// An error serving a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppError {
+    /// Nothing is served at the request's host or path.
+    RoutingError(String),
+    /// GRIP isn't available, such as when publishing isn't configured.
+    GripError(String),
+    /// The client isn't authenticated, or isn't allowed what it asked for.
+    AuthError { message: String, forbidden: bool },
+    /// The request is malformed.
+    ParseError(String),
+    /// The request's method isn't one the endpoint allows.
+    MethodError(String),
+    /// The request's body is larger than allowed.
+    SizeError(String),
+    /// The request is well-formed, but its content can't be used, such as
+    /// a webhook payload its templates don't fit.
+    ContentError(String),
+    /// The client went over a limit, such as how many publishes may be
+    /// scheduled.
+    LimitError(String),
+    /// A backend or Fanout failed to handle a request made for the client.
+    UpstreamError(String),
+    /// The app can't serve the request for now, such as during maintenance
+    /// or while a webhook source has no secret.
+    UnavailableError(String),
+}
+
+impl AppError {
+    /// Returns an error for a client that failed to authenticate.
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        AppError::AuthError {
+            message: message.into(),
+            forbidden: false,
+        }
+    }
+
+    /// Returns an error for a client that isn't allowed what it asked for.
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        AppError::AuthError {
+            message: message.into(),
+            forbidden: true,
+        }
+    }
+
+    /// Returns the error's name in response bodies and logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::RoutingError(_) => "routing_error",
+            AppError::GripError(_) => "grip_error",
+            AppError::AuthError { .. } => "auth_error",
+            AppError::ParseError(_) => "parse_error",
+            AppError::MethodError(_) => "method_error",
+            AppError::SizeError(_) => "size_error",
+            AppError::ContentError(_) => "content_error",
+            AppError::LimitError(_) => "limit_error",
+            AppError::UpstreamError(_) => "upstream_error",
+            AppError::UnavailableError(_) => "unavailable_error",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::RoutingError(m)
+            | AppError::GripError(m)
+            | AppError::AuthError { message: m, .. }
+            | AppError::ParseError(m)
+            | AppError::MethodError(m)
+            | AppError::SizeError(m)
+            | AppError::ContentError(m)
+            | AppError::LimitError(m)
+            | AppError::UpstreamError(m)
+            | AppError::UnavailableError(m) => m,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::RoutingError(_) => StatusCode::NOT_FOUND,
+            AppError::GripError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::AuthError {
+                forbidden: false, ..
+            } => StatusCode::UNAUTHORIZED,
+            AppError::AuthError {
+                forbidden: true, ..
+            } => StatusCode::FORBIDDEN,
+            AppError::ParseError(_) => StatusCode::BAD_REQUEST,
+            AppError::MethodError(_) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::SizeError(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::ContentError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::LimitError(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::UpstreamError(_) => StatusCode::BAD_GATEWAY,
+            AppError::UnavailableError(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Logs the error and returns the response to the request that failed.
+    pub fn response(&self) -> Response {
+        let status = self.status();
+
+        logging::set_context("error", self.kind());
+        if status.is_server_error() {
+            log_error!("responding with {}: {}", status.as_u16(), self);
+        } else {
+            log_info!("responding with {}: {}", status.as_u16(), self);
+        }
+
+        let mut resp = Response::from_status(status)
+            .with_header("Content-Type", "application/json")
+            .with_body(format!(
+                "{}\n",
+                json!({ "error": self.kind(), "message": self.message() })
+            ));
+        if status == StatusCode::UNAUTHORIZED {
+            resp.set_header("WWW-Authenticate", "Bearer");
+        }
+        resp
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.message())
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<AppError> for Response {
+    fn from(e: AppError) -> Self {
+        e.response()
+    }
+}
+
+impl From<BodyError> for AppError {
+    fn from(e: BodyError) -> Self {
+        match e {
+            BodyError::TooLarge(limit) => {
+                AppError::SizeError(format!("Body larger than {} bytes.", limit))
+            }
+            BodyError::Io(e) => AppError::ParseError(format!("Failed to read body: {}", e)),
+        }
+    }
+}
+
+impl From<ChannelError> for AppError {
+    fn from(e: ChannelError) -> Self {
+        match e {
+            ChannelError::NotAllowed(_) => AppError::forbidden(e.to_string()),
+            e => AppError::ParseError(format!("Invalid channel: {}", e)),
+        }
+    }
+}
+
+impl From<HookError> for AppError {
+    fn from(e: HookError) -> Self {
+        let message = format!("Can't accept webhook: {}", e);
+        match e {
+            HookError::UnknownSource => AppError::RoutingError(message),
+            HookError::Body(e) => AppError::from(e),
+            HookError::MissingSecret => AppError::UnavailableError(message),
+            HookError::MissingSignature | HookError::BadSignature | HookError::StaleTimestamp => {
+                AppError::unauthorized(message)
+            }
+            HookError::InvalidPayload(_) | HookError::InvalidChannel(_) => {
+                AppError::ContentError(message)
+            }
+            HookError::PublishNotConfigured => AppError::GripError(message),
+            // a 5xx gets the provider to retry the delivery
+            HookError::PublishFailed(_) => AppError::UpstreamError(message),
+        }
+    }
+}
+
+impl From<Limited> for AppError {
+    fn from(e: Limited) -> Self {
+        AppError::LimitError(format!("Too many requests, retry in {}s.", e.retry_after))
+    }
+}
+
+impl From<ScheduleError> for AppError {
+    fn from(e: ScheduleError) -> Self {
+        let message = format!("Can't schedule publish: {}", e);
+        match e {
+            ScheduleError::Full(_) => AppError::LimitError(message),
+            ScheduleError::NoStore | ScheduleError::Store(_) => AppError::GripError(message),
+        }
+    }
+}
+
+impl From<TokenError> for AppError {
+    fn from(e: TokenError) -> Self {
+        AppError::forbidden(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_statuses_of_refusals() {
+        let cases = [
+            (AppError::from(BodyError::TooLarge(10)), 413),
+            (AppError::from(BodyError::Io("reset".into())), 400),
+            (AppError::from(HookError::UnknownSource), 404),
+            (AppError::from(HookError::BadSignature), 401),
+            (AppError::from(HookError::InvalidPayload("x".into())), 422),
+            (AppError::from(HookError::MissingSecret), 503),
+            (AppError::from(HookError::PublishFailed("x".into())), 502),
+            (AppError::from(Limited { retry_after: 5 }), 429),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status().as_u16(), status, "{error}");
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Forwarding headers for proxied requests.
+//!
+//! Backends behind Fanout see requests coming from Fastly, so the original
+//! client is described to them with the `X-Forwarded-*` headers and the
+//! standard `Forwarded` header (RFC 7239). Values sent by the client are
+//! kept and appended to, as proxies in front of this service add their own
+//! hops there. Services that aren't behind another proxy should turn on
+//! `forwarded_strip_inbound`, since clients could otherwise pass spoofed
+//! addresses on to the backend.
+
+use fastly::Request;
+use std::net::IpAddr;
+
+use crate::config;
+
+/// Setting that, when `true`, drops forwarding headers sent by the client
+/// before adding our own.
+pub const STRIP_INBOUND_SETTING: &str = "forwarded_strip_inbound";
+
+const INBOUND_HEADERS: &[&str] = &[
+    "Forwarded",
+    "X-Forwarded-For",
+    "X-Forwarded-Host",
+    "X-Forwarded-Port",
+    "X-Forwarded-Proto",
+];
+
+/// Adds forwarding headers describing the client of `req`, which was made
+/// for `host` over TLS if `tls` is set.
+pub fn apply(req: &mut Request, host: &str, tls: bool) {
+    if config::setting(STRIP_INBOUND_SETTING).as_deref() == Some("true") {
+        for name in INBOUND_HEADERS {
+            req.remove_header(*name);
+        }
+    }
+
+    let proto = if tls { "https" } else { "http" };
+    let port = req.get_url().port().unwrap_or(if tls { 443 } else { 80 });
+    let client = req.get_client_ip_addr();
+
+    if let Some(addr) = client {
+        append(req, "X-Forwarded-For", &addr.to_string());
+    }
+
+    // the host and scheme are those of the hop reaching us, so these replace
+    // rather than extend what the client sent
+    req.set_header("X-Forwarded-Host", host);
+    req.set_header("X-Forwarded-Proto", proto);
+    req.set_header("X-Forwarded-Port", port.to_string());
+
+    let mut element = String::new();
+    if let Some(addr) = client {
+        element.push_str(&format!("for={};", node(addr)));
+    }
+    element.push_str(&format!("host={};proto={}", quote(host), proto));
+    append(req, "Forwarded", &element);
+}
+
+/// Formats an address as a `Forwarded` node, quoting IPv6 addresses as the
+/// RFC requires.
+fn node(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("\"[{}]\"", v6),
+    }
+}
+
+/// Quotes a `Forwarded` value unless it is a plain token.
+fn quote(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Appends a value to a comma-separated list header.
+fn append(req: &mut Request, name: &str, value: &str) {
+    let combined = match req.get_header_str(name) {
+        Some(existing) if !existing.trim().is_empty() => format!("{}, {}", existing, value),
+        _ => value.to_string(),
+    };
+    req.set_header(name, combined);
+}
@@ -0,0 +1,40 @@
+//! Client geolocation, for sending requests to regional deployments of
+//! origins, see [`crate::rules`].
+
+use std::collections::BTreeMap;
+
+use fastly::geo::geo_lookup;
+use fastly::Request;
+
+/// Where a client is, as told by the Compute geolocation API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    /// Two-letter continent code, such as `EU`.
+    pub continent: String,
+    /// ISO 3166-1 alpha-2 country code, such as `DE`.
+    pub country: String,
+}
+
+impl Region {
+    /// Returns the entry of `map` for the most specific key naming the
+    /// region, the country before the continent. Keys are case-insensitive.
+    pub fn lookup<'a>(&self, map: &'a BTreeMap<String, String>) -> Option<&'a str> {
+        let find = |code: &str| {
+            map.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(code))
+                .map(|(_, value)| value.as_str())
+        };
+
+        find(&self.country).or_else(|| find(&self.continent))
+    }
+}
+
+/// Returns the region of the client of `req`, if it can be located.
+pub fn client_region(req: &Request) -> Option<Region> {
+    let geo = geo_lookup(req.get_client_ip_addr()?)?;
+
+    Some(Region {
+        continent: geo.continent().as_code().to_string(),
+        country: geo.country_code().to_string(),
+    })
+}
@@ -0,0 +1,407 @@
+//! GraphQL subscriptions over WebSocket.
+//!
+//! Implements the server side of the `graphql-transport-ws` protocol, so
+//! GraphQL clients can subscribe through Fanout without a GraphQL server
+//! behind it. Operations aren't executed: each subscription is mapped onto a
+//! GRIP channel derived from its root field and arguments (see
+//! [`channel_for`]), and whatever is published on that channel is delivered
+//! to the subscriber as the operation's results.
+//!
+//! Clients pick their own operation ids, which published messages must
+//! carry. The connection stores the id of each subscription in a meta value
+//! named after its channel, and subscribes with the `var-subst` filter, so
+//! Fanout substitutes the id into published content. [`publish_payload`]
+//! produces content with the placeholder in place.
+
+use fastly::Request;
+use fastly::Response;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+use crate::grip::{GripControl, FILTER_VAR_SUBST};
+use crate::router::Route;
+use crate::ws::{self, WsContext, WsHandler};
+use crate::{log_debug, log_warn};
+
+/// The subprotocol implemented by this module.
+pub const PROTOCOL: &str = "graphql-transport-ws";
+
+/// Prefix of the GRIP channels subscriptions are mapped onto.
+pub const CHANNEL_PREFIX: &str = "graphql/";
+
+/// Meta value set once the connection has been initialised.
+const INIT_META: &str = "gql-init";
+
+/// Meta value holding the connection's subscriptions, a JSON object from
+/// operation id to channel.
+const SUBS_META: &str = "gql-subs";
+
+const CLOSE_INVALID_MESSAGE: u16 = 4400;
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+const CLOSE_SUBSCRIBER_EXISTS: u16 = 4409;
+const CLOSE_TOO_MANY_INITS: u16 = 4429;
+
+/// A message sent by a client.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit,
+    Ping {
+        #[serde(default)]
+        payload: Option<Value>,
+    },
+    Pong,
+    Subscribe {
+        id: String,
+        payload: SubscribePayload,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SubscribePayload {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Value>>,
+    #[serde(default)]
+    operation_name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Var(String),
+    Str(String),
+    Num(String),
+    Punct(char),
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            // commas are insignificant in GraphQL
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => {
+                chars.next();
+            }
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('r') => s.push('\r'),
+                            Some('u') => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                let c = u32::from_str_radix(&hex, 16)
+                                    .ok()
+                                    .and_then(char::from_u32)
+                                    .ok_or("invalid unicode escape")?;
+                                s.push(c);
+                            }
+                            Some(c) => s.push(c),
+                            None => return Err("unterminated string".into()),
+                        },
+                        Some(c) => s.push(c),
+                        None => return Err("unterminated string".into()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            '$' => {
+                chars.next();
+                tokens.push(Token::Var(take_name(&mut chars)));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                tokens.push(Token::Name(take_name(&mut chars)));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut n = String::new();
+                while let Some(c) =
+                    chars.next_if(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.'))
+                {
+                    n.push(c);
+                }
+                tokens.push(Token::Num(n));
+            }
+            c => {
+                chars.next();
+                tokens.push(Token::Punct(c));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn take_name(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+        name.push(c);
+    }
+    name
+}
+
+/// Returns the channel a subscription operation is mapped onto: its root
+/// field, followed by the values of the field's arguments in the order
+/// given, e.g. `graphql/messageAdded/42` for
+/// `subscription { messageAdded(roomId: "42") { text } }`.
+///
+/// Arguments may be literals or variables. Lists and input objects aren't
+/// supported as arguments, and nor are queries and mutations, which would
+/// need executing.
+pub fn channel_for(
+    query: &str,
+    variables: Option<&Map<String, Value>>,
+    operation_name: Option<&str>,
+) -> Result<String, String> {
+    let tokens = tokenize(query)?;
+    let mut pos = 0;
+
+    // find the operation to run
+    loop {
+        match tokens.get(pos) {
+            Some(Token::Name(kw)) if kw == "subscription" => {
+                let name = match tokens.get(pos + 1) {
+                    Some(Token::Name(name)) => Some(name.as_str()),
+                    _ => None,
+                };
+                if operation_name.is_none() || operation_name == name {
+                    pos += 1;
+                    break;
+                }
+            }
+            Some(Token::Name(kw)) if kw == "query" || kw == "mutation" => {
+                if operation_name.is_none() {
+                    return Err(format!("{kw} operations are not supported"));
+                }
+            }
+            Some(Token::Punct('{')) if pos == 0 => {
+                return Err("query operations are not supported".into());
+            }
+            Some(_) => {}
+            None => return Err("no subscription operation found".into()),
+        }
+        pos += 1;
+    }
+
+    // skip the name, variable definitions and directives up to the
+    // selection set
+    while let Some(token) = tokens.get(pos) {
+        pos += 1;
+        if *token == Token::Punct('{') {
+            break;
+        }
+    }
+
+    let mut field = match tokens.get(pos) {
+        Some(Token::Name(name)) => name.clone(),
+        _ => return Err("subscription has no root field".into()),
+    };
+    pos += 1;
+    if tokens.get(pos) == Some(&Token::Punct(':')) {
+        field = match tokens.get(pos + 1) {
+            Some(Token::Name(name)) => name.clone(),
+            _ => return Err("invalid field alias".into()),
+        };
+        pos += 2;
+    }
+
+    let mut segments = vec![field];
+
+    if tokens.get(pos) == Some(&Token::Punct('(')) {
+        pos += 1;
+        loop {
+            match (tokens.get(pos), tokens.get(pos + 1), tokens.get(pos + 2)) {
+                (Some(Token::Punct(')')), _, _) => break,
+                (Some(Token::Name(_)), Some(Token::Punct(':')), Some(value)) => {
+                    segments.push(argument_value(value, variables)?);
+                    pos += 3;
+                }
+                _ => return Err("unsupported field arguments".into()),
+            }
+        }
+    }
+
+    Ok(format!("{}{}", CHANNEL_PREFIX, segments.join("/")))
+}
+
+fn argument_value(token: &Token, variables: Option<&Map<String, Value>>) -> Result<String, String> {
+    match token {
+        Token::Str(s) | Token::Num(s) | Token::Name(s) => Ok(s.clone()),
+        Token::Var(name) => match variables.and_then(|v| v.get(name)) {
+            Some(Value::String(s)) => Ok(s.clone()),
+            Some(v @ (Value::Number(_) | Value::Bool(_))) => Ok(v.to_string()),
+            Some(_) => Err(format!("unsupported value for variable ${name}")),
+            None => Err(format!("variable ${name} is not set")),
+        },
+        Token::Punct(_) => Err("unsupported field arguments".into()),
+    }
+}
+
+/// Returns the name of the meta value holding the operation id of a
+/// connection's subscription to `channel`.
+pub fn id_meta_name(channel: &str) -> String {
+    let name: String = channel
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("gql-id-{}", name)
+}
+
+/// Returns the `ws-message` content delivering an execution result, such as
+/// `{"data": {...}}`, to the subscribers of `channel`.
+pub fn publish_payload(channel: &str, result: &Value) -> String {
+    // var-subst replaces the placeholder with the subscriber's own id
+    let id = format!("%({})s", id_meta_name(channel));
+    json!({ "id": id, "type": "next", "payload": result }).to_string()
+}
+
+/// Returns the `ws-message` content telling the subscribers of `channel`
+/// that no more results will follow.
+pub fn complete_payload(channel: &str) -> String {
+    let id = format!("%({})s", id_meta_name(channel));
+    json!({ "id": id, "type": "complete" }).to_string()
+}
+
+/// Serves GraphQL subscriptions over a WebSocket connection.
+struct GraphqlWs<'a> {
+    route: &'a Route,
+}
+
+impl GraphqlWs<'_> {
+    fn subscriptions(ctx: &WsContext) -> BTreeMap<String, String> {
+        ctx.meta(SUBS_META)
+            .and_then(|s| serde_json::from_str(s).ok())
+            .unwrap_or_default()
+    }
+
+    fn set_subscriptions(ctx: &mut WsContext, subs: &BTreeMap<String, String>) {
+        let value = serde_json::to_string(subs).expect("strings serialize");
+        ctx.set_meta(SUBS_META, &value);
+    }
+
+    fn close(ctx: &mut WsContext, code: u16, reason: &str) {
+        log_debug!("closing graphql connection with {code}: {reason}");
+        ctx.out.write_control(&GripControl::Close {
+            code: Some(code),
+            reason: Some(reason.to_string()),
+        });
+    }
+
+    fn send(ctx: &mut WsContext, message: Value) {
+        ctx.out.write_text(&message.to_string());
+    }
+
+    fn subscribe(&self, ctx: &mut WsContext, id: String, payload: SubscribePayload) {
+        if ctx.meta(INIT_META).is_none() {
+            Self::close(ctx, CLOSE_UNAUTHORIZED, "Unauthorized");
+            return;
+        }
+
+        let mut subs = Self::subscriptions(ctx);
+        if subs.contains_key(&id) {
+            Self::close(
+                ctx,
+                CLOSE_SUBSCRIBER_EXISTS,
+                &format!("Subscriber for {id} already exists"),
+            );
+            return;
+        }
+
+        let channel = match channel_for(
+            &payload.query,
+            payload.variables.as_ref(),
+            payload.operation_name.as_deref(),
+        ) {
+            Ok(channel) => channel,
+            Err(e) => {
+                Self::send(
+                    ctx,
+                    json!({ "id": id, "type": "error", "payload": [{ "message": e }] }),
+                );
+                return;
+            }
+        };
+
+        ctx.set_meta(&id_meta_name(&channel), &id);
+        ctx.subscribe_filtered(&[self.route.channel(&channel)], &[FILTER_VAR_SUBST]);
+        subs.insert(id, channel);
+        Self::set_subscriptions(ctx, &subs);
+    }
+
+    fn complete(&self, ctx: &mut WsContext, id: &str) {
+        let mut subs = Self::subscriptions(ctx);
+        if let Some(channel) = subs.remove(id) {
+            ctx.unsubscribe(&[self.route.channel(&channel)]);
+            Self::set_subscriptions(ctx, &subs);
+        }
+    }
+}
+
+impl WsHandler for GraphqlWs<'_> {
+    fn protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        let message = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(message) => message,
+            Err(e) => {
+                log_warn!("invalid graphql-ws message: {e}");
+                Self::close(ctx, CLOSE_INVALID_MESSAGE, "Invalid message received");
+                return;
+            }
+        };
+
+        match message {
+            ClientMessage::ConnectionInit => {
+                if ctx.meta(INIT_META).is_some() {
+                    Self::close(
+                        ctx,
+                        CLOSE_TOO_MANY_INITS,
+                        "Too many initialisation requests",
+                    );
+                    return;
+                }
+                ctx.set_meta(INIT_META, "1");
+                Self::send(ctx, json!({ "type": "connection_ack" }));
+            }
+            ClientMessage::Ping { payload } => {
+                let mut pong = json!({ "type": "pong" });
+                if let Some(payload) = payload {
+                    pong["payload"] = payload;
+                }
+                Self::send(ctx, pong);
+            }
+            ClientMessage::Pong => {}
+            ClientMessage::Subscribe { id, payload } => self.subscribe(ctx, id, payload),
+            ClientMessage::Complete { id } => self.complete(ctx, &id),
+        }
+    }
+
+    fn on_binary(&mut self, ctx: &mut WsContext, _data: Vec<u8>) {
+        Self::close(ctx, CLOSE_INVALID_MESSAGE, "Invalid message received");
+    }
+}
+
+/// Handles a WebSocket-over-HTTP GraphQL request forwarded by Fanout.
+pub fn handle(req: Request, route: &Route) -> Response {
+    ws::serve(req, &route.limits, &mut GraphqlWs { route })
+}
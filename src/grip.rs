@@ -0,0 +1,264 @@
+//! GRIP protocol helpers.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::pkcs8::DecodePublicKey;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+
+mod control;
+mod features;
+mod keep_alive;
+mod response;
+#[cfg(test)]
+mod tests;
+
+pub use control::{ContentFormat, GripControl, MessageType, CONTROL_PREFIX};
+pub use features::{GripFeatures, FEATURE_HEADER};
+pub use keep_alive::{KeepAlive, KeepAliveFormat, KeepAliveOptions};
+pub use response::{GripResponseBuilder, INSTRUCT_CONTENT_TYPE};
+
+/// Name of the secret holding the key used to verify `Grip-Sig`.
+pub const SIG_KEY_SECRET: &str = "grip_sig_key";
+
+/// Name of the setting overriding the expected `Grip-Sig` issuer.
+pub const SIG_ISS_SETTING: &str = "grip_sig_iss";
+
+/// Issuer used by Fastly Fanout when signing proxied requests.
+pub const DEFAULT_SIG_ISS: &str = "fastly";
+
+/// GRIP filter skipping messages published by the subscriber itself: those
+/// whose `sender` meta, see [`crate::publish::Item::with_sender`], is the
+/// subscriber's [`USER_META`].
+pub const FILTER_SKIP_SELF: &str = "skip-self";
+
+/// GRIP filter skipping messages from the users listed in the subscriber's
+/// `skip_users` meta.
+pub const FILTER_SKIP_USERS: &str = "skip-users";
+
+/// GRIP filter only delivering messages whose sender is also subscribed to
+/// the channel.
+pub const FILTER_REQUIRE_SUB: &str = "require-sub";
+
+/// GRIP filter building the ids of messages from their channels' last ids.
+pub const FILTER_BUILD_ID: &str = "build-id";
+
+/// GRIP filter substituting the subscriber's meta values for `%(name)s`
+/// in message content.
+pub const FILTER_VAR_SUBST: &str = "var-subst";
+
+/// Connection meta value naming the user a subscriber publishes as, which
+/// the `skip-self` and `skip-users` filters compare senders with.
+pub const USER_META: &str = "user";
+
+/// How Fanout holds a request open after the origin responds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HoldMode {
+    /// Deliver a single published response, as used for long-polling.
+    Response,
+    /// Stream published content, as used for SSE and HTTP streaming.
+    Stream,
+}
+
+impl HoldMode {
+    /// Returns the value used in the `Grip-Hold` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HoldMode::Response => "response",
+            HoldMode::Stream => "stream",
+        }
+    }
+}
+
+/// How long Fanout may hold a route's requests, set with the `timeouts`
+/// field of the route.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct HoldTimeouts {
+    /// Seconds response holds, as used for long-polling, last at most.
+    pub response: Option<u32>,
+    /// Seconds stream holds, as used for SSE, last at most.
+    pub stream: Option<u32>,
+}
+
+impl HoldTimeouts {
+    /// Returns the longest timeout of holds in `mode`, if bounded.
+    pub fn get(&self, mode: HoldMode) -> Option<u32> {
+        match mode {
+            HoldMode::Response => self.response,
+            HoldMode::Stream => self.stream,
+        }
+    }
+}
+
+/// Reasons a `Grip-Sig` token can be rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SigError {
+    /// No verification key is configured.
+    MissingKey,
+    /// The configured key could not be parsed.
+    InvalidKey,
+    /// The token is not a well-formed JWT.
+    Malformed,
+    /// The token uses an algorithm that doesn't match the configured key.
+    UnsupportedAlg(String),
+    /// The signature does not match the token contents.
+    BadSignature,
+    /// The `iss` claim is missing or not the expected issuer.
+    BadIssuer,
+    /// The `exp` claim is missing or in the past.
+    Expired,
+}
+
+impl fmt::Display for SigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SigError::MissingKey => write!(f, "no Grip-Sig key configured"),
+            SigError::InvalidKey => write!(f, "Grip-Sig key is invalid"),
+            SigError::Malformed => write!(f, "Grip-Sig is not a valid JWT"),
+            SigError::UnsupportedAlg(alg) => write!(f, "unsupported Grip-Sig algorithm: {alg}"),
+            SigError::BadSignature => write!(f, "Grip-Sig signature mismatch"),
+            SigError::BadIssuer => write!(f, "Grip-Sig issuer mismatch"),
+            SigError::Expired => write!(f, "Grip-Sig expired"),
+        }
+    }
+}
+
+impl std::error::Error for SigError {}
+
+/// Key used to verify `Grip-Sig` tokens.
+///
+/// The key type determines the only algorithm accepted, so a token can't
+/// choose to have a public key treated as an HMAC secret.
+pub enum SigKey {
+    /// Shared secret for HS256, as used by Pushpin realms.
+    Hmac(Vec<u8>),
+    /// Public key for ES256, as used by Fastly Fanout.
+    Es256(VerifyingKey),
+}
+
+impl SigKey {
+    /// Parses a key as stored in the Secret Store. PEM-encoded public keys
+    /// are used for ES256, anything else is taken as an HS256 secret.
+    pub fn from_bytes(key: &[u8]) -> Result<Self, SigError> {
+        if key.starts_with(b"-----BEGIN PUBLIC KEY-----") {
+            let pem = std::str::from_utf8(key).map_err(|_| SigError::InvalidKey)?;
+            let key =
+                VerifyingKey::from_public_key_pem(pem.trim()).map_err(|_| SigError::InvalidKey)?;
+            Ok(SigKey::Es256(key))
+        } else if key.is_empty() {
+            Err(SigError::InvalidKey)
+        } else {
+            Ok(SigKey::Hmac(key.to_vec()))
+        }
+    }
+
+    fn alg(&self) -> &'static str {
+        match self {
+            SigKey::Hmac(_) => "HS256",
+            SigKey::Es256(_) => "ES256",
+        }
+    }
+
+    fn verify(&self, signed: &[u8], sig: &[u8]) -> bool {
+        match self {
+            SigKey::Hmac(secret) => {
+                let mut mac = match Hmac::<Sha256>::new_from_slice(secret) {
+                    Ok(mac) => mac,
+                    Err(_) => return false,
+                };
+                mac.update(signed);
+                mac.verify_slice(sig).is_ok()
+            }
+            SigKey::Es256(key) => match Signature::from_slice(sig) {
+                Ok(sig) => key.verify(signed, &sig).is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+/// Claims carried by a verified `Grip-Sig` token.
+#[derive(Debug, Deserialize)]
+pub struct Claims {
+    pub iss: Option<String>,
+    pub exp: Option<u64>,
+}
+
+/// Checks the signature of a JWT against `key` and returns its claims.
+/// Claims such as `exp` are left for the caller to check.
+pub fn decode_jwt<T: DeserializeOwned>(token: &str, key: &SigKey) -> Result<T, SigError> {
+    let mut parts = token.split('.');
+    let (header, payload, sig) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(h), Some(p), Some(s), None) => (h, p, s),
+        _ => return Err(SigError::Malformed),
+    };
+
+    let decode = |s: &str| URL_SAFE_NO_PAD.decode(s).map_err(|_| SigError::Malformed);
+
+    let h: Header = serde_json::from_slice(&decode(header)?).map_err(|_| SigError::Malformed)?;
+    if h.alg != key.alg() {
+        return Err(SigError::UnsupportedAlg(h.alg));
+    }
+
+    let signed_len = header.len() + 1 + payload.len();
+    if !key.verify(&token.as_bytes()[..signed_len], &decode(sig)?) {
+        return Err(SigError::BadSignature);
+    }
+
+    serde_json::from_slice(&decode(payload)?).map_err(|_| SigError::Malformed)
+}
+
+/// Verifies a `Grip-Sig` JWT against `key`, checking that it was issued by
+/// `iss` and has not expired as of `now` (seconds since the Unix epoch).
+pub fn verify_sig(token: &str, key: &SigKey, iss: &str, now: u64) -> Result<Claims, SigError> {
+    let claims: Claims = decode_jwt(token, key)?;
+
+    if claims.iss.as_deref() != Some(iss) {
+        return Err(SigError::BadIssuer);
+    }
+
+    match claims.exp {
+        Some(exp) if exp > now => Ok(claims),
+        _ => Err(SigError::Expired),
+    }
+}
+
+/// Verifies a `Grip-Sig` header value using the key and issuer from the
+/// service configuration.
+pub fn verify_request_sig(token: &str) -> Result<Claims, SigError> {
+    let key = SigKey::from_bytes(&config::secret(SIG_KEY_SECRET).ok_or(SigError::MissingKey)?)?;
+    let iss = config::setting(SIG_ISS_SETTING).unwrap_or_else(|| DEFAULT_SIG_ISS.to_string());
+
+    verify_sig(token, &key, &iss, unix_now())
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn unix_now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
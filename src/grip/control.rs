@@ -0,0 +1,124 @@
+//! WebSocket-over-HTTP GRIP control messages.
+//!
+//! Control messages are TEXT events whose content is `c:` followed by a JSON
+//! object; Fanout acts on them instead of forwarding them to the client.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::ws_events::WsEvent;
+
+/// Prefix marking a TEXT event as a GRIP control message.
+pub const CONTROL_PREFIX: &str = "c:";
+
+/// Kind of WebSocket message Fanout sends for keep-alives and delayed sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageType {
+    Text,
+    Binary,
+    Ping,
+    Pong,
+}
+
+/// Encoding of control message content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentFormat {
+    Base64,
+}
+
+/// A GRIP control message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum GripControl {
+    /// Subscribe the connection to a channel, optionally applying filters
+    /// to messages published on it.
+    Subscribe {
+        channel: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        filters: Vec<String>,
+    },
+    /// Unsubscribe the connection from a channel.
+    Unsubscribe { channel: String },
+    /// Stop forwarding client messages to the origin.
+    Detach,
+    /// Have Fanout send a message when the connection is otherwise idle.
+    KeepAlive {
+        #[serde(rename = "message-type", skip_serializing_if = "Option::is_none")]
+        message_type: Option<MessageType>,
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        format: Option<ContentFormat>,
+        timeout: u32,
+    },
+    /// Change how long Fanout holds the connection open without activity.
+    SetHold {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u32>,
+    },
+    /// Close the connection with the given code and reason.
+    Close {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<u16>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Acknowledge a message delivered by Fanout.
+    Ack { id: String },
+    /// Send a message to the client after a delay unless cancelled.
+    SendDelayed {
+        #[serde(rename = "message-type", skip_serializing_if = "Option::is_none")]
+        message_type: Option<MessageType>,
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        format: Option<ContentFormat>,
+        timeout: u32,
+    },
+    /// Cancel a previous `send-delayed` message.
+    CancelSendDelayed,
+    /// Ask Fanout to send a request to the origin to refresh the connection.
+    Refresh,
+}
+
+impl GripControl {
+    /// Returns a `send-delayed` control message having Fanout send
+    /// `content` to the client as a TEXT message after `timeout` seconds,
+    /// unless it is cancelled or replaced by another `send-delayed` first.
+    pub fn send_delayed_text(content: impl Into<String>, timeout: u32) -> Self {
+        GripControl::SendDelayed {
+            message_type: Some(MessageType::Text),
+            content: content.into(),
+            format: None,
+            timeout,
+        }
+    }
+
+    /// Returns a `send-delayed` control message for a BINARY message, see
+    /// [`GripControl::send_delayed_text`].
+    pub fn send_delayed_binary(content: &[u8], timeout: u32) -> Self {
+        GripControl::SendDelayed {
+            message_type: Some(MessageType::Binary),
+            content: STANDARD.encode(content),
+            format: Some(ContentFormat::Base64),
+            timeout,
+        }
+    }
+
+    /// Returns the JSON representation of the control message.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("control messages always serialize")
+    }
+
+    /// Returns the control message as a TEXT event.
+    pub fn to_ws_event(&self) -> WsEvent {
+        WsEvent::Text(format!("{}{}", CONTROL_PREFIX, self.to_json()))
+    }
+
+    /// Parses a control message from TEXT event content, returning `None` if
+    /// the content is not a control message.
+    pub fn from_text(text: &str) -> Option<Self> {
+        serde_json::from_str(text.strip_prefix(CONTROL_PREFIX)?).ok()
+    }
+}
@@ -0,0 +1,174 @@
+//! GRIP capability negotiation.
+//!
+//! The proxy lists the GRIP features it supports in the `Grip-Feature`
+//! header of the requests it forwards, e.g.
+//! `Grip-Feature: status, session, link:next, filter:skip-self`, so handlers
+//! can avoid instructions it would ignore or refuse. Proxies sending no
+//! such header are assumed to support every feature, as they always have.
+
+use std::fmt;
+use std::ops::BitOr;
+
+use fastly::Request;
+
+use crate::log_info;
+
+/// Request header listing the GRIP features of the proxy.
+pub const FEATURE_HEADER: &str = "Grip-Feature";
+
+/// Set of GRIP features supported by the proxy.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct GripFeatures(u32);
+
+impl GripFeatures {
+    /// Holds may answer with a status other than 200, see `Grip-Status`.
+    pub const STATUS: Self = GripFeatures(1 << 0);
+    /// WebSocket connections may have sessions, see the `session` control
+    /// message.
+    pub const SESSION: Self = GripFeatures(1 << 1);
+    /// Streams may follow a `Grip-Link` with `rel=next`.
+    pub const LINK_NEXT: Self = GripFeatures(1 << 2);
+    /// Subscriptions may skip messages published by the connection itself.
+    pub const FILTER_SKIP_SELF: Self = GripFeatures(1 << 3);
+    /// Subscriptions may skip messages published by given users.
+    pub const FILTER_SKIP_USERS: Self = GripFeatures(1 << 4);
+    /// Subscriptions may require the publisher to be subscribed.
+    pub const FILTER_REQUIRE_SUB: Self = GripFeatures(1 << 5);
+    /// Published content may have its variables substituted.
+    pub const FILTER_VAR_SUBST: Self = GripFeatures(1 << 6);
+    /// WebSocket connections may be asked for a new request right away,
+    /// see the `refresh` control message.
+    pub const REFRESH: Self = GripFeatures(1 << 7);
+    /// WebSocket connections may be detached from the origin, see the
+    /// `detach` control message.
+    pub const DETACH: Self = GripFeatures(1 << 8);
+
+    /// Feature names as they appear in `Grip-Feature`.
+    const NAMES: &'static [(&'static str, GripFeatures)] = &[
+        ("status", Self::STATUS),
+        ("session", Self::SESSION),
+        ("link:next", Self::LINK_NEXT),
+        ("filter:skip-self", Self::FILTER_SKIP_SELF),
+        ("filter:skip-users", Self::FILTER_SKIP_USERS),
+        ("filter:require-sub", Self::FILTER_REQUIRE_SUB),
+        ("filter:var-subst", Self::FILTER_VAR_SUBST),
+        ("refresh", Self::REFRESH),
+        ("detach", Self::DETACH),
+    ];
+
+    /// No features.
+    pub const fn empty() -> Self {
+        GripFeatures(0)
+    }
+
+    /// Every known feature.
+    pub fn all() -> Self {
+        Self::NAMES
+            .iter()
+            .fold(Self::empty(), |all, &(_, feature)| all | feature)
+    }
+
+    /// Returns whether all of `features` are supported.
+    pub fn contains(self, features: GripFeatures) -> bool {
+        self.0 & features.0 == features.0
+    }
+
+    /// Parses the comma-separated values of `Grip-Feature` headers,
+    /// returning the known features along with the names of unknown ones.
+    pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> (Self, Vec<&'a str>) {
+        let mut features = Self::empty();
+        let mut unknown = Vec::new();
+
+        for name in values
+            .into_iter()
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+        {
+            match Self::NAMES
+                .iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            {
+                Some(&(_, feature)) => features = features | feature,
+                None => unknown.push(name),
+            }
+        }
+
+        (features, unknown)
+    }
+
+    /// Returns the features the proxy forwarding `req` supports, all of
+    /// them if it doesn't say. Unknown features are logged.
+    pub fn from_request(req: &Request) -> Self {
+        let values = req.get_header_all_str(FEATURE_HEADER);
+        if values.is_empty() {
+            return Self::all();
+        }
+
+        let (features, unknown) = Self::parse(values);
+        if !unknown.is_empty() {
+            log_info!(
+                "proxy supports unknown GRIP features {}",
+                unknown.join(", ")
+            );
+        }
+        features
+    }
+}
+
+/// Every feature, as assumed of proxies that don't say.
+impl Default for GripFeatures {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl BitOr for GripFeatures {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        GripFeatures(self.0 | other.0)
+    }
+}
+
+impl fmt::Debug for GripFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set()
+            .entries(
+                Self::NAMES
+                    .iter()
+                    .filter(|&&(_, feature)| self.contains(feature))
+                    .map(|(name, _)| name),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_features_across_headers() {
+        let (features, unknown) =
+            GripFeatures::parse(["status, Link:Next,", " detach, no-such-thing"]);
+        assert_eq!(
+            features,
+            GripFeatures::STATUS | GripFeatures::LINK_NEXT | GripFeatures::DETACH
+        );
+        assert_eq!(unknown, ["no-such-thing"]);
+        assert!(!features.contains(GripFeatures::REFRESH));
+        assert!(!features.contains(GripFeatures::STATUS | GripFeatures::REFRESH));
+        assert_eq!(
+            format!("{features:?}"),
+            r#"{"status", "link:next", "detach"}"#
+        );
+    }
+
+    #[test]
+    fn assumes_everything_of_silent_proxies() {
+        let (features, _) = GripFeatures::parse([]);
+        assert_eq!(features, GripFeatures::empty());
+        assert!(GripFeatures::default().contains(GripFeatures::REFRESH | GripFeatures::DETACH));
+    }
+}
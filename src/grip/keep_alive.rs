@@ -0,0 +1,147 @@
+//! GRIP keep-alive configuration.
+//!
+//! The same settings drive both the `Grip-Keep-Alive` header on HTTP stream
+//! holds and the `keep-alive` control message on WebSocket connections.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{ContentFormat, GripControl, MessageType};
+use crate::config;
+
+/// Setting holding the default keep-alive timeout in seconds.
+pub const TIMEOUT_SETTING: &str = "keep_alive_timeout";
+
+/// Keep-alive timeout used when none is configured.
+pub const DEFAULT_TIMEOUT: u32 = 20;
+
+/// How keep-alive content is encoded in the `Grip-Keep-Alive` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepAliveFormat {
+    /// C-style escapes, so `\n` can be written in a header.
+    Cstring,
+    /// Base64, for arbitrary bytes.
+    Base64,
+}
+
+/// Content Fanout sends on an idle connection, and how often.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepAlive {
+    pub content: String,
+    pub format: KeepAliveFormat,
+    pub timeout: u32,
+}
+
+impl KeepAlive {
+    /// Returns a keep-alive sending `content`, using the configured default
+    /// timeout.
+    pub fn new(content: impl Into<String>) -> Self {
+        KeepAlive {
+            content: content.into(),
+            format: KeepAliveFormat::Cstring,
+            timeout: default_timeout(),
+        }
+    }
+
+    pub fn with_format(mut self, format: KeepAliveFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: u32) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the value of the `Grip-Keep-Alive` header.
+    pub fn header_value(&self) -> String {
+        let (content, format) = match self.format {
+            KeepAliveFormat::Cstring => (cstring_escape(&self.content), "cstring"),
+            KeepAliveFormat::Base64 => (STANDARD.encode(&self.content), "base64"),
+        };
+
+        format!("{}; format={}; timeout={}", content, format, self.timeout)
+    }
+
+    /// Returns the `keep-alive` object of a GRIP instruct body.
+    pub fn instruct_value(&self) -> Value {
+        match self.format {
+            KeepAliveFormat::Cstring => json!({
+                "content": self.content,
+                "timeout": self.timeout,
+            }),
+            KeepAliveFormat::Base64 => json!({
+                "content-bin": STANDARD.encode(&self.content),
+                "timeout": self.timeout,
+            }),
+        }
+    }
+
+    /// Returns the equivalent WebSocket control message.
+    pub fn to_control(&self, message_type: MessageType) -> GripControl {
+        let (content, format) = match self.format {
+            KeepAliveFormat::Cstring => (self.content.clone(), None),
+            KeepAliveFormat::Base64 => {
+                (STANDARD.encode(&self.content), Some(ContentFormat::Base64))
+            }
+        };
+
+        GripControl::KeepAlive {
+            message_type: Some(message_type),
+            content,
+            format,
+            timeout: self.timeout,
+        }
+    }
+}
+
+/// How a route's WebSocket connections are kept alive, read from its
+/// `keep_alive` field.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct KeepAliveOptions {
+    /// Seconds of inactivity before a keep-alive is sent. Defaults to the
+    /// `keep_alive_timeout` setting.
+    pub timeout: Option<u32>,
+    /// Kind of message sent: a ping by default, or an application-level
+    /// text or binary message.
+    #[serde(rename = "type")]
+    pub message_type: Option<MessageType>,
+    /// Content of the message sent.
+    pub content: String,
+}
+
+impl KeepAliveOptions {
+    /// Returns the `keep-alive` control message setting these options up.
+    pub fn to_control(&self) -> GripControl {
+        let mut keep_alive = KeepAlive::new(self.content.as_str());
+        if let Some(timeout) = self.timeout {
+            keep_alive = keep_alive.with_timeout(timeout);
+        }
+        keep_alive.to_control(self.message_type.unwrap_or(MessageType::Ping))
+    }
+}
+
+/// Returns the keep-alive timeout from the settings store, or
+/// [`DEFAULT_TIMEOUT`].
+pub fn default_timeout() -> u32 {
+    config::setting(TIMEOUT_SETTING)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
+
+fn cstring_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,385 @@
+//! Building GRIP hold responses.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fastly::http::StatusCode;
+use fastly::{Body, Response};
+use serde_json::{json, Map, Value};
+
+use super::{HoldMode, HoldTimeouts, KeepAlive};
+use crate::channels;
+use crate::logging;
+
+/// A channel a hold subscribes to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChannelSpec {
+    name: String,
+    prev_id: Option<String>,
+    filters: Vec<String>,
+}
+
+/// Content type of GRIP instructions given in the response body.
+pub const INSTRUCT_CONTENT_TYPE: &str = "application/grip-instruct";
+
+/// Builds a GRIP response instructing Fanout how to hold a request.
+///
+/// When our app receives a non-WebSocket request (i.e. normal HTTP) and wants
+/// to make it long lived (longpoll or SSE), we call handoff_fanout on it, and
+/// Fanout will then forward that request to the nominated backend. In this
+/// app, that backend is this same Compute service, where we then need to
+/// respond with some Grip headers to tell Fanout to hold the connection.
+///
+/// The instructions can also be given as an `application/grip-instruct`
+/// JSON body instead, see [`GripResponseBuilder::instruct`].
+#[derive(Debug, Default)]
+pub struct GripResponseBuilder {
+    content_type: Option<String>,
+    hold: Option<HoldMode>,
+    channels: Vec<ChannelSpec>,
+    keep_alive: Option<KeepAlive>,
+    timeout: Option<u32>,
+    max_timeouts: HoldTimeouts,
+    next_link: Option<(String, Option<u32>)>,
+    last: Vec<String>,
+    status: Option<StatusCode>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    instruct: bool,
+}
+
+impl GripResponseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    pub fn hold(mut self, mode: HoldMode) -> Self {
+        self.hold = Some(mode);
+        self
+    }
+
+    /// Holds the request as a stream, e.g. for SSE.
+    pub fn hold_stream(self) -> Self {
+        self.hold(HoldMode::Stream)
+    }
+
+    /// Holds the request until a single response is published, e.g. for
+    /// long-polling.
+    pub fn hold_response(self) -> Self {
+        self.hold(HoldMode::Response)
+    }
+
+    /// Subscribes the hold to a channel.
+    pub fn channel(mut self, name: &str) -> Self {
+        self.channels.push(ChannelSpec {
+            name: name.to_string(),
+            prev_id: None,
+            filters: Vec::new(),
+        });
+        self
+    }
+
+    /// Subscribes the hold to a channel, applying GRIP `filters` to what is
+    /// published on it, e.g. [`super::FILTER_SKIP_SELF`].
+    pub fn channel_filtered(mut self, name: &str, filters: &[&str]) -> Self {
+        self.channels.push(ChannelSpec {
+            name: name.to_string(),
+            prev_id: None,
+            filters: filters.iter().map(|f| f.to_string()).collect(),
+        });
+        self
+    }
+
+    /// Subscribes the hold to several channels, e.g. a per-user channel
+    /// along with a broadcast one.
+    pub fn channels<S: AsRef<str>>(self, names: &[S]) -> Self {
+        names
+            .iter()
+            .fold(self, |builder, name| builder.channel(name.as_ref()))
+    }
+
+    /// Subscribes the hold to a channel, telling Fanout the id of the last
+    /// message the client has for it.
+    pub fn channel_with_prev_id(mut self, name: &str, prev_id: &str) -> Self {
+        self.channels.push(ChannelSpec {
+            name: name.to_string(),
+            prev_id: Some(prev_id.to_string()),
+            filters: Vec::new(),
+        });
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: KeepAlive) -> Self {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Sets how many seconds Fanout holds the request.
+    pub fn timeout(mut self, seconds: u32) -> Self {
+        self.timeout = Some(seconds);
+        self
+    }
+
+    /// Bounds the hold timeout by the route's `timeouts` for the hold mode.
+    /// Longer timeouts are lowered to the bound, which also applies to holds
+    /// without a timeout of their own.
+    pub fn max_timeouts(mut self, timeouts: &HoldTimeouts) -> Self {
+        self.max_timeouts = *timeouts;
+        self
+    }
+
+    /// Sets the URL Fanout requests to continue the stream, optionally
+    /// after `timeout` seconds.
+    pub fn link_next(mut self, url: &str, timeout: Option<u32>) -> Self {
+        self.next_link = Some((url.to_string(), timeout));
+        self
+    }
+
+    /// Tells Fanout the client has already seen messages on `channel` up to
+    /// and including `last_id`.
+    ///
+    /// Ids that can't be safely carried in the header, e.g. because they
+    /// came from a client and contain parameter separators, are ignored.
+    pub fn last_id(mut self, channel: &str, last_id: &str) -> Self {
+        if is_valid_param(last_id) {
+            self.last.push(format!("{}; last-id={}", channel, last_id));
+        }
+        self
+    }
+
+    /// Sets the status of the response Fanout sends when a response hold
+    /// times out, such as `304 Not Modified` or `204 No Content`, given in
+    /// the `Grip-Status` header. The response carries the headers set on
+    /// the builder, and its body unless the status forbids one.
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Adds an arbitrary header to the response.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the initial body, sent right away on stream holds and as the
+    /// response on timeout for response holds.
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Gives the hold instructions in an `application/grip-instruct` body
+    /// rather than in headers, as some origin frameworks do. The content
+    /// type, headers and body set on the builder become the `response` that
+    /// Fanout sends to the client. Next links and last ids are still sent
+    /// as headers, as the instruct format has no place for them.
+    pub fn instruct(mut self) -> Self {
+        self.instruct = true;
+        self
+    }
+
+    pub fn build(self) -> Response {
+        if let Some(hold) = self.hold {
+            logging::set_context("grip_mode", hold.as_str());
+        }
+
+        let mut resp = Response::from_status(StatusCode::OK);
+        for (name, value) in self.headers() {
+            resp.append_header(name, value);
+        }
+
+        let body = if self.instruct {
+            self.to_instruct().to_string().into_bytes()
+        } else {
+            self.sent_body().to_vec()
+        };
+        resp.set_body(Body::from(body));
+        resp
+    }
+
+    /// Returns the headers of the response [`GripResponseBuilder::build`]
+    /// makes, in order.
+    pub fn headers(&self) -> Vec<(&str, String)> {
+        let mut headers = Vec::new();
+
+        if self.instruct {
+            headers.push(("Content-Type", INSTRUCT_CONTENT_TYPE.to_string()));
+        } else {
+            if let Some(ct) = &self.content_type {
+                headers.push(("Content-Type", ct.clone()));
+            }
+
+            if let Some(hold) = self.hold {
+                headers.push(("Grip-Hold", hold.as_str().to_string()));
+            }
+
+            if !self.channels.is_empty() {
+                let channels: Vec<String> = self
+                    .hold_channels()
+                    .into_iter()
+                    .map(|(name, prev_id, filters)| {
+                        let mut value = name.to_string();
+                        if let Some(prev_id) = prev_id {
+                            value.push_str(&format!("; prev-id={}", prev_id));
+                        }
+                        for filter in filters {
+                            value.push_str(&format!("; filter={}", filter));
+                        }
+                        value
+                    })
+                    .collect();
+                headers.push(("Grip-Channel", channels.join(", ")));
+            }
+
+            if let Some(keep_alive) = &self.keep_alive {
+                headers.push(("Grip-Keep-Alive", keep_alive.header_value()));
+            }
+
+            if let Some(timeout) = self.hold_timeout() {
+                headers.push(("Grip-Timeout", timeout.to_string()));
+            }
+        }
+
+        if let Some((url, timeout)) = &self.next_link {
+            headers.push(("Grip-Link", next_link_value(url, *timeout)));
+        }
+
+        for last in &self.last {
+            headers.push(("Grip-Last", last.clone()));
+        }
+
+        if !self.instruct {
+            if let Some(status) = self.status {
+                headers.push(("Grip-Status", status_line(status)));
+            }
+
+            for (name, value) in &self.headers {
+                headers.push((name.as_str(), value.clone()));
+            }
+        }
+
+        headers
+    }
+
+    /// Returns the `application/grip-instruct` body giving the hold
+    /// instructions, see [`GripResponseBuilder::instruct`].
+    pub fn to_instruct(&self) -> Value {
+        let mut hold = Map::new();
+
+        if let Some(mode) = self.hold {
+            hold.insert("mode".into(), mode.as_str().into());
+        }
+
+        let channels: Vec<Value> = self
+            .hold_channels()
+            .into_iter()
+            .map(|(name, prev_id, filters)| {
+                let mut channel = json!({ "name": name });
+                if let Some(prev_id) = prev_id {
+                    channel["prev-id"] = prev_id.into();
+                }
+                if !filters.is_empty() {
+                    channel["filters"] = filters.into();
+                }
+                channel
+            })
+            .collect();
+        hold.insert("channels".into(), channels.into());
+
+        if let Some(timeout) = self.hold_timeout() {
+            hold.insert("timeout".into(), timeout.into());
+        }
+
+        if let Some(keep_alive) = &self.keep_alive {
+            hold.insert("keep-alive".into(), keep_alive.instruct_value());
+        }
+
+        let mut headers = Map::new();
+        if let Some(ct) = &self.content_type {
+            headers.insert("Content-Type".into(), ct.as_str().into());
+        }
+        for (name, value) in &self.headers {
+            headers.insert(name.clone(), value.as_str().into());
+        }
+
+        let mut response = Map::new();
+        let status = self.status.unwrap_or(StatusCode::OK);
+        response.insert("code".into(), status.as_u16().into());
+        if let Some(reason) = status.canonical_reason() {
+            response.insert("reason".into(), reason.into());
+        }
+        response.insert("headers".into(), headers.into());
+        match std::str::from_utf8(self.sent_body()) {
+            Ok(body) => response.insert("body".into(), body.into()),
+            Err(_) => response.insert("body-bin".into(), STANDARD.encode(self.sent_body()).into()),
+        };
+
+        json!({ "hold": hold, "response": response })
+    }
+
+    /// Returns the names, previous ids and filters of the channels the hold
+    /// subscribes to. Streams are also subscribed to
+    /// [`channels::ALL_CHANNEL`], so they can be drained by the admin API.
+    fn hold_channels(&self) -> Vec<(&str, Option<&str>, &[String])> {
+        let mut specs: Vec<_> = self
+            .channels
+            .iter()
+            .map(|c| (c.name.as_str(), c.prev_id.as_deref(), c.filters.as_slice()))
+            .collect();
+        if self.hold == Some(HoldMode::Stream) && !specs.is_empty() {
+            specs.push((channels::ALL_CHANNEL, None, &[]));
+        }
+        specs
+    }
+
+    /// Returns the hold timeout, within the bound for the hold mode.
+    fn hold_timeout(&self) -> Option<u32> {
+        let max = self.hold.and_then(|mode| self.max_timeouts.get(mode));
+        match (self.timeout, max) {
+            (Some(timeout), Some(max)) => Some(timeout.min(max)),
+            (timeout, max) => timeout.or(max),
+        }
+    }
+
+    /// Returns the body, which is dropped if the timeout status forbids one.
+    fn sent_body(&self) -> &[u8] {
+        if self.status.is_some_and(forbids_body) {
+            &[]
+        } else {
+            &self.body
+        }
+    }
+}
+
+fn next_link_value(url: &str, timeout: Option<u32>) -> String {
+    match timeout {
+        Some(t) => format!("<{}>; rel=next; timeout={}", url, t),
+        None => format!("<{}>; rel=next", url),
+    }
+}
+
+/// Returns the value of the `Grip-Status` header for a status.
+fn status_line(status: StatusCode) -> String {
+    match status.canonical_reason() {
+        Some(reason) => format!("{} {}", status.as_u16(), reason),
+        None => status.as_u16().to_string(),
+    }
+}
+
+fn forbids_body(status: StatusCode) -> bool {
+    status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+}
+
+fn is_valid_param(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !matches!(c, ';' | ',' | '"' | '\\'))
+}
@@ -0,0 +1,264 @@
+//! Golden-file tests of the GRIP headers, instruct bodies and control
+//! messages the app generates.
+//!
+//! Each case is compared with its file under `tests/fixtures/grip/`, where
+//! headers are listed one per line as `Name: value` and JSON is kept as
+//! serialized. The cases follow the examples of the GRIP specification.
+//! Run with `UPDATE_GOLDEN=1` to rewrite the files after an intended change,
+//! and review the diff.
+
+use std::fs;
+use std::path::PathBuf;
+
+use fastly::http::StatusCode;
+
+use super::{
+    ContentFormat, GripControl, GripResponseBuilder, HoldTimeouts, KeepAlive, KeepAliveFormat,
+    MessageType, FILTER_BUILD_ID, FILTER_SKIP_SELF,
+};
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/grip")
+        .join(name)
+}
+
+/// Compares `actual` with the golden file `name`, or rewrites the file if
+/// `UPDATE_GOLDEN` is set.
+fn assert_golden(name: &str, actual: &str) {
+    let path = golden_path(name);
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+
+    let expected =
+        fs::read_to_string(&path).unwrap_or_else(|e| panic!("can't read {}: {e}", path.display()));
+    assert_eq!(actual, expected, "{name} differs from its golden file");
+}
+
+fn headers_text(builder: &GripResponseBuilder) -> String {
+    builder
+        .headers()
+        .iter()
+        .map(|(name, value)| format!("{name}: {value}\n"))
+        .collect()
+}
+
+/// Checks the headers and instruct body of a hold against the golden files
+/// `{name}.headers`, `{name}.instruct.headers` and `{name}.json`.
+fn assert_hold(name: &str, builder: GripResponseBuilder) {
+    assert_golden(&format!("{name}.headers"), &headers_text(&builder));
+
+    let builder = builder.instruct();
+    assert_golden(&format!("{name}.instruct.headers"), &headers_text(&builder));
+    assert_golden(
+        &format!("{name}.json"),
+        &format!("{}\n", builder.to_instruct()),
+    );
+}
+
+fn keep_alive(content: &str, format: KeepAliveFormat, timeout: u32) -> KeepAlive {
+    KeepAlive {
+        content: content.to_string(),
+        format,
+        timeout,
+    }
+}
+
+#[test]
+fn response_hold() {
+    assert_hold(
+        "response-hold",
+        GripResponseBuilder::new()
+            .hold_response()
+            .channel("mychannel")
+            .timeout(55)
+            .content_type("application/json")
+            .body("{}\n"),
+    );
+}
+
+#[test]
+fn stream_hold() {
+    assert_hold(
+        "stream-hold",
+        GripResponseBuilder::new()
+            .hold_stream()
+            .channel("mychannel")
+            .content_type("text/event-stream")
+            .keep_alive(keep_alive(
+                "event: keep-alive\ndata: \n\n",
+                KeepAliveFormat::Cstring,
+                20,
+            ))
+            .header("Cache-Control", "no-cache")
+            .body("event: stream-open\ndata: \n\n"),
+    );
+}
+
+#[test]
+fn filtered_channels() {
+    assert_hold(
+        "filters",
+        GripResponseBuilder::new()
+            .hold_stream()
+            .channel_filtered("mychannel", &[FILTER_SKIP_SELF, FILTER_BUILD_ID])
+            .channel_with_prev_id("user:alice", "1")
+            .content_type("text/event-stream"),
+    );
+}
+
+#[test]
+fn channels_with_prev_ids() {
+    assert_hold(
+        "prev-id",
+        GripResponseBuilder::new()
+            .hold_response()
+            .channel_with_prev_id("mychannel", "1")
+            .channel("user:alice")
+            .channel_with_prev_id("c:conn-1", "a2b3"),
+    );
+}
+
+#[test]
+fn binary_keep_alive() {
+    assert_hold(
+        "keep-alive-base64",
+        GripResponseBuilder::new()
+            .hold_stream()
+            .channel("bin")
+            .keep_alive(keep_alive("\u{0}\u{1}\\\"", KeepAliveFormat::Base64, 30)),
+    );
+}
+
+#[test]
+fn escaped_keep_alive() {
+    assert_hold(
+        "keep-alive-cstring",
+        GripResponseBuilder::new()
+            .hold_stream()
+            .channel("esc")
+            .keep_alive(keep_alive("a\\b\r\n\t\"c\"", KeepAliveFormat::Cstring, 15)),
+    );
+}
+
+#[test]
+fn links_and_last_ids() {
+    assert_hold(
+        "links",
+        GripResponseBuilder::new()
+            .hold_stream()
+            .channel("mychannel")
+            .link_next("/stream/?after=2", Some(120))
+            .last_id("mychannel", "2")
+            .last_id("other", "x;y")
+            .last_id("third", "3"),
+    );
+}
+
+#[test]
+fn bounded_timeouts() {
+    let timeouts = HoldTimeouts {
+        response: Some(30),
+        stream: Some(3600),
+    };
+
+    assert_hold(
+        "bounded-response",
+        GripResponseBuilder::new()
+            .hold_response()
+            .channel("mychannel")
+            .timeout(55)
+            .max_timeouts(&timeouts),
+    );
+    assert_hold(
+        "bounded-stream",
+        GripResponseBuilder::new()
+            .max_timeouts(&timeouts)
+            .hold_stream()
+            .channel("mychannel"),
+    );
+}
+
+#[test]
+fn timeout_status() {
+    assert_hold(
+        "not-modified",
+        GripResponseBuilder::new()
+            .hold_response()
+            .channel("mychannel")
+            .status(StatusCode::NOT_MODIFIED)
+            .header("ETag", "\"v1\"")
+            .body("dropped"),
+    );
+}
+
+#[test]
+fn escaped_bodies() {
+    assert_hold(
+        "escaped-body",
+        GripResponseBuilder::new()
+            .hold_response()
+            .channel("mychannel")
+            .content_type("application/json; charset=utf-8")
+            .body("{\"text\":\"h\u{e9}llo \\\"you\\\"\\n</script>\"}\n\u{2028}"),
+    );
+
+    assert_hold(
+        "binary-body",
+        GripResponseBuilder::new()
+            .hold_response()
+            .channel("mychannel")
+            .content_type("application/octet-stream")
+            .body(vec![0xff, 0x00, 0xfe, b'a']),
+    );
+}
+
+#[test]
+fn control_messages() {
+    let controls = [
+        GripControl::Subscribe {
+            channel: "mychannel".into(),
+            filters: Vec::new(),
+        },
+        GripControl::Subscribe {
+            channel: "mychannel".into(),
+            filters: vec!["skip-self".into(), "build-id".into()],
+        },
+        GripControl::Unsubscribe {
+            channel: "mychannel".into(),
+        },
+        GripControl::Detach,
+        keep_alive("{}", KeepAliveFormat::Cstring, 30).to_control(MessageType::Text),
+        keep_alive("\u{0}ping", KeepAliveFormat::Base64, 25).to_control(MessageType::Binary),
+        GripControl::KeepAlive {
+            message_type: None,
+            content: String::new(),
+            format: Some(ContentFormat::Base64),
+            timeout: 20,
+        },
+        GripControl::SetHold { timeout: Some(60) },
+        GripControl::SetHold { timeout: None },
+        GripControl::Close {
+            code: Some(4000),
+            reason: Some("bye \"now\"".into()),
+        },
+        GripControl::Close {
+            code: None,
+            reason: None,
+        },
+        GripControl::Ack { id: "a1".into() },
+        GripControl::send_delayed_text("{\"type\":\"timeout\"}", 10),
+        GripControl::send_delayed_binary(&[0, 1, 2], 5),
+        GripControl::CancelSendDelayed,
+        GripControl::Refresh,
+    ];
+
+    let text: String = controls
+        .iter()
+        .map(|control| format!("{}\n", control.to_json()))
+        .collect();
+    assert_golden("controls.jsonl", &text);
+}
@@ -0,0 +1,80 @@
+//! Handing requests off to Fanout, and what to do when that fails.
+//!
+//! A failed handoff leaves the client without a response. Instead, the
+//! request is retried against the `fallback_backend` without Fanout, if one
+//! is configured, and otherwise answered with a 502 error page carrying the
+//! request id so users can quote it when reporting the problem.
+
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+
+use crate::config;
+use crate::metrics;
+use crate::request_id;
+use crate::{log_error, log_info, log_warn};
+
+/// Setting naming a backend that requests are sent to directly when handing
+/// them off to Fanout fails.
+pub const FALLBACK_SETTING: &str = "fallback_backend";
+
+const ERROR_PAGE: &str = include_str!("error_page.html");
+
+/// Returns the error page for a request that couldn't be forwarded.
+pub fn error_page(status: StatusCode) -> Response {
+    let body = ERROR_PAGE
+        .replace("{status}", status.as_str())
+        .replace("{reason}", status.canonical_reason().unwrap_or("Error"))
+        .replace("{request_id}", &request_id::current());
+
+    Response::from_status(status)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_header("Cache-Control", "no-store")
+        .with_body(body)
+}
+
+/// Hands a request off to Fanout, forwarding it to `backend`. If that fails
+/// and `fallback` is set, the request is sent there directly instead. In
+/// local development the request is sent to `backend` directly.
+pub fn handoff(req: Request, backend: &str, fallback: bool) {
+    log_info!("handoff to backend {backend}");
+
+    // there is no Fanout in local development, so go straight to the origin
+    if config::local_dev() {
+        log_info!("local-dev: sending to {backend} without Fanout");
+        let resp = req.send(backend).unwrap_or_else(|e| {
+            log_error!("request to {backend} failed: {e}");
+            error_page(StatusCode::BAD_GATEWAY)
+        });
+        request_id::tag(resp).send_to_client();
+        return;
+    }
+
+    let e = match req.handoff_fanout(backend) {
+        Ok(()) => {
+            metrics::incr("handoffs_total", &[("result", "ok")]);
+            return;
+        }
+        Err(e) => e,
+    };
+    metrics::incr("handoffs_total", &[("result", "error")]);
+    log_error!("handoff to {backend} failed: {e}");
+
+    let req = e.into_sent_req();
+
+    let fallback_backend = config::setting(FALLBACK_SETTING).filter(|_| fallback);
+    let resp = match fallback_backend {
+        Some(fallback) => {
+            log_warn!("sending to fallback backend {fallback}");
+            req.send(fallback.as_str()).unwrap_or_else(|e| {
+                log_error!("request to fallback backend {fallback} failed: {e}");
+                error_page(StatusCode::BAD_GATEWAY)
+            })
+        }
+        None => error_page(StatusCode::BAD_GATEWAY),
+    };
+
+    // the failed handoff already counts as the response to the client as far
+    // as the SDK is concerned, so go through the handles to send this one
+    let (resp, body) = request_id::tag(resp).into_handles();
+    resp.send_to_client(body);
+}
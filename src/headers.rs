@@ -0,0 +1,94 @@
+//! Security headers of the responses the app generates itself.
+//!
+//! Responses made by the app rather than a backend (test pages, static
+//! assets, errors and everything else served under `.fanoutcdn.com`) get
+//! the header policy of their route, set with its `headers` field:
+//!
+//! ```json
+//! {"headers": {"hsts_max_age": 31536000, "hsts_include_subdomains": true}}
+//! ```
+//!
+//! By default, responses carry `X-Content-Type-Options: nosniff`, HTML ones
+//! a `Content-Security-Policy` fit for the demo pages, and any `Server` and
+//! `X-Powered-By` headers are removed. `Strict-Transport-Security` is only
+//! sent, on HTTPS responses, once `hsts_max_age` is set, as browsers keep
+//! to it for that long. An empty `csp` sends no policy, and `nosniff` and
+//! `hide_server` can be turned off.
+
+use fastly::Response;
+use serde::Deserialize;
+
+/// Content security policy of HTML responses by default. The demo pages
+/// carry their scripts and styles inline and only connect to their own
+/// origin.
+pub const DEFAULT_CSP: &str = "default-src 'self'; script-src 'self' 'unsafe-inline'; \
+     style-src 'self' 'unsafe-inline'; object-src 'none'; frame-ancestors 'none'";
+
+/// Headers naming the software serving a response, removed with
+/// `hide_server`.
+const SERVER_HEADERS: &[&str] = &["Server", "X-Powered-By"];
+
+/// Headers added to, and removed from, a route's own responses.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct HeaderPolicy {
+    /// `max-age` of `Strict-Transport-Security`, in seconds. Not sent unless
+    /// set.
+    pub hsts_max_age: Option<u32>,
+    /// Whether `Strict-Transport-Security` covers subdomains too.
+    pub hsts_include_subdomains: bool,
+    /// Whether to send `X-Content-Type-Options: nosniff`.
+    pub nosniff: bool,
+    /// `Content-Security-Policy` of HTML responses, none if empty.
+    pub csp: String,
+    /// Whether to remove the headers naming the server.
+    pub hide_server: bool,
+}
+
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        HeaderPolicy {
+            hsts_max_age: None,
+            hsts_include_subdomains: false,
+            nosniff: true,
+            csp: DEFAULT_CSP.to_string(),
+            hide_server: true,
+        }
+    }
+}
+
+impl HeaderPolicy {
+    /// Applies the policy to a response sent over HTTPS if `tls` is set.
+    /// Headers the response already has are kept, but for those naming the
+    /// server.
+    pub fn apply(&self, mut resp: Response, tls: bool) -> Response {
+        if let Some(max_age) = self.hsts_max_age.filter(|_| tls) {
+            if !resp.contains_header("Strict-Transport-Security") {
+                let mut value = format!("max-age={max_age}");
+                if self.hsts_include_subdomains {
+                    value.push_str("; includeSubDomains");
+                }
+                resp.set_header("Strict-Transport-Security", value);
+            }
+        }
+
+        if self.nosniff && !resp.contains_header("X-Content-Type-Options") {
+            resp.set_header("X-Content-Type-Options", "nosniff");
+        }
+
+        let html = resp
+            .get_header_str("Content-Type")
+            .is_some_and(|ct| ct.trim_start().starts_with("text/html"));
+        if html && !self.csp.is_empty() && !resp.contains_header("Content-Security-Policy") {
+            resp.set_header("Content-Security-Policy", self.csp.as_str());
+        }
+
+        if self.hide_server {
+            for name in SERVER_HEADERS {
+                resp.remove_header(*name);
+            }
+        }
+
+        resp
+    }
+}
@@ -0,0 +1,80 @@
+//! Health checks of the app's dependencies.
+//!
+//! Each dependency is probed and reported as `ok`, `error` (with details) or
+//! `unconfigured` when the service doesn't use it. Only errors make the
+//! service unhealthy, so a bare deployment without stores or a publisher
+//! still reports healthy.
+
+use fastly::{ConfigStore, KVStore};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::config;
+use crate::publish::Publisher;
+
+/// Outcome of probing a dependency.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Check {
+    Ok,
+    Error { detail: String },
+    Unconfigured,
+}
+
+impl Check {
+    fn error(detail: impl ToString) -> Self {
+        Check::Error {
+            detail: detail.to_string(),
+        }
+    }
+}
+
+/// Health of the service and its dependencies.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub healthy: bool,
+    pub service_version: String,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+fn check_config_store() -> Check {
+    match ConfigStore::try_open(config::SETTINGS_STORE) {
+        Ok(_) => Check::Ok,
+        Err(e) => Check::error(e),
+    }
+}
+
+fn check_kv_store() -> Check {
+    match KVStore::open(config::STATE_STORE) {
+        Ok(Some(store)) => match store.lookup("healthz") {
+            Ok(_) => Check::Ok,
+            Err(e) => Check::error(e),
+        },
+        Ok(None) => Check::Unconfigured,
+        Err(e) => Check::error(e),
+    }
+}
+
+fn check_publisher() -> Check {
+    match Publisher::from_config() {
+        Some(publisher) => match publisher.probe() {
+            Ok(()) => Check::Ok,
+            Err(e) => Check::error(e),
+        },
+        None => Check::Unconfigured,
+    }
+}
+
+/// Probes all dependencies.
+pub fn report() -> Report {
+    let mut checks = BTreeMap::new();
+    checks.insert("config_store", check_config_store());
+    checks.insert("kv_store", check_kv_store());
+    checks.insert("publish_endpoint", check_publisher());
+
+    Report {
+        healthy: !checks.values().any(|c| matches!(c, Check::Error { .. })),
+        service_version: std::env::var("FASTLY_SERVICE_VERSION").unwrap_or_default(),
+        checks,
+    }
+}
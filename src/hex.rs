@@ -0,0 +1,25 @@
+//! Lowercase hex encoding, of digests, signatures and random ids.
+
+/// Returns `bytes` as lowercase hex.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns `len` random bytes as lowercase hex.
+pub fn random(len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    getrandom::getrandom(&mut buf).expect("random source available");
+    encode(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_lowercase_pairs() {
+        assert_eq!(encode(&[0x00, 0x0f, 0xa0, 0xff]), "000fa0ff");
+        assert_eq!(encode(&[]), "");
+        assert_eq!(random(8).len(), 16);
+    }
+}
@@ -0,0 +1,169 @@
+//! Recent message history for replay to reconnecting clients.
+//!
+//! Items published with an id are appended to a per-channel ring buffer in
+//! the state KV Store. When a client comes back with the id of the last
+//! message it saw, the messages it missed can be sent before holding it
+//! again, so nothing published while it was away is lost. Fanout's own
+//! `Grip-Last` resumption only covers what it still has in memory.
+//!
+//! Buffers are updated with a read-modify-write, so concurrent publishes to
+//! the same channel may drop each other's entries. Clients then miss the
+//! replay of those messages, not their live delivery.
+//!
+//! The channels most recently published to are also listed, with when they
+//! last were, for the admin API to show.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::grip::unix_now;
+use crate::hex;
+use crate::log_error;
+use crate::publish::{HttpResponse, Item};
+
+/// Setting holding how many messages are kept per channel. `0` disables
+/// history.
+pub const SIZE_SETTING: &str = "history_size";
+
+const DEFAULT_SIZE: usize = 20;
+
+/// Key of the list of channels recently published to.
+const RECENT_KEY: &str = "channels:recent";
+
+/// Number of channels kept in the list of those recently published to.
+const RECENT_SIZE: usize = 100;
+
+/// A channel recently published to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecentChannel {
+    pub channel: String,
+    /// When it was last published to, in seconds since the Unix epoch.
+    pub last_published: u64,
+}
+
+/// A message kept for replay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_stream: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_response: Option<HttpResponse>,
+}
+
+/// Returns how many messages are kept per channel.
+pub fn size() -> usize {
+    config::setting(SIZE_SETTING)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_SIZE)
+}
+
+/// Returns a new random message id.
+pub fn new_id() -> String {
+    hex::random(8)
+}
+
+fn key(channel: &str) -> String {
+    format!("history:{}", channel)
+}
+
+fn load(channel: &str) -> Vec<Entry> {
+    config::state_store()
+        .and_then(|store| store.lookup_str(&key(channel)).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+/// Appends a published item to its channel's history. Items without an id
+/// can't be replayed and are skipped.
+pub fn record(item: &Item) {
+    let size = size();
+    let id = match &item.id {
+        Some(id) if size > 0 => id.clone(),
+        _ => return,
+    };
+
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => return,
+    };
+
+    let mut entries = load(&item.channel);
+    entries.push(Entry {
+        id,
+        http_stream: item.formats.http_stream.as_ref().map(|s| s.content.clone()),
+        http_response: item.formats.http_response.clone(),
+    });
+    if entries.len() > size {
+        entries.drain(..entries.len() - size);
+    }
+
+    let value = serde_json::to_string(&entries).expect("entries always serialize");
+    if let Err(e) = store.insert(&key(&item.channel), value) {
+        log_error!("failed to record history of {}: {e}", item.channel);
+    }
+}
+
+/// Returns the messages published on `channel` after the one with id
+/// `last_id`, oldest first.
+///
+/// If `last_id` is no longer in the history, the client has missed more
+/// than is kept and gets everything there is.
+pub fn since(channel: &str, last_id: &str) -> Vec<Entry> {
+    if size() == 0 {
+        return Vec::new();
+    }
+
+    let mut entries = load(channel);
+    if let Some(pos) = entries.iter().position(|e| e.id == last_id) {
+        entries.drain(..=pos);
+    }
+    entries
+}
+
+/// Removes the messages kept for `channel`.
+pub fn clear(channel: &str) {
+    if let Some(store) = config::state_store() {
+        if let Err(e) = store.delete(&key(channel)) {
+            log_error!("failed to clear history of {channel}: {e}");
+        }
+    }
+}
+
+/// Returns the channels most recently published to, latest first.
+pub fn recent_channels() -> Vec<RecentChannel> {
+    config::state_store()
+        .and_then(|store| store.lookup_str(RECENT_KEY).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+/// Moves the channels of published items to the top of the list of those
+/// recently published to.
+pub fn touch_channels(items: &[Item]) {
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => return,
+    };
+
+    let now = unix_now();
+    let mut recent = recent_channels();
+    for item in items {
+        recent.retain(|r| r.channel != item.channel);
+        recent.insert(
+            0,
+            RecentChannel {
+                channel: item.channel.clone(),
+                last_published: now,
+            },
+        );
+    }
+    recent.truncate(RECENT_SIZE);
+
+    let value = serde_json::to_string(&recent).expect("channels always serialize");
+    if let Err(e) = store.insert(RECENT_KEY, value) {
+        log_error!("failed to record recent channels: {e}");
+    }
+}
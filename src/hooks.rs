@@ -0,0 +1,290 @@
+//! Webhook ingestion.
+//!
+//! `POST /hooks/{source}` turns webhooks from other services into realtime
+//! messages. Each source is configured in the `webhooks` setting, a JSON
+//! object keyed by source name:
+//!
+//! ```json
+//! {"github": {"signature": "github", "channel": "repo-${repository.name}",
+//!             "template": "{\"action\": \"${action}\", \"by\": \"${sender.login}\"}"}}
+//! ```
+//!
+//! The payload's signature is checked against the `webhook_secret_{source}`
+//! secret, then the channel and content are rendered from their templates
+//! and the content is published to the channel. Templates substitute
+//! `${path}` with the value at that dotted path in the JSON payload: strings
+//! as they are, other values as JSON. `${}` is the whole payload, which is
+//! also what is published if there is no `template`.
+
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::auth::constant_time_eq;
+use crate::channels;
+use crate::config;
+use crate::error::AppError;
+use crate::grip::unix_now;
+use crate::hex;
+use crate::history;
+use crate::limits::{read_body, BodyError};
+use crate::publish::{Item, Publisher};
+use crate::router::Route;
+use crate::sse::SseEvent;
+use crate::{log_error, log_warn};
+
+/// Setting holding the configuration of each webhook source.
+pub const WEBHOOKS_SETTING: &str = "webhooks";
+
+/// Prefix of the secrets holding each source's signing key.
+pub const SECRET_PREFIX: &str = "webhook_secret_";
+
+/// Seconds a Stripe-style signature timestamp may be off by.
+const TIMESTAMP_TOLERANCE: u64 = 300;
+
+/// How webhook payloads are signed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignatureScheme {
+    /// `X-Hub-Signature-256: sha256={hex}`, an HMAC-SHA256 of the body.
+    Github,
+    /// `Stripe-Signature: t={timestamp},v1={hex}`, an HMAC-SHA256 of the
+    /// timestamp, a dot and the body.
+    Stripe,
+    /// A hex HMAC-SHA256 of the body in the configured `header`, optionally
+    /// prefixed with `sha256=`.
+    HmacSha256,
+    /// No signature. Only for testing, as anyone can then publish.
+    None,
+}
+
+/// Configuration of a webhook source.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Source {
+    pub signature: SignatureScheme,
+    /// Header carrying `hmac-sha256` signatures.
+    #[serde(default)]
+    pub header: Option<String>,
+    /// Template of the channel to publish to.
+    pub channel: String,
+    /// Template of the content to publish.
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+/// Why a webhook was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookError {
+    UnknownSource,
+    Body(BodyError),
+    MissingSecret,
+    MissingSignature,
+    BadSignature,
+    StaleTimestamp,
+    InvalidPayload(String),
+    InvalidChannel(String),
+    PublishNotConfigured,
+    PublishFailed(String),
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookError::UnknownSource => write!(f, "unknown webhook source"),
+            HookError::Body(e) => write!(f, "{}", e),
+            HookError::MissingSecret => write!(f, "no secret configured for source"),
+            HookError::MissingSignature => write!(f, "signature missing"),
+            HookError::BadSignature => write!(f, "signature mismatch"),
+            HookError::StaleTimestamp => write!(f, "signature timestamp out of tolerance"),
+            HookError::InvalidPayload(e) => write!(f, "invalid payload: {}", e),
+            HookError::InvalidChannel(e) => write!(f, "invalid channel: {}", e),
+            HookError::PublishNotConfigured => write!(f, "publishing is not configured"),
+            HookError::PublishFailed(e) => write!(f, "publish failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HookError {}
+
+/// Returns the configuration of a webhook source.
+pub fn source(name: &str) -> Option<Source> {
+    let value = config::setting(WEBHOOKS_SETTING)?;
+    let mut sources: HashMap<String, Source> = match serde_json::from_str(&value) {
+        Ok(sources) => sources,
+        Err(e) => {
+            log_warn!("ignoring invalid webhooks setting: {e}");
+            return None;
+        }
+    };
+    sources.remove(name)
+}
+
+fn hmac_hex(key: &[u8], parts: &[&[u8]]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    hex::encode(&mac.finalize().into_bytes())
+}
+
+/// Checks the signature of a webhook payload.
+pub fn verify(req: &Request, source: &Source, key: &[u8], body: &[u8]) -> Result<(), HookError> {
+    let header = |name: &str| {
+        req.get_header_str(name)
+            .map(str::trim)
+            .ok_or(HookError::MissingSignature)
+    };
+    let check = |given: &str, expected: &str| {
+        if constant_time_eq(given.to_ascii_lowercase().as_bytes(), expected.as_bytes()) {
+            Ok(())
+        } else {
+            Err(HookError::BadSignature)
+        }
+    };
+
+    match source.signature {
+        SignatureScheme::None => Ok(()),
+        SignatureScheme::Github => {
+            let sig = header("X-Hub-Signature-256")?;
+            let sig = sig.strip_prefix("sha256=").ok_or(HookError::BadSignature)?;
+            check(sig, &hmac_hex(key, &[body]))
+        }
+        SignatureScheme::HmacSha256 => {
+            let name = source.header.as_deref().unwrap_or("X-Signature");
+            let sig = header(name)?;
+            let sig = sig.strip_prefix("sha256=").unwrap_or(sig);
+            check(sig, &hmac_hex(key, &[body]))
+        }
+        SignatureScheme::Stripe => {
+            let value = header("Stripe-Signature")?;
+            let mut timestamp = None;
+            let mut signatures = Vec::new();
+            for part in value.split(',') {
+                match part.trim().split_once('=') {
+                    Some(("t", t)) => timestamp = Some(t),
+                    Some(("v1", sig)) => signatures.push(sig),
+                    _ => {}
+                }
+            }
+
+            let timestamp = timestamp.ok_or(HookError::MissingSignature)?;
+            let t: u64 = timestamp.parse().map_err(|_| HookError::BadSignature)?;
+            if unix_now().abs_diff(t) > TIMESTAMP_TOLERANCE {
+                return Err(HookError::StaleTimestamp);
+            }
+
+            let expected = hmac_hex(key, &[timestamp.as_bytes(), b".", body]);
+            // during secret rolls there is a signature for each secret
+            if signatures.iter().any(|sig| check(sig, &expected).is_ok()) {
+                Ok(())
+            } else {
+                Err(HookError::BadSignature)
+            }
+        }
+    }
+}
+
+/// Renders a template against a JSON payload.
+pub fn render(template: &str, payload: &Value) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = match after.find('}') {
+            Some(end) => end,
+            None => {
+                out.push_str(&rest[start..]);
+                return out;
+            }
+        };
+
+        let path = &after[..end];
+        let value = if path.is_empty() {
+            Some(payload)
+        } else {
+            path.split('.').try_fold(payload, |v, key| match v {
+                Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+                v => v.get(key),
+            })
+        };
+        match value {
+            Some(Value::String(s)) => out.push_str(s),
+            Some(v) => out.push_str(&v.to_string()),
+            None => {}
+        }
+
+        rest = &after[end + 1..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn ingest(req: &mut Request, name: &str, route: &Route) -> Result<(), HookError> {
+    let source = source(name).ok_or(HookError::UnknownSource)?;
+    let body = read_body(req, route.limits.max_body).map_err(HookError::Body)?;
+
+    if source.signature != SignatureScheme::None {
+        let key = config::secret(&format!("{}{}", SECRET_PREFIX, name))
+            .filter(|k| !k.is_empty())
+            .ok_or(HookError::MissingSecret)?;
+        verify(req, &source, &key, &body)?;
+    }
+
+    let payload: Value =
+        serde_json::from_slice(&body).map_err(|e| HookError::InvalidPayload(e.to_string()))?;
+
+    let channel = render(&source.channel, &payload);
+    channels::check(&channel).map_err(|e| HookError::InvalidChannel(e.to_string()))?;
+    let channel = route.channel(&channel);
+
+    let content = match &source.template {
+        Some(template) => render(template, &payload),
+        None => payload.to_string(),
+    };
+
+    let publisher = Publisher::for_route(route).ok_or(HookError::PublishNotConfigured)?;
+
+    let id = history::new_id();
+    let item = Item::new(channel.as_str())
+        .with_id(id.as_str())
+        .http_stream(
+            SseEvent::new(content.as_str())
+                .with_id(id.as_str())
+                .encode(),
+        )
+        .http_response(content.as_str())
+        .ws_message(content);
+
+    publisher.publish(item).map_err(|e| {
+        log_error!("failed to publish webhook from {name} to {channel}: {e}");
+        HookError::PublishFailed(e.to_string())
+    })
+}
+
+/// Handles a `POST /hooks/{source}` request.
+pub fn handle(mut req: Request, route: &Route) -> Result<Response, AppError> {
+    if req.get_method() != Method::POST {
+        return Ok(
+            AppError::MethodError("Use POST to deliver webhooks.".into())
+                .response()
+                .with_header("Allow", "POST"),
+        );
+    }
+
+    let name = req
+        .get_path()
+        .strip_prefix("/hooks/")
+        .unwrap_or_default()
+        .to_string();
+
+    ingest(&mut req, &name, route)?;
+    Ok(Response::from_status(StatusCode::ACCEPTED).with_body("Accepted.\n"))
+}
@@ -0,0 +1,230 @@
+//! Idempotency keys of publish requests.
+//!
+//! Webhook sources and other publishers retry requests whose response they
+//! didn't get, which would deliver the same message twice. A publish
+//! request carrying an `Idempotency-Key` header has its response recorded
+//! in the state KV Store under that key and its channel, and requests
+//! repeating the key within the window set by
+//! `publish_idempotency_window` (seconds, a day by default) get the
+//! recorded response again, with `Idempotent-Replayed: true`, instead of
+//! publishing. Reusing a key for a different body is refused with `400`.
+//!
+//! Only successful publishes are recorded, so a failed one can be retried
+//! with the same key. Concurrent requests with the same key may both
+//! publish, as records are written once the publish is done.
+
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::error::AppError;
+use crate::grip;
+use crate::hex;
+use crate::log_error;
+
+/// Request header carrying the idempotency key.
+pub const KEY_HEADER: &str = "Idempotency-Key";
+
+/// Response header marking a replayed response.
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+/// Setting holding the seconds a key is remembered for.
+pub const WINDOW_SETTING: &str = "publish_idempotency_window";
+
+const DEFAULT_WINDOW: u64 = 86400;
+
+/// Longest idempotency key accepted.
+pub const MAX_KEY_LEN: usize = 255;
+
+fn window() -> u64 {
+    config::setting(WINDOW_SETTING)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_WINDOW)
+}
+
+/// The response to a publish request, as recorded for replays.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recorded {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Recorded {
+    pub fn new(status: StatusCode, body: impl Into<String>) -> Self {
+        Recorded {
+            status: status.as_u16(),
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Returns the response to send.
+    pub fn response(&self) -> Response {
+        let mut resp = Response::from_status(self.status);
+        for (name, value) in &self.headers {
+            resp.append_header(name.as_str(), value.as_str());
+        }
+        resp.with_body(self.body.as_str())
+    }
+}
+
+/// A recorded response, with what identifies the request it answered.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    /// Unix time the key is forgotten at.
+    expires: u64,
+    /// Hex SHA-256 of the request body.
+    body_hash: String,
+    response: Recorded,
+}
+
+/// The idempotency key of a publish request to a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    store_key: String,
+    body_hash: String,
+}
+
+impl Key {
+    /// Returns the key of a publish of `body` to `channel` by `req`, or
+    /// `None` if it has no idempotency key. Keys are limited to
+    /// [`MAX_KEY_LEN`] printable ASCII characters.
+    pub fn from_request(
+        req: &Request,
+        channel: &str,
+        body: &[u8],
+    ) -> Result<Option<Key>, AppError> {
+        let key = match req.get_header_str(KEY_HEADER) {
+            Some(key) => key.trim(),
+            None => return Ok(None),
+        };
+        if key.is_empty()
+            || key.len() > MAX_KEY_LEN
+            || !key.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+        {
+            return Err(AppError::ParseError(format!(
+                "Invalid {KEY_HEADER}, give up to {MAX_KEY_LEN} printable ASCII characters."
+            )));
+        }
+
+        Ok(Some(Key {
+            store_key: format!("idem:{}:{}", channel, key),
+            body_hash: hex::encode(&Sha256::digest(body)),
+        }))
+    }
+
+    /// Returns the response recorded for the key, if it's still remembered.
+    /// A key recorded for another body is refused.
+    pub fn replay(&self) -> Result<Option<Response>, AppError> {
+        let record = config::state_store()
+            .and_then(|store| store.lookup_str(&self.store_key).ok())
+            .flatten()
+            .and_then(|s| serde_json::from_str::<Record>(&s).ok());
+
+        Ok(self
+            .replayed(record, grip::unix_now())?
+            .map(|recorded| recorded.response()))
+    }
+
+    /// Returns the response to replay from the record kept for the key, if
+    /// it's still remembered at `now`.
+    fn replayed(&self, record: Option<Record>, now: u64) -> Result<Option<Recorded>, AppError> {
+        match record.filter(|r| r.expires > now) {
+            Some(r) if r.body_hash != self.body_hash => Err(AppError::ParseError(format!(
+                "{KEY_HEADER} was already used for a different body."
+            ))),
+            Some(r) => Ok(Some(r.response.with_header(REPLAYED_HEADER, "true"))),
+            None => Ok(None),
+        }
+    }
+
+    /// Records the response to the key's request for replays.
+    pub fn record(&self, response: &Recorded) {
+        let mut store = match config::state_store() {
+            Some(store) => store,
+            None => return,
+        };
+
+        let record = Record {
+            expires: grip::unix_now() + window(),
+            body_hash: self.body_hash.clone(),
+            response: response.clone(),
+        };
+        let value = serde_json::to_string(&record).expect("records always serialize");
+        if let Err(e) = store.insert(&self.store_key, value) {
+            log_error!("failed to record idempotency key {}: {e}", self.store_key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(body: &[u8]) -> Key {
+        let req = Request::post("https://example.com/publish/news").with_header(KEY_HEADER, "k-1");
+        Key::from_request(&req, "news", body).unwrap().unwrap()
+    }
+
+    fn record(body: &[u8]) -> Record {
+        Record {
+            expires: 200,
+            body_hash: key(body).body_hash,
+            response: Recorded::new(StatusCode::OK, "Published.\n").with_header("Ack-Id", "1"),
+        }
+    }
+
+    #[test]
+    fn replays_recorded_responses() {
+        let replayed = key(b"hello").replayed(Some(record(b"hello")), 100);
+        assert_eq!(
+            replayed.unwrap(),
+            Some(Recorded {
+                status: 200,
+                headers: vec![
+                    ("Ack-Id".into(), "1".into()),
+                    (REPLAYED_HEADER.into(), "true".into()),
+                ],
+                body: "Published.\n".into(),
+            })
+        );
+
+        // forgotten once the window is over
+        assert_eq!(
+            key(b"hello").replayed(Some(record(b"hello")), 200).unwrap(),
+            None
+        );
+        assert_eq!(key(b"hello").replayed(None, 100).unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_keys_reused_for_other_bodies() {
+        let err = key(b"bye")
+            .replayed(Some(record(b"hello")), 100)
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn validates_keys() {
+        let req = |key: &str| {
+            Request::post("https://example.com/publish/news").with_header(KEY_HEADER, key)
+        };
+        assert!(Key::from_request(&req(&"k".repeat(MAX_KEY_LEN + 1)), "news", b"").is_err());
+        assert!(Key::from_request(&req("  "), "news", b"").is_err());
+        assert!(
+            Key::from_request(&Request::post("https://example.com/"), "news", b"")
+                .unwrap()
+                .is_none()
+        );
+    }
+}
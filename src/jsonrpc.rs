@@ -0,0 +1,209 @@
+//! JSON-RPC 2.0 over WebSocket.
+//!
+//! [`JsonRpc`] is a [`WsHandler`] parsing TEXT messages as JSON-RPC requests
+//! and dispatching them to the handler closures registered for their
+//! methods, one request or batch at a time. Handlers get the connection's
+//! [`WsContext`], so they can subscribe it to channels; server-initiated
+//! notifications are then published to those channels with [`notify`].
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::log_debug;
+use crate::publish::{Item, PublishError, Publisher};
+use crate::ws::{WsContext, WsHandler};
+
+/// Error codes defined by the specification.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// A JSON-RPC error object.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn parse_error() -> Self {
+        Self::new(PARSE_ERROR, "Parse error")
+    }
+
+    pub fn invalid_request() -> Self {
+        Self::new(INVALID_REQUEST, "Invalid Request")
+    }
+
+    pub fn method_not_found() -> Self {
+        Self::new(METHOD_NOT_FOUND, "Method not found")
+    }
+
+    pub fn invalid_params(detail: impl fmt::Display) -> Self {
+        Self::new(INVALID_PARAMS, "Invalid params").with_data(detail.to_string().into())
+    }
+
+    pub fn internal_error() -> Self {
+        Self::new(INTERNAL_ERROR, "Internal error")
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+    /// Absent for notifications. A `null` id is still a request.
+    #[serde(default, deserialize_with = "some_value")]
+    id: Option<Value>,
+}
+
+fn some_value<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(d).map(Some)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+/// Returns a notification message calling `method` on the client.
+pub fn notification(method: &str, params: Value) -> String {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params }).to_string()
+}
+
+/// Publishes a notification to the connections subscribed to `channel`.
+pub fn notify(
+    publisher: &Publisher,
+    channel: &str,
+    method: &str,
+    params: Value,
+) -> Result<(), PublishError> {
+    publisher.publish(Item::new(channel).ws_message(notification(method, params)))
+}
+
+type Method<'a> = Box<dyn FnMut(&mut WsContext, Value) -> Result<Value, RpcError> + 'a>;
+
+/// Dispatches JSON-RPC requests to registered methods.
+#[derive(Default)]
+pub struct JsonRpc<'a> {
+    methods: HashMap<String, Method<'a>>,
+}
+
+impl<'a> JsonRpc<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the handler of a method. Handlers get the request's
+    /// params, or `null` if it had none.
+    pub fn method(
+        mut self,
+        name: &str,
+        handler: impl FnMut(&mut WsContext, Value) -> Result<Value, RpcError> + 'a,
+    ) -> Self {
+        self.methods.insert(name.to_string(), Box::new(handler));
+        self
+    }
+
+    /// Handles one request object, returning its response unless it was a
+    /// notification.
+    fn call(&mut self, ctx: &mut WsContext, value: Value) -> Option<Value> {
+        let req: Request = match serde_json::from_value(value) {
+            Ok(req) => req,
+            Err(_) => return Some(response(Value::Null, Err(RpcError::invalid_request()))),
+        };
+
+        let id = match req.id {
+            Some(id @ (Value::Null | Value::String(_) | Value::Number(_))) => Some(id),
+            Some(_) => return Some(response(Value::Null, Err(RpcError::invalid_request()))),
+            None => None,
+        };
+        if req.jsonrpc != "2.0" {
+            return Some(response(
+                id.unwrap_or(Value::Null),
+                Err(RpcError::invalid_request()),
+            ));
+        }
+
+        let result = match self.methods.get_mut(&req.method) {
+            Some(handler) => handler(ctx, req.params.unwrap_or(Value::Null)),
+            None => Err(RpcError::method_not_found()),
+        };
+
+        match id {
+            Some(id) => Some(response(id, result)),
+            None => {
+                if let Err(e) = result {
+                    log_debug!("notification {} failed: {e}", req.method);
+                }
+                None
+            }
+        }
+    }
+
+    /// Handles a message holding a request or a batch of them, returning
+    /// the message to send back, if any.
+    pub fn handle_message(&mut self, ctx: &mut WsContext, text: &str) -> Option<String> {
+        let value: Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(_) => return Some(response(Value::Null, Err(RpcError::parse_error())).to_string()),
+        };
+
+        match value {
+            Value::Array(batch) if batch.is_empty() => {
+                Some(response(Value::Null, Err(RpcError::invalid_request())).to_string())
+            }
+            Value::Array(batch) => {
+                let responses: Vec<Value> = batch
+                    .into_iter()
+                    .filter_map(|req| self.call(ctx, req))
+                    .collect();
+                // a batch of notifications gets no response at all
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(responses).to_string())
+                }
+            }
+            value => self.call(ctx, value).map(|resp| resp.to_string()),
+        }
+    }
+}
+
+impl WsHandler for JsonRpc<'_> {
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        if let Some(resp) = self.handle_message(ctx, &text) {
+            ctx.out.write_text(&resp);
+        }
+    }
+}
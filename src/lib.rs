@@ -0,0 +1,60 @@
+//! Building blocks for the Fanout.io realm app.
+//!
+//! The binary in `main.rs` wires these together into the Compute service;
+//! everything that doesn't need to talk to the client request directly lives
+//! here so it can be reused across handlers.
+
+pub mod ack;
+pub mod acl;
+pub mod activity;
+pub mod admin;
+pub mod auth;
+pub mod backends;
+pub mod bayeux;
+pub mod channels;
+pub mod chat;
+pub mod config;
+pub mod connections;
+pub mod cors;
+pub mod dead_letter;
+pub mod dispatch;
+pub mod envelope;
+pub mod error;
+pub mod forwarded;
+pub mod geo;
+pub mod graphql_ws;
+pub mod grip;
+pub mod handoff;
+pub mod headers;
+pub mod health;
+pub mod hex;
+pub mod history;
+pub mod hooks;
+pub mod idempotency;
+pub mod jsonrpc;
+pub mod limits;
+pub mod logging;
+pub mod maintenance;
+pub mod methods;
+pub mod metrics;
+pub mod mqtt;
+pub mod presence;
+pub mod publish;
+pub mod ratelimit;
+pub mod request_id;
+pub mod router;
+pub mod rules;
+pub mod schedule;
+pub mod sequence;
+pub mod session;
+pub mod signing;
+pub mod socketio;
+pub mod sockjs;
+pub mod sse;
+pub mod stomp;
+pub mod tenant;
+pub mod tokens;
+pub mod trace;
+pub mod transform;
+pub mod ws;
+pub mod ws_events;
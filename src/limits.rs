@@ -0,0 +1,92 @@
+//! Request body size limits.
+//!
+//! Bodies of publish requests are read into memory, and those of
+//! WebSocket-over-HTTP requests handled as their events are read, so their
+//! size is limited, per route with its `limits` field:
+//!
+//! ```json
+//! {"limits": {"max_body": 262144, "max_message": 16384, "max_messages_per_minute": 120}}
+//! ```
+//!
+//! Requests with larger bodies are refused with `413`. WebSocket messages
+//! larger than `max_message` close their connection with code 1009, and
+//! connections sending more TEXT and BINARY messages in a minute than
+//! `max_messages_per_minute`, if set, are closed with code 4429.
+
+use fastly::Request;
+use serde::Deserialize;
+use std::fmt;
+use std::io::Read;
+
+/// Largest body read by default, in bytes.
+pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+/// Largest WebSocket message accepted by default, in bytes.
+pub const DEFAULT_MAX_MESSAGE: usize = 64 * 1024;
+
+/// Close code sent when a WebSocket message is too big.
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Close code sent when a WebSocket connection sends messages too fast.
+pub const CLOSE_RATE_LIMITED: u16 = 4429;
+
+/// Size limits of a route's requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct BodyLimits {
+    /// Largest request body read, in bytes.
+    pub max_body: usize,
+    /// Largest WebSocket message accepted, in bytes.
+    pub max_message: usize,
+    /// Most TEXT and BINARY messages a WebSocket connection may send per
+    /// minute. Unlimited if unset.
+    pub max_messages_per_minute: Option<u32>,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        BodyLimits {
+            max_body: DEFAULT_MAX_BODY,
+            max_message: DEFAULT_MAX_MESSAGE,
+            max_messages_per_minute: None,
+        }
+    }
+}
+
+/// Error reading a request body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyError {
+    /// The body is larger than the limit.
+    TooLarge(usize),
+    Io(String),
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::TooLarge(limit) => write!(f, "body larger than {} bytes", limit),
+            BodyError::Io(e) => write!(f, "failed to read body: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+/// Reads the body of a request, failing if it is larger than `limit`
+/// bytes. Bodies whose `Content-Length` is over the limit aren't read.
+pub fn read_body(req: &mut Request, limit: usize) -> Result<Vec<u8>, BodyError> {
+    if req.get_content_length().is_some_and(|len| len > limit) {
+        return Err(BodyError::TooLarge(limit));
+    }
+
+    let mut data = Vec::new();
+    req.take_body()
+        .take(limit as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| BodyError::Io(e.to_string()))?;
+
+    if data.len() > limit {
+        return Err(BodyError::TooLarge(limit));
+    }
+    Ok(data)
+}
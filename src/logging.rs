@@ -0,0 +1,179 @@
+//! Structured request logging.
+//!
+//! Log lines are JSON objects carrying the message along with the context of
+//! the request being served (request id, host, path, chosen backend, GRIP
+//! hold mode, ...) and the time elapsed since the request started. They are
+//! written to the Fastly log endpoint named by the `log_endpoint` setting,
+//! or to stdout if none is configured.
+//!
+//! Use the [`log_debug!`], [`log_info!`], [`log_warn!`] and [`log_error!`]
+//! macros to log, and [`set_context`] to attach fields to all later lines.
+//!
+//! Unit tests run outside Compute, without settings or log endpoints, and
+//! log to stdout at `info`.
+
+#[cfg(not(test))]
+use fastly::log::Endpoint;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::time::Instant;
+
+#[cfg(not(test))]
+use crate::config;
+use crate::metrics;
+
+/// Setting naming the Fastly log endpoint to write to.
+pub const ENDPOINT_SETTING: &str = "log_endpoint";
+
+/// Setting holding the minimum level logged: `debug`, `info` (the
+/// default), `warn` or `error`.
+pub const LEVEL_SETTING: &str = "log_level";
+
+/// Severity of a log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "warn" | "warning" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+}
+
+struct Logger {
+    endpoint: Option<Box<dyn Write>>,
+    level: Level,
+    start: Instant,
+    context: Map<String, Value>,
+}
+
+impl Logger {
+    #[cfg(not(test))]
+    fn from_config() -> Self {
+        let endpoint = config::setting(ENDPOINT_SETTING).and_then(|name| {
+            Endpoint::try_from_name(&name)
+                .map(|endpoint| Box::new(endpoint) as Box<dyn Write>)
+                .map_err(|e| println!("invalid log endpoint {name}: {e}"))
+                .ok()
+        });
+
+        Logger {
+            endpoint,
+            level: config::setting(LEVEL_SETTING)
+                .and_then(|s| Level::parse(&s))
+                .unwrap_or(Level::Info),
+            start: Instant::now(),
+            context: Map::new(),
+        }
+    }
+
+    #[cfg(test)]
+    fn from_config() -> Self {
+        Logger {
+            endpoint: None,
+            level: Level::Info,
+            start: Instant::now(),
+            context: Map::new(),
+        }
+    }
+}
+
+thread_local! {
+    static LOGGER: RefCell<Option<Logger>> = const { RefCell::new(None) };
+}
+
+fn with_logger<T>(f: impl FnOnce(&mut Logger) -> T) -> T {
+    LOGGER.with(|cell| {
+        let mut logger = cell.borrow_mut();
+        f(logger.get_or_insert_with(Logger::from_config))
+    })
+}
+
+/// Sets up logging for a request, reading the configuration and starting
+/// the latency clock. Logging works without calling this, but latencies are
+/// then measured from the first log line.
+pub fn init() {
+    LOGGER.with(|cell| *cell.borrow_mut() = Some(Logger::from_config()));
+}
+
+/// Attaches a field to all following log lines of the request.
+pub fn set_context(key: &str, value: impl Into<Value>) {
+    with_logger(|logger| {
+        logger.context.insert(key.to_string(), value.into());
+    });
+}
+
+/// Writes a log line. Prefer the `log_*!` macros.
+pub fn log(level: Level, args: fmt::Arguments) {
+    if level == Level::Error {
+        metrics::incr("errors_total", &[]);
+    }
+
+    with_logger(|logger| {
+        if level < logger.level {
+            return;
+        }
+
+        let mut line = Map::new();
+        line.insert("level".to_string(), level.as_str().into());
+        line.insert("msg".to_string(), args.to_string().into());
+        line.extend(logger.context.clone());
+        line.insert(
+            "latency_ms".to_string(),
+            (logger.start.elapsed().as_millis() as u64).into(),
+        );
+
+        let line = Value::Object(line).to_string();
+
+        match &mut logger.endpoint {
+            Some(endpoint) => {
+                if let Err(e) = writeln!(endpoint, "{}", line) {
+                    println!("failed to write log line: {e}");
+                    println!("{}", line);
+                }
+            }
+            None => println!("{}", line),
+        }
+    });
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::logging::log($crate::logging::Level::Debug, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::logging::log($crate::logging::Level::Info, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::logging::log($crate::logging::Level::Warn, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::logging::log($crate::logging::Level::Error, format_args!($($arg)*)) };
+}
@@ -1,213 +1,921 @@
+use fanout_io_fastly_app::ack;
+use fanout_io_fastly_app::admin;
+use fanout_io_fastly_app::auth;
+use fanout_io_fastly_app::bayeux;
+use fanout_io_fastly_app::channels;
+use fanout_io_fastly_app::chat;
+use fanout_io_fastly_app::config;
+use fanout_io_fastly_app::cors;
+use fanout_io_fastly_app::dispatch::{self, Endpoint, Handlers, Outcome, Router};
+use fanout_io_fastly_app::error::AppError;
+use fanout_io_fastly_app::forwarded;
+use fanout_io_fastly_app::graphql_ws;
+use fanout_io_fastly_app::grip::{
+    unix_now_millis, GripControl, GripFeatures, GripResponseBuilder, KeepAlive, FILTER_SKIP_SELF,
+    USER_META,
+};
+use fanout_io_fastly_app::handoff;
+use fanout_io_fastly_app::health;
+use fanout_io_fastly_app::history;
+use fanout_io_fastly_app::hooks;
+use fanout_io_fastly_app::idempotency::{self, Recorded};
+use fanout_io_fastly_app::jsonrpc::{JsonRpc, RpcError};
+use fanout_io_fastly_app::limits::read_body;
+use fanout_io_fastly_app::logging;
+use fanout_io_fastly_app::methods;
+use fanout_io_fastly_app::metrics;
+use fanout_io_fastly_app::mqtt;
+use fanout_io_fastly_app::presence;
+use fanout_io_fastly_app::publish::{Batch, Item, Publisher};
+use fanout_io_fastly_app::ratelimit::{self, Scope};
+use fanout_io_fastly_app::request_id;
+use fanout_io_fastly_app::router::Route;
+use fanout_io_fastly_app::schedule;
+use fanout_io_fastly_app::sequence;
+use fanout_io_fastly_app::socketio;
+use fanout_io_fastly_app::sockjs;
+use fanout_io_fastly_app::sse::SseEvent;
+use fanout_io_fastly_app::stomp;
+use fanout_io_fastly_app::tokens::{self, ChannelClaims, TokenError};
+use fanout_io_fastly_app::trace;
+use fanout_io_fastly_app::ws::{self, WsContext, WsHandler};
+use fanout_io_fastly_app::ws_events::WsEvent;
+use fanout_io_fastly_app::{log_debug, log_error, log_info, log_warn};
 use fastly::http::StatusCode;
 use fastly::{Error, Request, Response};
-use std::collections::HashMap;
 
-/// Returns a GRIP response to initialize a stream
-///
-/// When our app receives a non-WebSocket request (i.e. normal HTTP) and wants
-/// to make it long lived (longpoll or SSE), we call handoff_fanout on it, and
-/// Fanout will then forward that request to the nominated backend.  In this app,
-/// that backend is this same Compute service, where we then need to respond
-/// with some Grip headers to tell Fanout to hold the connection for streaming.
-/// This function constructs such a response.
-pub fn grip_response(ctype: &str, ghold: &str, chan: &str) -> Response {
-    Response::from_status(StatusCode::OK)
-        .with_header("Content-Type", ctype)
-        .with_header("Grip-Hold", ghold)
-        .with_header("Grip-Channel", chan)
-        .with_body("")
+/// Subscribes WebSocket connections to the test channel.
+struct TestWs<'a> {
+    name: &'a str,
+    route: &'a Route,
+    channels: Vec<String>,
+    claims: Option<ChannelClaims>,
 }
 
-/// Returns a WebSocket-over-HTTP formatted TEXT message
-pub fn ws_text(msg: &str) -> Vec<u8> {
-    format!("TEXT {:02x}\r\n{}\r\n", msg.len(), msg)
-        .as_bytes()
-        .to_vec()
+impl WsHandler for TestWs<'_> {
+    fn reject(&mut self, req: &Request) -> Option<Response> {
+        match subscription(req, self.name, self.route) {
+            Ok((channels, claims)) => {
+                self.channels = channels;
+                self.claims = claims;
+                None
+            }
+            Err(e) => Some(AppError::from(e).response()),
+        }
+    }
+
+    fn accepts_acks(&self) -> bool {
+        true
+    }
+
+    fn on_open(&mut self, ctx: &mut WsContext) {
+        if let Some(claims) = &self.claims {
+            ctx.set_credentials(claims);
+        }
+        ctx.subscribe(&self.channels);
+    }
+
+    fn keep_alive(&self) -> Option<GripControl> {
+        Some(self.route.keep_alive.to_control())
+    }
+
+    fn on_text(&mut self, ctx: &mut WsContext, _text: String) {
+        let n = ctx.session().incr("messages");
+        log_debug!("received message {n} on connection {}", ctx.connection_id);
+    }
+
+    fn protocols(&self) -> Vec<String> {
+        config::setting("test_ws_protocols")
+            .map(|s| {
+                s.split(',')
+                    .map(str::trim)
+                    .filter(|p| !p.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn on_binary(&mut self, _ctx: &mut WsContext, data: Vec<u8>) {
+        // binary payloads (protobuf, MessagePack, ...) are opaque to the
+        // test handler, but must not be mistaken for text
+        log_debug!("received {} byte binary message", data.len());
+    }
+
+    fn on_close(&mut self, ctx: &mut WsContext, code: Option<u16>) {
+        log_debug!("client closed connection with code {code:?}");
+        ctx.out.write_event(&WsEvent::Close(code));
+    }
 }
 
-// Returns a channel-subscription command in a WebSocket-over-HTTP format
-pub fn ws_sub(ch: &str) -> Vec<u8> {
-    ws_text(format!("c:{{\"type\":\"subscribe\",\"channel\":\"{}\"}}", ch).as_str())
+/// Sends each message back on the connection it came from.
+struct EchoWs<'a> {
+    route: &'a Route,
 }
 
-fn handle_test_ws(mut req: Request, chan: &str) -> Response {
-    if req.get_header_str("Content-Type") != Some("application/websocket-events") {
-        return Response::from_status(StatusCode::BAD_REQUEST)
-            .with_body("Not a WebSocket-over-HTTP request.\n");
+impl WsHandler for EchoWs<'_> {
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        ctx.out.write_text(&text);
+    }
+
+    fn on_binary(&mut self, ctx: &mut WsContext, data: Vec<u8>) {
+        ctx.out.write_binary(&data);
+    }
+
+    fn keep_alive(&self) -> Option<GripControl> {
+        Some(self.route.keep_alive.to_control())
+    }
+}
+
+/// Subscribes WebSocket connections to a test channel and publishes each
+/// message they send to it, so every other subscriber sees it. Connections
+/// publish as themselves and subscribe with the `skip-self` filter, so
+/// their own messages aren't echoed back. The messages of one request are
+/// published together.
+struct BroadcastWs<'a> {
+    name: &'a str,
+    route: &'a Route,
+    channels: Vec<String>,
+    claims: Option<ChannelClaims>,
+    batch: Option<Batch>,
+}
+
+impl BroadcastWs<'_> {
+    fn publish(&mut self, item: Item) {
+        let batch = match &mut self.batch {
+            Some(b) => b,
+            None => {
+                log_warn!("dropping broadcast, publishing is not configured");
+                return;
+            }
+        };
+
+        if let Err(e) = batch.push(item) {
+            log_error!("failed to broadcast to {}: {e}", self.name);
+        }
+    }
+}
+
+impl WsHandler for BroadcastWs<'_> {
+    fn reject(&mut self, req: &Request) -> Option<Response> {
+        match subscription(req, self.name, self.route) {
+            Ok((channels, claims)) => {
+                self.channels = channels;
+                self.claims = claims;
+                None
+            }
+            Err(e) => Some(AppError::from(e).response()),
+        }
+    }
+
+    fn accepts_acks(&self) -> bool {
+        true
+    }
+
+    fn on_open(&mut self, ctx: &mut WsContext) {
+        if let Some(claims) = &self.claims {
+            ctx.set_credentials(claims);
+        }
+        let user = ctx.connection_id.clone();
+        ctx.set_meta(USER_META, &user);
+        ctx.subscribe_filtered(&self.channels, &[FILTER_SKIP_SELF]);
+    }
+
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        let item = message_item(&self.route.channel(self.name), &text)
+            .with_sender(ctx.connection_id.as_str());
+        let multi = multi_item(self.route, self.name, &item, &text);
+        self.publish(item);
+        self.publish(multi);
+    }
+
+    fn on_binary(&mut self, ctx: &mut WsContext, data: Vec<u8>) {
+        let item = Item::new(self.route.channel(self.name))
+            .ws_binary(&data)
+            .with_sender(ctx.connection_id.as_str());
+        self.publish(item);
     }
 
-    let req_body = req.take_body().into_bytes();
-    let mut resp_body: Vec<u8> = [].to_vec();
+    fn keep_alive(&self) -> Option<GripControl> {
+        Some(self.route.keep_alive.to_control())
+    }
+}
 
-    let mut resp = Response::from_status(StatusCode::OK)
-        .with_header("Content-Type", "application/websocket-events");
+/// Subscribes WebSocket connections to the test channel, then detaches
+/// them if the proxy can, so Fanout keeps them open without involving the
+/// app again.
+struct DetachedWs<'a> {
+    name: &'a str,
+    route: &'a Route,
+    channels: Vec<String>,
+}
 
-    if req_body.starts_with(b"OPEN\r\n") {
-        resp.set_header("Sec-WebSocket-Extensions", "grip; message-prefix=\"\"");
-        resp_body.extend("OPEN\r\n".as_bytes());
-        resp_body.extend(ws_sub(chan));
-        resp_body.extend(ws_text(
-            "c:{\"type\":\"keep-alive\",\"message-type\":\"ping\",\"content\":\"\",\"timeout\":20}",
-        ));
+impl WsHandler for DetachedWs<'_> {
+    fn reject(&mut self, req: &Request) -> Option<Response> {
+        match subscription(req, self.name, self.route) {
+            Ok((channels, _)) => {
+                self.channels = channels;
+                None
+            }
+            Err(e) => Some(AppError::from(e).response()),
+        }
     }
 
-    let close = b"CLOSE".as_slice();
-    if req_body.windows(close.len()).any(|w| w == close) {
-        resp_body.extend(b"CLOSE\r\n");
+    fn on_open(&mut self, ctx: &mut WsContext) {
+        ctx.subscribe(&self.channels);
+        if ctx.features.contains(GripFeatures::DETACH) {
+            ctx.out.write_detach();
+        }
     }
 
-    resp.set_body(resp_body);
-    resp
+    fn keep_alive(&self) -> Option<GripControl> {
+        Some(self.route.keep_alive.to_control())
+    }
 }
 
-fn handle_test(req: Request, chan: &str) -> Response {
-    match req.get_url().path() {
+/// Query parameter marking the requests Fanout makes to follow the
+/// `Grip-Link` of a test SSE stream.
+const CATCH_UP_PARAM: &str = "catch_up";
+
+/// Returns the channels a test subscription to `name` covers: the channel
+/// itself, plus those the channel templates give the client's token and
+/// the private channel of its subject. The token's claims are returned
+/// along with them.
+fn subscription(
+    req: &Request,
+    name: &str,
+    route: &Route,
+) -> Result<(Vec<String>, Option<ChannelClaims>), TokenError> {
+    let mut names = vec![name.to_string()];
+    let claims = tokens::authorize(req, name)?;
+    if let Some(claims) = &claims {
+        names.extend(channels::template_channels(claims));
+        if let Some(private) = claims.private_channel() {
+            if !names.contains(&private) {
+                names.push(private);
+            }
+        }
+    }
+
+    Ok((names.iter().map(|n| route.channel(n)).collect(), claims))
+}
+
+/// Subscribes a hold to channels, each with the `prev-id` of the last
+/// message published to it when prev-id chaining is on.
+fn hold_channels(resp: GripResponseBuilder, channels: &[String]) -> GripResponseBuilder {
+    channels
+        .iter()
+        .fold(resp, |resp, channel| match sequence::prev_id(channel) {
+            Some(prev_id) => resp.channel_with_prev_id(channel, &prev_id),
+            None => resp.channel(channel),
+        })
+}
+
+/// Response header carrying the id of a message delivered to a long-polling
+/// client, to be sent back as `Last-Event-ID` on its next poll.
+const EVENT_ID_HEADER: &str = "Event-ID";
+
+/// Response headers of acknowledged publishes, giving the id acks carry and
+/// the channel they are confirmed on.
+const ACK_ID_HEADER: &str = "Ack-Id";
+const ACK_CHANNEL_HEADER: &str = "Ack-Channel";
+
+/// Response header of scheduled publishes, giving their id.
+const SCHEDULED_ID_HEADER: &str = "Scheduled-Id";
+
+/// Suffix of the channel carrying a test channel's messages to
+/// `/test/sse/multi` streams, as events named after the channel. Clients
+/// can't name it themselves.
+const MULTI_SUFFIX: &str = "+multi";
+
+/// Most channels one `/test/sse/multi` stream may subscribe to.
+const MAX_MULTI_CHANNELS: usize = 16;
+
+/// Seconds Fanout holds a long-poll request before returning the hold body.
+const DEFAULT_LONGPOLL_TIMEOUT: u32 = 55;
+
+fn handle_test(req: Request, route: &Route) -> Response {
+    let path = req.get_url().path().to_string();
+    if path == "/test/sse/multi" {
+        return handle_sse_multi(&req, route);
+    }
+
+    // the channel can be picked with a path segment on /test/sse, or with
+    // the channel query parameter
+    let (endpoint, name) = match path.strip_prefix("/test/sse/") {
+        Some(name) => ("/test/sse", name.to_string()),
+        None => (
+            path.as_str(),
+            req.get_query_parameter("channel")
+                .unwrap_or("test")
+                .to_string(),
+        ),
+    };
+
+    if let Err(e) = channels::check(&name) {
+        return AppError::from(e).response();
+    }
+
+    let chan = route.channel(&name);
+    let chan = chan.as_str();
+
+    match endpoint {
         "/test" | "/test/" => {
             Response::from_status(StatusCode::OK).with_body("Hello from the Fanout test handler!\n")
         }
         "/test/sse" => {
-            let mut padding = b":".to_vec();
-            padding.extend(vec![b' '; 2048]);
-            padding.extend(b"\n\n");
+            let subscribed = match subscription(&req, &name, route) {
+                Ok((channels, _)) => channels,
+                Err(e) => return AppError::from(e).response(),
+            };
+            let catching_up = req.get_query_parameter(CATCH_UP_PARAM).is_some();
+
+            let resp = GripResponseBuilder::new()
+                .content_type("text/event-stream")
+                .hold_stream()
+                .keep_alive(KeepAlive::new(":\n\n"))
+                .max_timeouts(&route.timeouts);
+            let mut resp = hold_channels(resp, &subscribed);
+
+            // have Fanout come back for whatever was published before the
+            // hold existed, appending our answer to the stream
+            if !catching_up
+                && config::setting("test_sse_catch_up").as_deref() == Some("true")
+                && GripFeatures::from_request(&req).contains(GripFeatures::LINK_NEXT)
+            {
+                let mut link = format!("/test/sse/{}?{}=1", name, CATCH_UP_PARAM);
+                if let Some(token) = req.get_query_parameter(tokens::QUERY_PARAM) {
+                    link.push_str(&format!("&{}={}", tokens::QUERY_PARAM, token));
+                }
+                resp = resp.link_next(&link, None);
+            }
+
+            // a reconnecting EventSource tells us the last event it saw: we
+            // replay what it missed from the history, and Fanout only needs
+            // to deliver what came after that
+            let mut replay = Vec::new();
+            if let Some(last_id) = req.get_header_str("Last-Event-ID") {
+                let missed = history::since(chan, last_id);
+                for entry in &missed {
+                    if let Some(content) = &entry.http_stream {
+                        replay.extend(content.as_bytes());
+                    }
+                }
+                let resume_after = missed.last().map_or(last_id, |e| e.id.as_str());
+                resp = resp.last_id(chan, resume_after);
+            }
+
+            // the preamble was already sent at the start of the stream
+            if catching_up {
+                return resp.body(replay).build();
+            }
+
+            let mut body = route.sse.preamble().into_bytes();
+            body.extend(replay);
 
-            grip_response("text/event-stream", "stream", chan)
-                .with_header("Grip-Keep-Alive", ":\\n\\n; format=cstring; timeout=20")
-                .with_body(padding)
+            resp.body(body).build()
         }
-        "/test/ws" => handle_test_ws(req, chan),
-        _ => Response::from_status(StatusCode::NOT_FOUND).with_body("{\"error\": \"not found\"}\n"),
+        "/test/longpoll" => {
+            let subscribed = match subscription(&req, &name, route) {
+                Ok((channels, _)) => channels,
+                Err(e) => return AppError::from(e).response(),
+            };
+
+            let timeout = config::setting("test_longpoll_timeout")
+                .and_then(|s| s.parse::<u32>().ok())
+                .unwrap_or(DEFAULT_LONGPOLL_TIMEOUT);
+
+            // answer right away with the next message a returning client
+            // missed, if any
+            let last_id = req
+                .get_header_str("Last-Event-ID")
+                .or_else(|| req.get_query_parameter("last_event_id"));
+            if let Some(missed) = last_id
+                .map(|id| history::since(chan, id))
+                .and_then(|missed| missed.into_iter().find_map(|e| e.http_response))
+            {
+                let mut resp = Response::from_status(missed.code.unwrap_or(200));
+                for (name, value) in &missed.headers {
+                    resp.append_header(name.as_str(), value.as_str());
+                }
+                return resp.with_body(missed.body);
+            }
+
+            let resp = GripResponseBuilder::new()
+                .content_type("text/plain")
+                .hold_response()
+                .timeout(timeout)
+                .max_timeouts(&route.timeouts)
+                .body("No message published before timeout.\n");
+            let mut resp = hold_channels(resp, &subscribed);
+
+            // clients that would rather tell timeouts apart by status than
+            // by body can have them answered with e.g. a 204
+            if let Some(status) = config::setting("test_longpoll_timeout_status")
+                .and_then(|s| s.parse::<u16>().ok())
+                .and_then(|code| StatusCode::from_u16(code).ok())
+            {
+                resp = resp.status(status);
+            }
+
+            resp.build()
+        }
+        "/test/ws" => ws::serve(
+            req,
+            &route.limits,
+            &mut TestWs {
+                name: &name,
+                route,
+                channels: Vec::new(),
+                claims: None,
+            },
+        ),
+        "/test/ws/echo" => ws::serve(req, &route.limits, &mut EchoWs { route }),
+        "/test/ws/broadcast" => ws::serve(
+            req,
+            &route.limits,
+            &mut BroadcastWs {
+                name: &name,
+                route,
+                channels: Vec::new(),
+                claims: None,
+                batch: Publisher::for_route(route).map(|p| p.batch()),
+            },
+        ),
+        "/test/ws/detached" => ws::serve(
+            req,
+            &route.limits,
+            &mut DetachedWs {
+                name: &name,
+                route,
+                channels: Vec::new(),
+            },
+        ),
+        "/test/jsonrpc" => ws::serve(req, &route.limits, &mut test_jsonrpc(route)),
+        _ => AppError::RoutingError(format!("No test endpoint at {}.", endpoint)).response(),
     }
 }
 
-const EVENTSOURCE_MIN_JS: &str = include_str!("../static/eventsource.min.js");
-const FAYE_BROWSER_1_1_2_FANOUT1_MIN_JS: &str =
-    include_str!("../static/faye-browser-1.1.2-fanout1-min.js");
-const FAYE_BROWSER_1_1_2_FANOUT1_MIN_JS_MAP: &str =
-    include_str!("../static/faye-browser-1.1.2-fanout1-min.js.map");
-const FAYE_BROWSER_1_1_2_FANOUT1_JS: &str = include_str!("../static/faye-browser-1.1.2-fanout1.js");
-const FAYE_BROWSER_MIN_JS: &str = include_str!("../static/faye-browser-min.js");
-const FAYE_BROWSER_MIN_JS_MAP: &str = include_str!("../static/faye-browser-min.js.map");
-const FAYE_BROWSER_JS: &str = include_str!("../static/faye-browser.js");
-const JSON2_JS: &str = include_str!("../static/json2.js");
-const RECONNECTING_EVENTSOURCE_JS: &str = include_str!("../static/reconnecting-eventsource.js");
+/// Holds an SSE stream subscribed to each of the test channels listed in
+/// the `channels` query parameter, whose messages arrive as events named
+/// after their channel.
+fn handle_sse_multi(req: &Request, route: &Route) -> Response {
+    let names: Vec<&str> = req
+        .get_query_parameter("channels")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .collect();
+    if names.is_empty() || names.len() > MAX_MULTI_CHANNELS {
+        return AppError::ParseError(format!("Give between 1 and {MAX_MULTI_CHANNELS} channels."))
+            .response();
+    }
 
-fn handle_static(req: Request) -> Response {
-    let fname = req.get_url().path_segments().unwrap().last().unwrap();
+    let mut subscribed = Vec::new();
+    for name in names {
+        if let Err(e) = channels::check(name) {
+            return AppError::from(e).response();
+        }
+        if let Err(e) = tokens::authorize(req, name) {
+            return AppError::from(e).response();
+        }
+        let chan = route.channel(name) + MULTI_SUFFIX;
+        if !subscribed.contains(&chan) {
+            subscribed.push(chan);
+        }
+    }
 
-    let mut files = HashMap::new();
-    files.insert("eventsource.min.js", EVENTSOURCE_MIN_JS);
-    files.insert(
-        "faye-browser-1.1.2-fanout1-min.js",
-        FAYE_BROWSER_1_1_2_FANOUT1_MIN_JS,
-    );
-    files.insert(
-        "faye-browser-1.1.2-fanout1-min.js.map",
-        FAYE_BROWSER_1_1_2_FANOUT1_MIN_JS_MAP,
-    );
-    files.insert(
-        "faye-browser-1.1.2-fanout1.js",
-        FAYE_BROWSER_1_1_2_FANOUT1_JS,
-    );
-    files.insert("faye-browser-min.js", FAYE_BROWSER_MIN_JS);
-    files.insert("faye-browser-min.js.map", FAYE_BROWSER_MIN_JS_MAP);
-    files.insert("faye-browser.js", FAYE_BROWSER_JS);
-    files.insert("json2.js", JSON2_JS);
-    files.insert("reconnecting-eventsource.js", RECONNECTING_EVENTSOURCE_JS);
-
-    let data = match files.get(fname) {
-        Some(s) => s,
-        None => return Response::from_status(StatusCode::NOT_FOUND),
+    GripResponseBuilder::new()
+        .content_type("text/event-stream")
+        .hold_stream()
+        .keep_alive(KeepAlive::new(":\n\n"))
+        .max_timeouts(&route.timeouts)
+        .channels(&subscribed)
+        .body(route.sse.preamble())
+        .build()
+}
+
+/// Returns the item delivering a message published to the test channel
+/// `name` to `/test/sse/multi` streams, as an event named after it, with
+/// the id of `item`, its delivery to the channel itself.
+fn multi_item(route: &Route, name: &str, item: &Item, body: &str) -> Item {
+    let mut event = SseEvent::new(body).with_event(name);
+    if let Some(id) = &item.id {
+        event = event.with_id(id.as_str());
+    }
+    Item::new(route.channel(name) + MULTI_SUFFIX).http_stream(event.encode())
+}
+
+/// Returns the JSON-RPC methods of `/test/jsonrpc`: `echo`, and
+/// `subscribe`/`unsubscribe` taking `{"channel": name}`, so clients can
+/// receive notifications published to test channels.
+fn test_jsonrpc(route: &Route) -> JsonRpc<'_> {
+    fn channel_param(route: &Route, params: &serde_json::Value) -> Result<String, RpcError> {
+        let name = params
+            .get("channel")
+            .and_then(|c| c.as_str())
+            .ok_or_else(|| RpcError::invalid_params("channel is required"))?;
+        channels::check(name).map_err(RpcError::invalid_params)?;
+        // connections here present no token proving who they are
+        if channels::private_owner(name).is_some() {
+            return Err(RpcError::invalid_params(
+                "private channels can't be subscribed",
+            ));
+        }
+        Ok(route.channel(name))
+    }
+
+    JsonRpc::new()
+        .method("echo", |_, params| Ok(params))
+        .method("subscribe", move |ctx, params| {
+            ctx.subscribe(&[channel_param(route, &params)?]);
+            Ok(serde_json::Value::Bool(true))
+        })
+        .method("unsubscribe", move |ctx, params| {
+            ctx.unsubscribe(&[channel_param(route, &params)?]);
+            Ok(serde_json::Value::Bool(true))
+        })
+}
+
+/// Returns the item delivering a message to every kind of subscriber of a
+/// channel, under a new id.
+fn message_item(chan: &str, body: &str) -> Item {
+    // ids let reconnecting clients be replayed what they missed
+    let id = history::new_id();
+
+    let mut item = Item::new(chan)
+        .with_id(id.as_str())
+        .http_stream(SseEvent::new(body).with_id(id.as_str()).encode())
+        .http_response(body)
+        .ws_message(body);
+    if let Some(resp) = &mut item.formats.http_response {
+        resp.headers.push((EVENT_ID_HEADER.to_string(), id));
+    }
+    item
+}
+
+fn handle_publish(mut req: Request, route: &Route) -> Result<Response, AppError> {
+    if let Err(limited) = ratelimit::check(&req, &route.rate_limit, Scope::Publish) {
+        log_info!("refusing publish: {limited}");
+        return Ok(limited.response());
+    }
+
+    if !auth::check_api_key(&req, auth::PUBLISH_API_KEY_SECRET) {
+        return Err(AppError::unauthorized("Invalid API key."));
+    }
+
+    let name = match req.get_path().strip_prefix("/publish/") {
+        Some(c) if !c.is_empty() && !c.contains('/') => c.to_string(),
+        _ => return Err(AppError::RoutingError("No channel to publish to.".into())),
     };
+    let chan = route.channel(&name);
+    let deliver_at = schedule::deliver_at(&req, unix_now_millis())?;
+
+    let publisher = Publisher::for_route(route)
+        .ok_or_else(|| AppError::GripError("Publishing is not configured.".into()))?;
+
+    let is_json = req
+        .get_header_str("Content-Type")
+        .map(|ct| ct.starts_with("application/json"))
+        .unwrap_or(false);
+
+    let body = read_body(&mut req, route.limits.max_body)?;
+    // retries of a publish already made get the response it got
+    let idempotency_key = idempotency::Key::from_request(&req, &chan, &body)?;
+    if let Some(resp) = idempotency_key
+        .as_ref()
+        .map(|k| k.replay())
+        .transpose()?
+        .flatten()
+    {
+        log_info!("replaying publish to {chan}");
+        return Ok(resp);
+    }
 
-    let ctype = if fname.ends_with(".js") {
-        "application/javascript"
-    } else if fname.ends_with(".map") {
-        "application/octet-stream"
+    let body = String::from_utf8(body)
+        .map_err(|_| AppError::ParseError("Body is not valid UTF-8.".into()))?;
+
+    if is_json {
+        serde_json::from_str::<serde_json::Value>(&body)
+            .map_err(|e| AppError::ParseError(format!("Invalid JSON body: {e}")))?;
+    }
+
+    let item = message_item(&chan, &body);
+    let multi = multi_item(route, &name, &item, &body);
+    let failed = |e| AppError::UpstreamError(format!("Publish to {chan} failed: {e}"));
+
+    let recorded = if let Some(at) = deliver_at {
+        if req.get_query_parameter("ack").is_some() {
+            return Err(AppError::ParseError(
+                "Scheduled publishes can't be acknowledged.".into(),
+            ));
+        }
+        // transformed now, as they are delivered without the route
+        let items = [item, multi]
+            .into_iter()
+            .filter_map(|item| publisher.transform(item))
+            .collect();
+        let id = schedule::schedule(items, at)?;
+        log_info!("scheduled publish {id} to {chan} at {at}");
+        Recorded::new(StatusCode::ACCEPTED, "Scheduled.\n").with_header(SCHEDULED_ID_HEADER, id)
+    } else if req.get_query_parameter("ack").is_some() {
+        // WebSocket subscribers are asked to acknowledge the message, with
+        // confirmations published to the channel named in the response
+        let id = publisher.publish_with_ack(item).map_err(failed)?;
+        publisher.publish(multi).map_err(failed)?;
+        Recorded::new(StatusCode::OK, "Published.\n")
+            .with_header(ACK_ID_HEADER, id)
+            .with_header(ACK_CHANNEL_HEADER, ack::confirmation_channel(&chan))
     } else {
-        "text/plain"
+        publisher.publish_items(&[item, multi]).map_err(failed)?;
+        Recorded::new(StatusCode::OK, "Published.\n")
     };
 
-    Response::from_status(StatusCode::OK)
-        .with_header("Content-Type", ctype.as_bytes())
-        .with_body(data.as_bytes())
+    if let Some(key) = &idempotency_key {
+        key.record(&recorded);
+    }
+
+    // publishes scheduled earlier go out with the traffic
+    if let Some(publisher) = Publisher::from_config() {
+        schedule::deliver_due(&publisher);
+    }
+
+    Ok(recorded.response())
 }
 
-fn is_tls(req: &Request) -> bool {
-    req.get_url().scheme().eq_ignore_ascii_case("https")
+fn handle_presence(req: Request, route: &Route) -> Result<Response, AppError> {
+    let name = req
+        .get_path()
+        .strip_prefix("/presence/")
+        .unwrap_or_default();
+    channels::validate_name(name)?;
+
+    let members = presence::members(&route.channel(name));
+    let body = serde_json::json!({
+        "channel": name,
+        "count": members.len(),
+        "connections": members,
+    });
+
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(format!("{}\n", body)))
 }
 
-fn main() -> Result<(), Error> {
-    // Log service version
-    println!(
-        "FASTLY_SERVICE_VERSION: {}",
-        std::env::var("FASTLY_SERVICE_VERSION").unwrap_or_else(|_| String::new())
-    );
+fn handle_healthz() -> Response {
+    let report = health::report();
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
 
-    let mut req = Request::from_client().with_pass(true);
+    Response::from_status(status)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(serde_json::to_string(&report).expect("reports always serialize") + "\n")
+}
 
-    let host = match req.get_url().host_str() {
-        Some(s) => s.to_string(),
-        None => {
-            Response::from_status(StatusCode::NOT_FOUND)
-                .with_body("Unknown host\n")
-                .send_to_client();
-            return Ok(());
-        }
+fn handle_static(req: Request) -> Response {
+    let path = req.get_path();
+    let asset_path = dispatch::STATIC_PREFIXES
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .unwrap_or_default();
+    serve_asset(&req, asset_path)
+}
+
+/// Serves the static asset at `path`, relative to the `static/` directory.
+fn serve_asset(req: &Request, path: &str) -> Response {
+    let asset = match static_asset(path) {
+        Some(asset) => asset,
+        None => return AppError::RoutingError(format!("No asset at {}.", path)).response(),
     };
 
-    let path = req.get_path().to_string();
+    // serve a pre-compressed variant if the client accepts one
+    let encoding = req
+        .get_header_str("Accept-Encoding")
+        .and_then(preferred_encoding);
+    let (body, encoding) = match encoding {
+        Some("br") => (asset.brotli, Some("br")),
+        Some("gzip") => (asset.gzip, Some("gzip")),
+        _ => (asset.data, None),
+    };
+
+    let etag = static_etag(asset.hash, encoding);
+
+    let mut resp = Response::new()
+        .with_header("ETag", etag.as_str())
+        .with_header("Cache-Control", format!("public, max-age={STATIC_MAX_AGE}"))
+        .with_header("Vary", "Accept-Encoding");
 
-    if let Some(addr) = req.get_client_ip_addr() {
-        req.set_header("X-Forwarded-For", addr.to_string());
+    if req
+        .get_header_str("If-None-Match")
+        .map(|inm| etag_matches(inm, &etag))
+        .unwrap_or(false)
+    {
+        return resp.with_status(StatusCode::NOT_MODIFIED);
     }
 
-    if is_tls(&req) {
-        req.set_header("X-Forwarded-Proto", "https");
+    if let Some(enc) = encoding {
+        resp.set_header("Content-Encoding", enc);
     }
 
-    if host.ends_with(".fanoutcdn.com") {
-        if path.starts_with("/test/static/") || path.starts_with("/bayeux/static/") {
-            handle_static(req).send_to_client();
-            return Ok(());
-        }
+    resp.with_status(StatusCode::OK)
+        .with_header("Content-Type", asset.content_type)
+        .with_body(body)
+}
 
-        if path == "/test" || path.starts_with("/test/") {
-            if req.get_header_str("Grip-Sig").is_some() {
-                // request is from fanout
-                handle_test(req, "test").send_to_client();
-            } else {
-                // not from fanout, hand it off to fanout to manage
-                let backend = format!("self_{}", host);
-                println!("handoff to backend {backend}");
-                req.handoff_fanout(&backend).map_err(|e| {
-                    println!("Some error happened: {e:?}");
-                    e
-                })?;
-            }
+/// A file from `static/`, embedded at build time along with its
+/// pre-compressed variants.
+struct StaticAsset {
+    /// Path relative to `static/`.
+    path: &'static str,
+    content_type: &'static str,
+    /// Hex digest of the content, for ETags.
+    hash: &'static str,
+    data: &'static [u8],
+    gzip: &'static [u8],
+    brotli: &'static [u8],
+}
 
-            return Ok(());
-        }
+include!(concat!(env!("OUT_DIR"), "/static_assets.rs"));
+
+/// Returns a static asset by its path relative to `static/`.
+fn static_asset(path: &str) -> Option<&'static StaticAsset> {
+    STATIC_ASSETS
+        .binary_search_by(|a| a.path.cmp(path))
+        .ok()
+        .map(|i| &STATIC_ASSETS[i])
+}
+
+/// Returns the best pre-compressed encoding allowed by an `Accept-Encoding`
+/// header value, preferring Brotli over gzip.
+fn preferred_encoding(accept: &str) -> Option<&'static str> {
+    let accepts = |name: &str| {
+        accept.split(',').any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let coding = parts.next().unwrap_or_default();
+            let q = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (coding.eq_ignore_ascii_case(name) || coding == "*") && q > 0.0
+        })
+    };
+
+    if accepts("br") {
+        Some("br")
+    } else if accepts("gzip") {
+        Some("gzip")
+    } else {
+        None
+    }
+}
+
+/// Seconds browsers and caches may reuse static assets without revalidating.
+const STATIC_MAX_AGE: u32 = 86400;
+
+/// Returns a strong ETag derived from the content hash of an asset.
+/// Encoded variants get distinct tags, since their bodies differ.
+fn static_etag(hex: &str, encoding: Option<&str>) -> String {
+    match encoding {
+        Some(enc) => format!("\"{}-{}\"", hex, enc),
+        None => format!("\"{}\"", hex),
+    }
+}
+
+/// Returns whether an `If-None-Match` header value matches `etag`.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|t| t.trim())
+        .any(|t| t == "*" || t.strip_prefix("W/").unwrap_or(t) == etag)
+}
+
+fn is_tls(req: &Request) -> bool {
+    req.get_url().scheme().eq_ignore_ascii_case("https")
+}
 
-        if path == "/bayeux" || path.starts_with("/bayeux/") {
-            return Ok(req.handoff_fanout("bayeux-handler")?);
+/// The app's endpoints, served for the [`Router`].
+struct App;
+
+impl Handlers for App {
+    fn serve(&self, endpoint: Endpoint, req: Request, route: &Route) -> Response {
+        let method = req.get_method().clone();
+        let origin = req.get_header_str("Origin").map(str::to_string);
+        let origin = origin.as_deref();
+
+        match endpoint {
+            Endpoint::Healthz => methods::finish(&method, handle_healthz()),
+            Endpoint::Metrics => {
+                let resp = Response::from_status(StatusCode::OK)
+                    .with_header("Content-Type", metrics::CONTENT_TYPE)
+                    .with_header("Cache-Control", "no-store")
+                    .with_body(metrics::render());
+                methods::finish(&method, resp)
+            }
+            Endpoint::Static => methods::finish(&method, cors::apply(origin, handle_static(req))),
+            Endpoint::Test => {
+                methods::finish(&method, cors::apply(origin, handle_test(req, route)))
+            }
+            Endpoint::Publish => {
+                let resp = handle_publish(req, route).unwrap_or_else(Response::from);
+                cors::apply(origin, resp)
+            }
+            Endpoint::Admin => {
+                let resp = admin::handle(req, route).unwrap_or_else(Response::from);
+                methods::finish(&method, resp)
+            }
+            Endpoint::Hooks => hooks::handle(req, route).unwrap_or_else(Response::from),
+            Endpoint::Presence => {
+                let resp = handle_presence(req, route).unwrap_or_else(Response::from);
+                methods::finish(&method, cors::apply(origin, resp))
+            }
+            Endpoint::ChatPage => methods::finish(&method, serve_asset(&req, "chat.html")),
+            Endpoint::ChatWs => {
+                let room = chat::parse_path(req.get_path())
+                    .map(|(room, _)| room.to_string())
+                    .unwrap_or_default();
+                chat::handle_ws(req, &room, route)
+            }
+            Endpoint::Demo => {
+                let fname = match req
+                    .get_path()
+                    .trim_start_matches("/demo")
+                    .trim_start_matches('/')
+                {
+                    "" => "index.html",
+                    fname => fname,
+                }
+                .to_string();
+                methods::finish(&method, serve_asset(&req, &fname))
+            }
+            Endpoint::Graphql => graphql_ws::handle(req, route),
+            Endpoint::Mqtt => mqtt::handle(req, route),
+            Endpoint::Stomp => stomp::handle(req, route),
+            Endpoint::SocketIo => cors::apply(origin, socketio::handle(req, route)),
+            Endpoint::SockJs => cors::apply(origin, sockjs::handle(req, route)),
+            Endpoint::Bayeux => cors::apply(origin, bayeux::handle(req, route)),
         }
     }
+}
 
-    let backend = {
-        let backend_prefix = if is_tls(&req) {
-            "https_backend_"
-        } else {
-            "http_backend_"
-        };
+/// Sends the response to the client, tagged with the request id.
+fn send(resp: Response) {
+    request_id::tag(resp).send_to_client();
+}
 
-        format!("{}{}", backend_prefix, host)
-    };
+fn main() -> Result<(), Error> {
+    let result = serve();
+    metrics::flush();
+    result
+}
+
+fn serve() -> Result<(), Error> {
+    logging::init();
+    logging::set_context(
+        "service_version",
+        std::env::var("FASTLY_SERVICE_VERSION").unwrap_or_else(|_| String::new()),
+    );
+    logging::set_context(
+        "fastly_trace_id",
+        std::env::var("FASTLY_TRACE_ID").unwrap_or_else(|_| String::new()),
+    );
 
-    println!("handoff to backend {backend}");
-    req.handoff_fanout(backend.as_str()).map_err(|e| {
-        println!("Some error happened: {e:?}");
-        e
-    })?;
+    let mut req = Request::from_client().with_pass(true);
+    request_id::init(&mut req);
+    trace::propagate(&mut req);
+
+    if let Some(host) = req.get_url().host_str().map(str::to_string) {
+        let tls = is_tls(&req);
+        logging::set_context("host", host.as_str());
+        logging::set_context("path", req.get_path());
+        forwarded::apply(&mut req, &host, tls);
+    }
+
+    match Router::new(App).handle(req) {
+        Outcome::Respond(resp) => send(resp),
+        Outcome::Handoff {
+            req,
+            backend,
+            fallback,
+        } => {
+            logging::set_context("backend", backend.as_str());
+            handoff::handoff(req, &backend, fallback);
+        }
+        Outcome::Forward { req, backend } => {
+            logging::set_context("backend", backend.as_str());
+            log_info!("sending to backend {backend}");
+            let resp = req.send(backend.as_str()).map_err(|e| {
+                log_error!("request to {backend} failed: {e}");
+                e
+            })?;
+            send(resp);
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,43 @@
+//! Maintenance mode, for origin maintenance windows.
+//!
+//! While the `maintenance_mode` setting is `true`, new connections are
+//! refused with a `503` and a `Retry-After` header, and WebSocket
+//! connections opening are closed with code `1013` (try again later).
+//! Connections already held by Fanout are left alone.
+
+use fastly::Response;
+
+use crate::config;
+use crate::error::AppError;
+
+/// Setting turning maintenance mode on, with `true`.
+pub const MODE_SETTING: &str = "maintenance_mode";
+
+/// Setting holding the seconds clients are told to wait before trying again.
+pub const RETRY_AFTER_SETTING: &str = "maintenance_retry_after";
+
+/// Seconds clients are told to wait if `maintenance_retry_after` is unset.
+pub const DEFAULT_RETRY_AFTER: u32 = 300;
+
+/// WebSocket close code of connections opening during maintenance.
+pub const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+/// Returns whether maintenance mode is on.
+pub fn enabled() -> bool {
+    config::setting(MODE_SETTING).as_deref() == Some("true")
+}
+
+/// Returns the seconds clients are told to wait before trying again.
+pub fn retry_after() -> u32 {
+    config::setting(RETRY_AFTER_SETTING)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+/// Returns the `503` response refusing new connections.
+pub fn response() -> Response {
+    AppError::UnavailableError("Down for maintenance, try again later.".into())
+        .response()
+        .with_header("Retry-After", retry_after().to_string())
+        .with_header("Cache-Control", "no-store")
+}
@@ -0,0 +1,80 @@
+//! Request method handling shared by the app's endpoints.
+//!
+//! Each endpoint accepts a set of methods. `OPTIONS` requests are answered
+//! with the set in an `Allow` header, and requests with other methods get a
+//! `405` carrying the same header. Read-only endpoints accept `HEAD`, which
+//! is answered with the headers the `GET` response would have.
+
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+
+use crate::error::AppError;
+
+/// Methods of read-only endpoints.
+pub const GET_HEAD: &[Method] = &[Method::GET, Method::HEAD];
+
+/// Methods of endpoints accepting WebSocket connections: `GET` for clients,
+/// `POST` for the WebSocket-over-HTTP requests Fanout makes.
+pub const GET_POST: &[Method] = &[Method::GET, Method::POST];
+
+/// Methods of endpoints receiving data.
+pub const POST: &[Method] = &[Method::POST];
+
+/// Returns the value of the `Allow` header for a set of methods.
+pub fn allow_value(allowed: &[Method]) -> String {
+    allowed
+        .iter()
+        .map(Method::as_str)
+        .chain(std::iter::once("OPTIONS"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns the response to a request whose method isn't handled by the
+/// endpoint itself: `OPTIONS`, or one the endpoint doesn't allow.
+pub fn check(req: &Request, allowed: &[Method]) -> Option<Response> {
+    let method = req.get_method();
+
+    if method == Method::OPTIONS {
+        return Some(
+            Response::from_status(StatusCode::NO_CONTENT)
+                .with_header("Allow", allow_value(allowed)),
+        );
+    }
+
+    if allowed.contains(method) {
+        return None;
+    }
+
+    Some(
+        AppError::MethodError(format!("Method {} not allowed.", method))
+            .response()
+            .with_header("Allow", allow_value(allowed)),
+    )
+}
+
+/// Turns the response to a `GET` into the response to a `HEAD`, dropping
+/// the body and any GRIP instructions, so Fanout doesn't hold the request.
+pub fn head_response(mut resp: Response) -> Response {
+    let grip_headers: Vec<String> = resp
+        .get_header_names_str()
+        .into_iter()
+        .filter(|name| name.to_ascii_lowercase().starts_with("grip-"))
+        .map(str::to_string)
+        .collect();
+    for name in grip_headers {
+        resp.remove_header(name.as_str());
+    }
+
+    resp.take_body();
+    resp
+}
+
+/// Applies [`head_response`] if `req` is a `HEAD` request.
+pub fn finish(method: &Method, resp: Response) -> Response {
+    if method == Method::HEAD {
+        head_response(resp)
+    } else {
+        resp
+    }
+}
@@ -0,0 +1,108 @@
+//! Traffic counters in Prometheus text format.
+//!
+//! Counters are kept per instance, so `/metrics` shows what the instance
+//! serving the scrape has seen. Since Compute instances are short-lived,
+//! the counters of each request can also be written to a Fastly log
+//! endpoint named by the `metrics_endpoint` setting, as one JSON line per
+//! request, for summing up in the logging provider.
+
+use fastly::log::Endpoint;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+
+use crate::config;
+use crate::log_warn;
+
+/// Setting naming the Fastly log endpoint counters are pushed to.
+pub const ENDPOINT_SETTING: &str = "metrics_endpoint";
+
+/// Content type of the Prometheus text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+type Labels = Vec<(&'static str, String)>;
+
+thread_local! {
+    static COUNTERS: RefCell<BTreeMap<(&'static str, Labels), u64>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Adds `n` to a counter.
+pub fn add(name: &'static str, labels: &[(&'static str, &str)], n: u64) {
+    let labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+    COUNTERS.with(|c| *c.borrow_mut().entry((name, labels)).or_default() += n);
+}
+
+/// Increments a counter.
+pub fn incr(name: &'static str, labels: &[(&'static str, &str)]) {
+    add(name, labels, 1)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders all counters in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let mut last_name = "";
+
+    COUNTERS.with(|c| {
+        for ((name, labels), value) in c.borrow().iter() {
+            if *name != last_name {
+                let _ = writeln!(out, "# TYPE {} counter", name);
+                last_name = name;
+            }
+
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            if labels.is_empty() {
+                let _ = writeln!(out, "{} {}", name, value);
+            } else {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+            }
+        }
+    });
+
+    out
+}
+
+/// Writes the counters to the metrics log endpoint, if one is configured,
+/// and resets them so the next request starts from zero.
+pub fn flush() {
+    let name = match config::setting(ENDPOINT_SETTING) {
+        Some(name) => name,
+        None => return,
+    };
+
+    let counters = COUNTERS.with(|c| std::mem::take(&mut *c.borrow_mut()));
+    if counters.is_empty() {
+        return;
+    }
+
+    let metrics: Vec<Value> = counters
+        .into_iter()
+        .map(|((name, labels), value)| {
+            let labels: Map<String, Value> = labels
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.into()))
+                .collect();
+            serde_json::json!({ "name": name, "labels": labels, "value": value })
+        })
+        .collect();
+
+    let line = serde_json::json!({ "metrics": metrics });
+
+    match Endpoint::try_from_name(&name) {
+        Ok(mut endpoint) => {
+            let _ = writeln!(endpoint, "{}", line);
+        }
+        Err(e) => log_warn!("invalid metrics endpoint {name}: {e}"),
+    }
+}
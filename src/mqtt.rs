@@ -0,0 +1,332 @@
+//! MQTT over WebSocket.
+//!
+//! A minimal MQTT 3.1.1 broker facade, so MQTT-over-WebSocket clients can
+//! connect to `/mqtt`. Topics map onto GRIP channels (see
+//! [`grip_channel`]): SUBSCRIBE subscribes the connection to the topics'
+//! channels, and PUBLISH is sent on through the configured [`Publisher`] as
+//! a PUBLISH packet for the subscribers of the topic.
+//!
+//! Messages are delivered at QoS 0 whatever the client asks for. Wildcard
+//! subscriptions, retained messages, wills and persistent sessions aren't
+//! supported.
+
+use fastly::{Request, Response};
+use std::fmt;
+
+use crate::publish::{Item, Publisher};
+use crate::router::Route;
+use crate::ws::{self, WsContext, WsHandler};
+use crate::{log_debug, log_error, log_warn};
+
+/// The subprotocol implemented by this module.
+pub const PROTOCOL: &str = "mqtt";
+
+/// Prefix of the GRIP channels topics are mapped onto.
+pub const GRIP_CHANNEL_PREFIX: &str = "mqtt/";
+
+/// Protocol level of MQTT 3.1.1.
+const PROTOCOL_LEVEL: u8 = 4;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// CONNACK return code refusing an unsupported protocol level.
+const CONNACK_BAD_PROTOCOL: u8 = 1;
+
+/// SUBACK return code refusing a subscription.
+const SUBACK_FAILURE: u8 = 0x80;
+
+/// Meta value set once the client has sent CONNECT.
+const CONNECTED_META: &str = "mqtt-connected";
+
+/// Returns the GRIP channel a topic is mapped onto.
+pub fn grip_channel(topic: &str) -> String {
+    format!("{}{}", GRIP_CHANNEL_PREFIX, topic)
+}
+
+/// Error parsing an MQTT packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    /// The data ends before the packet does.
+    Truncated,
+    /// The remaining length takes more than four bytes.
+    BadLength,
+    /// A string isn't valid UTF-8.
+    BadString,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Truncated => write!(f, "packet is truncated"),
+            PacketError::BadLength => write!(f, "malformed remaining length"),
+            PacketError::BadString => write!(f, "string is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+/// A control packet: its type, the flags of the fixed header and the rest
+/// of the packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Packet<'a> {
+    kind: u8,
+    flags: u8,
+    body: &'a [u8],
+}
+
+/// Splits a WebSocket message into the control packets it carries.
+fn parse_packets(mut data: &[u8]) -> Result<Vec<Packet<'_>>, PacketError> {
+    let mut packets = Vec::new();
+
+    while !data.is_empty() {
+        let first = data[0];
+
+        let mut len = 0usize;
+        let mut pos = 1;
+        loop {
+            if pos > 4 {
+                return Err(PacketError::BadLength);
+            }
+            let byte = *data.get(pos).ok_or(PacketError::Truncated)?;
+            len |= usize::from(byte & 0x7f) << (7 * (pos - 1));
+            pos += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let body = data.get(pos..pos + len).ok_or(PacketError::Truncated)?;
+        packets.push(Packet {
+            kind: first >> 4,
+            flags: first & 0x0f,
+            body,
+        });
+        data = &data[pos + len..];
+    }
+
+    Ok(packets)
+}
+
+/// Reads the fields of a packet body.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, PacketError> {
+        let (&b, rest) = self.data.split_first().ok_or(PacketError::Truncated)?;
+        self.data = rest;
+        Ok(b)
+    }
+
+    fn u16(&mut self) -> Result<u16, PacketError> {
+        Ok(u16::from(self.u8()?) << 8 | u16::from(self.u8()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], PacketError> {
+        let len = usize::from(self.u16()?);
+        if self.data.len() < len {
+            return Err(PacketError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<&'a str, PacketError> {
+        std::str::from_utf8(self.bytes()?).map_err(|_| PacketError::BadString)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+}
+
+fn encode_packet(kind: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind << 4 | flags];
+
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+
+    out.extend(body);
+    out
+}
+
+fn encode_string(out: &mut Vec<u8>, s: &str) {
+    out.extend((s.len() as u16).to_be_bytes());
+    out.extend(s.as_bytes());
+}
+
+/// Returns the QoS 0 PUBLISH packet delivering `payload` on `topic`.
+pub fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+    encode_string(&mut body, topic);
+    body.extend(payload);
+    encode_packet(PUBLISH, 0, &body)
+}
+
+/// Returns the item delivering `payload` to the subscribers of `topic`.
+pub fn publish_item(route: &Route, topic: &str, payload: &[u8]) -> Item {
+    Item::new(route.channel(&grip_channel(topic))).ws_binary(&publish_packet(topic, payload))
+}
+
+fn is_valid_topic_filter(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+/// Serves MQTT over a WebSocket connection.
+struct MqttWs<'a> {
+    route: &'a Route,
+}
+
+impl MqttWs<'_> {
+    fn handle_packet(&self, ctx: &mut WsContext, packet: &Packet) -> Result<(), PacketError> {
+        let mut r = Reader { data: packet.body };
+
+        if packet.kind != CONNECT && ctx.meta(CONNECTED_META).is_none() {
+            log_warn!("mqtt packet type {} before CONNECT", packet.kind);
+            ctx.out.write_close(1002);
+            return Ok(());
+        }
+
+        match packet.kind {
+            CONNECT => {
+                let protocol = r.string()?;
+                let level = r.u8()?;
+                if protocol != "MQTT" || level != PROTOCOL_LEVEL {
+                    ctx.out
+                        .write_binary(&encode_packet(CONNACK, 0, &[0, CONNACK_BAD_PROTOCOL]));
+                    ctx.out.write_close(1002);
+                    return Ok(());
+                }
+                let _flags = r.u8()?;
+                let _keep_alive = r.u16()?;
+                let client_id = r.string()?;
+                log_debug!("mqtt client {client_id:?} connected");
+
+                ctx.set_meta(CONNECTED_META, "1");
+                ctx.out.write_binary(&encode_packet(CONNACK, 0, &[0, 0]));
+            }
+            PUBLISH => {
+                let qos = (packet.flags >> 1) & 0x03;
+                let topic = r.string()?;
+                let packet_id = if qos > 0 { Some(r.u16()?) } else { None };
+                let payload = r.rest();
+
+                match Publisher::for_route(self.route) {
+                    Some(publisher) => {
+                        if let Err(e) = publisher.publish(publish_item(self.route, topic, payload))
+                        {
+                            log_error!("failed to publish mqtt message to {topic}: {e}");
+                        }
+                    }
+                    None => log_warn!("dropping mqtt message, publishing is not configured"),
+                }
+
+                let ack = match (qos, packet_id) {
+                    (1, Some(id)) => Some((PUBACK, id)),
+                    (2, Some(id)) => Some((PUBREC, id)),
+                    _ => None,
+                };
+                if let Some((kind, id)) = ack {
+                    ctx.out
+                        .write_binary(&encode_packet(kind, 0, &id.to_be_bytes()));
+                }
+            }
+            PUBREL => {
+                let id = r.u16()?;
+                ctx.out
+                    .write_binary(&encode_packet(PUBCOMP, 0, &id.to_be_bytes()));
+            }
+            SUBSCRIBE => {
+                let id = r.u16()?;
+                let mut body = id.to_be_bytes().to_vec();
+                let mut channels = Vec::new();
+                while !r.data.is_empty() {
+                    let topic = r.string()?;
+                    let _qos = r.u8()?;
+                    if is_valid_topic_filter(topic) {
+                        channels.push(self.route.channel(&grip_channel(topic)));
+                        body.push(0);
+                    } else {
+                        body.push(SUBACK_FAILURE);
+                    }
+                }
+                ctx.subscribe(&channels);
+                ctx.out.write_binary(&encode_packet(SUBACK, 0, &body));
+            }
+            UNSUBSCRIBE => {
+                let id = r.u16()?;
+                let mut channels = Vec::new();
+                while !r.data.is_empty() {
+                    channels.push(self.route.channel(&grip_channel(r.string()?)));
+                }
+                ctx.unsubscribe(&channels);
+                ctx.out
+                    .write_binary(&encode_packet(UNSUBACK, 0, &id.to_be_bytes()));
+            }
+            PINGREQ => {
+                ctx.out.write_binary(&encode_packet(PINGRESP, 0, &[]));
+            }
+            DISCONNECT => {
+                ctx.out.write_close(1000);
+            }
+            kind => log_debug!("ignoring mqtt packet type {kind}"),
+        }
+
+        Ok(())
+    }
+}
+
+impl WsHandler for MqttWs<'_> {
+    fn protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn on_binary(&mut self, ctx: &mut WsContext, data: Vec<u8>) {
+        let result = parse_packets(&data).and_then(|packets| {
+            packets
+                .iter()
+                .try_for_each(|packet| self.handle_packet(ctx, packet))
+        });
+
+        if let Err(e) = result {
+            log_warn!("closing mqtt connection: {e}");
+            ctx.out.write_close(1002);
+        }
+    }
+
+    fn on_text(&mut self, ctx: &mut WsContext, _text: String) {
+        // MQTT is only ever carried in binary messages
+        ctx.out.write_close(1003);
+    }
+}
+
+/// Handles a WebSocket-over-HTTP MQTT request forwarded by Fanout.
+pub fn handle(req: Request, route: &Route) -> Response {
+    ws::serve(req, &route.limits, &mut MqttWs { route })
+}
@@ -0,0 +1,104 @@
+//! Tracking which connections are subscribed to a channel.
+//!
+//! Each channel's members are kept in the state KV Store as a map from
+//! connection id to the unix time their membership expires. Connections
+//! leave when they close, but one that vanishes without Fanout telling us
+//! would stay forever, so memberships are refreshed whenever the connection
+//! shows signs of life (such as answering a keep-alive ping) and expire
+//! otherwise.
+
+use std::collections::BTreeMap;
+
+use crate::config;
+use crate::grip;
+use crate::log_error;
+
+/// Setting holding the seconds a membership lasts without being refreshed.
+pub const TTL_SETTING: &str = "presence_ttl";
+
+const DEFAULT_TTL: u64 = 60;
+
+type Members = BTreeMap<String, u64>;
+
+fn ttl() -> u64 {
+    config::setting(TTL_SETTING)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_TTL)
+}
+
+fn key(channel: &str) -> String {
+    format!("presence:{}", channel)
+}
+
+fn load(channel: &str) -> Members {
+    config::state_store()
+        .and_then(|store| store.lookup_str(&key(channel)).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn update(channel: &str, f: impl FnOnce(&mut Members)) {
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => return,
+    };
+
+    let now = grip::unix_now();
+    let mut members = load(channel);
+    members.retain(|_, expires| *expires > now);
+    f(&mut members);
+
+    let result = if members.is_empty() {
+        store.delete(&key(channel))
+    } else {
+        store.insert(
+            &key(channel),
+            serde_json::to_string(&members).expect("members always serialize"),
+        )
+    };
+
+    if let Err(e) = result {
+        log_error!("failed to update presence of {channel}: {e}");
+    }
+}
+
+/// Records a connection as subscribed to a channel, or extends its
+/// membership if it already is.
+pub fn join(channel: &str, connection_id: &str) {
+    let expires = grip::unix_now() + ttl();
+    update(channel, |members| {
+        members.insert(connection_id.to_string(), expires);
+    });
+}
+
+/// Extends the membership of a connection.
+pub fn refresh(channel: &str, connection_id: &str) {
+    join(channel, connection_id)
+}
+
+/// Removes a connection from a channel.
+pub fn leave(channel: &str, connection_id: &str) {
+    update(channel, |members| {
+        members.remove(connection_id);
+    });
+}
+
+/// Forgets every connection of a channel.
+pub fn clear(channel: &str) {
+    if let Some(store) = config::state_store() {
+        if let Err(e) = store.delete(&key(channel)) {
+            log_error!("failed to clear presence of {channel}: {e}");
+        }
+    }
+}
+
+/// Returns the ids of the connections subscribed to a channel.
+pub fn members(channel: &str) -> Vec<String> {
+    let now = grip::unix_now();
+    load(channel)
+        .into_iter()
+        .filter(|(_, expires)| *expires > now)
+        .map(|(id, _)| id)
+        .collect()
+}
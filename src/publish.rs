@@ -0,0 +1,806 @@
+//! Publishing messages to Fanout.
+//!
+//! Messages are sent to the Fanout publish endpoint using the EPCP (Extensible
+//! Pubsub Control Protocol) JSON format: a list of items, each naming a
+//! channel and carrying the content to deliver in one or more formats. Each
+//! format is delivered to subscribers holding that kind of connection:
+//!
+//! * `http-stream`: appended to streaming (e.g. SSE) responses.
+//! * `http-response`: sent as the response to long-polling requests.
+//! * `ws-message`: sent as a message on WebSocket connections.
+//!
+//! # Message ids
+//!
+//! Items may carry an `id` and the `prev-id` of the item published before it
+//! on the same channel. Fanout uses them to detect gaps: a subscriber that
+//! has seen `prev-id` gets the item right away, otherwise Fanout may first
+//! fetch what it missed from the origin. For SSE, the `id` should also be
+//! the `id:` field of the event in the `http-stream` content, so that a
+//! reconnecting client's `Last-Event-ID` names a position Fanout knows about
+//! and resumption via `Grip-Last` picks up after it.
+//!
+//! With the `prev_id_chaining` setting on, the publisher numbers the items
+//! of each channel itself, see [`sequence`].
+//!
+//! Items with an id are also recorded in the channel [`history`], from which
+//! the test handlers replay what a reconnecting client missed.
+//!
+//! In local development (the `local-dev` feature), a publisher that only
+//! logs the items is used when no publish endpoint is configured.
+//!
+//! # Retries
+//!
+//! Publish requests that fail transiently, because they couldn't be sent or
+//! the endpoint answered with a retryable status (`429`, `502`, `503` or
+//! `504` by default), are retried following the publisher's
+//! [`RetryPolicy`]: up to `publish_retry_attempts` attempts in all, waiting
+//! a random time up to an exponentially growing delay between them. Each
+//! retry is logged and counted in the `publish_retries_total` metric, and
+//! the last error is returned once the attempts run out. The items are then
+//! kept as [`dead_letter`]s, to be published again later.
+//!
+//! # Batching
+//!
+//! Handlers publishing many items while serving one request, such as a
+//! WebSocket handler answering a burst of client messages, can collect them
+//! in a [`Batch`], which sends them together once it holds
+//! `publish_batch_max` items (100 by default) and when it is flushed or
+//! dropped. Items keep their order, so those for the same channel reach
+//! subscribers as they were published, and a full batch is sent before more
+//! are taken, keeping requests to the endpoint bounded.
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use fastly::http::request::SendError;
+use fastly::http::StatusCode;
+use fastly::Request;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+use crate::ack;
+use crate::activity;
+use crate::config;
+use crate::dead_letter;
+use crate::grip;
+use crate::history;
+use crate::metrics;
+use crate::router::Route;
+use crate::sequence;
+use crate::transform::{self, TransformRule};
+use crate::{log_error, log_info, log_warn};
+
+/// Setting naming the backend that reaches the publish endpoint.
+pub const BACKEND_SETTING: &str = "publish_backend";
+
+/// Setting holding the URL of the publish endpoint, e.g.
+/// `https://api.fastly.com/service/{service_id}/publish/`.
+pub const URL_SETTING: &str = "publish_url";
+
+/// Setting selecting how requests are authenticated: `fastly-key` (the
+/// default), `bearer`, `basic` or `jwt`.
+pub const AUTH_SETTING: &str = "publish_auth";
+
+/// Setting holding the issuer of `jwt` publish tokens.
+pub const JWT_ISS_SETTING: &str = "publish_jwt_iss";
+
+/// Secret holding the publish credential. For `basic` it is `user:password`,
+/// for `jwt` the key tokens are signed with.
+pub const KEY_SECRET: &str = "publish_key";
+
+/// Setting holding the most items a [`Batch`] sends in one request.
+pub const BATCH_MAX_SETTING: &str = "publish_batch_max";
+
+/// Items a [`Batch`] sends in one request when not configured.
+const DEFAULT_BATCH_MAX: usize = 100;
+
+/// Backend and URL of the publisher used in local development when none is
+/// configured, which only logs what it would publish.
+const LOCAL_DEV: &str = "local-dev";
+
+/// Setting holding how many times a publish request is attempted.
+pub const RETRY_ATTEMPTS_SETTING: &str = "publish_retry_attempts";
+
+/// Setting holding the delay before the first retry, in milliseconds.
+pub const RETRY_BACKOFF_SETTING: &str = "publish_retry_backoff_ms";
+
+/// Setting holding the comma-separated statuses that are retried.
+pub const RETRY_STATUSES_SETTING: &str = "publish_retry_statuses";
+
+/// Item meta value naming the user who published it, see
+/// [`Item::with_sender`].
+pub const SENDER_META: &str = "sender";
+
+/// Seconds `jwt` publish tokens are valid for.
+const JWT_LIFETIME: u64 = 600;
+
+/// Content for subscribers holding HTTP streaming connections, or the
+/// `close` action ending their streams.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HttpStream {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+}
+
+/// Content for subscribers holding long-polling requests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HttpResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// Content for subscribers holding WebSocket connections, or the `close`
+/// action closing them with an optional code and reason.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WsMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(
+        rename = "content-bin",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub content_bin: Option<String>,
+}
+
+/// The formats an item is published in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Formats {
+    #[serde(
+        rename = "http-stream",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub http_stream: Option<HttpStream>,
+    #[serde(
+        rename = "http-response",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub http_response: Option<HttpResponse>,
+    #[serde(
+        rename = "ws-message",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ws_message: Option<WsMessage>,
+}
+
+/// A message published to a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Item {
+    pub channel: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "prev-id", default, skip_serializing_if = "Option::is_none")]
+    pub prev_id: Option<String>,
+    /// Values GRIP filters look at, such as the `sender` of the item.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+    pub formats: Formats,
+}
+
+impl Item {
+    pub fn new(channel: impl Into<String>) -> Self {
+        Item {
+            channel: channel.into(),
+            id: None,
+            prev_id: None,
+            meta: BTreeMap::new(),
+            formats: Formats::default(),
+        }
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_prev_id(mut self, prev_id: impl Into<String>) -> Self {
+        self.prev_id = Some(prev_id.into());
+        self
+    }
+
+    /// Sets a meta value of the item, for GRIP filters to look at.
+    pub fn with_meta(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.meta.insert(name.into(), value.into());
+        self
+    }
+
+    /// Names the user publishing the item, so subscribers with that
+    /// [`grip::USER_META`] and the [`grip::FILTER_SKIP_SELF`] filter don't
+    /// get it back.
+    pub fn with_sender(self, user: impl Into<String>) -> Self {
+        self.with_meta(SENDER_META, user)
+    }
+
+    /// Adds content to append to HTTP streaming responses.
+    pub fn http_stream(mut self, content: impl Into<String>) -> Self {
+        self.formats.http_stream = Some(HttpStream {
+            content: content.into(),
+            action: None,
+        });
+        self
+    }
+
+    /// Adds a body to send as the response to long-polling requests.
+    pub fn http_response(mut self, body: impl Into<String>) -> Self {
+        self.formats.http_response = Some(HttpResponse {
+            code: None,
+            headers: Vec::new(),
+            body: body.into(),
+        });
+        self
+    }
+
+    /// Adds a TEXT message to send to WebSocket connections.
+    pub fn ws_message(mut self, content: impl Into<String>) -> Self {
+        self.formats.ws_message = Some(WsMessage {
+            action: None,
+            code: None,
+            reason: None,
+            content: Some(content.into()),
+            content_bin: None,
+        });
+        self
+    }
+
+    /// Adds a BINARY message to send to WebSocket connections.
+    pub fn ws_binary(mut self, content: &[u8]) -> Self {
+        self.formats.ws_message = Some(WsMessage {
+            action: None,
+            code: None,
+            reason: None,
+            content: None,
+            content_bin: Some(STANDARD.encode(content)),
+        });
+        self
+    }
+
+    /// Makes the item close the HTTP streams and WebSocket connections
+    /// subscribed to its channel, the latter with `code` if given.
+    pub fn close(mut self, code: Option<u16>) -> Self {
+        self.formats.http_stream = Some(HttpStream {
+            content: String::new(),
+            action: Some("close".to_string()),
+        });
+        self.formats.ws_message = Some(WsMessage {
+            action: Some("close".to_string()),
+            code,
+            reason: None,
+            content: None,
+            content_bin: None,
+        });
+        self
+    }
+
+    /// Like [`Item::close`], also giving WebSocket connections the reason
+    /// they're closed, e.g. a hint as to when to reconnect.
+    pub fn close_with_reason(self, code: Option<u16>, reason: impl Into<String>) -> Self {
+        let mut item = self.close(code);
+        if let Some(ws) = &mut item.formats.ws_message {
+            ws.reason = Some(reason.into());
+        }
+        item
+    }
+}
+
+/// How requests to the publish endpoint are authenticated.
+#[derive(Clone)]
+pub enum Auth {
+    /// A Fastly API token, sent in the `Fastly-Key` header.
+    FastlyKey(String),
+    /// A static bearer token.
+    Bearer(String),
+    /// HTTP basic credentials.
+    Basic { user: String, password: String },
+    /// A short-lived HS256 JWT, as accepted by Pushpin.
+    Jwt { iss: String, key: Vec<u8> },
+}
+
+impl Auth {
+    /// Returns the header name and value carrying the credentials.
+    pub fn header(&self) -> (&'static str, String) {
+        match self {
+            Auth::FastlyKey(key) => ("Fastly-Key", key.clone()),
+            Auth::Bearer(token) => ("Authorization", format!("Bearer {}", token)),
+            Auth::Basic { user, password } => (
+                "Authorization",
+                format!(
+                    "Basic {}",
+                    STANDARD.encode(format!("{}:{}", user, password))
+                ),
+            ),
+            Auth::Jwt { iss, key } => (
+                "Authorization",
+                format!("Bearer {}", sign_jwt(iss, key, grip::unix_now())),
+            ),
+        }
+    }
+}
+
+fn sign_jwt(iss: &str, key: &[u8], now: u64) -> String {
+    let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#);
+    let claims = serde_json::json!({ "iss": iss, "exp": now + JWT_LIFETIME });
+    let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+    let signed = format!("{}.{}", header, payload);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(signed.as_bytes());
+    let sig = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+    format!("{}.{}", signed, sig)
+}
+
+/// How failed publish requests are retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts made in all, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each one after it.
+    pub backoff: Duration,
+    /// Longest delay between attempts.
+    pub max_backoff: Duration,
+    /// Statuses from the publish endpoint that are worth retrying.
+    pub retry_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            retry_statuses: vec![429, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns a policy making a single attempt.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Returns the policy configured for the service, using the defaults
+    /// for what isn't configured.
+    pub fn from_config() -> Self {
+        let mut policy = RetryPolicy::default();
+
+        if let Some(n) = config::setting(RETRY_ATTEMPTS_SETTING).and_then(|s| s.trim().parse().ok())
+        {
+            policy.max_attempts = u32::max(n, 1);
+        }
+
+        if let Some(ms) = config::setting(RETRY_BACKOFF_SETTING).and_then(|s| s.trim().parse().ok())
+        {
+            policy.backoff = Duration::from_millis(ms);
+        }
+
+        if let Some(statuses) = config::setting(RETRY_STATUSES_SETTING) {
+            policy.retry_statuses = statuses
+                .split(',')
+                .filter_map(|s| s.trim().parse().ok())
+                .collect();
+        }
+
+        policy
+    }
+
+    /// Returns whether a failed attempt should be retried.
+    pub fn is_retryable(&self, error: &PublishError) -> bool {
+        match error {
+            PublishError::Send(_) => true,
+            PublishError::Status(status, _) => self.retry_statuses.contains(&status.as_u16()),
+        }
+    }
+
+    /// Returns how long to wait before the attempt after `attempt` (counted
+    /// from 1): a random time up to the backoff for that attempt, so
+    /// publishers failing together don't retry together.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+
+        let mut buf = [0u8; 8];
+        getrandom::getrandom(&mut buf).expect("random source available");
+        let fraction = u64::from_le_bytes(buf) as f64 / u64::MAX as f64;
+
+        ceiling.mul_f64(fraction)
+    }
+}
+
+/// Errors from publishing.
+#[derive(Debug)]
+pub enum PublishError {
+    /// The request could not be sent to the publish backend.
+    Send(Box<SendError>),
+    /// The publish endpoint rejected the request.
+    Status(StatusCode, String),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::Send(e) => write!(f, "failed to send publish request: {e}"),
+            PublishError::Status(status, body) => {
+                write!(f, "publish rejected with {status}: {body}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+impl From<SendError> for PublishError {
+    fn from(e: SendError) -> Self {
+        PublishError::Send(Box::new(e))
+    }
+}
+
+/// Sends publish requests to the Fanout publish endpoint.
+#[derive(Clone)]
+pub struct Publisher {
+    backend: String,
+    url: String,
+    auth: Option<Auth>,
+    retry: RetryPolicy,
+    transforms: Vec<TransformRule>,
+}
+
+impl Publisher {
+    /// Returns a publisher sending requests for `url` to `backend`.
+    pub fn new(backend: impl Into<String>, url: impl Into<String>) -> Self {
+        Publisher {
+            backend: backend.into(),
+            url: url.into(),
+            auth: None,
+            retry: RetryPolicy::default(),
+            transforms: Vec::new(),
+        }
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the transforms items pass through before being published, see
+    /// [`transform`].
+    pub fn with_transforms(mut self, rules: Vec<TransformRule>) -> Self {
+        self.transforms = rules;
+        self
+    }
+
+    /// Returns the publisher configured for the service, if any.
+    pub fn from_config() -> Option<Self> {
+        if config::local_dev() && config::setting(URL_SETTING).is_none() {
+            return Some(Publisher::new(LOCAL_DEV, LOCAL_DEV).with_retry(RetryPolicy::none()));
+        }
+
+        let backend = config::setting(BACKEND_SETTING)?;
+        let url = config::setting(URL_SETTING)?;
+        let publisher = Publisher::new(backend, url).with_retry(RetryPolicy::from_config());
+
+        let key = match config::secret(KEY_SECRET) {
+            Some(key) => key,
+            None => return Some(publisher),
+        };
+        let key_str = String::from_utf8_lossy(&key).trim().to_string();
+
+        let auth = match config::setting(AUTH_SETTING).as_deref() {
+            None | Some("fastly-key") => Auth::FastlyKey(key_str),
+            Some("bearer") => Auth::Bearer(key_str),
+            Some("basic") => {
+                let (user, password) = key_str.split_once(':').unwrap_or((&key_str, ""));
+                Auth::Basic {
+                    user: user.to_string(),
+                    password: password.to_string(),
+                }
+            }
+            Some("jwt") => Auth::Jwt {
+                iss: config::setting(JWT_ISS_SETTING).unwrap_or_default(),
+                key,
+            },
+            Some(other) => {
+                log_warn!("unknown publish auth scheme {other}, sending no credentials");
+                return Some(publisher);
+            }
+        };
+
+        Some(publisher.with_auth(auth))
+    }
+
+    /// Returns the publisher configured for the service, applying the
+    /// transforms of `route` to the channels published to on its behalf.
+    pub fn for_route(route: &Route) -> Option<Self> {
+        let rules = route
+            .transforms
+            .iter()
+            .map(|rule| TransformRule {
+                channel: route.channel(&rule.channel),
+                transform: rule.transform.clone(),
+            })
+            .collect();
+
+        Some(Publisher::from_config()?.with_transforms(rules))
+    }
+
+    /// Checks that the publish endpoint can be reached. Any response short
+    /// of a server error counts, since probing without items may well be
+    /// refused.
+    pub fn probe(&self) -> Result<(), PublishError> {
+        if self.backend == LOCAL_DEV {
+            return Ok(());
+        }
+
+        let mut req = Request::get(self.url.as_str());
+        if let Some(auth) = &self.auth {
+            let (name, value) = auth.header();
+            req.set_header(name, value);
+        }
+
+        let mut resp = req.send(self.backend.as_str())?;
+
+        if resp.get_status().is_server_error() {
+            return Err(PublishError::Status(
+                resp.get_status(),
+                resp.take_body_str(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Passes an item through the publisher's transforms, as publishing it
+    /// would, returning `None` if one drops it.
+    pub fn transform(&self, item: Item) -> Option<Item> {
+        transform::apply(&self.transforms, item)
+    }
+
+    /// Returns a batch collecting items to publish with this publisher.
+    pub fn batch(&self) -> Batch {
+        Batch::new(self.clone())
+    }
+
+    /// Publishes a single item.
+    pub fn publish(&self, item: Item) -> Result<(), PublishError> {
+        self.publish_items(&[item])
+    }
+
+    /// Publishes a single item, asking WebSocket clients to acknowledge its
+    /// TEXT message, see [`ack`]. Returns the id acks will carry.
+    pub fn publish_with_ack(&self, mut item: Item) -> Result<String, PublishError> {
+        // numbered before it's wrapped, so the envelope carries the id the
+        // item is published and kept in history under
+        if sequence::chaining() {
+            sequence::chain(&mut item);
+        }
+        let item = ack::request(item);
+        let id = item.id.clone().unwrap_or_default();
+        self.publish_numbered(&[item], false)?;
+        Ok(id)
+    }
+
+    /// Publishes several items in one request, retrying it as the retry
+    /// policy allows. Items are first numbered if prev-id chaining is on,
+    /// see [`sequence`], and pass through the publisher's transforms, which
+    /// may drop them. Items with an id are also kept in the
+    /// channel history for replay, and items that couldn't be published as
+    /// dead letters.
+    pub fn publish_items(&self, items: &[Item]) -> Result<(), PublishError> {
+        self.publish_numbered(items, sequence::chaining())
+    }
+
+    /// Publishes several items like [`Publisher::publish_items`], numbering
+    /// them only if `number` is set.
+    fn publish_numbered(&self, items: &[Item], number: bool) -> Result<(), PublishError> {
+        let transformed: Vec<Item>;
+        let items = if self.transforms.is_empty() && !number {
+            items
+        } else {
+            transformed = items
+                .iter()
+                .cloned()
+                .map(|mut item| {
+                    if number && item.prev_id.is_none() {
+                        sequence::chain(&mut item);
+                    }
+                    item
+                })
+                .filter_map(|item| transform::apply(&self.transforms, item))
+                .collect();
+            &transformed
+        };
+
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.try_publish_items(items);
+        if let Err(e) = &result {
+            dead_letter::record(items, e);
+        }
+        result
+    }
+
+    /// Publishes several items like [`Publisher::publish_items`], except
+    /// that they aren't transformed, and items that couldn't be published
+    /// aren't kept as dead letters.
+    pub fn try_publish_items(&self, items: &[Item]) -> Result<(), PublishError> {
+        #[derive(Serialize)]
+        struct Body<'a> {
+            items: &'a [Item],
+        }
+
+        let body = serde_json::to_string(&Body { items }).expect("items always serialize");
+
+        let mut attempt = 1;
+        let result = loop {
+            match self.send_items(&body) {
+                Ok(()) => break Ok(()),
+                Err(e) if attempt < self.retry.max_attempts && self.retry.is_retryable(&e) => {
+                    let delay = self.retry.delay(attempt);
+                    log_warn!(
+                        "publish attempt {attempt} failed, retrying in {}ms: {e}",
+                        delay.as_millis()
+                    );
+                    metrics::incr("publish_retries_total", &[]);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        if let Err(e) = result {
+            log_error!(
+                "failed to publish {} items after {attempt} attempts: {e}",
+                items.len()
+            );
+            metrics::add(
+                "published_items_total",
+                &[("result", "error")],
+                items.len() as u64,
+            );
+            return Err(e);
+        }
+
+        metrics::add(
+            "published_items_total",
+            &[("result", "ok")],
+            items.len() as u64,
+        );
+
+        for item in items {
+            history::record(item);
+        }
+        history::touch_channels(items);
+        activity::touch(items.iter().map(|item| item.channel.as_str()));
+
+        Ok(())
+    }
+
+    fn send_items(&self, body: &str) -> Result<(), PublishError> {
+        if self.backend == LOCAL_DEV {
+            log_info!("local-dev: would publish {body}");
+            return Ok(());
+        }
+
+        let mut req = Request::post(self.url.as_str())
+            .with_header("Content-Type", "application/json")
+            .with_body(body);
+
+        if let Some(auth) = &self.auth {
+            let (name, value) = auth.header();
+            req.set_header(name, value);
+        }
+
+        let mut resp = req.send(self.backend.as_str())?;
+
+        if !resp.get_status().is_success() {
+            return Err(PublishError::Status(
+                resp.get_status(),
+                resp.take_body_str(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Items collected to be published in as few requests as possible.
+///
+/// Items are sent once `max` of them are collected, when the batch is
+/// flushed and, failing that, when it is dropped, in which case errors are
+/// only logged.
+pub struct Batch {
+    publisher: Publisher,
+    items: Vec<Item>,
+    max: usize,
+}
+
+impl Batch {
+    /// Returns an empty batch for `publisher`, holding up to the configured
+    /// `publish_batch_max` items.
+    pub fn new(publisher: Publisher) -> Self {
+        let max = config::setting(BATCH_MAX_SETTING)
+            .and_then(|s| s.trim().parse().ok())
+            .filter(|&max| max > 0)
+            .unwrap_or(DEFAULT_BATCH_MAX);
+
+        Batch {
+            publisher,
+            items: Vec::new(),
+            max,
+        }
+    }
+
+    /// Sets how many items are sent in one request.
+    pub fn with_max(mut self, max: usize) -> Self {
+        self.max = max.max(1);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Adds an item, sending the batch if that fills it.
+    pub fn push(&mut self, item: Item) -> Result<(), PublishError> {
+        self.items.push(item);
+        if self.items.len() >= self.max {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the items collected so far. They are dropped whether or not
+    /// publishing succeeds.
+    pub fn flush(&mut self) -> Result<(), PublishError> {
+        if self.items.is_empty() {
+            return Ok(());
+        }
+
+        let items = std::mem::take(&mut self.items);
+        self.publisher.publish_items(&items)
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        let count = self.items.len();
+        if let Err(e) = self.flush() {
+            log_error!("failed to publish batch of {count} items: {e}");
+        }
+    }
+}
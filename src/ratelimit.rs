@@ -0,0 +1,225 @@
+//! Per-client rate limiting.
+//!
+//! Requests establishing connections and publish requests can be limited
+//! per client IP, separately for each route. Limits are checked with
+//! Fastly's edge rate limiter, using the [`RATE_COUNTER`] rate counter and
+//! the [`PENALTY_BOX`] penalty box. Where the edge rate limiter isn't
+//! available, a token bucket kept in the `fanout_state` KV Store is used
+//! instead. It is best-effort, as concurrent requests may both take the
+//! last token.
+//!
+//! Limits are set in a route's `rate_limit` field:
+//!
+//! ```json
+//! {"rate_limit": {"connect": {"rps": 5}, "publish": {"rps": 20, "window": 60}}}
+//! ```
+//!
+//! along with the most connections a client may keep open at once, see
+//! [`crate::connections`].
+
+use fastly::erl::{Penaltybox, RateCounter, RateWindow, ERL};
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+use crate::config;
+use crate::error::AppError;
+use crate::grip::unix_now_millis;
+use crate::{log_debug, log_error};
+
+/// Name of the rate counter used by the edge rate limiter.
+pub const RATE_COUNTER: &str = "fanout_rate";
+
+/// Name of the penalty box used by the edge rate limiter.
+pub const PENALTY_BOX: &str = "fanout_penalty";
+
+const DEFAULT_WINDOW: u32 = 10;
+const DEFAULT_PENALTY: u32 = 60;
+
+/// Kind of request a limit applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Requests establishing a connection through Fanout.
+    Connect,
+    /// Requests to the publish endpoint.
+    Publish,
+}
+
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Connect => "connect",
+            Scope::Publish => "publish",
+        }
+    }
+}
+
+fn default_window() -> u32 {
+    DEFAULT_WINDOW
+}
+
+fn default_penalty() -> u32 {
+    DEFAULT_PENALTY
+}
+
+/// A rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Limit {
+    /// Requests per second allowed on average over the window.
+    pub rps: u32,
+    /// Seconds the rate is averaged over: `1`, `10` or `60`.
+    #[serde(default = "default_window")]
+    pub window: u32,
+    /// Seconds a client exceeding the limit is refused for. The edge rate
+    /// limiter rounds it to whole minutes, between 1 and 60.
+    #[serde(default = "default_penalty")]
+    pub penalty: u32,
+}
+
+impl Limit {
+    fn rate_window(&self) -> RateWindow {
+        match self.window {
+            0..=1 => RateWindow::OneSec,
+            2..=10 => RateWindow::TenSecs,
+            _ => RateWindow::SixtySecs,
+        }
+    }
+}
+
+/// The rate limits of a route. Requests aren't limited by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub connect: Option<Limit>,
+    pub publish: Option<Limit>,
+    /// Most WebSocket connections to the app's own endpoints a client may
+    /// keep open.
+    pub max_connections: Option<u32>,
+}
+
+impl RateLimits {
+    fn get(&self, scope: Scope) -> Option<&Limit> {
+        match scope {
+            Scope::Connect => self.connect.as_ref(),
+            Scope::Publish => self.publish.as_ref(),
+        }
+    }
+}
+
+/// A client went over its limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limited {
+    /// Seconds until the client may try again.
+    pub retry_after: u64,
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limited for {}s", self.retry_after)
+    }
+}
+
+impl std::error::Error for Limited {}
+
+impl Limited {
+    /// Returns the `429` response refusing the request.
+    pub fn response(&self) -> Response {
+        AppError::from(*self)
+            .response()
+            .with_header("Retry-After", self.retry_after.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Bucket {
+    tokens: f64,
+    /// Milliseconds since the Unix epoch of the last update.
+    at: u64,
+}
+
+/// Takes a token from the client's bucket in the KV Store. Buckets hold up
+/// to a window's worth of requests and refill at `rps`.
+fn check_bucket(entry: &str, limit: &Limit) -> Result<(), Limited> {
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => {
+            log_debug!("no state store, not rate limiting {entry}");
+            return Ok(());
+        }
+    };
+
+    let key = format!("ratelimit:{}", entry);
+    let rate = f64::from(limit.rps);
+    let capacity = rate * f64::from(limit.window.max(1));
+    let now = unix_now_millis();
+
+    let mut bucket = store
+        .lookup_str(&key)
+        .ok()
+        .flatten()
+        .and_then(|s| serde_json::from_str::<Bucket>(&s).ok())
+        .unwrap_or(Bucket {
+            tokens: capacity,
+            at: now,
+        });
+
+    let elapsed = now.saturating_sub(bucket.at) as f64 / 1000.0;
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+    bucket.at = now;
+
+    let result = if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else if rate > 0.0 {
+        Err(Limited {
+            retry_after: ((1.0 - bucket.tokens) / rate).ceil() as u64,
+        })
+    } else {
+        Err(Limited {
+            retry_after: u64::from(limit.penalty),
+        })
+    };
+
+    let value = serde_json::to_string(&bucket).expect("bucket serializes");
+    if let Err(e) = store.insert(&key, value) {
+        log_error!("failed to save rate limit bucket {key}: {e}");
+    }
+
+    result
+}
+
+fn check_entry(entry: &str, limit: &Limit) -> Result<(), Limited> {
+    let erl = ERL::open(
+        RateCounter::open(RATE_COUNTER),
+        Penaltybox::open(PENALTY_BOX),
+    );
+    let penalty = Duration::from_secs(u64::from(limit.penalty));
+
+    match erl.check_rate(entry, 1, limit.rate_window(), limit.rps, penalty) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(Limited {
+            retry_after: u64::from(limit.penalty.clamp(60, 3600)),
+        }),
+        Err(e) => {
+            log_debug!("edge rate limiter unavailable ({e}), using state store");
+            check_bucket(entry, limit)
+        }
+    }
+}
+
+/// Checks a request against the route's limit for `scope`, counting it
+/// against the client's IP.
+pub fn check(req: &Request, limits: &RateLimits, scope: Scope) -> Result<(), Limited> {
+    let limit = match limits.get(scope) {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    let client = match req.get_client_ip_addr() {
+        Some(ip) => ip.to_string(),
+        None => return Ok(()),
+    };
+
+    check_entry(&format!("{}:{}", scope.as_str(), client), limit)
+}
@@ -0,0 +1,78 @@
+//! Request ids.
+//!
+//! Every request gets an id, a random UUID, which is forwarded to origins
+//! in the `X-Request-Id` header, returned on the app's responses and added
+//! to the logging context, so edge logs can be matched up with origin logs.
+//! Requests Fanout forwards back to the app keep the id given to them when
+//! they were handed off, and so do requests arriving with a well-formed id
+//! from a proxy in front of the app.
+
+use fastly::{Request, Response};
+use std::cell::RefCell;
+
+use crate::hex;
+use crate::logging;
+
+/// Header carrying the request id.
+pub const HEADER: &str = "X-Request-Id";
+
+/// Longest request id accepted from a client.
+const MAX_LEN: usize = 128;
+
+thread_local! {
+    static CURRENT: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Returns a new random (version 4) UUID.
+pub fn generate() -> String {
+    let mut buf = [0u8; 16];
+    getrandom::getrandom(&mut buf).expect("random source available");
+    buf[6] = (buf[6] & 0x0f) | 0x40;
+    buf[8] = (buf[8] & 0x3f) | 0x80;
+
+    let hex = hex::encode(&buf);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Returns whether `id` may be used as a request id: up to 128 letters,
+/// digits, `-`, `_`, `.` and `:`.
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+/// Picks the id of the request being served, keeping a valid one it
+/// arrived with, sets it on the request and logs it.
+pub fn init(req: &mut Request) -> String {
+    let id = match req.get_header_str(HEADER) {
+        Some(id) if is_valid(id) => id.to_string(),
+        _ => generate(),
+    };
+
+    req.set_header(HEADER, id.as_str());
+    logging::set_context("request_id", id.as_str());
+    CURRENT.with(|current| *current.borrow_mut() = id.clone());
+
+    id
+}
+
+/// Returns the id of the request being served.
+pub fn current() -> String {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Sets the request id header of a response.
+pub fn tag(mut resp: Response) -> Response {
+    resp.set_header(HEADER, current());
+    resp
+}
@@ -0,0 +1,192 @@
+//! Host-based routing of proxied requests.
+//!
+//! Routes are looked up in the `fanout_routes` Config Store, keyed by host
+//! pattern. For a request to `api.example.com` the keys tried are, in order,
+//! `api.example.com`, `*.example.com`, `*.com` and `*`. Each value is a JSON
+//! object:
+//!
+//! ```json
+//! {"backend": "origin_a", "hold": "stream", "channel_prefix": "a:"}
+//! ```
+//!
+//! A route may also name an `origin` (`host` or `host:port`) to reach
+//! through a dynamic backend, see [`crate::backends`], how its SSE streams
+//! are opened with `sse`, see [`StreamOptions`], how its WebSocket
+//! connections are kept alive with `keep_alive`, see [`KeepAliveOptions`],
+//! its clients' rate limits with `rate_limit`, see [`RateLimits`], and the
+//! size of its request bodies and messages with `limits`, see
+//! [`BodyLimits`]. Routes shared by several customers can keep their
+//! channels apart with `tenant`, see [`crate::tenant`], and messages can
+//! be rewritten before they are published with `transforms`, see
+//! [`crate::transform`]. How long Fanout may hold the host's long-polling
+//! and streaming requests is bounded with `timeouts`, see [`HoldTimeouts`],
+//! and the security headers of the app's own responses are set with
+//! `headers`, see [`HeaderPolicy`]. Routes with `require_tls` redirect
+//! plaintext requests opening connections to HTTPS.
+//!
+//! All fields are optional. Hosts without a matching route use the backend
+//! naming convention `https_backend_{host}` (or `http_backend_{host}` for
+//! plaintext requests) and no channel prefix.
+
+use serde::Deserialize;
+
+use crate::config;
+use crate::grip::{HoldMode, HoldTimeouts, KeepAliveOptions};
+use crate::headers::HeaderPolicy;
+use crate::limits::BodyLimits;
+use crate::log_warn;
+use crate::ratelimit::RateLimits;
+use crate::sse::StreamOptions;
+use crate::tenant::TenantSource;
+use crate::transform::TransformRule;
+
+/// Where and how a request for a given host is handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// Name of the backend Fanout forwards requests to.
+    pub backend: String,
+    /// Hold mode expected for the host's streaming endpoints, if known.
+    pub hold: Option<HoldMode>,
+    /// Prefix applied to channel names used on behalf of the host.
+    pub channel_prefix: String,
+    /// Origin to reach through a dynamic backend, if dynamic backends are
+    /// enabled.
+    pub origin: Option<String>,
+    /// How SSE streams served for the host are opened.
+    pub sse: StreamOptions,
+    /// How WebSocket connections served for the host are kept alive.
+    pub keep_alive: KeepAliveOptions,
+    /// Per-client rate limits of the host's connections and publishes.
+    pub rate_limit: RateLimits,
+    /// Size limits of the host's request bodies and WebSocket messages.
+    pub limits: BodyLimits,
+    /// Where the tenant of the host's requests comes from, if the host is
+    /// shared by several tenants.
+    pub tenant_source: Option<TenantSource>,
+    /// Tenant of the request being served, whose namespace channel names
+    /// are in. Set once resolved from `tenant_source`.
+    pub tenant: Option<String>,
+    /// Transforms applied to the messages published on behalf of the host.
+    pub transforms: Vec<TransformRule>,
+    /// Longest hold timeouts of the host's requests.
+    pub timeouts: HoldTimeouts,
+    /// Security headers of the responses the app makes for the host.
+    pub headers: HeaderPolicy,
+    /// Whether plaintext requests opening connections are redirected to
+    /// HTTPS.
+    pub require_tls: bool,
+}
+
+impl Route {
+    /// Returns the route following the default backend naming convention.
+    pub fn default_for_host(host: &str, tls: bool) -> Self {
+        let backend_prefix = if tls {
+            "https_backend_"
+        } else {
+            "http_backend_"
+        };
+
+        Route {
+            backend: format!("{}{}", backend_prefix, host),
+            hold: None,
+            channel_prefix: String::new(),
+            origin: None,
+            sse: StreamOptions::default(),
+            keep_alive: KeepAliveOptions::default(),
+            rate_limit: RateLimits::default(),
+            limits: BodyLimits::default(),
+            tenant_source: None,
+            tenant: None,
+            transforms: Vec::new(),
+            timeouts: HoldTimeouts::default(),
+            headers: HeaderPolicy::default(),
+            require_tls: false,
+        }
+    }
+
+    /// Returns the full name of a channel for this route, in the tenant's
+    /// namespace if there is one.
+    pub fn channel(&self, name: &str) -> String {
+        match &self.tenant {
+            Some(tenant) => format!("{}{}:{}", self.channel_prefix, tenant, name),
+            None => format!("{}{}", self.channel_prefix, name),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RouteConfig {
+    backend: Option<String>,
+    hold: Option<HoldMode>,
+    #[serde(default)]
+    channel_prefix: String,
+    origin: Option<String>,
+    #[serde(default)]
+    sse: StreamOptions,
+    #[serde(default)]
+    keep_alive: KeepAliveOptions,
+    #[serde(default)]
+    rate_limit: RateLimits,
+    #[serde(default)]
+    limits: BodyLimits,
+    tenant: Option<TenantSource>,
+    #[serde(default)]
+    transforms: Vec<TransformRule>,
+    #[serde(default)]
+    timeouts: HoldTimeouts,
+    #[serde(default)]
+    headers: HeaderPolicy,
+    #[serde(default)]
+    require_tls: bool,
+}
+
+/// Returns the Config Store keys to try for `host`, most specific first.
+pub fn host_patterns(host: &str) -> Vec<String> {
+    let mut patterns = vec![host.to_string()];
+
+    let mut rest = host;
+    while let Some((_, parent)) = rest.split_once('.') {
+        patterns.push(format!("*.{}", parent));
+        rest = parent;
+    }
+
+    patterns.push("*".to_string());
+    patterns
+}
+
+/// Returns the route for a request to `host`.
+pub fn route_for_host(host: &str, tls: bool) -> Route {
+    let mut route = Route::default_for_host(host, tls);
+
+    for pattern in host_patterns(host) {
+        let value = match config::lookup(config::ROUTES_STORE, &pattern) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        match serde_json::from_str::<RouteConfig>(&value) {
+            Ok(rc) => {
+                if let Some(backend) = rc.backend {
+                    route.backend = backend;
+                }
+                route.hold = rc.hold;
+                route.channel_prefix = rc.channel_prefix;
+                route.origin = rc.origin;
+                route.sse = rc.sse;
+                route.keep_alive = rc.keep_alive;
+                route.rate_limit = rc.rate_limit;
+                route.limits = rc.limits;
+                route.tenant_source = rc.tenant;
+                route.transforms = rc.transforms;
+                route.timeouts = rc.timeouts;
+                route.headers = rc.headers;
+                route.require_tls = rc.require_tls;
+            }
+            Err(e) => log_warn!("ignoring invalid route for {pattern}: {e}"),
+        }
+
+        break;
+    }
+
+    route
+}
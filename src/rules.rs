@@ -0,0 +1,216 @@
+//! Declarative routing rules for proxied requests.
+//!
+//! The `routing_rules` setting holds a JSON array of rules, tried in order
+//! before a request is forwarded. The first rule matching the request's
+//! host, path and method decides the backend, whether the request goes
+//! through Fanout, and how its path is rewritten:
+//!
+//! ```json
+//! [
+//!   {"path": "/realtime/*", "backend": "realtime_origin"},
+//!   {"host": "*.example.com", "path": "/api/*", "methods": ["GET", "POST"],
+//!    "backend": "api_origin", "fanout": false, "rewrite": "/v1/"}
+//! ]
+//! ```
+//!
+//! All fields are optional. `host` is a glob where `*` matches any run of
+//! characters, `path` a prefix (a trailing `*` is allowed for clarity) and
+//! `rewrite` replaces the matched prefix. Rules without a `backend` use the
+//! host's route. Requests matching no rule are forwarded through Fanout
+//! using the host's route, as before.
+//!
+//! Origins deployed in several regions can be reached at the one closest to
+//! the client with `regions`, mapping continent codes (`EU`) or country codes
+//! (`DE`) to backends, where `{host}` stands for the request's host:
+//!
+//! ```json
+//! [{"path": "/realtime/*", "backend": "realtime_origin",
+//!   "regions": {"EU": "https_backend_eu_{host}", "AS": "realtime_asia"}}]
+//! ```
+//!
+//! A `canary` sends a percentage of clients to another backend, for rolling
+//! out a new origin. Clients are split by a hash of their IP address, so
+//! each one stays on one side and can't choose it:
+//!
+//! ```json
+//! [{"path": "/realtime/*", "backend": "realtime_origin",
+//!   "canary": {"backend": "realtime_next", "percent": 10}}]
+//! ```
+//!
+//! Origins keeping per-client state in memory can list their instances as
+//! `backends`. Each client is then pinned to one of them by a hash of its
+//! IP address, so the connections it opens reach the same instance. Only
+//! the request opening a connection goes through the app; Fanout sends the
+//! events that follow to the backend it was handed off to.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::geo::Region;
+use crate::log_warn;
+
+/// Setting holding the routing rules.
+pub const RULES_SETTING: &str = "routing_rules";
+
+/// A routing rule.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Rule {
+    pub host: Option<String>,
+    pub path: Option<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub backend: Option<String>,
+    /// Whether matching requests are handed off to Fanout, rather than
+    /// sent straight to the backend.
+    #[serde(default = "default_fanout")]
+    pub fanout: bool,
+    pub rewrite: Option<String>,
+    /// Backends of regional deployments, by continent or country code.
+    #[serde(default)]
+    pub regions: BTreeMap<String, String>,
+    /// Backend a share of clients are sent to instead.
+    pub canary: Option<Canary>,
+    /// Instances of the origin clients are pinned to, overriding `backend`.
+    #[serde(default)]
+    pub backends: Vec<String>,
+}
+
+/// A backend taking a share of a rule's clients.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Canary {
+    pub backend: String,
+    /// Percentage of clients sent to the canary, from `0` to `100`.
+    pub percent: u8,
+}
+
+fn default_fanout() -> bool {
+    true
+}
+
+impl Rule {
+    fn path_prefix(&self) -> &str {
+        let path = self.path.as_deref().unwrap_or("");
+        path.strip_suffix('*').unwrap_or(path)
+    }
+
+    /// Returns whether the rule applies to a request.
+    pub fn matches(&self, host: &str, method: &str, path: &str) -> bool {
+        if let Some(pattern) = &self.host {
+            if !glob_match(&pattern.to_ascii_lowercase(), &host.to_ascii_lowercase()) {
+                return false;
+            }
+        }
+
+        if !self.methods.is_empty() && !self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+        {
+            return false;
+        }
+
+        path.starts_with(self.path_prefix())
+    }
+
+    /// Returns the backend of a matching request to `host` from a client in
+    /// `region`, identified by `client` (its IP address):
+    /// the canary if the client is among its share, the instance of
+    /// `backends` the client is pinned to, its regional backend if there is
+    /// one, or `backend`.
+    pub fn backend_for(
+        &self,
+        host: &str,
+        region: Option<&Region>,
+        client: Option<&str>,
+    ) -> Option<String> {
+        if let (Some(canary), Some(client)) = (&self.canary, client) {
+            if bucket(client) < canary.percent.min(100) {
+                return Some(canary.backend.clone());
+            }
+        }
+
+        if let (false, Some(client)) = (self.backends.is_empty(), client) {
+            let i = (hash(client) % self.backends.len() as u64) as usize;
+            return Some(self.backends[i].clone());
+        }
+
+        match region.and_then(|region| region.lookup(&self.regions)) {
+            Some(backend) => Some(backend.replace("{host}", host)),
+            None => self.backend.clone(),
+        }
+    }
+
+    /// Returns the path a matching request is forwarded with.
+    pub fn rewrite_path(&self, path: &str) -> String {
+        match &self.rewrite {
+            Some(replacement) => {
+                let rest = &path[self.path_prefix().len()..];
+                let rewritten = format!("{}{}", replacement, rest);
+                if rewritten.starts_with('/') {
+                    rewritten
+                } else {
+                    format!("/{}", rewritten)
+                }
+            }
+            None => path.to_string(),
+        }
+    }
+}
+
+/// Returns a stable hash of `key`, the same in every instance of the app.
+pub fn hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("digests are 32 bytes"))
+}
+
+/// Returns a stable bucket from `0` to `99` for `key`, splitting clients
+/// evenly.
+pub fn bucket(key: &str) -> u8 {
+    (hash(key) % 100) as u8
+}
+
+/// Matches `value` against a glob where `*` matches any run of characters.
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // no `*` at all, so the whole value must have matched
+        None => return rest.is_empty(),
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Returns the configured routing rules.
+pub fn load() -> Vec<Rule> {
+    let value = match config::setting(RULES_SETTING) {
+        Some(v) => v,
+        None => return Vec::new(),
+    };
+
+    serde_json::from_str(&value).unwrap_or_else(|e| {
+        log_warn!("ignoring invalid routing rules: {e}");
+        Vec::new()
+    })
+}
+
+/// Returns the first configured rule matching a request.
+pub fn find(host: &str, method: &str, path: &str) -> Option<Rule> {
+    load()
+        .into_iter()
+        .find(|rule| rule.matches(host, method, path))
+}
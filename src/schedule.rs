@@ -0,0 +1,320 @@
+//! Publishes scheduled for later.
+//!
+//! Publish requests can ask for their message to be delivered later, see
+//! [`crate::admin`] and the publish endpoint. Until then its items are kept
+//! in the state KV Store under `scheduled:{id}`, and listed by delivery time
+//! under `scheduled:index`, as the KV Store can't be listed from Compute.
+//!
+//! Nothing runs on its own in Compute, so scheduled publishes are delivered
+//! once due by whatever comes first: a later publish request, or a request
+//! to `POST /admin/scheduled/deliver`, which a cron job can make every
+//! minute or so. Delivery is as late as the traffic that triggers it. The
+//! index is updated with a read-modify-write, so publishes scheduled or
+//! delivered concurrently may drop each other's entries.
+
+use fastly::Request;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::config;
+use crate::error::AppError;
+use crate::grip::unix_now_millis;
+use crate::history;
+use crate::publish::{Item, Publisher};
+use crate::{log_error, log_info};
+
+/// Setting holding how many publishes may be scheduled at once.
+pub const MAX_PENDING_SETTING: &str = "scheduled_publish_max";
+
+const DEFAULT_MAX_PENDING: usize = 1000;
+
+/// Furthest in the future a publish may be scheduled, in milliseconds.
+pub const MAX_DELAY_MS: u64 = 7 * 24 * 3600 * 1000;
+
+const PREFIX: &str = "scheduled:";
+
+const INDEX_KEY: &str = "scheduled:index";
+
+/// A publish waiting for its time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scheduled {
+    pub id: String,
+    /// When to deliver, in milliseconds since the Unix epoch.
+    pub deliver_at: u64,
+    pub items: Vec<Item>,
+}
+
+/// An entry of the index: which publish is due when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    id: String,
+    deliver_at: u64,
+}
+
+/// Reasons a publish can't be scheduled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// There is no state store to keep it in.
+    NoStore,
+    /// As many publishes as allowed are already scheduled.
+    Full(usize),
+    Store(String),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::NoStore => write!(f, "no state store to keep scheduled publishes in"),
+            ScheduleError::Full(max) => write!(f, "{max} publishes are already scheduled"),
+            ScheduleError::Store(e) => write!(f, "failed to keep scheduled publish: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+/// Returns when a publish request asks for its message to be delivered,
+/// from its `delay_ms` query parameter or its `deliver_at` one, in
+/// milliseconds since the Unix epoch, given the time `now`. Times that
+/// aren't in the future mean right away, and give `None`.
+pub fn deliver_at(req: &Request, now: u64) -> Result<Option<u64>, AppError> {
+    let parse = |name: &str| -> Result<Option<u64>, AppError> {
+        req.get_query_parameter(name)
+            .map(|v| {
+                v.parse::<u64>()
+                    .map_err(|_| AppError::ParseError(format!("Invalid {name} {v}.")))
+            })
+            .transpose()
+    };
+
+    let at = match (parse("delay_ms")?, parse("deliver_at")?) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::ParseError(
+                "Give either delay_ms or deliver_at.".into(),
+            ))
+        }
+        (Some(delay), None) => now.saturating_add(delay),
+        (None, Some(at)) => at,
+        (None, None) => return Ok(None),
+    };
+
+    if at <= now {
+        Ok(None)
+    } else if at - now > MAX_DELAY_MS {
+        Err(AppError::ParseError(format!(
+            "Publishes can't be scheduled more than {} days ahead.",
+            MAX_DELAY_MS / 86_400_000
+        )))
+    } else {
+        Ok(Some(at))
+    }
+}
+
+fn max_pending() -> usize {
+    config::setting(MAX_PENDING_SETTING)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_MAX_PENDING)
+}
+
+fn key(id: &str) -> String {
+    format!("{}{}", PREFIX, id)
+}
+
+fn load_index() -> Vec<Entry> {
+    config::state_store()
+        .and_then(|store| store.lookup_str(INDEX_KEY).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn save_index(entries: &[Entry]) {
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => return,
+    };
+
+    let value = serde_json::to_string(entries).expect("entries always serialize");
+    if let Err(e) = store.insert(INDEX_KEY, value) {
+        log_error!("failed to save scheduled publish index: {e}");
+    }
+}
+
+/// Keeps `items` to be published at `deliver_at`, in milliseconds since
+/// the Unix epoch. Returns the id of the scheduled publish.
+pub fn schedule(items: Vec<Item>, deliver_at: u64) -> Result<String, ScheduleError> {
+    let mut store = config::state_store().ok_or(ScheduleError::NoStore)?;
+
+    let mut entries = load_index();
+    let max = max_pending();
+    if entries.len() >= max {
+        return Err(ScheduleError::Full(max));
+    }
+
+    let scheduled = Scheduled {
+        id: history::new_id(),
+        deliver_at,
+        items,
+    };
+    let value = serde_json::to_string(&scheduled).expect("scheduled publishes always serialize");
+    store
+        .insert(&key(&scheduled.id), value)
+        .map_err(|e| ScheduleError::Store(e.to_string()))?;
+
+    insert(
+        &mut entries,
+        Entry {
+            id: scheduled.id.clone(),
+            deliver_at,
+        },
+    );
+    save_index(&entries);
+
+    Ok(scheduled.id)
+}
+
+/// Adds an entry to the index, kept in delivery order, earliest first, and
+/// after those due at the same time.
+fn insert(entries: &mut Vec<Entry>, entry: Entry) {
+    let at = entries.partition_point(|e| e.deliver_at <= entry.deliver_at);
+    entries.insert(at, entry);
+}
+
+/// Takes the entries due by `now` off the index.
+fn take_due(entries: &mut Vec<Entry>, now: u64) -> Vec<Entry> {
+    let due = entries.partition_point(|e| e.deliver_at <= now);
+    entries.drain(..due).collect()
+}
+
+/// Returns the scheduled publish with the given id, if it's still pending.
+pub fn get(id: &str) -> Option<Scheduled> {
+    config::state_store()
+        .and_then(|store| store.lookup_str(&key(id)).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+}
+
+/// Returns the pending publishes, earliest first.
+pub fn list() -> Vec<Scheduled> {
+    load_index().iter().filter_map(|e| get(&e.id)).collect()
+}
+
+/// Outcome of delivering the publishes that are due.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Delivery {
+    /// Ids of the publishes delivered.
+    pub published: Vec<String>,
+    /// Ids of the publishes that failed, and were kept as dead letters.
+    pub failed: Vec<String>,
+}
+
+/// Publishes whatever is due, forgetting it either way: publishes that
+/// fail are kept as dead letters by the publisher.
+pub fn deliver_due(publisher: &Publisher) -> Delivery {
+    let mut delivery = Delivery::default();
+
+    // taken off the index first, so a slow delivery isn't repeated by the
+    // requests coming in meanwhile
+    let mut entries = load_index();
+    let due = take_due(&mut entries, unix_now_millis());
+    if due.is_empty() {
+        return delivery;
+    }
+    save_index(&entries);
+
+    let store = config::state_store();
+    for entry in due {
+        let scheduled = match get(&entry.id) {
+            Some(scheduled) => scheduled,
+            None => continue,
+        };
+        if let Some(store) = &store {
+            let _ = store.delete(&key(&entry.id));
+        }
+
+        match publisher.publish_items(&scheduled.items) {
+            Ok(()) => {
+                log_info!("delivered scheduled publish {}", entry.id);
+                delivery.published.push(entry.id);
+            }
+            Err(e) => {
+                log_error!("failed to deliver scheduled publish {}: {e}", entry.id);
+                delivery.failed.push(entry.id);
+            }
+        }
+    }
+
+    delivery
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, deliver_at: u64) -> Entry {
+        Entry {
+            id: id.into(),
+            deliver_at,
+        }
+    }
+
+    #[test]
+    fn keeps_index_in_delivery_order() {
+        let mut entries = Vec::new();
+        for (id, at) in [("c", 30), ("a", 10), ("b", 20), ("b2", 20), ("d", 5)] {
+            insert(&mut entries, entry(id, at));
+        }
+        let ids: Vec<&str> = entries.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["d", "a", "b", "b2", "c"]);
+
+        assert_eq!(take_due(&mut entries, 4), []);
+        assert_eq!(
+            take_due(&mut entries, 20),
+            [
+                entry("d", 5),
+                entry("a", 10),
+                entry("b", 20),
+                entry("b2", 20)
+            ]
+        );
+        assert_eq!(entries, [entry("c", 30)]);
+    }
+
+    #[test]
+    fn reads_delivery_times() {
+        let now = 1_000_000;
+        let at = |query: &str| {
+            deliver_at(
+                &Request::post(format!("https://example.com/publish/news?{query}")),
+                now,
+            )
+        };
+
+        assert_eq!(at("").unwrap(), None);
+        assert_eq!(at("delay_ms=500").unwrap(), Some(now + 500));
+        assert_eq!(at("deliver_at=1000500").unwrap(), Some(now + 500));
+        assert_eq!(
+            at(&format!("delay_ms={MAX_DELAY_MS}")).unwrap(),
+            Some(now + MAX_DELAY_MS)
+        );
+
+        // times that aren't ahead mean right away
+        assert_eq!(at("delay_ms=0").unwrap(), None);
+        assert_eq!(at("deliver_at=999999").unwrap(), None);
+
+        for query in [
+            "delay_ms=soon",
+            "deliver_at=-1",
+            "delay_ms=1&deliver_at=1000001",
+            &format!("delay_ms={}", MAX_DELAY_MS + 1),
+        ] {
+            assert_eq!(at(query).unwrap_err().status(), 400, "{query}");
+        }
+    }
+
+    #[test]
+    fn refuses_full_schedules_as_client_limits() {
+        assert_eq!(AppError::from(ScheduleError::Full(10)).status(), 429);
+        assert_eq!(AppError::from(ScheduleError::NoStore).status(), 503);
+    }
+}
@@ -0,0 +1,98 @@
+//! Per-channel message sequence numbers, and GRIP prev-id chaining.
+//!
+//! Messages published to a channel can be numbered in order, the number
+//! kept in the state KV Store. With the `prev_id_chaining` setting on,
+//! every published item gets its channel's next number as its `id` and the
+//! one before as its `prev-id`, and holds subscribe to channels with the
+//! `prev-id` of the last message published. Fanout then knows when a
+//! subscriber is about to miss a message, and recovers it rather than
+//! delivering around the gap.
+//!
+//! Numbers are updated with a read-modify-write, so concurrent publishes to
+//! the same channel may be given the same number.
+
+use crate::config;
+use crate::log_error;
+use crate::publish::Item;
+
+/// Setting turning prev-id chaining on.
+pub const CHAINING_SETTING: &str = "prev_id_chaining";
+
+fn key(channel: &str) -> String {
+    format!("seq:{}", channel)
+}
+
+/// Returns whether prev-id chaining is on.
+pub fn chaining() -> bool {
+    config::setting(CHAINING_SETTING).as_deref() == Some("true")
+}
+
+/// Returns the number of the last message published to `channel`.
+pub fn last(channel: &str) -> Option<u64> {
+    config::state_store()?
+        .lookup_str(&key(channel))
+        .ok()
+        .flatten()
+        .and_then(|s| s.parse().ok())
+}
+
+/// Returns the next number of `channel` and the one before it, if any, or
+/// `None` without the KV Store.
+pub fn next(channel: &str) -> Option<(u64, Option<u64>)> {
+    let mut store = config::state_store()?;
+
+    let prev = last(channel);
+    let seq = prev.map_or(1, |p| p + 1);
+
+    if let Err(e) = store.insert(&key(channel), seq.to_string()) {
+        log_error!("failed to save sequence number of {channel}: {e}");
+    }
+
+    Some((seq, prev))
+}
+
+/// Returns the `prev-id` to subscribe holds to `channel` with, when prev-id
+/// chaining is on and the channel has been published to.
+pub fn prev_id(channel: &str) -> Option<String> {
+    if !chaining() {
+        return None;
+    }
+    last(channel).map(|seq| seq.to_string())
+}
+
+/// Numbers an item, making its channel's next number its id and the one
+/// before its prev-id. The SSE `id:` fields and `Event-ID` header the item
+/// carries are updated to match. Returns whether it was numbered.
+pub fn chain(item: &mut Item) -> bool {
+    let (seq, prev) = match next(&item.channel) {
+        Some(next) => next,
+        None => return false,
+    };
+    let id = seq.to_string();
+
+    if let Some(stream) = &mut item.formats.http_stream {
+        stream.content = stream
+            .content
+            .split_inclusive('\n')
+            .map(|line| {
+                if line.starts_with("id:") {
+                    format!("id: {}\n", id)
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect();
+    }
+
+    if let Some(response) = &mut item.formats.http_response {
+        for (name, value) in &mut response.headers {
+            if name.eq_ignore_ascii_case("Event-ID") {
+                *value = id.clone();
+            }
+        }
+    }
+
+    item.id = Some(id);
+    item.prev_id = prev.map(|p| p.to_string());
+    true
+}
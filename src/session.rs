@@ -0,0 +1,97 @@
+//! Per-connection WebSocket session state.
+//!
+//! WebSocket-over-HTTP requests are independent of each other, so anything a
+//! handler needs to remember about a connection between events is kept in
+//! the state KV Store, keyed by the connection's `Connection-Id`. Small
+//! values that are needed on every request are better kept as connection
+//! meta values (see [`crate::ws::WsContext::set_meta`]), which cost no
+//! lookups.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::config;
+use crate::{log_error, log_warn};
+
+/// State kept for a WebSocket connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    /// Channels the connection is subscribed to.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Who the connection is authenticated as, if anyone.
+    #[serde(default)]
+    pub identity: Option<String>,
+    /// When the credentials the connection was opened with expire, as
+    /// seconds since the Unix epoch.
+    #[serde(default)]
+    pub token_exp: Option<u64>,
+    /// Handler-defined counters, e.g. of messages received.
+    #[serde(default)]
+    pub counters: BTreeMap<String, u64>,
+}
+
+impl Session {
+    /// Loads the session of a connection, or returns an empty one if there
+    /// is none or the state store isn't linked.
+    pub fn load(connection_id: &str) -> Self {
+        config::state_store()
+            .and_then(|store| store.lookup_str(&key(connection_id)).ok())
+            .flatten()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    /// Stores the session of a connection.
+    pub fn save(&self, connection_id: &str) {
+        let mut store = match config::state_store() {
+            Some(store) => store,
+            None => {
+                log_warn!("no state store, websocket session is not kept");
+                return;
+            }
+        };
+
+        let value = serde_json::to_string(self).expect("sessions always serialize");
+        if let Err(e) = store.insert(&key(connection_id), value) {
+            log_error!("failed to save session of connection {connection_id}: {e}");
+        }
+    }
+
+    /// Removes the session of a connection that has gone away.
+    pub fn delete(connection_id: &str) {
+        let store = match config::state_store() {
+            Some(store) => store,
+            None => return,
+        };
+
+        if let Err(e) = store.delete(&key(connection_id)) {
+            log_error!("failed to delete session of connection {connection_id}: {e}");
+        }
+    }
+
+    /// Adds a channel to the subscribed ones, returning whether it is new.
+    pub fn subscribe(&mut self, channel: &str) -> bool {
+        if self.channels.iter().any(|c| c == channel) {
+            return false;
+        }
+        self.channels.push(channel.to_string());
+        true
+    }
+
+    /// Removes a channel from the subscribed ones.
+    pub fn unsubscribe(&mut self, channel: &str) {
+        self.channels.retain(|c| c != channel);
+    }
+
+    /// Increments a counter, returning its new value.
+    pub fn incr(&mut self, counter: &str) -> u64 {
+        let n = self.counters.entry(counter.to_string()).or_default();
+        *n += 1;
+        *n
+    }
+}
+
+fn key(connection_id: &str) -> String {
+    format!("session:{}", connection_id)
+}
@@ -0,0 +1,114 @@
+//! Signing requests forwarded to origins.
+//!
+//! So origins can check that requests really came through this app, the
+//! requests sent to them can carry an HMAC-SHA256 signature over the
+//! method, the path (with the query string) and the `Date` header, which
+//! the app sets. The signed string is these joined by newlines:
+//!
+//! ```text
+//! GET
+//! /api/items?page=2
+//! Tue, 15 Oct 2024 12:00:00 GMT
+//! ```
+//!
+//! and the signature is sent hex-encoded along with the id of the key used:
+//!
+//! ```text
+//! X-Edge-Signature: keyid=v2, signature=6f3c...
+//! ```
+//!
+//! Signing is enabled by the `edge_signing_key_id` setting, naming the
+//! secret `edge_signing_key_{id}` to sign with. Keys are rotated by adding
+//! the secret for a new id and switching the setting over, with origins
+//! accepting both ids in the meantime.
+
+use fastly::Request;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config;
+use crate::grip::unix_now;
+use crate::hex;
+use crate::log_warn;
+
+/// Header carrying the signature.
+pub const SIGNATURE_HEADER: &str = "X-Edge-Signature";
+
+/// Setting naming the key requests are signed with.
+pub const KEY_ID_SETTING: &str = "edge_signing_key_id";
+
+/// Prefix of the secrets holding signing keys.
+pub const KEY_SECRET_PREFIX: &str = "edge_signing_key_";
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats seconds since the Unix epoch as an HTTP date, such as
+/// `Tue, 15 Oct 2024 12:00:00 GMT`.
+pub fn http_date(secs: u64) -> String {
+    let days = secs / 86400;
+    let rem = secs % 86400;
+
+    // civil date from days since the epoch, in 400 year eras from 0000-03-01
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Returns the hex HMAC-SHA256 signature of a request.
+pub fn signature(key: &[u8], method: &str, path: &str, date: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(format!("{}\n{}\n{}", method, path, date).as_bytes());
+    hex::encode(&mac.finalize().into_bytes())
+}
+
+/// Signs a request about to be sent to an origin, if signing is enabled.
+/// Signatures sent by clients are always removed.
+pub fn sign(req: &mut Request) {
+    req.remove_header(SIGNATURE_HEADER);
+
+    let key_id = match config::setting(KEY_ID_SETTING) {
+        Some(id) if !id.is_empty() => id,
+        _ => return,
+    };
+
+    let key = match config::secret(&format!("{}{}", KEY_SECRET_PREFIX, key_id)) {
+        Some(key) if !key.is_empty() => key,
+        _ => {
+            log_warn!("not signing request, no secret for signing key {key_id}");
+            return;
+        }
+    };
+
+    let date = http_date(unix_now());
+    let path = match req.get_query_str() {
+        Some(query) => format!("{}?{}", req.get_path(), query),
+        None => req.get_path().to_string(),
+    };
+    let sig = signature(&key, req.get_method_str(), &path, &date);
+
+    req.set_header("Date", date);
+    req.set_header(
+        SIGNATURE_HEADER,
+        format!("keyid={}, signature={}", key_id, sig),
+    );
+}
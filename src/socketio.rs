@@ -0,0 +1,437 @@
+//! Socket.IO compatibility.
+//!
+//! Implements enough of Engine.IO 4 and Socket.IO 5 for existing Socket.IO
+//! clients to connect to `/socket.io/` and exchange events through GRIP
+//! channels. Each namespace maps onto a GRIP channel (see
+//! [`grip_channel`]): clients connecting to a namespace are subscribed to
+//! it, and events they emit are published to it through the configured
+//! [`Publisher`], reaching every client connected to the namespace. Server
+//! code can emit to a namespace by publishing [`event_packet`] content.
+//!
+//! Both transports are supported:
+//!
+//! * `polling`, via GET requests held on the namespaces' channels, with the
+//!   session's namespaces and queued replies kept in the state KV Store.
+//!   Replies to packets sent with POST are queued there, and a noop packet
+//!   is published on the session's own channel to wake up its poll.
+//! * `websocket`, via WebSocket-over-HTTP, either directly or upgraded from
+//!   a polling session. Fanout sends Engine.IO pings as keep-alives.
+//!
+//! Acknowledgements are answered right away with no arguments, rooms and
+//! binary attachments aren't supported.
+
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config;
+use crate::grip::{GripControl, GripResponseBuilder, KeepAlive, MessageType};
+use crate::hex;
+use crate::publish::{Item, Publisher};
+use crate::router::Route;
+use crate::ws::{self, WsContext, WsHandler};
+use crate::ws_events;
+use crate::{log_debug, log_error, log_warn};
+
+/// Prefix of the GRIP channels namespaces are mapped onto.
+pub const GRIP_CHANNEL_PREFIX: &str = "socketio";
+
+/// Seconds between the pings clients are sent.
+pub const PING_INTERVAL: u32 = 25;
+
+/// Seconds clients wait for a ping beyond the interval before giving up.
+pub const PING_TIMEOUT: u32 = 20;
+
+/// Largest payload clients may send, in bytes.
+const MAX_PAYLOAD: usize = 1_000_000;
+
+/// Separates packets in a polling payload.
+const RECORD_SEPARATOR: char = '\x1e';
+
+/// Meta value holding the session id of a WebSocket connection.
+const SID_META: &str = "sio-sid";
+
+// Engine.IO packet types
+const OPEN: char = '0';
+const CLOSE: char = '1';
+const PING: char = '2';
+const PONG: char = '3';
+const MESSAGE: char = '4';
+const NOOP: char = '6';
+
+// Socket.IO packet types
+const CONNECT: char = '0';
+const DISCONNECT: char = '1';
+const EVENT: char = '2';
+const ACK: char = '3';
+
+/// Returns the GRIP channel a namespace is mapped onto, e.g. `socketio/`
+/// for the main namespace and `socketio/chat` for `/chat`.
+pub fn grip_channel(namespace: &str) -> String {
+    format!("{}{}", GRIP_CHANNEL_PREFIX, namespace)
+}
+
+/// Returns the channel only the session `sid` is subscribed to.
+fn session_channel(sid: &str) -> String {
+    format!("{}-session/{}", GRIP_CHANNEL_PREFIX, sid)
+}
+
+/// Returns the Engine.IO packet emitting an event to the clients of a
+/// namespace, for publishing on its channel.
+pub fn event_packet(namespace: &str, event: &str, data: &serde_json::Value) -> String {
+    socket_packet(EVENT, namespace, &json!([event, data]).to_string())
+}
+
+fn socket_packet(kind: char, namespace: &str, data: &str) -> String {
+    if namespace == "/" {
+        format!("{}{}{}", MESSAGE, kind, data)
+    } else {
+        format!("{}{}{},{}", MESSAGE, kind, namespace, data)
+    }
+}
+
+fn open_packet(sid: &str, upgrades: &[&str]) -> String {
+    let handshake = json!({
+        "sid": sid,
+        "upgrades": upgrades,
+        "pingInterval": PING_INTERVAL * 1000,
+        "pingTimeout": PING_TIMEOUT * 1000,
+        "maxPayload": MAX_PAYLOAD,
+    });
+    format!("{}{}", OPEN, handshake)
+}
+
+fn new_sid() -> String {
+    hex::random(16)
+}
+
+/// A Socket.IO packet, as carried in an Engine.IO message.
+#[derive(Debug, PartialEq, Eq)]
+struct SocketPacket<'a> {
+    kind: char,
+    namespace: &'a str,
+    ack_id: Option<&'a str>,
+    data: &'a str,
+}
+
+fn parse_socket_packet(s: &str) -> Option<SocketPacket<'_>> {
+    let kind = s.chars().next()?;
+    let mut rest = &s[kind.len_utf8()..];
+
+    let mut namespace = "/";
+    if rest.starts_with('/') {
+        let end = rest.find(',').unwrap_or(rest.len());
+        namespace = &rest[..end];
+        rest = rest.get(end + 1..).unwrap_or("");
+    }
+
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let ack_id = Some(&rest[..digits]).filter(|id| !id.is_empty());
+
+    Some(SocketPacket {
+        kind,
+        namespace,
+        ack_id,
+        data: &rest[digits..],
+    })
+}
+
+/// What handling a client's packets asks of the transport.
+#[derive(Debug, Default)]
+struct Outcome {
+    replies: Vec<String>,
+    joined: Vec<String>,
+    left: Vec<String>,
+    closed: bool,
+}
+
+fn process(packets: &[&str], sid: &str, route: &Route) -> Outcome {
+    let mut out = Outcome::default();
+    let mut publisher = None;
+
+    for packet in packets {
+        let mut chars = packet.chars();
+        match chars.next() {
+            Some(PING) => {
+                out.replies.push(format!("{}{}", PONG, chars.as_str()));
+                continue;
+            }
+            Some(CLOSE) => {
+                out.closed = true;
+                continue;
+            }
+            Some(MESSAGE) => {}
+            _ => continue,
+        }
+
+        let socket = match parse_socket_packet(chars.as_str()) {
+            Some(socket) => socket,
+            None => continue,
+        };
+
+        match socket.kind {
+            CONNECT => {
+                let data = json!({ "sid": sid }).to_string();
+                out.replies
+                    .push(socket_packet(CONNECT, socket.namespace, &data));
+                out.joined.push(socket.namespace.to_string());
+            }
+            DISCONNECT => out.left.push(socket.namespace.to_string()),
+            EVENT => {
+                if let Some(id) = socket.ack_id {
+                    out.replies
+                        .push(socket_packet(ACK, socket.namespace, &format!("{}[]", id)));
+                }
+
+                let publisher = match publisher.get_or_insert_with(|| Publisher::for_route(route)) {
+                    Some(publisher) => publisher,
+                    None => {
+                        log_warn!("dropping socket.io event, publishing is not configured");
+                        continue;
+                    }
+                };
+
+                let content = socket_packet(EVENT, socket.namespace, socket.data);
+                let item = Item::new(route.channel(&grip_channel(socket.namespace)))
+                    .http_response(content.clone())
+                    .ws_message(content);
+                if let Err(e) = publisher.publish(item) {
+                    log_error!("failed to publish socket.io event: {e}");
+                }
+            }
+            _ => log_debug!("ignoring socket.io packet type {}", socket.kind),
+        }
+    }
+
+    out
+}
+
+/// State of a polling session.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    #[serde(default)]
+    namespaces: Vec<String>,
+    /// Packets waiting for the next poll.
+    #[serde(default)]
+    pending: Vec<String>,
+}
+
+fn state_key(sid: &str) -> String {
+    format!("socketio:{}", sid)
+}
+
+fn load_state(sid: &str) -> State {
+    config::state_store()
+        .and_then(|store| store.lookup_str(&state_key(sid)).ok())
+        .flatten()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn save_state(sid: &str, state: Option<&State>) {
+    let mut store = match config::state_store() {
+        Some(store) => store,
+        None => {
+            log_warn!("no state store, socket.io polling sessions are not kept");
+            return;
+        }
+    };
+
+    let key = state_key(sid);
+    let result = match state {
+        Some(state) => store.insert(
+            &key,
+            serde_json::to_string(state).expect("state serializes"),
+        ),
+        None => store.delete(&key),
+    };
+
+    if let Err(e) = result {
+        log_error!("failed to save socket.io session {sid}: {e}");
+    }
+}
+
+fn text_response(status: StatusCode, body: impl Into<String>) -> Response {
+    Response::from_status(status)
+        .with_header("Content-Type", "text/plain; charset=UTF-8")
+        .with_body(body.into())
+}
+
+fn error_response(code: u8, message: &str) -> Response {
+    Response::from_status(StatusCode::BAD_REQUEST)
+        .with_header("Content-Type", "application/json")
+        .with_body(json!({ "code": code, "message": message }).to_string())
+}
+
+/// Handles a polling request forwarded by Fanout.
+fn handle_polling(mut req: Request, route: &Route) -> Response {
+    let sid = match req.get_query_parameter("sid") {
+        Some(sid) => sid.to_string(),
+        None if req.get_method() == Method::GET => {
+            let sid = new_sid();
+            save_state(&sid, Some(&State::default()));
+            return text_response(StatusCode::OK, open_packet(&sid, &["websocket"]));
+        }
+        None => return error_response(1, "Session ID unknown"),
+    };
+
+    let mut state = load_state(&sid);
+
+    if req.get_method() == Method::POST {
+        let body = req.take_body_str();
+        let packets: Vec<&str> = body.split(RECORD_SEPARATOR).collect();
+        let out = process(&packets, &sid, route);
+
+        if out.closed {
+            save_state(&sid, None);
+            return text_response(StatusCode::OK, "ok");
+        }
+
+        state.namespaces.retain(|ns| !out.left.contains(ns));
+        for ns in out.joined {
+            if !state.namespaces.contains(&ns) {
+                state.namespaces.push(ns);
+            }
+        }
+
+        let wake = !out.replies.is_empty();
+        state.pending.extend(out.replies);
+        save_state(&sid, Some(&state));
+
+        // a poll held before the replies were queued gets a noop, and the
+        // client polls again right away to pick them up
+        if wake {
+            if let Some(publisher) = Publisher::from_config() {
+                let item = Item::new(route.channel(&session_channel(&sid)))
+                    .http_response(NOOP.to_string());
+                if let Err(e) = publisher.publish(item) {
+                    log_error!("failed to wake socket.io session {sid}: {e}");
+                }
+            }
+        }
+
+        return text_response(StatusCode::OK, "ok");
+    }
+
+    if !state.pending.is_empty() {
+        let pending = std::mem::take(&mut state.pending);
+        save_state(&sid, Some(&state));
+        return text_response(StatusCode::OK, pending.join(&RECORD_SEPARATOR.to_string()));
+    }
+
+    let mut channels: Vec<String> = state
+        .namespaces
+        .iter()
+        .map(|ns| route.channel(&grip_channel(ns)))
+        .collect();
+    channels.push(route.channel(&session_channel(&sid)));
+
+    // the hold times out with a ping, which the client answers before
+    // polling again
+    GripResponseBuilder::new()
+        .content_type("text/plain; charset=UTF-8")
+        .hold_response()
+        .channels(&channels)
+        .timeout(PING_INTERVAL)
+        .max_timeouts(&route.timeouts)
+        .body(PING.to_string())
+        .build()
+}
+
+/// Serves Socket.IO over a WebSocket connection.
+struct SocketIoWs<'a> {
+    route: &'a Route,
+    /// Session being upgraded from polling, if any.
+    upgrading: Option<String>,
+}
+
+impl WsHandler for SocketIoWs<'_> {
+    fn on_open(&mut self, ctx: &mut WsContext) {
+        let sid = match self.upgrading.take() {
+            Some(sid) => sid,
+            None => {
+                let sid = new_sid();
+                ctx.out.write_text(&open_packet(&sid, &[]));
+                sid
+            }
+        };
+        ctx.set_meta(SID_META, &sid);
+
+        let ping = KeepAlive::new(PING.to_string()).with_timeout(PING_INTERVAL);
+        ctx.out.write_control(&ping.to_control(MessageType::Text));
+    }
+
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        let sid = ctx.meta(SID_META).unwrap_or_default().to_string();
+
+        match text.as_str() {
+            "2probe" => {
+                ctx.out.write_text("3probe");
+                return;
+            }
+            "5" => {
+                // the polling session moves over to this connection
+                let state = load_state(&sid);
+                let channels: Vec<String> = state
+                    .namespaces
+                    .iter()
+                    .map(|ns| self.route.channel(&grip_channel(ns)))
+                    .collect();
+                ctx.subscribe(&channels);
+                for packet in &state.pending {
+                    ctx.out.write_text(packet);
+                }
+                save_state(&sid, None);
+                return;
+            }
+            _ => {}
+        }
+
+        let out = process(&[text.as_str()], &sid, self.route);
+        for reply in &out.replies {
+            ctx.out.write_text(reply);
+        }
+
+        let route = self.route;
+        let joined: Vec<String> = out
+            .joined
+            .iter()
+            .map(|ns| route.channel(&grip_channel(ns)))
+            .collect();
+        let left: Vec<String> = out
+            .left
+            .iter()
+            .map(|ns| route.channel(&grip_channel(ns)))
+            .collect();
+        ctx.subscribe(&joined);
+        ctx.unsubscribe(&left);
+
+        if out.closed {
+            ctx.out.write_control(&GripControl::Close {
+                code: None,
+                reason: None,
+            });
+        }
+    }
+}
+
+/// Handles a Socket.IO request forwarded by Fanout, using the transport
+/// matching the request.
+pub fn handle(req: Request, route: &Route) -> Response {
+    if req.get_query_parameter("EIO") != Some("4") {
+        return error_response(5, "Unsupported protocol version");
+    }
+
+    if req.get_header_str("Content-Type") == Some(ws_events::CONTENT_TYPE) {
+        let upgrading = req.get_query_parameter("sid").map(str::to_string);
+        return ws::serve(req, &route.limits, &mut SocketIoWs { route, upgrading });
+    }
+
+    match req.get_query_parameter("transport") {
+        Some("polling") => handle_polling(req, route),
+        _ => error_response(0, "Transport unknown"),
+    }
+}
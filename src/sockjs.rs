@@ -0,0 +1,244 @@
+//! SockJS transports backed by GRIP.
+//!
+//! Legacy SockJS clients can use this app as their server at `/sockjs`. The
+//! `xhr_streaming` and `eventsource` transports are stream holds and the
+//! `websocket` transport is served over WebSocket-over-HTTP, with Fanout
+//! sending the heartbeat frames as keep-alives.
+//!
+//! SockJS has no notion of channels, so every session receives what is
+//! published to one broadcast channel, and messages sent by clients are
+//! published there through the configured [`Publisher`]. The eventsource
+//! transport frames messages differently from the others, so its sessions
+//! hold a channel of their own; [`publish_items`] returns the items
+//! delivering a message on both.
+
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use serde_json::json;
+
+use crate::error::AppError;
+use crate::grip::{GripResponseBuilder, KeepAlive, MessageType};
+use crate::publish::{Item, Publisher};
+use crate::router::Route;
+use crate::ws::{self, WsContext, WsHandler};
+use crate::{log_error, log_warn};
+
+/// Path prefix the SockJS endpoints are served under.
+pub const PATH_PREFIX: &str = "/sockjs";
+
+/// GRIP channel delivering messages to `xhr_streaming` and `websocket`
+/// sessions.
+pub const CHANNEL: &str = "sockjs";
+
+/// GRIP channel delivering messages to `eventsource` sessions.
+pub const EVENTSOURCE_CHANNEL: &str = "sockjs-eventsource";
+
+/// Seconds between heartbeat frames.
+const HEARTBEAT_INTERVAL: u32 = 25;
+
+/// Bytes of `h` sent before the first frame of an `xhr_streaming`
+/// response, to get past browsers buffering the start of it.
+const XHR_STREAMING_PRELUDE: usize = 2048;
+
+const OPEN_FRAME: &str = "o";
+const HEARTBEAT_FRAME: &str = "h";
+
+/// Returns the frame carrying messages to a client.
+pub fn message_frame<S: AsRef<str>>(messages: &[S]) -> String {
+    let messages: Vec<&str> = messages.iter().map(AsRef::as_ref).collect();
+    format!("a{}", json!(messages))
+}
+
+/// Returns the frame closing a session.
+fn close_frame(code: u16, reason: &str) -> String {
+    format!("c{}", json!([code, reason]))
+}
+
+fn eventsource_event(frame: &str) -> String {
+    format!("data: {}\r\n\r\n", frame)
+}
+
+/// Returns the items delivering `message` to all SockJS sessions, framed
+/// for each kind of transport.
+pub fn publish_items(route: &Route, message: &str) -> Vec<Item> {
+    let frame = message_frame(&[message]);
+    vec![
+        Item::new(route.channel(CHANNEL))
+            .http_stream(format!("{}\n", frame))
+            .ws_message(frame.as_str()),
+        Item::new(route.channel(EVENTSOURCE_CHANNEL)).http_stream(eventsource_event(&frame)),
+    ]
+}
+
+/// Publishes messages sent by a client.
+fn relay(route: &Route, messages: &[String]) {
+    let publisher = match Publisher::for_route(route) {
+        Some(publisher) => publisher,
+        None => {
+            log_warn!("dropping sockjs messages, publishing is not configured");
+            return;
+        }
+    };
+
+    let items: Vec<Item> = messages
+        .iter()
+        .flat_map(|m| publish_items(route, m))
+        .collect();
+    if let Err(e) = publisher.publish_items(&items) {
+        log_error!("failed to publish sockjs messages: {e}");
+    }
+}
+
+/// Parses the messages of an `xhr_send` body or a WebSocket frame, a JSON
+/// array of strings or a single string.
+fn parse_messages(body: &str) -> Option<Vec<String>> {
+    match serde_json::from_str(body) {
+        Ok(messages) => Some(messages),
+        Err(_) => serde_json::from_str::<String>(body).ok().map(|m| vec![m]),
+    }
+}
+
+fn info() -> Response {
+    let mut entropy = [0u8; 4];
+    getrandom::getrandom(&mut entropy).expect("random source available");
+
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "application/json; charset=UTF-8")
+        .with_header(
+            "Cache-Control",
+            "no-store, no-cache, must-revalidate, max-age=0",
+        )
+        .with_body(
+            json!({
+                "websocket": true,
+                "origins": ["*:*"],
+                "cookie_needed": false,
+                "entropy": u32::from_be_bytes(entropy),
+            })
+            .to_string(),
+        )
+}
+
+fn xhr_streaming(route: &Route) -> Response {
+    let mut body = "h".repeat(XHR_STREAMING_PRELUDE);
+    body.push('\n');
+    body.push_str(OPEN_FRAME);
+    body.push('\n');
+
+    GripResponseBuilder::new()
+        .content_type("application/javascript; charset=UTF-8")
+        .hold_stream()
+        .channel(&route.channel(CHANNEL))
+        .keep_alive(
+            KeepAlive::new(format!("{}\n", HEARTBEAT_FRAME)).with_timeout(HEARTBEAT_INTERVAL),
+        )
+        .max_timeouts(&route.timeouts)
+        .body(body)
+        .build()
+}
+
+fn eventsource(route: &Route) -> Response {
+    let mut body = "\r\n".to_string();
+    body.push_str(&eventsource_event(OPEN_FRAME));
+
+    GripResponseBuilder::new()
+        .content_type("text/event-stream; charset=UTF-8")
+        .hold_stream()
+        .channel(&route.channel(EVENTSOURCE_CHANNEL))
+        .keep_alive(
+            KeepAlive::new(eventsource_event(HEARTBEAT_FRAME)).with_timeout(HEARTBEAT_INTERVAL),
+        )
+        .max_timeouts(&route.timeouts)
+        .body(body)
+        .build()
+}
+
+fn xhr_send(mut req: Request, route: &Route) -> Response {
+    let body = req.take_body_str();
+    if body.is_empty() {
+        return Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            .with_body("Payload expected.");
+    }
+
+    match parse_messages(&body) {
+        Some(messages) => {
+            relay(route, &messages);
+            Response::from_status(StatusCode::NO_CONTENT)
+                .with_header("Content-Type", "text/plain; charset=UTF-8")
+        }
+        None => Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            .with_body("Broken JSON encoding."),
+    }
+}
+
+/// Serves the SockJS `websocket` transport.
+struct SockJsWs<'a> {
+    route: &'a Route,
+}
+
+impl WsHandler for SockJsWs<'_> {
+    fn on_open(&mut self, ctx: &mut WsContext) {
+        ctx.out.write_text(OPEN_FRAME);
+        ctx.subscribe(&[self.route.channel(CHANNEL)]);
+
+        let heartbeat = KeepAlive::new(HEARTBEAT_FRAME).with_timeout(HEARTBEAT_INTERVAL);
+        ctx.out
+            .write_control(&heartbeat.to_control(MessageType::Text));
+    }
+
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        if text.is_empty() {
+            return;
+        }
+
+        match parse_messages(&text) {
+            Some(messages) => relay(self.route, &messages),
+            None => {
+                // broken frames end the session, as with other servers
+                ctx.out.write_text(&close_frame(3000, "Broken framing."));
+                ctx.out.write_close(3000);
+            }
+        }
+    }
+}
+
+/// Handles a SockJS request forwarded by Fanout. Session paths have the
+/// form `/sockjs/{server}/{session}/{transport}`.
+pub fn handle(req: Request, route: &Route) -> Response {
+    let path = req.get_path().to_string();
+    let rest = path
+        .strip_prefix(PATH_PREFIX)
+        .unwrap_or_default()
+        .trim_start_matches('/');
+
+    if rest.is_empty() {
+        return Response::from_status(StatusCode::OK)
+            .with_header("Content-Type", "text/plain; charset=UTF-8")
+            .with_body("Welcome to SockJS!\n");
+    }
+
+    if rest == "info" {
+        return match *req.get_method() {
+            Method::GET => info(),
+            _ => Response::from_status(StatusCode::NO_CONTENT),
+        };
+    }
+
+    let parts: Vec<&str> = rest.split('/').collect();
+    let transport = match parts.as_slice() {
+        [server, session, transport]
+            if !server.is_empty() && !session.is_empty() && !session.contains('.') =>
+        {
+            *transport
+        }
+        _ => return AppError::RoutingError("No SockJS endpoint here.".into()).response(),
+    };
+
+    match (req.get_method(), transport) {
+        (&Method::POST, "xhr_streaming") => xhr_streaming(route),
+        (&Method::GET, "eventsource") => eventsource(route),
+        (&Method::POST, "xhr_send") => xhr_send(req, route),
+        (_, "websocket") => ws::serve(req, &route.limits, &mut SockJsWs { route }),
+        _ => AppError::RoutingError("No SockJS endpoint here.".into()).response(),
+    }
+}
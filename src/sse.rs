@@ -0,0 +1,172 @@
+//! Server-Sent Events formatting.
+//!
+//! Events are written the same way whether they open a held stream or are
+//! published as `http-stream` content, so both go through [`SseEvent`].
+
+use serde::Deserialize;
+use std::fmt;
+
+/// Bytes of padding opening a stream unless configured otherwise.
+pub const DEFAULT_PADDING: usize = 2048;
+
+/// An event in a `text/event-stream`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    /// Reconnection delay in milliseconds.
+    pub retry: Option<u32>,
+}
+
+impl SseEvent {
+    pub fn new(data: impl Into<String>) -> Self {
+        SseEvent {
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: u32) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Returns the event as it is written to the stream, ending with the
+    /// blank line that dispatches it.
+    pub fn encode(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for SseEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(id) = &self.id {
+            // clients ignore ids containing NUL
+            writeln!(f, "id: {}", single_line(id).replace('\0', ""))?;
+        }
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", single_line(event))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry)?;
+        }
+
+        // each line of the data gets its own field, which the client joins
+        // back together with newlines
+        for line in lines(&self.data) {
+            writeln!(f, "data: {}", line)?;
+        }
+
+        writeln!(f)
+    }
+}
+
+/// Splits text on any of the line endings the event stream format accepts.
+fn lines(s: &str) -> Vec<String> {
+    s.replace("\r\n", "\n")
+        .split(['\r', '\n'])
+        .map(str::to_string)
+        .collect()
+}
+
+/// Folds a field value onto one line, since a line break would end it.
+fn single_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+/// Returns a comment, which clients ignore.
+pub fn comment(text: &str) -> String {
+    let mut out = String::new();
+    for line in lines(text) {
+        out.push(':');
+        if !line.is_empty() {
+            out.push(' ');
+            out.push_str(&line);
+        }
+        out.push('\n');
+    }
+    out.push('\n');
+    out
+}
+
+/// Returns a comment of about `size` bytes, sent first so that proxies and
+/// browsers that buffer the start of a response pass the stream on right
+/// away.
+pub fn padding(size: usize) -> String {
+    let mut out = String::with_capacity(size + 3);
+    out.push(':');
+    out.push_str(&" ".repeat(size));
+    out.push_str("\n\n");
+    out
+}
+
+/// Returns a directive setting the client's reconnection delay, without
+/// dispatching an event.
+pub fn retry(ms: u32) -> String {
+    format!("retry: {}\n\n", ms)
+}
+
+/// How a route's SSE streams are opened, set with the `sse` field of the
+/// route.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct StreamOptions {
+    /// Bytes of padding sent first, `0` for none.
+    pub padding: usize,
+    /// Reconnection delay in milliseconds to send to clients.
+    pub retry: Option<u32>,
+    /// Whether to send an `open` event once the stream is established.
+    pub open_event: bool,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions {
+            padding: DEFAULT_PADDING,
+            retry: None,
+            open_event: false,
+        }
+    }
+}
+
+impl StreamOptions {
+    /// Returns what a new stream starts with, ahead of any events.
+    pub fn preamble(&self) -> String {
+        let mut body = initial_body(self.padding, &[]);
+        match (self.open_event, self.retry) {
+            (true, retry) => {
+                let mut open = SseEvent::new("").with_event("open");
+                open.retry = retry;
+                body.push_str(&open.encode());
+            }
+            (false, Some(ms)) => body.push_str(&retry(ms)),
+            (false, None) => {}
+        }
+        body
+    }
+}
+
+/// Returns the body that opens a held stream: the padding followed by any
+/// events to send before published ones.
+pub fn initial_body(padding_size: usize, events: &[SseEvent]) -> String {
+    let mut body = if padding_size > 0 {
+        padding(padding_size)
+    } else {
+        String::new()
+    };
+    for event in events {
+        body.push_str(&event.encode());
+    }
+    body
+}
@@ -0,0 +1,480 @@
+//! STOMP 1.2 over WebSocket.
+//!
+//! Lets message-broker-style clients connect to `/stomp`. Destinations map
+//! onto GRIP channels (see [`grip_channel`]): SUBSCRIBE and UNSUBSCRIBE
+//! become subscribe and unsubscribe control messages, and SEND is published
+//! through the configured [`Publisher`] as a MESSAGE frame for the
+//! destination's subscribers. Receipts are sent for any frame asking for
+//! one, and heart-beats from the server are sent by Fanout as keep-alives.
+//!
+//! MESSAGE frames name the subscription they are delivered for, which the
+//! client chose. As with GraphQL subscriptions, each connection keeps the id
+//! of its subscription to a destination in a meta value, which Fanout
+//! substitutes into published frames through the `var-subst` filter.
+//!
+//! Acknowledgements and transactions aren't supported: subscriptions behave
+//! as `ack:auto`, and frames sent in a transaction are published right away.
+
+use fastly::{Request, Response};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::grip::{GripControl, KeepAlive, MessageType, FILTER_VAR_SUBST};
+use crate::history;
+use crate::publish::{Item, Publisher};
+use crate::router::Route;
+use crate::ws::{self, WsContext, WsHandler};
+use crate::{log_debug, log_error, log_warn};
+
+/// The subprotocol implemented by this module.
+pub const PROTOCOL: &str = "v12.stomp";
+
+/// Prefix of the GRIP channels destinations are mapped onto.
+pub const GRIP_CHANNEL_PREFIX: &str = "stomp";
+
+/// Milliseconds between the heart-beats we send, at the least.
+const HEART_BEAT_INTERVAL: u32 = 10_000;
+
+/// Meta value set once the client has connected.
+const CONNECTED_META: &str = "stomp-connected";
+
+/// Meta value holding the connection's subscriptions, a JSON object from
+/// subscription id to destination.
+const SUBS_META: &str = "stomp-subs";
+
+/// Returns the GRIP channel a destination is mapped onto, e.g.
+/// `stomp/topic/news` for `/topic/news`.
+pub fn grip_channel(destination: &str) -> String {
+    if destination.starts_with('/') {
+        format!("{}{}", GRIP_CHANNEL_PREFIX, destination)
+    } else {
+        format!("{}/{}", GRIP_CHANNEL_PREFIX, destination)
+    }
+}
+
+/// Returns the name of the meta value holding the id of a connection's
+/// subscription to `destination`.
+pub fn subscription_meta_name(destination: &str) -> String {
+    let name: String = destination
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("stomp-sub{}", name)
+}
+
+/// Error parsing a STOMP frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame has no command line.
+    MissingCommand,
+    /// A header line has no colon.
+    BadHeader(String),
+    /// A header value uses an undefined escape sequence.
+    BadEscape(String),
+    /// The frame isn't terminated by a NUL byte.
+    Unterminated,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::MissingCommand => write!(f, "frame has no command"),
+            FrameError::BadHeader(line) => write!(f, "malformed header line {:?}", line),
+            FrameError::BadEscape(value) => write!(f, "undefined escape in {:?}", value),
+            FrameError::Unterminated => write!(f, "frame is not NUL-terminated"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// A STOMP frame.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Frame {
+    pub command: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(command: &str) -> Self {
+        Frame {
+            command: command.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Returns the value of a header. Repeated headers take their first
+    /// value, as the specification requires.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether header values of this frame are escaped, which CONNECT and
+    /// CONNECTED frames are not.
+    fn escapes_headers(command: &str) -> bool {
+        command != "CONNECT" && command != "CONNECTED"
+    }
+
+    /// Parses a frame, ignoring the heart-beat EOLs that may precede it.
+    pub fn parse(data: &[u8]) -> Result<Option<Frame>, FrameError> {
+        let start = data
+            .iter()
+            .position(|&b| b != b'\n' && b != b'\r')
+            .unwrap_or(data.len());
+        let data = &data[start..];
+        if data.is_empty() {
+            return Ok(None);
+        }
+
+        let header_end = find(data, b"\n\n")
+            .map(|i| (i, i + 2))
+            .into_iter()
+            .chain(find(data, b"\r\n\r\n").map(|i| (i, i + 4)))
+            .min()
+            .unwrap_or((data.len(), data.len()));
+        let head = String::from_utf8_lossy(&data[..header_end.0]);
+        let mut lines = head.lines();
+
+        let command = match lines.next() {
+            Some(command) if !command.is_empty() => command.to_string(),
+            _ => return Err(FrameError::MissingCommand),
+        };
+        let escaped = Self::escapes_headers(&command);
+
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| FrameError::BadHeader(line.to_string()))?;
+            if escaped {
+                headers.push((unescape(name)?, unescape(value)?));
+            } else {
+                headers.push((name.to_string(), value.to_string()));
+            }
+        }
+
+        let rest = &data[header_end.1.min(data.len())..];
+        let content_length = headers
+            .iter()
+            .find(|(n, _)| n == "content-length")
+            .and_then(|(_, v)| v.parse::<usize>().ok());
+        let body = match content_length {
+            Some(len) if rest.get(len) == Some(&0) => rest[..len].to_vec(),
+            Some(_) => return Err(FrameError::Unterminated),
+            None => match rest.iter().position(|&b| b == 0) {
+                Some(end) => rest[..end].to_vec(),
+                None => return Err(FrameError::Unterminated),
+            },
+        };
+
+        Ok(Some(Frame {
+            command,
+            headers,
+            body,
+        }))
+    }
+
+    /// Returns the frame as sent over the wire.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let escaped = Self::escapes_headers(&self.command);
+
+        let mut out = self.command.clone();
+        out.push('\n');
+        for (name, value) in &self.headers {
+            if escaped {
+                out.push_str(&format!("{}:{}\n", escape(name), escape(value)));
+            } else {
+                out.push_str(&format!("{}:{}\n", name, value));
+            }
+        }
+        out.push('\n');
+
+        let mut out = out.into_bytes();
+        out.extend(&self.body);
+        out.push(0);
+        out
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            ':' => out.push_str("\\c"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Result<String, FrameError> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some('c') => out.push(':'),
+            _ => return Err(FrameError::BadEscape(s.to_string())),
+        }
+    }
+    Ok(out)
+}
+
+/// Returns the MESSAGE frame delivering `body` to the subscribers of
+/// `destination`, with the subscription header left for Fanout to fill in.
+pub fn message_frame(destination: &str, content_type: Option<&str>, body: &[u8]) -> Frame {
+    let mut frame = Frame::new("MESSAGE")
+        .with_header("destination", destination)
+        .with_header("message-id", &history::new_id())
+        .with_header(
+            "subscription",
+            &format!("%({})s", subscription_meta_name(destination)),
+        );
+    if let Some(content_type) = content_type {
+        frame = frame.with_header("content-type", content_type);
+    }
+    frame
+        .with_header("content-length", &body.len().to_string())
+        .with_body(body)
+}
+
+/// Returns the item delivering a MESSAGE frame to its destination's
+/// subscribers.
+pub fn publish_item(route: &Route, frame: &Frame) -> Item {
+    let destination = frame.header("destination").unwrap_or_default();
+    let item = Item::new(route.channel(&grip_channel(destination)));
+    match String::from_utf8(frame.to_bytes()) {
+        Ok(text) => item.ws_message(text),
+        Err(e) => item.ws_binary(e.as_bytes()),
+    }
+}
+
+/// Picks the heart-beat interval we send at, given the client's `cy`: the
+/// larger of what it wants and what we can offer, or none if it wants none.
+fn negotiate_heart_beat(client: Option<&str>) -> u32 {
+    let wanted = client
+        .and_then(|hb| hb.split_once(','))
+        .and_then(|(_, cy)| cy.trim().parse::<u32>().ok())
+        .unwrap_or(0);
+
+    if wanted == 0 {
+        0
+    } else {
+        wanted.max(HEART_BEAT_INTERVAL)
+    }
+}
+
+/// Serves STOMP over a WebSocket connection.
+struct StompWs<'a> {
+    route: &'a Route,
+}
+
+impl StompWs<'_> {
+    fn subscriptions(ctx: &WsContext) -> BTreeMap<String, String> {
+        ctx.meta(SUBS_META)
+            .and_then(|s| serde_json::from_str(s).ok())
+            .unwrap_or_default()
+    }
+
+    fn set_subscriptions(ctx: &mut WsContext, subs: &BTreeMap<String, String>) {
+        let value = serde_json::to_string(subs).expect("strings serialize");
+        ctx.set_meta(SUBS_META, &value);
+    }
+
+    fn send(ctx: &mut WsContext, frame: &Frame) {
+        match String::from_utf8(frame.to_bytes()) {
+            Ok(text) => ctx.out.write_text(&text),
+            Err(e) => ctx.out.write_binary(e.as_bytes()),
+        };
+    }
+
+    /// Sends an ERROR frame and closes the connection, as the protocol
+    /// requires after an error.
+    fn error(ctx: &mut WsContext, frame: Option<&Frame>, message: &str) {
+        log_warn!("stomp error: {message}");
+        let mut error = Frame::new("ERROR").with_header("message", message);
+        if let Some(receipt) = frame.and_then(|f| f.header("receipt")) {
+            error = error.with_header("receipt-id", receipt);
+        }
+        Self::send(ctx, &error);
+        ctx.out.write_control(&GripControl::Close {
+            code: None,
+            reason: None,
+        });
+    }
+
+    fn handle_frame(&self, ctx: &mut WsContext, frame: Frame) {
+        let connected = ctx.meta(CONNECTED_META).is_some();
+
+        match frame.command.as_str() {
+            "CONNECT" | "STOMP" => {
+                let versions = frame.header("accept-version").unwrap_or("1.0");
+                if !versions.split(',').any(|v| v.trim() == "1.2") {
+                    Self::error(ctx, Some(&frame), "Supported protocol versions are 1.2");
+                    return;
+                }
+
+                let interval = negotiate_heart_beat(frame.header("heart-beat"));
+                if interval > 0 {
+                    let heart_beat = KeepAlive::new("\n").with_timeout(interval.div_ceil(1000));
+                    ctx.out
+                        .write_control(&heart_beat.to_control(MessageType::Text));
+                }
+
+                ctx.set_meta(CONNECTED_META, "1");
+                Self::send(
+                    ctx,
+                    &Frame::new("CONNECTED")
+                        .with_header("version", "1.2")
+                        .with_header("heart-beat", &format!("{},0", interval))
+                        .with_header("server", "fanout-io-app"),
+                );
+                return;
+            }
+            _ if !connected => {
+                Self::error(ctx, Some(&frame), "Not connected");
+                return;
+            }
+            "SUBSCRIBE" => {
+                let (destination, id) = match (frame.header("destination"), frame.header("id")) {
+                    (Some(destination), Some(id)) => (destination.to_string(), id.to_string()),
+                    _ => {
+                        Self::error(ctx, Some(&frame), "SUBSCRIBE requires destination and id");
+                        return;
+                    }
+                };
+
+                ctx.set_meta(&subscription_meta_name(&destination), &id);
+                ctx.subscribe_filtered(
+                    &[self.route.channel(&grip_channel(&destination))],
+                    &[FILTER_VAR_SUBST],
+                );
+                let mut subs = Self::subscriptions(ctx);
+                subs.insert(id, destination);
+                Self::set_subscriptions(ctx, &subs);
+            }
+            "UNSUBSCRIBE" => {
+                let id = match frame.header("id") {
+                    Some(id) => id,
+                    None => {
+                        Self::error(ctx, Some(&frame), "UNSUBSCRIBE requires id");
+                        return;
+                    }
+                };
+
+                let mut subs = Self::subscriptions(ctx);
+                if let Some(destination) = subs.remove(id) {
+                    ctx.unsubscribe(&[self.route.channel(&grip_channel(&destination))]);
+                    Self::set_subscriptions(ctx, &subs);
+                }
+            }
+            "SEND" => {
+                let destination = match frame.header("destination") {
+                    Some(destination) => destination,
+                    None => {
+                        Self::error(ctx, Some(&frame), "SEND requires destination");
+                        return;
+                    }
+                };
+
+                let message = message_frame(destination, frame.header("content-type"), &frame.body);
+                match Publisher::for_route(self.route) {
+                    Some(publisher) => {
+                        if let Err(e) = publisher.publish(publish_item(self.route, &message)) {
+                            log_error!("failed to publish stomp message to {destination}: {e}");
+                            Self::error(ctx, Some(&frame), "Publish failed");
+                            return;
+                        }
+                    }
+                    None => {
+                        Self::error(ctx, Some(&frame), "Sending is not supported");
+                        return;
+                    }
+                }
+            }
+            "DISCONNECT" => {}
+            "ACK" | "NACK" | "BEGIN" | "COMMIT" | "ABORT" => {
+                log_debug!("ignoring stomp {} frame", frame.command);
+            }
+            command => {
+                Self::error(ctx, Some(&frame), &format!("Unknown command {command}"));
+                return;
+            }
+        }
+
+        if let Some(receipt) = frame.header("receipt") {
+            Self::send(
+                ctx,
+                &Frame::new("RECEIPT").with_header("receipt-id", receipt),
+            );
+        }
+
+        if frame.command == "DISCONNECT" {
+            ctx.out.write_control(&GripControl::Close {
+                code: None,
+                reason: None,
+            });
+        }
+    }
+
+    fn handle_data(&self, ctx: &mut WsContext, data: &[u8]) {
+        match Frame::parse(data) {
+            Ok(Some(frame)) => self.handle_frame(ctx, frame),
+            // a heart-beat
+            Ok(None) => {}
+            Err(e) => Self::error(ctx, None, &e.to_string()),
+        }
+    }
+}
+
+impl WsHandler for StompWs<'_> {
+    fn protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        self.handle_data(ctx, text.as_bytes());
+    }
+
+    fn on_binary(&mut self, ctx: &mut WsContext, data: Vec<u8>) {
+        self.handle_data(ctx, &data);
+    }
+}
+
+/// Handles a WebSocket-over-HTTP STOMP request forwarded by Fanout.
+pub fn handle(req: Request, route: &Route) -> Response {
+    ws::serve(req, &route.limits, &mut StompWs { route })
+}
@@ -0,0 +1,53 @@
+//! Per-tenant channel namespaces.
+//!
+//! When several customers share the service, channels of the same name
+//! must not collide. A route's `tenant` field says where the tenant of a
+//! request comes from:
+//!
+//! * `host`: the first label of the request's host, so `acme.example.com`
+//!   belongs to tenant `acme`.
+//! * `claim`: the `tenant` claim of the request's channel token, see
+//!   [`crate::tokens`].
+//!
+//! Channels used on behalf of the request are then named
+//! `{tenant}:{channel}`, after the route's channel prefix, in subscriptions,
+//! `Grip-Channel` headers and publishes alike. Requests whose tenant can't
+//! be determined are refused.
+
+use fastly::Request;
+use serde::Deserialize;
+
+use crate::channels;
+use crate::log_info;
+use crate::tokens;
+
+/// Where the tenant of a request comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TenantSource {
+    Host,
+    Claim,
+}
+
+/// Returns the tenant of a request to `host`, if it has a valid one.
+pub fn resolve(req: &Request, host: &str, source: TenantSource) -> Option<String> {
+    let tenant = match source {
+        TenantSource::Host => host.split('.').next().map(str::to_string),
+        TenantSource::Claim => match tokens::claims(req) {
+            Ok(claims) => claims.and_then(|c| c.tenant),
+            Err(e) => {
+                log_info!("no tenant from channel token: {e}");
+                None
+            }
+        },
+    }?;
+
+    let tenant = tenant.to_ascii_lowercase();
+    match channels::validate_name(&tenant) {
+        Ok(()) => Some(tenant),
+        Err(e) => {
+            log_info!("ignoring invalid tenant {tenant:?}: {e}");
+            None
+        }
+    }
+}
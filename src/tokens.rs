@@ -0,0 +1,167 @@
+//! Signed channel tokens.
+//!
+//! When the `channel_token_key` secret is set, clients may only subscribe to
+//! channels named in a token signed with it. Tokens are JWTs whose claims
+//! list the permitted channels:
+//!
+//! ```json
+//! {"channels": ["room1", "room-*"], "sub": "alice", "exp": 1700000000}
+//! ```
+//!
+//! As with `Grip-Sig`, a PEM-encoded public key in the secret selects ES256
+//! and anything else is used as an HS256 shared secret. `exp` is optional,
+//! but honoured when present.
+//!
+//! A token with a `sub` claim also permits its subject's private channel,
+//! `user:{sub}`, and no other private channel.
+//!
+//! Browsers can't set headers on EventSource or WebSocket requests, so the
+//! token is taken from the `token` query parameter as well as from an
+//! `Authorization: Bearer` header.
+
+use fastly::Request;
+use serde::Deserialize;
+use std::fmt;
+
+use crate::auth;
+use crate::channels;
+use crate::config;
+use crate::grip::{self, SigError, SigKey};
+
+/// Secret holding the key channel tokens are verified with.
+pub const KEY_SECRET: &str = "channel_token_key";
+
+/// Query parameter carrying the token.
+pub const QUERY_PARAM: &str = "token";
+
+/// Claims carried by a channel token.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChannelClaims {
+    #[serde(default)]
+    pub channels: Vec<String>,
+    pub sub: Option<String>,
+    pub exp: Option<u64>,
+    /// Tenant the token was issued for, see [`crate::tenant`].
+    pub tenant: Option<String>,
+}
+
+impl ChannelClaims {
+    /// Returns whether the token permits subscribing to `channel`. Entries
+    /// may be patterns such as `room-*`. Private channels are only
+    /// permitted to their owner, whatever the entries.
+    pub fn allows(&self, channel: &str) -> bool {
+        match channels::private_owner(channel) {
+            Some(owner) => self.sub.as_deref() == Some(owner),
+            None => self.channels.iter().any(|c| channels::matches(c, channel)),
+        }
+    }
+
+    /// Returns the private channel of the token's subject, if it has a
+    /// `sub` making a valid channel name.
+    pub fn private_channel(&self) -> Option<String> {
+        let channel = channels::private_channel(self.sub.as_deref()?);
+        channels::validate_name(&channel).ok().map(|()| channel)
+    }
+}
+
+/// Reasons a subscription is refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenError {
+    /// The request carries no token.
+    Missing,
+    /// The token is malformed or its signature doesn't verify.
+    Invalid(SigError),
+    /// The token's `exp` has passed.
+    Expired,
+    /// The token doesn't name the channel.
+    ChannelNotAllowed(String),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Missing => write!(f, "channel token required"),
+            TokenError::Invalid(_) => write!(f, "invalid channel token"),
+            TokenError::Expired => write!(f, "channel token expired"),
+            TokenError::ChannelNotAllowed(c) => write!(f, "channel token does not permit {c}"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// Verifies a channel token against `key` as of `now`.
+pub fn verify(token: &str, key: &SigKey, now: u64) -> Result<ChannelClaims, TokenError> {
+    let claims: ChannelClaims = grip::decode_jwt(token, key).map_err(TokenError::Invalid)?;
+
+    match claims.exp {
+        Some(exp) if exp <= now => Err(TokenError::Expired),
+        _ => Ok(claims),
+    }
+}
+
+/// Returns the channel token presented with a request, if any.
+pub fn request_token(req: &Request) -> Option<&str> {
+    req.get_query_parameter(QUERY_PARAM)
+        .or_else(|| auth::bearer_token(req))
+}
+
+/// Verifies a channel token with the configured key.
+///
+/// Returns `None` if channel tokens aren't configured, or the verified
+/// claims otherwise.
+pub fn verify_configured(token: Option<&str>) -> Result<Option<ChannelClaims>, TokenError> {
+    let key = match config::secret(KEY_SECRET) {
+        Some(key) if !key.is_empty() => key,
+        _ => return Ok(None),
+    };
+    let key = SigKey::from_bytes(&key).map_err(TokenError::Invalid)?;
+
+    let token = token.ok_or(TokenError::Missing)?;
+    verify(token, &key, grip::unix_now()).map(Some)
+}
+
+/// Verifies the channel token presented with a request, see
+/// [`verify_configured`].
+pub fn claims(req: &Request) -> Result<Option<ChannelClaims>, TokenError> {
+    verify_configured(request_token(req))
+}
+
+/// Returns the token carried by a `{"type": "token", "token": "..."}`
+/// message, which WebSocket clients send to renew their credentials.
+pub fn renewal_token(text: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Renewal {
+        #[serde(rename = "type")]
+        kind: String,
+        token: String,
+    }
+
+    if !text.trim_start().starts_with('{') {
+        return None;
+    }
+    serde_json::from_str::<Renewal>(text)
+        .ok()
+        .filter(|r| r.kind == "token")
+        .map(|r| r.token)
+}
+
+/// Checks that a request may subscribe to `channel`.
+///
+/// Returns `None` if channel tokens aren't configured, in which case all
+/// channels but private ones are public, or the verified claims otherwise.
+pub fn authorize(req: &Request, channel: &str) -> Result<Option<ChannelClaims>, TokenError> {
+    let claims = match claims(req)? {
+        Some(claims) => claims,
+        None if channels::private_owner(channel).is_some() => {
+            return Err(TokenError::ChannelNotAllowed(channel.to_string()))
+        }
+        None => return Ok(None),
+    };
+
+    if !claims.allows(channel) {
+        return Err(TokenError::ChannelNotAllowed(channel.to_string()));
+    }
+
+    Ok(Some(claims))
+}
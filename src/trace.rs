@@ -0,0 +1,110 @@
+//! W3C Trace Context propagation.
+//!
+//! Requests arriving with a valid `traceparent` continue that trace: the
+//! request is forwarded with a new span id of ours as the parent, and any
+//! `tracestate` is passed along untouched. Requests without one start a new
+//! trace. Either way the trace id is added to the logging context, so log
+//! lines can be matched up with spans from Fanout and the origin.
+
+use fastly::Request;
+
+use crate::hex;
+use crate::logging;
+
+const VERSION: &str = "00";
+
+/// Sampled flag, set on traces we start.
+const FLAGS_SAMPLED: &str = "01";
+
+/// A parsed `traceparent` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub parent_id: String,
+    pub flags: String,
+}
+
+impl TraceParent {
+    /// Parses a `traceparent` header value, rejecting malformed values and
+    /// the all-zero ids the spec forbids.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+
+        // future versions may append fields, version 00 may not
+        if version == VERSION && parts.next().is_some() {
+            return None;
+        }
+
+        let is_hex = |s: &str, len: usize| {
+            s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        let is_zero = |s: &str| s.bytes().all(|b| b == b'0');
+
+        if !is_hex(version, 2)
+            || version == "ff"
+            || !is_hex(trace_id, 32)
+            || is_zero(trace_id)
+            || !is_hex(parent_id, 16)
+            || is_zero(parent_id)
+            || !is_hex(flags, 2)
+        {
+            return None;
+        }
+
+        Some(TraceParent {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            flags: flags.to_string(),
+        })
+    }
+
+    /// Starts a new sampled trace.
+    pub fn new_root() -> Self {
+        TraceParent {
+            trace_id: hex::random(16),
+            parent_id: hex::random(8),
+            flags: FLAGS_SAMPLED.to_string(),
+        }
+    }
+
+    /// Returns the context for a child span of this one.
+    pub fn child(&self) -> Self {
+        TraceParent {
+            trace_id: self.trace_id.clone(),
+            parent_id: hex::random(8),
+            flags: self.flags.clone(),
+        }
+    }
+
+    pub fn header_value(&self) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            VERSION, self.trace_id, self.parent_id, self.flags
+        )
+    }
+}
+
+/// Sets the `traceparent` of a request about to be forwarded, continuing
+/// the client's trace if it sent one, and logs the trace id.
+pub fn propagate(req: &mut Request) -> TraceParent {
+    let trace = match req
+        .get_header_str("traceparent")
+        .and_then(TraceParent::parse)
+    {
+        Some(parent) => parent.child(),
+        None => {
+            // tracestate is meaningless without the traceparent it belongs to
+            req.remove_header("tracestate");
+            TraceParent::new_root()
+        }
+    };
+
+    req.set_header("traceparent", trace.header_value());
+    logging::set_context("trace_id", trace.trace_id.as_str());
+
+    trace
+}
@@ -0,0 +1,200 @@
+//! Rewriting messages before they are published.
+//!
+//! A route can have its messages pass through transforms, each a function
+//! given an item about to be published that returns it, rewritten or not,
+//! or `None` to drop it. Transforms are applied per channel, in the order
+//! of the route's `transforms` rules:
+//!
+//! ```json
+//! {"transforms": [{"channel": "chat-*", "transform": "redact"},
+//!                 {"transform": "timestamp"}]}
+//! ```
+//!
+//! Rules match channels by name, before the route's prefix and tenant are
+//! applied, with a trailing `*` matching any suffix, and apply to all
+//! channels without a `channel`. The built-in transforms are:
+//!
+//! * `redact`: replaces email addresses with `[redacted]`.
+//! * `timestamp`: adds the server time, in milliseconds since the Unix
+//!   epoch, as a `ts` field of messages that are JSON objects.
+//! * `envelope`: wraps messages in an envelope with a per-channel sequence
+//!   number, see [`crate::envelope`].
+//!
+//! Others can be added with [`register`].
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::channels;
+use crate::envelope;
+use crate::grip::unix_now_millis;
+use crate::log_warn;
+use crate::publish::Item;
+
+/// A transform: returns the item to publish in place of the given one, or
+/// `None` to drop it.
+pub type Transform = fn(Item) -> Option<Item>;
+
+/// A route's rule applying a transform to the channels matching a pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TransformRule {
+    #[serde(default = "any_channel")]
+    pub channel: String,
+    pub transform: String,
+}
+
+fn any_channel() -> String {
+    "*".to_string()
+}
+
+thread_local! {
+    static REGISTRY: RefCell<HashMap<String, Transform>> = RefCell::new(HashMap::new());
+}
+
+/// Registers a transform under `name`, which routes can then use. Built-in
+/// transforms of the same name are overridden.
+pub fn register(name: &str, transform: Transform) {
+    REGISTRY.with(|r| r.borrow_mut().insert(name.to_string(), transform));
+}
+
+/// Returns the transform registered under `name`, or the built-in one.
+pub fn lookup(name: &str) -> Option<Transform> {
+    if let Some(transform) = REGISTRY.with(|r| r.borrow().get(name).copied()) {
+        return Some(transform);
+    }
+
+    match name {
+        "redact" => Some(redact),
+        "timestamp" => Some(timestamp),
+        "envelope" => Some(envelope::wrap),
+        _ => None,
+    }
+}
+
+/// Applies the rules matching the item's channel, in order. Returns `None`
+/// if one of them drops it.
+pub fn apply(rules: &[TransformRule], mut item: Item) -> Option<Item> {
+    for rule in rules {
+        if !channels::matches(&rule.channel, &item.channel) {
+            continue;
+        }
+
+        match lookup(&rule.transform) {
+            Some(transform) => item = transform(item)?,
+            None => log_warn!("skipping unknown transform {}", rule.transform),
+        }
+    }
+
+    Some(item)
+}
+
+/// Rewrites the text content of an item in all its formats: the stream
+/// content, the response body and the TEXT WebSocket message. Binary
+/// WebSocket messages are left alone.
+pub fn map_text(mut item: Item, f: impl Fn(&str) -> String) -> Item {
+    if let Some(stream) = &mut item.formats.http_stream {
+        stream.content = f(&stream.content);
+    }
+    if let Some(response) = &mut item.formats.http_response {
+        response.body = f(&response.body);
+    }
+    if let Some(content) = item
+        .formats
+        .ws_message
+        .as_mut()
+        .and_then(|ws| ws.content.as_mut())
+    {
+        *content = f(content);
+    }
+    item
+}
+
+/// Built-in transform replacing email addresses with `[redacted]`.
+pub fn redact(item: Item) -> Option<Item> {
+    Some(map_text(item, redact_emails))
+}
+
+fn redact_emails(text: &str) -> String {
+    let is_local = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '%' | '+' | '-');
+    let is_domain = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-');
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('@') {
+        let local_start = rest[..at]
+            .rfind(|c: char| !is_local(c))
+            .map_or(0, |i| i + 1);
+        let domain_len = rest[at + 1..]
+            .find(|c: char| !is_domain(c))
+            .unwrap_or(rest.len() - at - 1);
+        let domain = rest[at + 1..at + 1 + domain_len].trim_end_matches('.');
+
+        if local_start < at && domain.contains('.') {
+            out.push_str(&rest[..local_start]);
+            out.push_str("[redacted]");
+            rest = &rest[at + 1 + domain.len()..];
+        } else {
+            out.push_str(&rest[..=at]);
+            rest = &rest[at + 1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Built-in transform adding the server time as a `ts` field of messages
+/// that are JSON objects. In streams, each `data:` line holding a JSON
+/// object gets it.
+pub fn timestamp(item: Item) -> Option<Item> {
+    let now = unix_now_millis();
+
+    let add_ts = |text: &str| -> Option<String> {
+        match serde_json::from_str::<Value>(text) {
+            Ok(Value::Object(mut map)) => {
+                map.insert("ts".into(), now.into());
+                Some(Value::Object(map).to_string())
+            }
+            _ => None,
+        }
+    };
+
+    let mut item = item;
+    if let Some(stream) = &mut item.formats.http_stream {
+        stream.content = stream
+            .content
+            .split_inclusive('\n')
+            .map(|line| {
+                let data = line
+                    .strip_prefix("data: ")
+                    .map(|d| d.trim_end_matches('\n'));
+                match data.and_then(add_ts) {
+                    Some(data) => format!(
+                        "data: {}{}",
+                        data,
+                        &line[line.trim_end_matches('\n').len()..]
+                    ),
+                    None => line.to_string(),
+                }
+            })
+            .collect();
+    }
+    if let Some(response) = &mut item.formats.http_response {
+        if let Some(body) = add_ts(&response.body) {
+            response.body = body;
+        }
+    }
+    if let Some(content) = item
+        .formats
+        .ws_message
+        .as_mut()
+        .and_then(|ws| ws.content.as_mut())
+    {
+        if let Some(with_ts) = add_ts(content) {
+            *content = with_ts;
+        }
+    }
+
+    Some(item)
+}
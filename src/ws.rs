@@ -0,0 +1,869 @@
+//! Serving WebSocket-over-HTTP requests.
+//!
+//! Fanout batches up the activity of a WebSocket connection into requests
+//! carrying one or more events. [`serve`] parses a request, hands each event
+//! to the matching callback of a [`WsHandler`] and collects what the handler
+//! writes into the response, so handlers only deal with one event at a time.
+//!
+//! Fanout keeps no state for the origin beyond the connection's id and its
+//! meta values: `Set-Meta-{name}` response headers store a value on the
+//! connection, and it comes back as `Meta-{name}` on every later request.
+//! Handlers read and write both through [`WsContext`], which also gives
+//! access to the connection's [`Session`] for state too big for meta values.
+//! Subscribing through the context also keeps the channels' [`presence`] up
+//! to date.
+//!
+//! Handlers speaking a subprotocol list it in [`WsHandler::protocols`]. The
+//! first protocol offered by the client in `Sec-WebSocket-Protocol` that the
+//! handler supports is echoed back on OPEN and remembered as a meta value,
+//! and clients offering none of them are closed with code 1002.
+//!
+//! Handlers wanting GRIP keep-alives return them from
+//! [`WsHandler::keep_alive`]. The keep-alive is set up when the connection
+//! opens, and a digest of it is remembered as a meta value, so that when
+//! the configuration changes, long-lived connections are sent the new one
+//! with the response to their next event.
+//!
+//! The GRIP extension is accepted with the message prefix of the
+//! `ws_message_prefix` setting, empty by default. Fanout then only forwards
+//! TEXT messages starting with it, stripping it, and treats those starting
+//! with `c:` as control messages. A prefix such as `m:` keeps application
+//! messages that happen to start with `c:` from being taken for control
+//! messages; [`WsEventWriter::write_text`] adds it. Since Fanout remembers
+//! the prefix a connection opened with, changes only apply to new ones.
+//!
+//! Along with the GRIP extension, connections accept `permessage-deflate`
+//! when the client offers it and the `ws_compression` setting is on, so
+//! Fanout compresses messages on the way to the client.
+//!
+//! If the `ws_allowed_origins` setting is set, browsers may only open
+//! connections from the pages of the origins it lists: connections whose
+//! `Origin` isn't one of them are closed on OPEN with code 4403. Clients
+//! sending no `Origin`, which browsers always do, aren't affected.
+//!
+//! Every connection is also subscribed to its own channel, `c:{connection-id}`
+//! (see [`channels::connection_channel`]), so it can be singled out, e.g.
+//! closed by the admin API, and to [`channels::ALL_CHANNEL`], so all of them
+//! can be drained at once. Such channels aren't recorded in the session or
+//! in presence, and clients can't name them.
+//!
+//! Connections counted against their client's cap on open connections take
+//! their hold on OPEN, refresh it on any later event and release it when
+//! they close, see [`crate::connections`].
+//!
+//! Connections opened with an expiring channel token have its expiry kept in
+//! their session, see [`WsContext::set_credentials`], and checked on each of
+//! their requests, which keep-alives make regular. Once the token is within
+//! a minute of expiring, a `refresh` control message has Fanout send the
+//! origin another request right away, on which
+//! [`WsHandler::on_token_expiring`] asks the client for a new token, right
+//! away if the proxy doesn't list `refresh` in its `Grip-Feature`. Clients
+//! answer with `{"type": "token", "token": "..."}`, and a valid token for
+//! the same subject extends the connection. Connections whose token has
+//! expired are closed with code 4401.
+
+use fastly::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use crate::ack;
+use crate::activity;
+use crate::channels;
+use crate::config;
+use crate::connections::Hold;
+use crate::error::AppError;
+use crate::grip::{unix_now, GripControl, GripFeatures};
+use crate::hex;
+use crate::limits::{BodyError, BodyLimits, CLOSE_MESSAGE_TOO_BIG, CLOSE_RATE_LIMITED};
+use crate::maintenance;
+use crate::metrics;
+use crate::presence;
+use crate::publish::{Item, Publisher};
+use crate::session::Session;
+use crate::tokens::{self, ChannelClaims, TokenError};
+use crate::ws_events::{self, EventReader, ParseError, WsEvent, WsEventWriter};
+use crate::{log_debug, log_error, log_info, log_warn};
+
+/// Header naming the connection a WebSocket-over-HTTP request belongs to.
+pub const CONNECTION_ID_HEADER: &str = "Connection-Id";
+
+/// Prefix of request headers carrying connection meta values.
+const META_PREFIX: &str = "meta-";
+
+/// Prefix of response headers setting connection meta values.
+const SET_META_PREFIX: &str = "Set-Meta-";
+
+/// Header carrying the client's offered subprotocols, and our choice.
+pub const PROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+/// Header carrying the client's offered extensions, and those accepted.
+pub const EXTENSIONS_HEADER: &str = "Sec-WebSocket-Extensions";
+
+/// Setting enabling `permessage-deflate` for clients offering it.
+pub const COMPRESSION_SETTING: &str = "ws_compression";
+
+/// Setting holding the GRIP message prefix of TEXT messages.
+pub const MESSAGE_PREFIX_SETTING: &str = "ws_message_prefix";
+
+/// Meta value remembering the message prefix the connection opened with.
+const PREFIX_META: &str = "ws-prefix";
+
+/// Meta value remembering the negotiated subprotocol.
+const PROTOCOL_META: &str = "ws-protocol";
+
+/// Meta value holding a digest of the connection's keep-alive.
+const KEEP_ALIVE_META: &str = "ws-keep-alive";
+
+/// Close code sent to clients offering no supported subprotocol.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Setting listing the origins allowed to open connections.
+pub const ALLOWED_ORIGINS_SETTING: &str = "ws_allowed_origins";
+
+/// Close code sent to connections from origins that aren't allowed.
+pub const CLOSE_ORIGIN_NOT_ALLOWED: u16 = 4403;
+
+/// Close code sent to connections whose credentials expired.
+pub const CLOSE_TOKEN_EXPIRED: u16 = 4401;
+
+/// Seconds before a connection's token expires that the client is asked
+/// for a new one.
+pub const TOKEN_REFRESH_WINDOW: u64 = 60;
+
+/// Session counter holding the minute, since the Unix epoch, the
+/// connection's messages are being counted in.
+const RATE_MINUTE_COUNTER: &str = "ws-rate-minute";
+
+/// Session counter holding the messages the connection sent in that minute.
+const RATE_COUNT_COUNTER: &str = "ws-rate-count";
+
+/// Meta value recording how far the renewal of the connection's token got,
+/// as `{exp}:refresh` or `{exp}:asked`.
+const TOKEN_META: &str = "ws-token";
+
+/// The runtime services serving a connection relies on: settings, the
+/// connection's state in the KV Store and publishing.
+///
+/// All methods default to the services of the Fanout runtime, so tests can
+/// serve requests with stand-ins for them.
+pub trait WsRuntime {
+    /// Returns the value of a setting.
+    fn setting(&self, name: &str) -> Option<String> {
+        config::setting(name)
+    }
+
+    /// Returns whether maintenance mode is on.
+    fn maintenance(&self) -> bool {
+        maintenance::enabled()
+    }
+
+    fn load_session(&self, connection_id: &str) -> Session {
+        Session::load(connection_id)
+    }
+
+    fn save_session(&self, connection_id: &str, session: &Session) {
+        session.save(connection_id)
+    }
+
+    fn delete_session(&self, connection_id: &str) {
+        Session::delete(connection_id)
+    }
+
+    /// Adds the connection to the presence of `channel`.
+    fn join(&self, channel: &str, connection_id: &str) {
+        presence::join(channel, connection_id)
+    }
+
+    /// Removes the connection from the presence of `channel`.
+    fn leave(&self, channel: &str, connection_id: &str) {
+        presence::leave(channel, connection_id)
+    }
+
+    /// Keeps the connection in the presence of `channel`.
+    fn refresh_presence(&self, channel: &str, connection_id: &str) {
+        presence::refresh(channel, connection_id)
+    }
+
+    /// Records activity on `channels`.
+    fn touch(&self, channels: &[&str]) {
+        activity::touch(channels.iter().copied())
+    }
+
+    fn refresh_hold(&self, hold: &Hold) {
+        hold.refresh()
+    }
+
+    fn release_hold(&self, hold: &Hold) {
+        hold.release()
+    }
+
+    /// Verifies a channel token with the configured keys.
+    fn verify_token(&self, token: &str) -> Result<Option<ChannelClaims>, TokenError> {
+        tokens::verify_configured(Some(token))
+    }
+
+    /// Publishes an item with the configured publisher, returning whether
+    /// it was published.
+    fn publish(&self, item: Item) -> bool {
+        let publisher = match Publisher::from_config() {
+            Some(p) => p,
+            None => {
+                log_warn!(
+                    "dropping publish to {}, publishing is not configured",
+                    item.channel
+                );
+                return false;
+            }
+        };
+
+        let channel = item.channel.clone();
+        match publisher.publish(item) {
+            Ok(()) => true,
+            Err(e) => {
+                log_error!("failed to publish to {channel}: {e}");
+                false
+            }
+        }
+    }
+}
+
+/// The services of the Fanout runtime.
+struct Compute;
+
+impl WsRuntime for Compute {}
+
+/// The connection a WebSocket-over-HTTP request belongs to, and where
+/// handlers write the events to send back.
+pub struct WsContext<'a> {
+    pub connection_id: String,
+    /// GRIP features of the proxy, for handlers to adapt to.
+    pub features: GripFeatures,
+    meta: HashMap<String, String>,
+    set_meta: Vec<(String, String)>,
+    session: Option<Session>,
+    hold: Option<Hold>,
+    closed: bool,
+    alive: bool,
+    runtime: &'a dyn WsRuntime,
+    pub out: WsEventWriter,
+}
+
+impl<'a> WsContext<'a> {
+    /// Returns the context of the connection `req` was made for, served
+    /// with `runtime`.
+    pub fn from_request(req: &Request, runtime: &'a dyn WsRuntime) -> Self {
+        let meta: HashMap<String, String> = req
+            .get_header_names_str()
+            .into_iter()
+            .filter_map(|name| {
+                let key = name
+                    .to_ascii_lowercase()
+                    .strip_prefix(META_PREFIX)?
+                    .to_string();
+                let value = req.get_header_str(name)?.to_string();
+                Some((key, value))
+            })
+            .collect();
+
+        let prefix = meta.get(PREFIX_META).cloned().unwrap_or_default();
+
+        WsContext {
+            connection_id: req
+                .get_header_str(CONNECTION_ID_HEADER)
+                .unwrap_or_default()
+                .to_string(),
+            features: GripFeatures::from_request(req),
+            meta,
+            set_meta: Vec::new(),
+            session: None,
+            hold: Hold::from_request(req),
+            closed: false,
+            alive: false,
+            runtime,
+            out: WsEventWriter::new().with_message_prefix(prefix),
+        }
+    }
+
+    /// Returns a meta value of the connection. Names are case-insensitive.
+    pub fn meta(&self, name: &str) -> Option<&str> {
+        self.meta
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Stores a meta value on the connection, visible to later events of
+    /// this request and to all later requests of the connection.
+    pub fn set_meta(&mut self, name: &str, value: &str) {
+        self.meta
+            .insert(name.to_ascii_lowercase(), value.to_string());
+        self.set_meta.push((name.to_string(), value.to_string()));
+    }
+
+    /// Returns the subprotocol negotiated when the connection opened.
+    pub fn protocol(&self) -> Option<&str> {
+        self.meta(PROTOCOL_META)
+    }
+
+    /// Returns the connection's session, loading it on first use. Changes
+    /// are saved once all events of the request have been handled, and the
+    /// session is deleted when the connection closes.
+    pub fn session(&mut self) -> &mut Session {
+        let (connection_id, runtime) = (&self.connection_id, self.runtime);
+        self.session
+            .get_or_insert_with(|| runtime.load_session(connection_id))
+    }
+
+    /// Counts a TEXT or BINARY message sent by the connection in its
+    /// session, returning whether it's within `max` messages this minute.
+    fn count_message(&mut self, max: u32) -> bool {
+        let minute = unix_now() / 60;
+        let session = self.session();
+        if session.counters.get(RATE_MINUTE_COUNTER) != Some(&minute) {
+            session
+                .counters
+                .insert(RATE_MINUTE_COUNTER.to_string(), minute);
+            session.counters.remove(RATE_COUNT_COUNTER);
+        }
+        session.incr(RATE_COUNT_COUNTER) <= u64::from(max)
+    }
+
+    /// Subscribes the connection to channels, recording them in the session
+    /// and the channels' presence.
+    pub fn subscribe<S: AsRef<str>>(&mut self, channels: &[S]) {
+        self.subscribe_filtered(channels, &[]);
+    }
+
+    /// Subscribes the connection to channels like [`WsContext::subscribe`],
+    /// applying GRIP `filters` to what is published on them.
+    pub fn subscribe_filtered<S: AsRef<str>>(&mut self, channels: &[S], filters: &[&str]) {
+        self.out.write_subscribe_filtered(channels, filters);
+        for channel in channels {
+            let channel = channel.as_ref();
+            self.session().subscribe(channel);
+            if !self.connection_id.is_empty() {
+                self.runtime.join(channel, &self.connection_id);
+            }
+        }
+        let channels: Vec<&str> = channels.iter().map(AsRef::as_ref).collect();
+        self.runtime.touch(&channels);
+    }
+
+    /// Unsubscribes the connection from channels.
+    pub fn unsubscribe<S: AsRef<str>>(&mut self, channels: &[S]) {
+        for channel in channels {
+            let channel = channel.as_ref();
+            self.out.write_control(&GripControl::Unsubscribe {
+                channel: channel.to_string(),
+            });
+            self.session().unsubscribe(channel);
+            if !self.connection_id.is_empty() {
+                self.runtime.leave(channel, &self.connection_id);
+            }
+        }
+    }
+
+    /// Records who the connection is authenticated as, and when its
+    /// credentials expire, from the claims of its channel token.
+    pub fn set_credentials(&mut self, claims: &ChannelClaims) {
+        let session = self.session();
+        session.identity = claims.sub.clone();
+        session.token_exp = claims.exp;
+    }
+
+    /// Replaces the connection's credentials with those of a renewed token,
+    /// which must be valid and for the same subject. Returns whether they
+    /// were replaced.
+    fn renew_credentials(&mut self, token: &str) -> bool {
+        let claims = match self.runtime.verify_token(token) {
+            Ok(Some(claims)) => claims,
+            Ok(None) => return false,
+            Err(e) => {
+                log_info!("refusing renewed token: {e}");
+                return false;
+            }
+        };
+
+        if claims.sub != self.session().identity {
+            log_info!("refusing renewed token for another subject");
+            return false;
+        }
+
+        self.session().token_exp = claims.exp;
+        true
+    }
+
+    /// Publishes an item with the configured publisher, returning whether
+    /// it was published.
+    pub(crate) fn publish(&self, item: Item) -> bool {
+        self.runtime.publish(item)
+    }
+
+    fn finish(&mut self) {
+        if let Some(hold) = &self.hold {
+            if self.closed {
+                self.runtime.release_hold(hold);
+            } else {
+                self.runtime.refresh_hold(hold);
+            }
+        }
+
+        if self.connection_id.is_empty() {
+            return;
+        }
+
+        if self.closed {
+            for channel in self.session().channels.clone() {
+                self.runtime.leave(&channel, &self.connection_id);
+            }
+            self.runtime.delete_session(&self.connection_id);
+            return;
+        }
+
+        if self.alive {
+            let channels = self.session().channels.clone();
+            for channel in &channels {
+                self.runtime.refresh_presence(channel, &self.connection_id);
+            }
+            let channels: Vec<&str> = channels.iter().map(String::as_str).collect();
+            self.runtime.touch(&channels);
+        }
+
+        if let Some(session) = &self.session {
+            self.runtime.save_session(&self.connection_id, session);
+        }
+    }
+}
+
+/// Callbacks for the events of a WebSocket connection.
+///
+/// Each callback may write events to send back to the client to
+/// `ctx.out`. All have defaults, so handlers only implement those they care
+/// about.
+pub trait WsHandler {
+    /// Decides whether to refuse a new connection, before its OPEN event is
+    /// handled. Returning a response rejects the connection with it.
+    fn reject(&mut self, _req: &Request) -> Option<Response> {
+        None
+    }
+
+    /// The client connected. The OPEN reply is written before this is called.
+    fn on_open(&mut self, _ctx: &mut WsContext) {}
+
+    fn on_text(&mut self, _ctx: &mut WsContext, _text: String) {}
+
+    fn on_binary(&mut self, _ctx: &mut WsContext, data: Vec<u8>) {
+        log_debug!("ignoring {} byte binary message", data.len());
+    }
+
+    /// The client closed the connection. By default the close code is
+    /// mirrored back so the client sees the reason it gave.
+    fn on_close(&mut self, ctx: &mut WsContext, code: Option<u16>) {
+        ctx.out.write_event(&WsEvent::Close(code));
+    }
+
+    /// The connection went away without a close handshake. Nothing can be
+    /// sent anymore, so this is only for teardown such as removing the
+    /// connection from presence lists.
+    fn on_disconnect(&mut self, ctx: &mut WsContext) {
+        log_debug!("connection {} disconnected", ctx.connection_id);
+    }
+
+    /// Subprotocols the handler speaks, in order of preference. Clients
+    /// offering subprotocols must offer one of these, unless the list is
+    /// empty, in which case no subprotocol is negotiated.
+    fn protocols(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The keep-alive Fanout sends on the connection when it is idle, if
+    /// any, as a `keep-alive` control message.
+    fn keep_alive(&self) -> Option<GripControl> {
+        None
+    }
+
+    /// The connection's token is about to expire, at `expires` (seconds
+    /// since the Unix epoch). By default the client is asked for a new one
+    /// with a `{"type": "token-expiring", "expires": ...}` message.
+    fn on_token_expiring(&mut self, ctx: &mut WsContext, expires: u64) {
+        ctx.out.write_text(&format!(
+            r#"{{"type":"token-expiring","expires":{}}}"#,
+            expires
+        ));
+    }
+
+    /// Whether the handler's clients may acknowledge messages, see
+    /// [`ack`]. Their acks are confirmed instead of being passed to
+    /// [`WsHandler::on_text`].
+    fn accepts_acks(&self) -> bool {
+        false
+    }
+
+    /// Whether PING events are answered with PONGs. Handlers keeping their
+    /// connections alive with GRIP keep-alives may turn this off.
+    fn reply_to_ping(&self) -> bool {
+        ws_events::ping_reply_enabled()
+    }
+}
+
+/// Returns the subprotocols a client offered in its handshake.
+pub fn offered_protocols(req: &Request) -> Vec<String> {
+    req.get_header_all_str(PROTOCOL_HEADER)
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Returns the names of the extensions a client offered in its handshake,
+/// without their parameters.
+pub fn offered_extensions(req: &Request) -> Vec<String> {
+    req.get_header_all_str(EXTENSIONS_HEADER)
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|offer| offer.split(';').next())
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+/// Returns the configured GRIP message prefix, empty if unset or if it
+/// can't be given in the extension header.
+fn message_prefix(runtime: &dyn WsRuntime) -> String {
+    let prefix = runtime.setting(MESSAGE_PREFIX_SETTING).unwrap_or_default();
+    if prefix
+        .chars()
+        .all(|c| c.is_ascii_graphic() && !matches!(c, '"' | '\\'))
+    {
+        prefix
+    } else {
+        log_warn!("ignoring invalid WebSocket message prefix {prefix:?}");
+        String::new()
+    }
+}
+
+/// Returns the extensions accepted for a connection: always GRIP, with the
+/// message prefix, and `permessage-deflate` if the client offered it and
+/// compression is enabled.
+fn accepted_extensions(req: &Request, prefix: &str, runtime: &dyn WsRuntime) -> String {
+    let mut accepted = format!("grip; message-prefix=\"{}\"", prefix);
+
+    let compress = runtime.setting(COMPRESSION_SETTING).as_deref() == Some("true");
+    if compress
+        && offered_extensions(req)
+            .iter()
+            .any(|name| name == "permessage-deflate")
+    {
+        accepted.push_str(", permessage-deflate");
+    }
+
+    accepted
+}
+
+/// Picks the subprotocol for a connection: the handler's most preferred
+/// one among those offered. Returns `Err` if the client offered only
+/// unsupported ones.
+fn negotiate(offered: &[String], supported: &[String]) -> Result<Option<String>, ()> {
+    if offered.is_empty() || supported.is_empty() {
+        return Ok(None);
+    }
+
+    supported
+        .iter()
+        .find(|p| offered.contains(p))
+        .cloned()
+        .map(Some)
+        .ok_or(())
+}
+
+/// Returns whether an origin matches an allowlist entry, which may be `*`
+/// or contain one `*` standing for any part of the origin, as in
+/// `https://*.example.com`.
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            let origin = origin.to_ascii_lowercase();
+            origin.len() >= prefix.len() + suffix.len()
+                && origin.starts_with(&prefix.to_ascii_lowercase())
+                && origin.ends_with(&suffix.to_ascii_lowercase())
+        }
+        None => pattern.eq_ignore_ascii_case(origin),
+    }
+}
+
+/// Returns whether a connection from `origin` may be opened.
+fn origin_allowed(origin: Option<&str>, runtime: &dyn WsRuntime) -> bool {
+    let (allowed, origin) = match (runtime.setting(ALLOWED_ORIGINS_SETTING), origin) {
+        (Some(allowed), Some(origin)) => (allowed, origin),
+        _ => return true,
+    };
+
+    allowed
+        .split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .any(|a| origin_matches(a, origin))
+}
+
+/// Sends a keep-alive control message, unless the connection already has
+/// the same keep-alive.
+fn update_keep_alive(ctx: &mut WsContext, control: &GripControl) {
+    let json = serde_json::to_string(control).expect("control message serializes");
+    let digest = hex::encode(&Sha256::digest(json.as_bytes())[..8]);
+
+    if ctx.meta(KEEP_ALIVE_META) != Some(digest.as_str()) {
+        ctx.out.write_control(control);
+        ctx.set_meta(KEEP_ALIVE_META, &digest);
+    }
+}
+
+/// Closes the connection if its token expired, and otherwise gets a new
+/// token from the client once it is about to.
+fn check_credentials(ctx: &mut WsContext, handler: &mut impl WsHandler) {
+    let exp = match ctx.session().token_exp {
+        Some(exp) => exp,
+        None => return,
+    };
+
+    let now = unix_now();
+    if now >= exp {
+        log_info!("closing connection whose token expired");
+        ctx.out.write_close(CLOSE_TOKEN_EXPIRED);
+        ctx.closed = true;
+        return;
+    }
+
+    if exp - now > TOKEN_REFRESH_WINDOW {
+        return;
+    }
+
+    let refreshing = format!("{}:refresh", exp);
+    let asked = format!("{}:asked", exp);
+    match ctx.meta(TOKEN_META).map(str::to_string) {
+        Some(state) if state == asked => {}
+        Some(state) if state == refreshing => {
+            handler.on_token_expiring(ctx, exp);
+            ctx.set_meta(TOKEN_META, &asked);
+        }
+        // without refresh, the client is asked on the request at hand
+        _ if !ctx.features.contains(GripFeatures::REFRESH) => {
+            handler.on_token_expiring(ctx, exp);
+            ctx.set_meta(TOKEN_META, &asked);
+        }
+        _ => {
+            ctx.out.write_control(&GripControl::Refresh);
+            ctx.set_meta(TOKEN_META, &refreshing);
+        }
+    }
+}
+
+/// Serves a WebSocket-over-HTTP request with `handler`, within the size
+/// `limits` of its route.
+pub fn serve(mut req: Request, limits: &BodyLimits, handler: &mut impl WsHandler) -> Response {
+    let body = req.take_body();
+    match respond(&req, body, limits, handler, &Compute) {
+        Ok(Served::Events(mut resp, events)) => {
+            resp.set_body(events);
+            resp
+        }
+        Ok(Served::Rejected(resp)) => resp,
+        Err(e) => e.response(),
+    }
+}
+
+/// What serving a WebSocket-over-HTTP request came to.
+pub(crate) enum Served {
+    /// The response to the request, and the events it sends back.
+    Events(Response, Vec<u8>),
+    /// The handler rejected the connection with this response.
+    Rejected(Response),
+}
+
+/// Serves a WebSocket-over-HTTP request like [`serve`], reading its events
+/// from `body` and relying on `runtime` for everything but the request.
+pub(crate) fn respond(
+    req: &Request,
+    body: impl BufRead,
+    limits: &BodyLimits,
+    handler: &mut impl WsHandler,
+    runtime: &dyn WsRuntime,
+) -> Result<Served, AppError> {
+    if req.get_header_str("Content-Type") != Some(ws_events::CONTENT_TYPE) {
+        return Err(AppError::ParseError(
+            "Not a WebSocket-over-HTTP request.".into(),
+        ));
+    }
+
+    // events are handled as they are read, so a body over the limit is
+    // refused up front by its length, before any of them has effects.
+    // Bodies sent without a length are cut off at the limit instead
+    if req
+        .get_content_length()
+        .is_some_and(|len| len > limits.max_body)
+    {
+        return Err(BodyError::TooLarge(limits.max_body).into());
+    }
+
+    // one byte past the limit, to tell bodies over it from ones at it
+    let mut body = body.take(limits.max_body as u64 + 1);
+    let mut events = EventReader::new(&mut body).with_max_content(limits.max_message);
+
+    let mut ctx = WsContext::from_request(req, runtime);
+    let mut resp = ws_events::empty_response();
+
+    while let Some(event) = events.next() {
+        let event = match event {
+            Ok(event) => event,
+            Err(ParseError::TooLarge(len)) => {
+                log_info!("closing connection sending a {len} byte message");
+                ctx.out.write_close(CLOSE_MESSAGE_TOO_BIG);
+                ctx.closed = true;
+                break;
+            }
+            Err(_) if events.get_ref().limit() == 0 => {
+                return Err(BodyError::TooLarge(limits.max_body).into());
+            }
+            Err(e) => {
+                return Err(AppError::ParseError(format!(
+                    "Invalid WebSocket-over-HTTP body: {e}"
+                )));
+            }
+        };
+
+        metrics::incr("ws_events_total", &[("type", event.name())]);
+
+        if let (WsEvent::Text(_) | WsEvent::Binary(_), Some(max)) =
+            (&event, limits.max_messages_per_minute)
+        {
+            if !ctx.connection_id.is_empty() && !ctx.count_message(max) {
+                log_info!("closing connection sending over {max} messages a minute");
+                ctx.out.write_control(&GripControl::Close {
+                    code: Some(CLOSE_RATE_LIMITED),
+                    reason: Some(format!("Rate limit of {max} messages per minute exceeded.")),
+                });
+                ctx.closed = true;
+                break;
+            }
+        }
+
+        match event {
+            WsEvent::Open => {
+                if let Some(reject) = handler.reject(req) {
+                    return Ok(Served::Rejected(reject));
+                }
+
+                let prefix = message_prefix(runtime);
+                resp.set_header(
+                    EXTENSIONS_HEADER,
+                    accepted_extensions(req, &prefix, runtime),
+                );
+                ctx.out = std::mem::take(&mut ctx.out).with_message_prefix(prefix.as_str());
+                if !prefix.is_empty() {
+                    ctx.set_meta(PREFIX_META, &prefix);
+                }
+                ctx.out.write_open();
+
+                if runtime.maintenance() {
+                    log_info!("closing connection opening during maintenance");
+                    ctx.out.write_close(maintenance::CLOSE_TRY_AGAIN_LATER);
+                    ctx.closed = true;
+                    break;
+                }
+
+                let origin = req.get_header_str("Origin");
+                if !origin_allowed(origin, runtime) {
+                    log_info!("closing connection from disallowed origin {origin:?}");
+                    ctx.out.write_close(CLOSE_ORIGIN_NOT_ALLOWED);
+                    ctx.closed = true;
+                    break;
+                }
+
+                let offered = offered_protocols(req);
+                match negotiate(&offered, &handler.protocols()) {
+                    Ok(Some(protocol)) => {
+                        resp.set_header(PROTOCOL_HEADER, &protocol);
+                        ctx.set_meta(PROTOCOL_META, &protocol);
+                    }
+                    Ok(None) => {}
+                    Err(()) => {
+                        log_info!("closing connection offering unsupported protocols {offered:?}");
+                        ctx.out.write_close(CLOSE_PROTOCOL_ERROR);
+                        ctx.closed = true;
+                        break;
+                    }
+                }
+
+                if !ctx.connection_id.is_empty() {
+                    let own = channels::connection_channel(&ctx.connection_id);
+                    ctx.out.write_subscribe(&[own]);
+                }
+                ctx.out.write_subscribe(&[channels::ALL_CHANNEL]);
+
+                handler.on_open(&mut ctx);
+            }
+            WsEvent::Text(text) => {
+                if let Some(ack) = ack::parse(&text).filter(|_| handler.accepts_acks()) {
+                    ack::confirm(&mut ctx, &ack);
+                } else if let Some(token) =
+                    tokens::renewal_token(&text).filter(|_| ctx.session().token_exp.is_some())
+                {
+                    if !ctx.renew_credentials(&token) {
+                        ctx.out.write_close(CLOSE_TOKEN_EXPIRED);
+                        ctx.closed = true;
+                        break;
+                    }
+                } else {
+                    handler.on_text(&mut ctx, text);
+                }
+            }
+            WsEvent::Binary(data) => handler.on_binary(&mut ctx, data),
+            WsEvent::Ping(data) => {
+                if handler.reply_to_ping() {
+                    ctx.out.write_pong(&data);
+                }
+            }
+            WsEvent::Pong(_) => {
+                // answers to keep-alive pings show the client is still there
+                ctx.alive = true;
+            }
+            WsEvent::Close(code) => {
+                handler.on_close(&mut ctx, code);
+                ctx.closed = true;
+            }
+            WsEvent::Disconnect => {
+                handler.on_disconnect(&mut ctx);
+                ctx.closed = true;
+            }
+        }
+
+        // nothing is sent on a closed connection
+        if ctx.closed {
+            break;
+        }
+    }
+
+    if events.get_ref().limit() == 0 {
+        return Err(BodyError::TooLarge(limits.max_body).into());
+    }
+
+    if !ctx.closed {
+        check_credentials(&mut ctx, handler);
+    }
+
+    if !ctx.closed {
+        if let Some(control) = handler.keep_alive() {
+            update_keep_alive(&mut ctx, &control);
+        }
+    }
+
+    ctx.finish();
+
+    for (name, value) in &ctx.set_meta {
+        resp.set_header(format!("{SET_META_PREFIX}{name}"), value);
+    }
+
+    Ok(Served::Events(resp, ctx.out.into_bytes()))
+}
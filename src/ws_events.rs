@@ -0,0 +1,435 @@
+//! WebSocket-over-HTTP event parsing.
+//!
+//! Fanout relays WebSocket activity to the origin as HTTP requests whose
+//! bodies are a sequence of events, each a type name optionally followed by a
+//! hex content length, a CRLF, the content and a trailing CRLF:
+//!
+//! ```text
+//! OPEN\r\n
+//! TEXT 05\r\nhello\r\n
+//! CLOSE\r\n
+//! ```
+//!
+//! A response may carry no events at all, e.g. to answer a keep-alive
+//! request with nothing to send, see [`empty_response`].
+
+use std::fmt;
+use std::io::{BufRead, Read};
+
+use fastly::http::StatusCode;
+use fastly::Response;
+
+use crate::config;
+use crate::grip::GripControl;
+
+/// Content type of WebSocket-over-HTTP request and response bodies.
+pub const CONTENT_TYPE: &str = "application/websocket-events";
+
+/// Setting that, when `false`, stops handlers answering PING events with
+/// PONGs, for services relying on GRIP keep-alives to hold connections open.
+pub const PING_REPLY_SETTING: &str = "ws_ping_reply";
+
+/// Returns whether handlers should answer PING events. Defaults to `true`.
+pub fn ping_reply_enabled() -> bool {
+    config::setting(PING_REPLY_SETTING).as_deref() != Some("false")
+}
+
+/// A single WebSocket-over-HTTP event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WsEvent {
+    Open,
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// Connection closed, with the WebSocket close code if one was given.
+    Close(Option<u16>),
+    Disconnect,
+}
+
+impl WsEvent {
+    /// Returns the event's type name as used on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WsEvent::Open => "OPEN",
+            WsEvent::Text(_) => "TEXT",
+            WsEvent::Binary(_) => "BINARY",
+            WsEvent::Ping(_) => "PING",
+            WsEvent::Pong(_) => "PONG",
+            WsEvent::Close(_) => "CLOSE",
+            WsEvent::Disconnect => "DISCONNECT",
+        }
+    }
+}
+
+/// Errors produced when a request body is not a valid event stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The event type name is not one we know about.
+    UnknownType(String),
+    /// The content length is not valid hex.
+    BadLength(String),
+    /// An event line or its content runs past the end of the body.
+    Truncated,
+    /// Event content is not followed by a CRLF.
+    MissingCrlf,
+    /// TEXT content is not valid UTF-8.
+    InvalidUtf8,
+    /// An event that can't carry content was given some.
+    UnexpectedContent(String),
+    /// CLOSE content is too short to hold a close code.
+    BadCloseCode,
+    /// Event content is longer than allowed.
+    TooLarge(usize),
+    /// The body could not be read.
+    Io(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownType(t) => write!(f, "unknown event type: {t}"),
+            ParseError::BadLength(l) => write!(f, "invalid content length: {l}"),
+            ParseError::Truncated => write!(f, "truncated event"),
+            ParseError::MissingCrlf => write!(f, "event content not terminated by CRLF"),
+            ParseError::InvalidUtf8 => write!(f, "TEXT content is not valid UTF-8"),
+            ParseError::UnexpectedContent(t) => write!(f, "{t} event cannot have content"),
+            ParseError::BadCloseCode => write!(f, "CLOSE content is not a close code"),
+            ParseError::TooLarge(len) => write!(f, "event content of {len} bytes is too large"),
+            ParseError::Io(e) => write!(f, "failed to read body: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Longest event line read, enough for any type name and content length.
+const MAX_LINE_LEN: u64 = 64;
+
+/// Reads the events of a WebSocket-over-HTTP request body one at a time,
+/// so the body doesn't have to be held in memory whole.
+///
+/// ```
+/// # use fanout_io_fastly_app::ws_events::{EventReader, WsEvent};
+/// let body = &b"OPEN\r\nTEXT 05\r\nhello\r\n"[..];
+/// let mut events = EventReader::new(body);
+/// assert_eq!(events.next(), Some(Ok(WsEvent::Open)));
+/// assert_eq!(events.next(), Some(Ok(WsEvent::Text("hello".into()))));
+/// assert_eq!(events.next(), None);
+/// ```
+#[derive(Debug)]
+pub struct EventReader<R> {
+    reader: R,
+    max_content: usize,
+    done: bool,
+}
+
+impl<R: BufRead> EventReader<R> {
+    pub fn new(reader: R) -> Self {
+        EventReader {
+            reader,
+            max_content: usize::MAX,
+            done: false,
+        }
+    }
+
+    /// Limits the content length of events. Longer content isn't read, and
+    /// fails with [`ParseError::TooLarge`].
+    pub fn with_max_content(mut self, max_content: usize) -> Self {
+        self.max_content = max_content;
+        self
+    }
+
+    /// Returns the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    fn read_event(&mut self) -> Result<Option<WsEvent>, ParseError> {
+        let mut line = Vec::new();
+        let n = (&mut self.reader)
+            .take(MAX_LINE_LEN)
+            .read_until(b'\n', &mut line)
+            .map_err(|e| ParseError::Io(e.to_string()))?;
+        if n == 0 {
+            return Ok(None);
+        }
+
+        let line = line.strip_suffix(b"\r\n").ok_or(ParseError::Truncated)?;
+        let line = String::from_utf8_lossy(line);
+
+        let (name, len) = match line.split_once(' ') {
+            Some((name, len)) => {
+                let len = usize::from_str_radix(len, 16)
+                    .map_err(|_| ParseError::BadLength(len.to_string()))?;
+                (name, Some(len))
+            }
+            None => (line.as_ref(), None),
+        };
+
+        let content = match len {
+            Some(len) if len > self.max_content => return Err(ParseError::TooLarge(len)),
+            Some(len) => {
+                let mut content = Vec::new();
+                (&mut self.reader)
+                    .take((len as u64).saturating_add(2))
+                    .read_to_end(&mut content)
+                    .map_err(|e| ParseError::Io(e.to_string()))?;
+                if len.checked_add(2) != Some(content.len()) {
+                    return Err(ParseError::Truncated);
+                }
+                if content.split_off(len) != b"\r\n" {
+                    return Err(ParseError::MissingCrlf);
+                }
+                content
+            }
+            None => Vec::new(),
+        };
+
+        to_event(name, len.is_some(), content).map(Some)
+    }
+}
+
+impl<R: BufRead> Iterator for EventReader<R> {
+    type Item = Result<WsEvent, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.read_event() {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Parses a complete WebSocket-over-HTTP request body into its events.
+pub fn parse_events(body: &[u8]) -> Result<Vec<WsEvent>, ParseError> {
+    EventReader::new(body).collect()
+}
+
+fn to_event(name: &str, has_content: bool, content: Vec<u8>) -> Result<WsEvent, ParseError> {
+    let no_content = |event: WsEvent| {
+        if has_content {
+            Err(ParseError::UnexpectedContent(name.to_string()))
+        } else {
+            Ok(event)
+        }
+    };
+
+    let event = match name {
+        "OPEN" => no_content(WsEvent::Open)?,
+        "DISCONNECT" => no_content(WsEvent::Disconnect)?,
+        "TEXT" => WsEvent::Text(String::from_utf8(content).map_err(|_| ParseError::InvalidUtf8)?),
+        "BINARY" => WsEvent::Binary(content),
+        "PING" => WsEvent::Ping(content),
+        "PONG" => WsEvent::Pong(content),
+        "CLOSE" => match content.as_slice() {
+            [] => WsEvent::Close(None),
+            [hi, lo, ..] => WsEvent::Close(Some(u16::from_be_bytes([*hi, *lo]))),
+            _ => return Err(ParseError::BadCloseCode),
+        },
+        _ => return Err(ParseError::UnknownType(name.to_string())),
+    };
+
+    Ok(event)
+}
+
+/// Serializes events into a WebSocket-over-HTTP response body.
+///
+/// ```
+/// # use fanout_io_fastly_app::ws_events::WsEventWriter;
+/// let mut w = WsEventWriter::new();
+/// w.write_open().write_text("hello");
+/// assert_eq!(w.into_bytes(), b"OPEN\r\nTEXT 05\r\nhello\r\n");
+/// ```
+///
+/// Messages written with [`WsEventWriter::write_text`] start with the
+/// GRIP message prefix, if one was negotiated, which Fanout strips before
+/// sending them on. Control messages never carry it.
+#[derive(Debug, Default, Clone)]
+pub struct WsEventWriter {
+    buf: Vec<u8>,
+    message_prefix: String,
+}
+
+impl WsEventWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the prefix of TEXT messages for the client.
+    pub fn with_message_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.message_prefix = prefix.into();
+        self
+    }
+
+    /// Appends an arbitrary event.
+    pub fn write_event(&mut self, event: &WsEvent) -> &mut Self {
+        match event {
+            WsEvent::Open => self.write_bare("OPEN"),
+            WsEvent::Text(s) => self.write_content("TEXT", s.as_bytes()),
+            WsEvent::Binary(b) => self.write_content("BINARY", b),
+            WsEvent::Ping(b) => self.write_content("PING", b),
+            WsEvent::Pong(b) => self.write_content("PONG", b),
+            WsEvent::Close(None) => self.write_bare("CLOSE"),
+            WsEvent::Close(Some(code)) => self.write_close(*code),
+            WsEvent::Disconnect => self.write_bare("DISCONNECT"),
+        }
+    }
+
+    pub fn write_open(&mut self) -> &mut Self {
+        self.write_event(&WsEvent::Open)
+    }
+
+    /// Appends a TEXT message for the client, after the message prefix.
+    pub fn write_text(&mut self, msg: &str) -> &mut Self {
+        if self.message_prefix.is_empty() {
+            return self.write_content("TEXT", msg.as_bytes());
+        }
+
+        let content = format!("{}{}", self.message_prefix, msg);
+        self.write_content("TEXT", content.as_bytes())
+    }
+
+    pub fn write_binary(&mut self, data: &[u8]) -> &mut Self {
+        self.write_content("BINARY", data)
+    }
+
+    pub fn write_ping(&mut self, data: &[u8]) -> &mut Self {
+        self.write_content("PING", data)
+    }
+
+    pub fn write_pong(&mut self, data: &[u8]) -> &mut Self {
+        self.write_content("PONG", data)
+    }
+
+    /// Appends a CLOSE event carrying the given WebSocket close code.
+    pub fn write_close(&mut self, code: u16) -> &mut Self {
+        self.write_content("CLOSE", &code.to_be_bytes())
+    }
+
+    pub fn write_disconnect(&mut self) -> &mut Self {
+        self.write_event(&WsEvent::Disconnect)
+    }
+
+    /// Appends a GRIP control message.
+    pub fn write_control(&mut self, control: &GripControl) -> &mut Self {
+        self.write_event(&control.to_ws_event())
+    }
+
+    /// Appends a `detach` control message: Fanout stops forwarding the
+    /// client's events to the origin, and the connection lives on only to
+    /// receive what is published to its channels, until the client closes
+    /// it.
+    pub fn write_detach(&mut self) -> &mut Self {
+        self.write_control(&GripControl::Detach)
+    }
+
+    /// Schedules a TEXT message for Fanout to send to the client after
+    /// `timeout` seconds, replacing any message scheduled before. Writing
+    /// it again on each client event gives a debounce: the message only
+    /// goes out once the client has been quiet for `timeout` seconds.
+    ///
+    /// ```
+    /// # use fanout_io_fastly_app::ws_events::WsEventWriter;
+    /// let mut w = WsEventWriter::new();
+    /// w.write_send_delayed(r#"{"type": "stopped-typing"}"#, 3);
+    /// ```
+    pub fn write_send_delayed(&mut self, content: &str, timeout: u32) -> &mut Self {
+        self.write_control(&GripControl::send_delayed_text(content, timeout))
+    }
+
+    /// Schedules a BINARY message, see [`WsEventWriter::write_send_delayed`].
+    pub fn write_send_delayed_binary(&mut self, content: &[u8], timeout: u32) -> &mut Self {
+        self.write_control(&GripControl::send_delayed_binary(content, timeout))
+    }
+
+    /// Cancels the message scheduled with `send-delayed`, if it hasn't been
+    /// sent yet.
+    pub fn write_cancel_send_delayed(&mut self) -> &mut Self {
+        self.write_control(&GripControl::CancelSendDelayed)
+    }
+
+    /// Appends a subscribe control message for each of the channels.
+    pub fn write_subscribe<S: AsRef<str>>(&mut self, channels: &[S]) -> &mut Self {
+        self.write_subscribe_filtered(channels, &[])
+    }
+
+    /// Appends a subscribe control message for each of the channels,
+    /// applying `filters` to what is published on them.
+    pub fn write_subscribe_filtered<S: AsRef<str>>(
+        &mut self,
+        channels: &[S],
+        filters: &[&str],
+    ) -> &mut Self {
+        for channel in channels {
+            self.write_control(&GripControl::Subscribe {
+                channel: channel.as_ref().to_string(),
+                filters: filters.iter().map(|f| f.to_string()).collect(),
+            });
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn write_bare(&mut self, name: &str) -> &mut Self {
+        self.buf.extend(name.as_bytes());
+        self.buf.extend(b"\r\n");
+        self
+    }
+
+    fn write_content(&mut self, name: &str, content: &[u8]) -> &mut Self {
+        self.buf
+            .extend(format!("{} {:02x}\r\n", name, content.len()).as_bytes());
+        self.buf.extend(content);
+        self.buf.extend(b"\r\n");
+        self
+    }
+}
+
+/// Returns a WebSocket-over-HTTP response carrying no events yet, which
+/// is valid as it is.
+pub fn empty_response() -> Response {
+    Response::from_status(StatusCode::OK).with_header("Content-Type", CONTENT_TYPE)
+}
+
+/// Returns a WebSocket-over-HTTP formatted TEXT message
+pub fn ws_text(msg: &str) -> Vec<u8> {
+    let mut w = WsEventWriter::new();
+    w.write_text(msg);
+    w.into_bytes()
+}
+
+/// Returns a WebSocket-over-HTTP formatted BINARY message
+pub fn ws_binary(bytes: &[u8]) -> Vec<u8> {
+    let mut w = WsEventWriter::new();
+    w.write_binary(bytes);
+    w.into_bytes()
+}
+
+/// Returns channel-subscription commands in a WebSocket-over-HTTP format
+pub fn ws_sub<S: AsRef<str>>(channels: &[S]) -> Vec<u8> {
+    let mut w = WsEventWriter::new();
+    w.write_subscribe(channels);
+    w.into_bytes()
+}
+
+#[cfg(test)]
+mod tests;
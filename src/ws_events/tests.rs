@@ -0,0 +1,441 @@
+//! WebSocket-over-HTTP protocol tests.
+//!
+//! Each fixture under `tests/fixtures/ws-over-http/` pairs a request body,
+//! as Fanout would send it, with the exact response body expected for it.
+//! Requests are served by [`ws::respond`], the way [`ws::serve`] serves
+//! them, with a handler echoing messages and keeping connections alive
+//! with pings, and a stand-in for the runtime. Along with the handler's
+//! echoes, responses carry what the app adds: OPEN is answered with
+//! subscriptions to the connection's own channel and to `*`, the keep-alive
+//! is set up, CLOSE codes are mirrored and nothing is sent after a CLOSE or
+//! DISCONNECT.
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, BufReader, Read};
+
+use fastly::{Request, Response};
+
+use super::{
+    empty_response, parse_events, EventReader, ParseError, WsEvent, WsEventWriter, CONTENT_TYPE,
+};
+use crate::connections::Hold;
+use crate::error::AppError;
+use crate::grip::{GripControl, MessageType};
+use crate::limits::BodyLimits;
+use crate::publish::Item;
+use crate::session::Session;
+use crate::tokens::{ChannelClaims, TokenError};
+use crate::ws::{
+    self, Served, WsContext, WsHandler, WsRuntime, CONNECTION_ID_HEADER, EXTENSIONS_HEADER,
+};
+
+/// Stands in for the runtime: no settings, the session it's given and
+/// publishes kept for inspection.
+#[derive(Default)]
+struct Runtime {
+    session: Session,
+    published: RefCell<Vec<Item>>,
+}
+
+impl WsRuntime for Runtime {
+    fn setting(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    fn maintenance(&self) -> bool {
+        false
+    }
+
+    fn load_session(&self, _connection_id: &str) -> Session {
+        self.session.clone()
+    }
+
+    fn save_session(&self, _connection_id: &str, _session: &Session) {}
+
+    fn delete_session(&self, _connection_id: &str) {}
+
+    fn join(&self, _channel: &str, _connection_id: &str) {}
+
+    fn leave(&self, _channel: &str, _connection_id: &str) {}
+
+    fn refresh_presence(&self, _channel: &str, _connection_id: &str) {}
+
+    fn touch(&self, _channels: &[&str]) {}
+
+    fn refresh_hold(&self, _hold: &Hold) {}
+
+    fn release_hold(&self, _hold: &Hold) {}
+
+    fn verify_token(&self, _token: &str) -> Result<Option<ChannelClaims>, TokenError> {
+        Ok(None)
+    }
+
+    fn publish(&self, item: Item) -> bool {
+        self.published.borrow_mut().push(item);
+        true
+    }
+}
+
+/// Echoes messages, keeps connections alive with pings and accepts acks.
+struct Echo;
+
+impl WsHandler for Echo {
+    fn on_text(&mut self, ctx: &mut WsContext, text: String) {
+        ctx.out.write_text(&text);
+    }
+
+    fn on_binary(&mut self, ctx: &mut WsContext, data: Vec<u8>) {
+        ctx.out.write_binary(&data);
+    }
+
+    fn on_disconnect(&mut self, _ctx: &mut WsContext) {}
+
+    fn keep_alive(&self) -> Option<GripControl> {
+        Some(GripControl::KeepAlive {
+            message_type: Some(MessageType::Ping),
+            content: String::new(),
+            format: None,
+            timeout: 20,
+        })
+    }
+
+    fn accepts_acks(&self) -> bool {
+        true
+    }
+
+    fn reply_to_ping(&self) -> bool {
+        true
+    }
+}
+
+fn request() -> Request {
+    Request::post("https://example.com/test/ws")
+        .with_header("Content-Type", CONTENT_TYPE)
+        .with_header(CONNECTION_ID_HEADER, "conn-1")
+}
+
+fn respond(
+    req: &Request,
+    body: impl BufRead,
+    limits: &BodyLimits,
+    runtime: &Runtime,
+) -> Result<(Response, Vec<u8>), AppError> {
+    match ws::respond(req, body, limits, &mut Echo, runtime)? {
+        Served::Events(resp, events) => Ok((resp, events)),
+        Served::Rejected(_) => panic!("connection rejected"),
+    }
+}
+
+/// Returns the response body to a request of the connection carrying
+/// `body`.
+fn serve(body: impl BufRead) -> Vec<u8> {
+    respond(
+        &request(),
+        body,
+        &BodyLimits::default(),
+        &Runtime::default(),
+    )
+    .unwrap()
+    .1
+}
+
+/// Reads its input a few bytes at a time, like a body arriving in chunks.
+struct Chunked<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl Read for Chunked<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.chunk.min(buf.len()).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+fn chunked(data: &[u8], chunk: usize) -> impl BufRead + '_ {
+    BufReader::with_capacity(chunk, Chunked { data, chunk })
+}
+
+macro_rules! fixture {
+    ($name:literal) => {
+        (
+            $name,
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/ws-over-http/",
+                $name,
+                ".request"
+            )),
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/ws-over-http/",
+                $name,
+                ".response"
+            )),
+        )
+    };
+}
+
+const FIXTURES: &[(&str, &[u8], &[u8])] = &[
+    fixture!("open"),
+    fixture!("mixed"),
+    fixture!("uppercase-hex"),
+    fixture!("close"),
+    fixture!("close-with-reason"),
+    fixture!("close-without-code"),
+    fixture!("disconnect"),
+    fixture!("crlf-in-content"),
+    fixture!("keep-alive"),
+];
+
+#[test]
+fn fixtures_get_exact_responses() {
+    for (name, request, response) in FIXTURES {
+        let got = serve(*request);
+        assert_eq!(
+            String::from_utf8_lossy(&got),
+            String::from_utf8_lossy(response),
+            "{name}"
+        );
+        assert_eq!(&got, response, "{name}");
+    }
+}
+
+#[test]
+fn fixtures_parse_the_same_in_chunks() {
+    for (name, request, response) in FIXTURES {
+        for chunk in [1, 2, 3, 7] {
+            let got = serve(chunked(request, chunk));
+            assert_eq!(&got, response, "{name} in {chunk} byte chunks");
+        }
+    }
+}
+
+#[test]
+fn parses_multiple_events_in_one_body() {
+    let body = b"OPEN\r\nTEXT 05\r\nhello\r\nBINARY 03\r\n\x01\x02\x03\r\nPING\r\nPONG 01\r\nx\r\nCLOSE 02\r\n\x03\xe8\r\n";
+
+    assert_eq!(
+        parse_events(body),
+        Ok(vec![
+            WsEvent::Open,
+            WsEvent::Text("hello".into()),
+            WsEvent::Binary(vec![1, 2, 3]),
+            WsEvent::Ping(Vec::new()),
+            WsEvent::Pong(b"x".to_vec()),
+            WsEvent::Close(Some(1000)),
+        ])
+    );
+}
+
+#[test]
+fn parses_lengths_in_either_case() {
+    let content = "a".repeat(0xab);
+    for length in ["ab", "AB", "aB", "00ab"] {
+        let body = format!("TEXT {length}\r\n{content}\r\n");
+        assert_eq!(
+            parse_events(body.as_bytes()),
+            Ok(vec![WsEvent::Text(content.clone())]),
+            "{length}"
+        );
+    }
+}
+
+#[test]
+fn parses_close_split_across_chunks() {
+    let body = b"CLOSE 02\r\n\x0f\xa0\r\n";
+    for chunk in 1..body.len() {
+        let events: Result<Vec<_>, _> = EventReader::new(chunked(body, chunk)).collect();
+        assert_eq!(events, Ok(vec![WsEvent::Close(Some(4000))]), "{chunk}");
+    }
+}
+
+#[test]
+fn counts_content_length_in_bytes() {
+    let body = "TEXT 06\r\nh\u{e9}\u{e9}!\r\n".as_bytes();
+    assert_eq!(
+        parse_events(body),
+        Ok(vec![WsEvent::Text("h\u{e9}\u{e9}!".into())])
+    );
+
+    let mut out = WsEventWriter::new();
+    out.write_text("h\u{e9}\u{e9}!");
+    assert_eq!(out.into_bytes(), body);
+}
+
+#[test]
+fn refuses_malformed_bodies() {
+    let cases: &[(&[u8], ParseError)] = &[
+        (b"OPEN", ParseError::Truncated),
+        (b"OPEN\n", ParseError::Truncated),
+        (b"TEXT 05\r\nhel", ParseError::Truncated),
+        (b"TEXT 05\r\nhello!!", ParseError::MissingCrlf),
+        (b"TEXT zz\r\nhello\r\n", ParseError::BadLength("zz".into())),
+        (b"TEXT -1\r\n\r\n", ParseError::BadLength("-1".into())),
+        (
+            b"SHOUT 02\r\nhi\r\n",
+            ParseError::UnknownType("SHOUT".into()),
+        ),
+        (
+            b"OPEN 00\r\n\r\n",
+            ParseError::UnexpectedContent("OPEN".into()),
+        ),
+        (b"TEXT 01\r\n\xff\r\n", ParseError::InvalidUtf8),
+        (b"CLOSE 01\r\n\x03\r\n", ParseError::BadCloseCode),
+    ];
+
+    for (body, error) in cases {
+        assert_eq!(
+            parse_events(body),
+            Err(error.clone()),
+            "{}",
+            String::from_utf8_lossy(body)
+        );
+    }
+}
+
+#[test]
+fn stops_at_the_first_error() {
+    let body = b"OPEN\r\nTEXT 02\r\nhi\r\nNOPE\r\nTEXT 02\r\nhi\r\n";
+    let events: Vec<_> = EventReader::new(&body[..]).collect();
+
+    assert_eq!(
+        events,
+        vec![
+            Ok(WsEvent::Open),
+            Ok(WsEvent::Text("hi".into())),
+            Err(ParseError::UnknownType("NOPE".into())),
+        ]
+    );
+    let served = respond(
+        &request(),
+        &body[..],
+        &BodyLimits::default(),
+        &Runtime::default(),
+    );
+    assert!(matches!(served, Err(AppError::ParseError(_))));
+}
+
+#[test]
+fn refuses_content_over_the_limit() {
+    let body = b"TEXT 02\r\nhi\r\nTEXT 0B\r\nhello world\r\n";
+    let events: Vec<_> = EventReader::new(&body[..]).with_max_content(10).collect();
+
+    assert_eq!(
+        events,
+        vec![
+            Ok(WsEvent::Text("hi".into())),
+            Err(ParseError::TooLarge(11))
+        ]
+    );
+}
+
+#[test]
+fn writes_message_prefix_on_text_only() {
+    let mut out = WsEventWriter::new().with_message_prefix("m:");
+    out.write_open()
+        .write_text("hi")
+        .write_binary(b"hi")
+        .write_close(1000);
+
+    assert_eq!(
+        out.into_bytes(),
+        b"OPEN\r\nTEXT 04\r\nm:hi\r\nBINARY 02\r\nhi\r\nCLOSE 02\r\n\x03\xe8\r\n"
+    );
+}
+
+#[test]
+fn empty_responses_carry_no_events() {
+    let resp = empty_response();
+    assert_eq!(resp.get_status().as_u16(), 200);
+    assert_eq!(resp.get_header_str("Content-Type"), Some(CONTENT_TYPE));
+
+    assert!(WsEventWriter::new().is_empty());
+    assert_eq!(parse_events(b"").unwrap(), Vec::new());
+}
+
+#[test]
+fn opens_with_grip_extension_and_keep_alive() {
+    let (resp, _) = respond(
+        &request(),
+        &b"OPEN\r\n"[..],
+        &BodyLimits::default(),
+        &Runtime::default(),
+    )
+    .unwrap();
+
+    assert_eq!(resp.get_header_str("Content-Type"), Some(CONTENT_TYPE));
+    assert_eq!(
+        resp.get_header_str(EXTENSIONS_HEADER),
+        Some("grip; message-prefix=\"\"")
+    );
+    assert!(resp.get_header("Set-Meta-ws-keep-alive").is_some());
+}
+
+#[test]
+fn sets_up_keep_alives_once() {
+    let (resp, events) = respond(
+        &request(),
+        &b"PING\r\n"[..],
+        &BodyLimits::default(),
+        &Runtime::default(),
+    )
+    .unwrap();
+    let digest = resp.get_header_str("Set-Meta-ws-keep-alive").unwrap();
+    assert!(String::from_utf8_lossy(&events).contains("keep-alive"));
+
+    // the connection remembers its keep-alive, so it isn't sent again
+    let req = request().with_header("Meta-ws-keep-alive", digest);
+    let (resp, events) = respond(
+        &req,
+        &b"PING\r\n"[..],
+        &BodyLimits::default(),
+        &Runtime::default(),
+    )
+    .unwrap();
+    assert!(resp.get_header("Set-Meta-ws-keep-alive").is_none());
+    assert!(!String::from_utf8_lossy(&events).contains("keep-alive"));
+}
+
+#[test]
+fn confirms_acks_instead_of_echoing_them() {
+    let runtime = Runtime {
+        session: Session {
+            channels: vec!["news".into()],
+            ..Session::default()
+        },
+        ..Runtime::default()
+    };
+    let ack = r#"{"type":"ack","id":"m-1","channel":"news"}"#;
+    let body = format!("TEXT {:x}\r\n{ack}\r\n", ack.len());
+
+    let (_, events) = respond(
+        &request(),
+        body.as_bytes(),
+        &BodyLimits::default(),
+        &runtime,
+    )
+    .unwrap();
+    assert!(!String::from_utf8_lossy(&events).contains("m-1"));
+
+    let published = runtime.published.borrow();
+    assert_eq!(published.len(), 1);
+    assert_eq!(published[0].channel, "news.acks");
+    let content = published[0].formats.ws_message.as_ref().unwrap();
+    assert!(content.content.as_ref().unwrap().contains("\"id\":\"m-1\""));
+}
+
+#[test]
+fn refuses_bodies_over_the_limit_by_length() {
+    let body = b"TEXT 05\r\nhello\r\n";
+    let req = request().with_header("Content-Length", body.len().to_string());
+    let limits = BodyLimits {
+        max_body: 10,
+        ..BodyLimits::default()
+    };
+
+    let served = respond(&req, &body[..], &limits, &Runtime::default());
+    assert!(matches!(served, Err(AppError::SizeError(_))));
+}